@@ -0,0 +1,539 @@
+#![windows_subsystem = "windows"]
+
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    path::Path,
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    thread,
+};
+
+use clap::Parser;
+use eframe::egui;
+use listenfd::ListenFd;
+use remote_uci::{
+    init_logging, install_panic_hook, make_server_with, open_in_browser,
+    uci::{Eval, UciIn, UciOut},
+    Extensions, Middleware, Opts,
+};
+use tokio::sync::{oneshot, watch};
+
+fn main() -> Result<(), eframe::Error> {
+    let mut logger = env_logger::Builder::from_env(
+        env_logger::Env::new()
+            .filter("REMOTE_UCI_LOG")
+            .default_filter_or("info"),
+    );
+    logger.format_target(false).format_module_path(false);
+    init_logging(logger);
+    install_panic_hook(env::temp_dir());
+
+    eframe::run_native(
+        "External engine for Lichess",
+        eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default().with_inner_size([480.0, 560.0]),
+            ..eframe::NativeOptions::default()
+        },
+        Box::new(|_cc| Box::new(Gui::new())),
+    )
+}
+
+/// Port of the first provider. Further providers use the next free ports.
+const FIRST_PORT: u16 = 9670;
+
+enum State {
+    Stopped,
+    Starting,
+    Running { url: String },
+    Failed(String),
+}
+
+/// Sent from the thread that runs the provider.
+enum Event {
+    Started { url: String },
+    Stopped(Result<(), String>),
+}
+
+/// What the engine is currently analysing, for display.
+#[derive(Default)]
+struct Analysis {
+    position: Option<String>,
+    depth: Option<u32>,
+    eval: Option<Eval>,
+    pv: Option<String>,
+    nps: Option<u64>,
+}
+
+/// Watches commands between clients and the engine, without changing them.
+struct Observer {
+    analysis: Arc<Mutex<Analysis>>,
+    ctx: egui::Context,
+}
+
+impl Middleware for Observer {
+    fn client_command(&self, command: UciIn) -> Option<UciIn> {
+        if let UciIn::Position { ref fen, ref moves } = command {
+            let position = match fen {
+                Some(fen) => fen.to_string(),
+                None => "Starting position".to_owned(),
+            };
+            *self.analysis.lock().unwrap_or_else(PoisonError::into_inner) = Analysis {
+                position: Some(match moves.len() {
+                    0 => position,
+                    1 => format!("{position}, after 1 move"),
+                    n => format!("{position}, after {n} moves"),
+                }),
+                ..Analysis::default()
+            };
+            self.ctx.request_repaint();
+        }
+        Some(command)
+    }
+
+    fn engine_command(&self, command: UciOut) -> Option<UciOut> {
+        if let UciOut::Info {
+            multipv,
+            depth,
+            score: Some(ref score),
+            ref pv,
+            nps,
+            ..
+        } = command
+        {
+            if multipv.map_or(true, |n| n.get() == 1) {
+                let mut analysis = self.analysis.lock().unwrap_or_else(PoisonError::into_inner);
+                analysis.depth = depth;
+                analysis.eval = Some(score.eval.clone());
+                analysis.pv = pv.as_ref().map(|pv| {
+                    pv.iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                });
+                analysis.nps = nps.or(analysis.nps);
+                self.ctx.request_repaint();
+            }
+        }
+        Some(command)
+    }
+}
+
+/// One engine, served on its own port.
+struct Provider {
+    engine: String,
+    threads: u32,
+    hash: u32,
+    port: u16,
+    state: State,
+    events: Option<mpsc::Receiver<Event>>,
+    stop: Option<oneshot::Sender<()>>,
+    pause: Option<watch::Sender<bool>>,
+    analysis: Arc<Mutex<Analysis>>,
+}
+
+impl Provider {
+    fn new(port: u16, threads: u32) -> Provider {
+        Provider {
+            engine: String::new(),
+            threads,
+            hash: 256,
+            port,
+            state: State::Stopped,
+            events: None,
+            stop: None,
+            pause: None,
+            analysis: Arc::new(Mutex::new(Analysis::default())),
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        matches!(self.state, State::Stopped | State::Failed(_))
+    }
+
+    fn title(&self) -> String {
+        let name = Path::new(&self.engine)
+            .file_name()
+            .map_or_else(|| "New engine".into(), |name| name.to_string_lossy());
+        format!("{name} (port {})", self.port)
+    }
+
+    /// Command line of the provider, as if started from a terminal.
+    fn args(&self) -> [OsString; 9] {
+        [
+            "remote-uci".to_owned(),
+            "--engine".to_owned(),
+            self.engine.clone(),
+            "--bind".to_owned(),
+            format!("127.0.0.1:{}", self.port),
+            "--max-threads".to_owned(),
+            self.threads.to_string(),
+            "--max-hash".to_owned(),
+            self.hash.to_string(),
+        ]
+        .map(OsString::from)
+    }
+
+    fn connect(&mut self, ctx: &egui::Context) {
+        let args = self.args();
+        let opts = match Opts::try_parse_from(&args)
+            .map_err(|err| err.to_string())
+            .and_then(|mut opts| {
+                opts.complete(&args).map_err(|err| err.to_string())?;
+                Ok(opts)
+            }) {
+            Ok(opts) => opts,
+            Err(err) => {
+                self.state = State::Failed(err);
+                return;
+            }
+        };
+        *self.analysis.lock().unwrap_or_else(PoisonError::into_inner) = Analysis::default();
+        let observer = Arc::new(Observer {
+            analysis: Arc::clone(&self.analysis),
+            ctx: ctx.clone(),
+        });
+        let (events_tx, events_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let (pause_tx, pause_rx) = watch::channel(false);
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let extensions = Extensions {
+                middleware: vec![observer],
+                pause: Some(pause_rx),
+                ..Extensions::default()
+            };
+            let res = serve(opts, extensions, &events_tx, stop_rx, &ctx);
+            let _ = events_tx.send(Event::Stopped(res.map_err(|err| err.to_string())));
+            ctx.request_repaint();
+        });
+        self.events = Some(events_rx);
+        self.stop = Some(stop_tx);
+        self.pause = Some(pause_tx);
+        self.state = State::Starting;
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let events = match self.events {
+            Some(ref events) => events.try_iter().collect::<Vec<_>>(),
+            None => return,
+        };
+        for event in events {
+            match event {
+                Event::Started { url } => {
+                    open_in_browser(&url);
+                    self.state = State::Running { url };
+                }
+                Event::Stopped(res) => {
+                    self.events = None;
+                    self.stop = None;
+                    self.pause = None;
+                    self.state = match res {
+                        Ok(()) => State::Stopped,
+                        Err(err) => State::Failed(err),
+                    };
+                }
+            }
+        }
+    }
+
+    fn settings(&mut self, ui: &mut egui::Ui, max_threads: u32) {
+        egui::Grid::new("settings")
+            .num_columns(2)
+            .spacing([12.0, 8.0])
+            .show(ui, |ui| {
+                ui.label("Engine");
+                ui.horizontal(|ui| {
+                    if ui.button("Browse ...").clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .set_title("Choose the engine executable")
+                            .pick_file()
+                        {
+                            self.engine = path.display().to_string();
+                        }
+                    }
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.engine)
+                            .hint_text("Drop the engine executable here")
+                            .desired_width(f32::INFINITY),
+                    );
+                });
+                ui.end_row();
+
+                ui.label("Threads");
+                ui.add(egui::Slider::new(&mut self.threads, 1..=max_threads));
+                ui.end_row();
+
+                ui.label("Hash");
+                ui.add(
+                    egui::Slider::new(&mut self.hash, 16..=65536)
+                        .logarithmic(true)
+                        .suffix(" MiB"),
+                );
+                ui.end_row();
+
+                ui.label("Port");
+                ui.add(egui::DragValue::new(&mut self.port).clamp_range(1024..=65535));
+                ui.end_row();
+            });
+    }
+
+    fn analysis(&self, ui: &mut egui::Ui) {
+        let analysis = self.analysis.lock().unwrap_or_else(PoisonError::into_inner);
+        let position = match analysis.position {
+            Some(ref position) => position,
+            None => {
+                ui.label("Waiting for Lichess to start an analysis ...");
+                return;
+            }
+        };
+        egui::Grid::new("analysis")
+            .num_columns(2)
+            .spacing([12.0, 8.0])
+            .show(ui, |ui| {
+                ui.label("Position");
+                ui.add(egui::Label::new(position.as_str()).wrap(true));
+                ui.end_row();
+
+                ui.label("Depth");
+                ui.label(analysis.depth.map_or("-".to_owned(), |d| d.to_string()));
+                ui.end_row();
+
+                ui.label("Evaluation");
+                ui.label(analysis.eval.as_ref().map_or("-".to_owned(), format_eval));
+                ui.end_row();
+
+                ui.label("Speed");
+                ui.label(
+                    analysis
+                        .nps
+                        .map_or("-".to_owned(), |nps| format!("{} kN/s", nps / 1000)),
+                );
+                ui.end_row();
+
+                ui.label("Best line");
+                ui.add(egui::Label::new(analysis.pv.as_deref().unwrap_or("-")).wrap(true));
+                ui.end_row();
+            });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, max_threads: u32) {
+        let stopped = self.is_stopped();
+        ui.add_enabled_ui(stopped, |ui| self.settings(ui, max_threads));
+        ui.add_space(12.0);
+
+        let big = |text: &str| egui::Button::new(egui::RichText::new(text).size(20.0));
+        let size = [ui.available_width(), 48.0];
+        match self.state {
+            State::Stopped | State::Failed(_) => {
+                let ready = !self.engine.trim().is_empty();
+                if ui
+                    .add_enabled_ui(ready, |ui| ui.add_sized(size, big("Connect to Lichess")))
+                    .inner
+                    .clicked()
+                {
+                    self.connect(ctx);
+                }
+            }
+            State::Starting => {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Starting engine ...");
+                });
+            }
+            State::Running { ref url } => {
+                let url = url.clone();
+                if ui.add_sized(size, big("Disconnect")).clicked() {
+                    self.disconnect();
+                }
+                ui.horizontal(|ui| {
+                    if let Some(ref pause) = self.pause {
+                        let paused = *pause.borrow();
+                        if ui.button(if paused { "Resume" } else { "Pause" }).clicked() {
+                            pause.send_replace(!paused);
+                        }
+                    }
+                    ui.hyperlink_to("Open Lichess again", &url);
+                    if ui.button("Copy link").clicked() {
+                        ctx.copy_text(url);
+                    }
+                });
+                if self.pause.as_ref().map_or(false, |pause| *pause.borrow()) {
+                    ui.label("Paused. Lichess cannot use this engine until resumed.");
+                }
+            }
+        }
+        if let State::Failed(ref err) = self.state {
+            ui.colored_label(ui.visuals().error_fg_color, err);
+        }
+
+        if matches!(self.state, State::Running { .. }) {
+            ui.add_space(12.0);
+            ui.separator();
+            self.analysis(ui);
+        }
+    }
+}
+
+/// Several providers, e.g. for different engines, each on its own port.
+struct Gui {
+    providers: Vec<Provider>,
+    max_threads: u32,
+}
+
+impl Gui {
+    fn new() -> Gui {
+        let max_threads = thread::available_parallelism()
+            .map_or(1, |n| u32::try_from(n.get()).unwrap_or(u32::MAX));
+        Gui {
+            providers: vec![Provider::new(FIRST_PORT, (max_threads / 2).max(1))],
+            max_threads,
+        }
+    }
+
+    fn add_provider(&mut self) {
+        let port = next_port(&self.providers);
+        self.providers
+            .push(Provider::new(port, (self.max_threads / 2).max(1)));
+    }
+}
+
+impl eframe::App for Gui {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        for provider in &mut self.providers {
+            provider.handle_events();
+        }
+
+        // Dropped engines go to the first provider that is waiting for one.
+        if let Some(path) = ctx.input(|i| i.raw.dropped_files.iter().find_map(|f| f.path.clone())) {
+            if let Some(provider) = self
+                .providers
+                .iter_mut()
+                .filter(|p| p.is_stopped())
+                .min_by_key(|p| !p.engine.trim().is_empty())
+            {
+                provider.engine = path.display().to_string();
+            }
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("External engine for Lichess");
+            ui.add_space(12.0);
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let single = self.providers.len() == 1;
+                let mut removed = None;
+                for (i, provider) in self.providers.iter_mut().enumerate() {
+                    if single {
+                        provider.ui(ui, ctx, self.max_threads);
+                        continue;
+                    }
+                    egui::CollapsingHeader::new(provider.title())
+                        .id_source(i)
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            ui.push_id(i, |ui| provider.ui(ui, ctx, self.max_threads));
+                            if provider.is_stopped() && ui.button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                }
+                if let Some(i) = removed {
+                    self.providers.remove(i);
+                }
+
+                ui.add_space(12.0);
+                if ui.button("Add another engine").clicked() {
+                    self.add_provider();
+                }
+            });
+        });
+    }
+}
+
+/// First port that is not taken by one of the providers.
+fn next_port(providers: &[Provider]) -> u16 {
+    (FIRST_PORT..)
+        .find(|port| providers.iter().all(|p| p.port != *port))
+        .unwrap_or(FIRST_PORT)
+}
+
+fn format_eval(eval: &Eval) -> String {
+    match *eval {
+        Eval::Cp(cp) => format!("{:+.2}", cp as f64 / 100.0),
+        Eval::Mate(mate) => format!("#{mate}"),
+    }
+}
+
+/// Run the provider until stopped, from a dedicated thread, so that the
+/// interface stays responsive.
+fn serve(
+    opts: Opts,
+    extensions: Extensions,
+    events: &mpsc::Sender<Event>,
+    stop: oneshot::Receiver<()>,
+    ctx: &egui::Context,
+) -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let (specs, server, mut shutdown, _sockets) =
+            make_server_with(opts, ListenFd::empty(), extensions).await?;
+        if let Some(spec) = specs.first() {
+            let _ = events.send(Event::Started {
+                url: spec.registration_url(),
+            });
+            ctx.request_repaint();
+        }
+        let mut stopped = Ok(());
+        server
+            .with_graceful_shutdown(async {
+                tokio::select! {
+                    _ = stop => (),
+                    res = shutdown.wait() => stopped = res,
+                }
+            })
+            .await?;
+        Ok(stopped?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let mut provider = Provider::new(9671, 4);
+        provider.engine = "/usr/games/stockfish".to_owned();
+        let args = provider.args();
+        assert!(Opts::try_parse_from(&args).is_ok());
+        assert_eq!(args[4], "127.0.0.1:9671");
+        assert_eq!(args[6], "4");
+        assert_eq!(args[8], "256");
+    }
+
+    #[test]
+    fn test_next_port() {
+        assert_eq!(next_port(&[]), FIRST_PORT);
+        let providers = [
+            Provider::new(FIRST_PORT, 1),
+            Provider::new(FIRST_PORT + 2, 1),
+        ];
+        assert_eq!(next_port(&providers), FIRST_PORT + 1);
+    }
+
+    #[test]
+    fn test_format_eval() {
+        assert_eq!(format_eval(&Eval::Cp(35)), "+0.35");
+        assert_eq!(format_eval(&Eval::Cp(-120)), "-1.20");
+        assert_eq!(format_eval(&Eval::Mate(-3)), "#-3");
+    }
+}
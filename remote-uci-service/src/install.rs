@@ -0,0 +1,184 @@
+use std::{
+    env,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use clap::{ArgEnum, Parser};
+use windows_service::{
+    service::{
+        ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceState,
+        ServiceType,
+    },
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::SERVICE_NAME;
+
+/// Well-known SIDs, so that icacls does not depend on the system language.
+const SID_LOCAL_SERVICE: &str = "*S-1-5-19";
+const SID_LOCAL_SYSTEM: &str = "*S-1-5-18";
+const SID_ADMINISTRATORS: &str = "*S-1-5-32-544";
+
+#[derive(Debug, Parser)]
+#[clap(version)]
+pub enum Manage {
+    /// Install the service, or reconfigure it if it already exists, and
+    /// start it.
+    Install(InstallOpts),
+    /// Stop and remove the service.
+    Uninstall,
+}
+
+#[derive(Debug, Parser)]
+pub struct InstallOpts {
+    /// Account to run the service as.
+    #[clap(long, arg_enum)]
+    account: Account,
+    /// Directory for the secret file and the log. Defaults to
+    /// %LOCALAPPDATA%\External Engine for --account user, and must be given
+    /// for --account local-service.
+    #[clap(long, required_if_eq("account", "local-service"))]
+    data_dir: Option<PathBuf>,
+    /// Arguments for the provider, like --engine.
+    #[clap(last = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ArgEnum)]
+enum Account {
+    /// The current user, with access to their engine files and network
+    /// profile.
+    User,
+    /// The unprivileged LocalService account.
+    LocalService,
+}
+
+pub fn run(manage: Manage) -> Result<(), Box<dyn Error>> {
+    match manage {
+        Manage::Install(opts) => install(opts),
+        Manage::Uninstall => uninstall(),
+    }
+}
+
+fn install(opts: InstallOpts) -> Result<(), Box<dyn Error>> {
+    let (account_name, account_password, owner) = match opts.account {
+        Account::User => {
+            let user = format!(
+                "{}\\{}",
+                env::var("USERDOMAIN").unwrap_or_else(|_| ".".to_owned()),
+                env::var("USERNAME")?
+            );
+            let password = match env::var("REMOTE_UCI_SERVICE_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => rpassword::prompt_password(format!("Password for {user}: "))?,
+            };
+            (Some(user.clone()), Some(password), user)
+        }
+        Account::LocalService => (
+            Some("NT AUTHORITY\\LocalService".to_owned()),
+            None,
+            SID_LOCAL_SERVICE.to_owned(),
+        ),
+    };
+
+    let data_dir = match opts.data_dir {
+        Some(data_dir) => data_dir,
+        None => PathBuf::from(env::var_os("LOCALAPPDATA").ok_or("LOCALAPPDATA not set")?)
+            .join("External Engine"),
+    };
+    let data_dir = env::current_dir()?.join(data_dir);
+    fs::create_dir_all(&data_dir)?;
+    restrict_access(&data_dir, &owner)?;
+
+    let mut launch_arguments = vec![OsString::from("--data-dir"), data_dir.into_os_string()];
+    if !opts.args.iter().any(|arg| arg == "--secret-file") {
+        launch_arguments.push("--secret-file".into());
+        launch_arguments.push("remote-uci.secret".into());
+    }
+    launch_arguments.extend(opts.args);
+
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: SERVICE_NAME.into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: account_name.map(OsString::from),
+        account_password: account_password.map(OsString::from),
+    };
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let access = ServiceAccess::QUERY_STATUS
+        | ServiceAccess::CHANGE_CONFIG
+        | ServiceAccess::START
+        | ServiceAccess::STOP;
+    let service = match manager.open_service(SERVICE_NAME, access) {
+        Ok(service) => {
+            if service.query_status()?.current_state != ServiceState::Stopped {
+                service.stop()?;
+            }
+            service.change_config(&info)?;
+            println!("Reconfigured service {SERVICE_NAME:?}");
+            service
+        }
+        Err(_) => {
+            let service = manager.create_service(&info, access)?;
+            service.set_description("External UCI engine provider for lichess.org")?;
+            println!("Installed service {SERVICE_NAME:?}");
+            service
+        }
+    };
+
+    if let Err(err) = service.start(&[] as &[&OsStr]) {
+        if opts.account == Account::User {
+            eprintln!(
+                "Could not start the service. The account may need the \"Log on as a service\" \
+                 right, which can be granted in secpol.msc under Local Policies > User Rights \
+                 Assignment."
+            );
+        }
+        return Err(err.into());
+    }
+    println!("Started service {SERVICE_NAME:?}");
+    Ok(())
+}
+
+fn uninstall() -> Result<(), Box<dyn Error>> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    println!("Removed service {SERVICE_NAME:?}");
+    Ok(())
+}
+
+/// Let only the service account, SYSTEM and administrators access the data
+/// directory, because it contains the secret.
+fn restrict_access(dir: &Path, owner: &str) -> Result<(), Box<dyn Error>> {
+    let status = Command::new("icacls")
+        .arg(dir)
+        .args(["/inheritance:r", "/grant:r"])
+        .arg(format!("{owner}:(OI)(CI)M"))
+        .arg(format!("{SID_LOCAL_SYSTEM}:(OI)(CI)F"))
+        .arg(format!("{SID_ADMINISTRATORS}:(OI)(CI)F"))
+        .status()?;
+    if !status.success() {
+        return Err(format!("icacls failed to set permissions on {dir:?}: {status}").into());
+    }
+    Ok(())
+}
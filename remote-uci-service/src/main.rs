@@ -1,8 +1,19 @@
-use std::{error::Error, ffi::OsString, sync::Arc, time::Duration};
+mod install;
+
+use std::{
+    env,
+    error::Error,
+    ffi::{OsStr, OsString},
+    fs::{self, OpenOptions},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
+use install::Manage;
 use listenfd::ListenFd;
-use remote_uci::{make_server, Opts};
+use remote_uci::{init_logging, install_panic_hook, make_server, Opts};
 use tokio::sync::Notify;
 use windows_service::{
     define_windows_service,
@@ -14,10 +25,30 @@ use windows_service::{
     service_dispatcher,
 };
 
+/// Name of the service, as installed by the MSI or the install subcommand.
+const SERVICE_NAME: &str = "External Engine";
+
+/// Log in the data directory, and the previous one once it got too large.
+const LOG_FILE: &str = "remote-uci.log";
+const OLD_LOG_FILE: &str = "remote-uci.log.old";
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Parser)]
+struct ServiceOpts {
+    /// Directory for the log and relative paths like the secret file.
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    #[clap(flatten)]
+    opts: Opts,
+}
+
 define_windows_service!(ffi_service_main, service_main);
 
-fn main() -> Result<(), windows_service::Error> {
-    service_dispatcher::start("remote_uci", ffi_service_main)
+fn main() -> Result<(), Box<dyn Error>> {
+    match env::args_os().nth(1).as_deref().and_then(OsStr::to_str) {
+        Some("install" | "uninstall") => install::run(Manage::parse()),
+        _ => Ok(service_dispatcher::start("remote_uci", ffi_service_main)?),
+    }
 }
 
 fn service_status(state: ServiceState, wait_hint: Duration) -> ServiceStatus {
@@ -34,14 +65,62 @@ fn service_status(state: ServiceState, wait_hint: Duration) -> ServiceStatus {
 
 #[tokio::main(flavor = "current_thread")]
 async fn service_main(_args: Vec<OsString>) {
-    let _ = simple_logging::log_to_file("remote-uci.log", log::LevelFilter::Warn);
+    // Services start in the system directory, which is no place for the
+    // log or the secret.
+    let opts = ServiceOpts::try_parse();
+    if let Some(data_dir) = opts.as_ref().ok().and_then(|opts| opts.data_dir.as_ref()) {
+        let _ = env::set_current_dir(data_dir);
+    }
+    let opts = opts.map_err(Box::<dyn Error>::from).and_then(|mut opts| {
+        opts.opts.complete(&opts_args())?;
+        Ok(opts)
+    });
 
-    if let Err(err) = service_run().await {
+    // Keep the log across restarts of the service, but only the previous
+    // one once it gets large.
+    if fs::metadata(LOG_FILE).map_or(false, |meta| meta.len() > MAX_LOG_SIZE) {
+        let _ = fs::rename(LOG_FILE, OLD_LOG_FILE);
+    }
+    if let Ok(file) = OpenOptions::new().create(true).append(true).open(LOG_FILE) {
+        let mut logger = env_logger::Builder::new();
+        logger
+            .filter_level(log::LevelFilter::Warn)
+            .target(env_logger::Target::Pipe(Box::new(file)));
+        init_logging(logger);
+    }
+    install_panic_hook(
+        opts.as_ref()
+            .map_or_else(|_| env::temp_dir(), |opts| opts.opts.report_dir()),
+    );
+
+    let result = match opts {
+        Ok(opts) => service_run(opts.opts).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
         log::error!("Fatal error: {err}");
     }
 }
 
-async fn service_run() -> Result<(), Box<dyn Error>> {
+/// The command line without --data-dir, which is not one of the options of
+/// the provider.
+fn opts_args() -> Vec<OsString> {
+    let mut args = Vec::new();
+    let mut all = env::args_os();
+    while let Some(arg) = all.next() {
+        if arg == "--data-dir" {
+            all.next();
+        } else if !arg
+            .to_str()
+            .map_or(false, |arg| arg.starts_with("--data-dir="))
+        {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+async fn service_run(opts: Opts) -> Result<(), Box<dyn Error>> {
     let stop_rx = Arc::new(Notify::new());
     let stop_tx = Arc::clone(&stop_rx);
 
@@ -60,8 +139,9 @@ async fn service_run() -> Result<(), Box<dyn Error>> {
         Duration::from_secs(60),
     ))?;
 
-    let (_spec, server) = make_server(Opts::try_parse()?, ListenFd::empty()).await?;
+    let (_specs, server, mut shutdown, _sockets) = make_server(opts, ListenFd::empty()).await?;
 
+    let mut stopped = Ok(());
     server
         .with_graceful_shutdown(async {
             log::debug!("Set running ...");
@@ -69,7 +149,10 @@ async fn service_run() -> Result<(), Box<dyn Error>> {
                 .set_service_status(service_status(ServiceState::Running, Duration::default()))
                 .expect("set running");
             log::debug!("Waiting for shutdown event ...");
-            stop_rx.notified().await;
+            tokio::select! {
+                _ = stop_rx.notified() => (),
+                res = shutdown.wait() => stopped = res,
+            }
             log::debug!("Stop pending ...");
             status_handle
                 .set_service_status(service_status(
@@ -82,5 +165,5 @@ async fn service_run() -> Result<(), Box<dyn Error>> {
 
     status_handle.set_service_status(service_status(ServiceState::Stopped, Duration::default()))?;
 
-    Ok(())
+    Ok(stopped?)
 }
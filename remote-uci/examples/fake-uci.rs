@@ -0,0 +1,242 @@
+// Fake UCI engine, to try the provider end-to-end without a real engine:
+//
+//     cargo build --example fake-uci
+//     remote-uci --engine target/debug/examples/fake-uci
+//
+// It plays the first legal move, and misbehaves as configured with
+// environment variables:
+//
+// FAKE_UCI_DELAY_MS     Wait before answering each command.
+// FAKE_UCI_DEPTH_MS     Time to search each depth (default 50).
+// FAKE_UCI_CRASH_AFTER  Abort after receiving this many commands.
+// FAKE_UCI_GARBAGE      Print a malformed line after every this many lines.
+
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    process,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use remote_uci::uci::UciIn;
+use shakmaty::{uci::Uci, CastlingMode, Chess, Position};
+
+/// Depth of searches without limits.
+const DEFAULT_DEPTH: u32 = 12;
+
+/// Longest principal variation that is printed.
+const MAX_PV: u32 = 8;
+
+const GARBAGE: [&str; 4] = [
+    "info depth",
+    "bestmove e9e9",
+    "option name Broken type spin default x",
+    "\u{fffd}\u{fffd} not uci at all",
+];
+
+struct Settings {
+    delay: Duration,
+    depth_time: Duration,
+    crash_after: Option<u64>,
+    garbage: Option<u64>,
+}
+
+impl Settings {
+    fn from_env() -> Settings {
+        Settings {
+            delay: Duration::from_millis(env_var("FAKE_UCI_DELAY_MS").unwrap_or(0)),
+            depth_time: Duration::from_millis(env_var("FAKE_UCI_DEPTH_MS").unwrap_or(50)),
+            crash_after: env_var("FAKE_UCI_CRASH_AFTER"),
+            garbage: env_var("FAKE_UCI_GARBAGE").filter(|n| *n > 0),
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<u64> {
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(n) => Some(n),
+        Err(_) => {
+            eprintln!("fake-uci: ignoring {name}={value:?}");
+            None
+        }
+    }
+}
+
+struct Engine {
+    settings: Settings,
+    commands: Receiver<String>,
+    received: u64,
+    printed: u64,
+    pos: Chess,
+}
+
+impl Engine {
+    fn print(&mut self, line: &str) {
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{line}");
+        self.printed += 1;
+        if let Some(garbage) = self.settings.garbage {
+            if self.printed % garbage == 0 {
+                let _ = writeln!(
+                    stdout,
+                    "{}",
+                    GARBAGE[(self.printed / garbage) as usize % GARBAGE.len()]
+                );
+            }
+        }
+        let _ = stdout.flush();
+    }
+
+    /// Next command, or `None` if nothing arrived in time.
+    fn recv(&mut self, timeout: Duration) -> Option<UciIn> {
+        let line = match self.commands.recv_timeout(timeout) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => process::exit(0),
+        };
+        self.received += 1;
+        if self.settings.crash_after == Some(self.received) {
+            eprintln!("fake-uci: crashing after {} commands", self.received);
+            process::abort();
+        }
+        if line.trim() == "quit" {
+            process::exit(0);
+        }
+        thread::sleep(self.settings.delay);
+        match UciIn::from_line(&line) {
+            Ok(command) => command,
+            Err(err) => {
+                eprintln!("fake-uci: {line:?}: {err}");
+                None
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            match self.recv(Duration::MAX) {
+                Some(UciIn::Uci) => {
+                    self.print("id name fake-uci");
+                    self.print("id author remote-uci");
+                    self.print("option name Threads type spin default 1 min 1 max 512");
+                    self.print("option name Hash type spin default 16 min 1 max 33554432");
+                    self.print("option name MultiPV type spin default 1 min 1 max 500");
+                    self.print("option name UCI_Chess960 type check default false");
+                    self.print("uciok");
+                }
+                Some(UciIn::Isready) => self.print("readyok"),
+                Some(UciIn::Ucinewgame) => self.pos = Chess::default(),
+                Some(UciIn::Position { fen, moves }) => {
+                    let mut pos: Chess = match fen {
+                        Some(fen) => match fen.position(CastlingMode::Chess960) {
+                            Ok(pos) => pos,
+                            Err(err) => {
+                                eprintln!("fake-uci: illegal position: {err}");
+                                continue;
+                            }
+                        },
+                        None => Chess::default(),
+                    };
+                    for m in &moves {
+                        match m.to_move(&pos) {
+                            Ok(m) => pos.play_unchecked(&m),
+                            Err(err) => {
+                                eprintln!("fake-uci: illegal move {m}: {err}");
+                                break;
+                            }
+                        }
+                    }
+                    self.pos = pos;
+                }
+                Some(UciIn::Go {
+                    depth,
+                    movetime,
+                    infinite,
+                    ..
+                }) => {
+                    let depth = match depth {
+                        Some(depth) => depth,
+                        None if infinite => u32::MAX,
+                        None => DEFAULT_DEPTH,
+                    };
+                    self.search(depth, movetime);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn search(&mut self, max_depth: u32, movetime: Option<Duration>) {
+        let started = Instant::now();
+        let pv = self.pv();
+        let mut depth = 0;
+        loop {
+            if depth < max_depth && movetime.map_or(true, |t| started.elapsed() < t) {
+                depth += 1;
+                let nodes = u64::from(depth) * 1000;
+                let time = started.elapsed().as_millis().max(1);
+                let line = if pv.is_empty() {
+                    format!("info depth {depth} score cp 0 nodes {nodes} time {time}")
+                } else {
+                    format!(
+                        "info depth {depth} multipv 1 score cp {} nodes {nodes} nps {} time {time} pv {}",
+                        (depth % 7) * 3,
+                        u128::from(nodes) * 1000 / time,
+                        pv[..pv.len().min(depth as usize)].join(" ")
+                    )
+                };
+                self.print(&line);
+            } else if movetime.is_some() || max_depth != u32::MAX {
+                break;
+            }
+            match self.recv(self.settings.depth_time) {
+                Some(UciIn::Stop) => break,
+                Some(UciIn::Isready) => self.print("readyok"),
+                Some(command) => eprintln!("fake-uci: ignoring {command} while searching"),
+                None => (),
+            }
+        }
+        match pv.first() {
+            Some(best) => self.print(&format!("bestmove {best}")),
+            None => self.print("bestmove (none)"),
+        }
+    }
+
+    /// Principal variation of first legal moves.
+    fn pv(&self) -> Vec<String> {
+        let mode = CastlingMode::Standard;
+        let mut pos = self.pos.clone();
+        let mut pv = Vec::new();
+        while pv.len() < MAX_PV as usize {
+            let m = match pos.legal_moves().into_iter().next() {
+                Some(m) => m,
+                None => break,
+            };
+            pv.push(Uci::from_move(&m, mode).to_string());
+            pos.play_unchecked(&m);
+        }
+        pv
+    }
+}
+
+fn main() {
+    let (tx, commands) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    Engine {
+        settings: Settings::from_env(),
+        commands,
+        received: 0,
+        printed: 0,
+        pos: Chess::default(),
+    }
+    .run();
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use remote_uci::uci::{UciIn, UciOptionValue, UciOut};
+
+fuzz_target!(|data: &[u8]| {
+    // Option declaration from the engine on the first line, setoption from
+    // the client on the second.
+    let s = String::from_utf8_lossy(data);
+    let (declaration, setoption) = s.split_once('\n').unwrap_or((&s, ""));
+    let option = match UciOut::from_line(declaration) {
+        Ok(Some(UciOut::Option { option, .. })) => option,
+        _ => return,
+    };
+    let value = match UciIn::from_line(setoption) {
+        Ok(Some(UciIn::Setoption { value, .. })) => value,
+        _ => return,
+    };
+    if let Ok(accepted) = option.validate(value) {
+        let value = (accepted != UciOptionValue::Button).then(|| accepted.to_string());
+        assert_eq!(option.validate(value).ok(), Some(accepted));
+    }
+});
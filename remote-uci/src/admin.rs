@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use axum::{
+    extract::Path,
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{ConfigError, Guest},
+    idle::{self, Queue},
+    metrics::Connection,
+    private_file,
+    proxy::ClientIp,
+    server::ExternalWorkerOpts,
+    ws::{Secret, SharedEngine},
+};
+
+/// Guests from the config file, and guests added at runtime through the
+/// admin API.
+pub struct Guests {
+    fixed: HashMap<String, Guest>,
+    added: RwLock<HashMap<String, Guest>>,
+    path: Option<PathBuf>,
+}
+
+/// File that keeps guests added through the admin API, in the same format
+/// as the config file.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GuestsFile {
+    #[serde(default)]
+    guests: HashMap<String, Guest>,
+}
+
+impl Guests {
+    pub fn load(
+        fixed: HashMap<String, Guest>,
+        path: Option<PathBuf>,
+    ) -> Result<Guests, ConfigError> {
+        let added = match path {
+            Some(ref path) => match fs::read_to_string(path) {
+                Ok(file) => toml::from_str::<GuestsFile>(&file)?.guests,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+                Err(err) => return Err(err.into()),
+            },
+            None => HashMap::new(),
+        };
+        Ok(Guests {
+            fixed,
+            added: RwLock::new(added),
+            path,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Guest> {
+        self.fixed.get(name).cloned().or_else(|| {
+            self.added
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(name)
+                .cloned()
+        })
+    }
+
+    pub fn find(&self, secret: &Secret) -> Option<(String, Guest)> {
+        let added = self.added.read().unwrap_or_else(PoisonError::into_inner);
+        self.fixed
+            .iter()
+            .chain(added.iter())
+            .find(|(_, guest)| guest.secret == *secret)
+            .map(|(name, guest)| (name.clone(), guest.clone()))
+    }
+
+    fn save(&self, added: &HashMap<String, Guest>) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let file = toml::to_string(&GuestsFile {
+            guests: added.clone(),
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        private_file::write(path, file)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewGuest {
+    name: String,
+    max_threads: Option<u32>,
+    max_hash: Option<u32>,
+    max_multipv: Option<u32>,
+    max_elo: Option<u32>,
+    #[serde(default = "default_variants")]
+    variants: bool,
+}
+
+fn default_variants() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+pub struct GuestEntry {
+    name: String,
+    /// Guests from the config file can not be removed at runtime.
+    removable: bool,
+    max_threads: Option<u32>,
+    max_hash: Option<u32>,
+    max_multipv: Option<u32>,
+    max_elo: Option<u32>,
+    variants: bool,
+}
+
+impl GuestEntry {
+    fn new(name: &str, guest: &Guest, removable: bool) -> GuestEntry {
+        GuestEntry {
+            name: name.to_owned(),
+            removable,
+            max_threads: guest.max_threads,
+            max_hash: guest.max_hash,
+            max_multipv: guest.max_multipv,
+            max_elo: guest.max_elo,
+            variants: guest.variants,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AddedGuest {
+    name: String,
+    secret: Secret,
+    registration_url: String,
+}
+
+/// Routes to manage sessions and guests at runtime, for requests with
+/// `Authorization: Bearer <secret>`.
+pub fn router(
+    secret: Secret,
+    engine: Arc<SharedEngine>,
+    guests: Arc<Guests>,
+    spec: ExternalWorkerOpts,
+    queue: Option<Arc<Queue>>,
+) -> Router {
+    let router = match queue {
+        Some(queue) => Router::new().route(
+            "/api/queue",
+            get({
+                let queue = Arc::clone(&queue);
+                move || idle::status(queue)
+            })
+            .post(move |body| idle::append(queue, body)),
+        ),
+        None => Router::new(),
+    };
+    router
+        .route(
+            "/api/admin/sessions",
+            get({
+                let engine = Arc::clone(&engine);
+                move || sessions(engine)
+            }),
+        )
+        .route(
+            "/api/admin/sessions/:id",
+            delete({
+                let engine = Arc::clone(&engine);
+                move |id, client_ip| kick(engine, id, client_ip)
+            }),
+        )
+        .route(
+            "/api/admin/guests",
+            get({
+                let guests = Arc::clone(&guests);
+                move || list_guests(guests)
+            })
+            .post({
+                let guests = Arc::clone(&guests);
+                move |client_ip, new_guest| add_guest(guests, spec, client_ip, new_guest)
+            }),
+        )
+        .route(
+            "/api/admin/guests/:name",
+            delete(move |name, client_ip| remove_guest(engine, guests, name, client_ip)),
+        )
+        .route_layer(middleware::from_fn(move |req, next| {
+            authenticate(secret.clone(), req, next)
+        }))
+}
+
+/// Whether the request carries the admin secret as bearer token.
+pub fn is_authorized<B>(req: &Request<B>, secret: &Secret) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |token| Secret(token.to_owned()) == *secret)
+}
+
+async fn authenticate<B>(
+    secret: Secret,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    if is_authorized(&req, &secret) {
+        Ok(next.run(req).await)
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+async fn sessions(engine: Arc<SharedEngine>) -> Json<Vec<Connection>> {
+    Json(engine.connections())
+}
+
+/// Operator address for audit logs.
+fn operator(client_ip: Option<Extension<ClientIp>>) -> String {
+    match client_ip {
+        Some(Extension(ClientIp(ip))) => format!("operator at {ip}"),
+        None => "operator".to_owned(),
+    }
+}
+
+async fn kick(
+    engine: Arc<SharedEngine>,
+    Path(id): Path<u64>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> StatusCode {
+    if engine.kick(id) {
+        log::warn!(
+            "Closing connection {id} as requested by {}",
+            operator(client_ip)
+        );
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn list_guests(guests: Arc<Guests>) -> Json<Vec<GuestEntry>> {
+    let added = guests.added.read().unwrap_or_else(PoisonError::into_inner);
+    let mut entries: Vec<_> = guests
+        .fixed
+        .iter()
+        .map(|(name, guest)| GuestEntry::new(name, guest, false))
+        .chain(
+            added
+                .iter()
+                .map(|(name, guest)| GuestEntry::new(name, guest, true)),
+        )
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(entries)
+}
+
+async fn add_guest(
+    guests: Arc<Guests>,
+    mut spec: ExternalWorkerOpts,
+    client_ip: Option<Extension<ClientIp>>,
+    Json(new_guest): Json<NewGuest>,
+) -> Result<Json<AddedGuest>, StatusCode> {
+    let guest = Guest {
+        secret: Secret::random(),
+        max_threads: new_guest.max_threads,
+        max_hash: new_guest.max_hash,
+        max_multipv: new_guest.max_multipv,
+        max_elo: new_guest.max_elo,
+        variants: new_guest.variants,
+    };
+    {
+        let mut added = guests.added.write().unwrap_or_else(PoisonError::into_inner);
+        if guests.fixed.contains_key(&new_guest.name) || added.contains_key(&new_guest.name) {
+            return Err(StatusCode::CONFLICT);
+        }
+        added.insert(new_guest.name.clone(), guest.clone());
+        if let Err(err) = guests.save(&added) {
+            log::error!("Could not save guests: {err}");
+            added.remove(&new_guest.name);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    log::warn!("Added guest {} by {}", new_guest.name, operator(client_ip));
+    spec.secret = guest.secret.clone();
+    Ok(Json(AddedGuest {
+        name: new_guest.name,
+        secret: guest.secret,
+        registration_url: spec.registration_url(),
+    }))
+}
+
+async fn remove_guest(
+    engine: Arc<SharedEngine>,
+    guests: Arc<Guests>,
+    Path(name): Path<String>,
+    client_ip: Option<Extension<ClientIp>>,
+) -> StatusCode {
+    {
+        let mut added = guests.added.write().unwrap_or_else(PoisonError::into_inner);
+        let guest = match added.remove(&name) {
+            Some(guest) => guest,
+            None if guests.fixed.contains_key(&name) => return StatusCode::CONFLICT,
+            None => return StatusCode::NOT_FOUND,
+        };
+        if let Err(err) = guests.save(&added) {
+            log::error!("Could not save guests: {err}");
+            added.insert(name, guest);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    log::warn!("Removed guest {name} by {}", operator(client_ip));
+    engine.kick_guest(&name);
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guests_file() -> Result<(), toml::de::Error> {
+        let file: GuestsFile = toml::from_str(
+            r#"
+            [guests.club]
+            secret = "0123456789abcdef"
+            max-threads = 4
+            "#,
+        )?;
+        let club = &file.guests["club"];
+        assert_eq!(club.max_threads, Some(4));
+        assert_eq!(club.max_hash, None);
+        assert!(club.variants);
+
+        let saved: GuestsFile = toml::from_str(&toml::to_string(&file).unwrap())?;
+        assert_eq!(saved.guests["club"].secret, club.secret);
+        assert_eq!(saved.guests["club"].max_threads, Some(4));
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+
+use crate::{
+    baseline::NpsMonitorSnapshot,
+    engine::Evaluation,
+    gpu::GpuSnapshot,
+    latency::LatencySnapshot,
+    logs,
+    metrics::Snapshot,
+    proxy::ClientIp,
+    supervisor::Health,
+    uci::{UciOption, UciOptionName},
+    ws::SharedEngine,
+};
+
+#[derive(Serialize)]
+pub struct OptionEntry {
+    name: UciOptionName,
+    #[serde(flatten)]
+    option: UciOption,
+    settable: bool,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    version: &'static str,
+    name: Option<String>,
+    author: Option<String>,
+    evaluation: Option<Evaluation>,
+    network: Option<String>,
+    nps: Option<u64>,
+    nps_baseline: Option<NpsMonitorSnapshot>,
+    gpu: Option<GpuSnapshot>,
+    latency: LatencySnapshot,
+    sessions: Vec<Snapshot>,
+    health: Health,
+    paused: bool,
+}
+
+pub async fn status(engine: Arc<SharedEngine>) -> Json<Status> {
+    let info = engine.info();
+    Json(Status {
+        version: env!("CARGO_PKG_VERSION"),
+        name: info.name.clone(),
+        author: info.author.clone(),
+        evaluation: info.evaluation,
+        network: info.network.clone(),
+        nps: engine.nps(),
+        nps_baseline: engine.nps_baseline(),
+        gpu: engine.gpu(),
+        latency: engine.latency(),
+        sessions: engine.sessions(),
+        health: engine.health(),
+        paused: engine.is_paused(),
+    })
+}
+
+/// Whether the engine is running, for monitoring. Fails while the engine
+/// is being restarted.
+pub async fn health(engine: Arc<SharedEngine>) -> (StatusCode, Json<Health>) {
+    let health = engine.health();
+    let status = if health.is_running() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health))
+}
+
+pub async fn options(engine: Arc<SharedEngine>) -> Json<Vec<OptionEntry>> {
+    let info = engine.info();
+    let mut options: Vec<_> = info
+        .options
+        .iter()
+        .map(|(name, option)| OptionEntry {
+            name: name.clone(),
+            option: option.clone(),
+            settable: info.is_settable(name),
+        })
+        .collect();
+    options.sort_by_cached_key(|entry| entry.name.0.to_ascii_lowercase());
+    Json(options)
+}
+
+/// Recent log lines as plain text. Logs include analysed positions, so they
+/// are only available on the local machine.
+pub async fn logs(client_ip: Option<Extension<ClientIp>>) -> Result<String, StatusCode> {
+    match client_ip {
+        Some(Extension(ClientIp(ip))) if ip.is_loopback() => {
+            Ok(logs::recent().into_iter().map(|line| line + "\n").collect())
+        }
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
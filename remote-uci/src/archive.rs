@@ -0,0 +1,314 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    process,
+};
+
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use tokio::task;
+
+/// Extensions of executable scripts that are shipped alongside engines,
+/// e.g. to build them, and should not be mistaken for the engine.
+const SCRIPT_EXTENSIONS: [&str; 5] = ["sh", "bash", "py", "pl", "rb"];
+
+/// Archive formats that engines are distributed in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// Recognize the format by file name, and return the name without the
+    /// extension.
+    fn from_name(name: &str) -> Option<(Format, &str)> {
+        let lower = name.to_ascii_lowercase();
+        [
+            (".tar", Format::Tar),
+            (".tar.gz", Format::TarGz),
+            (".tgz", Format::TarGz),
+            (".zip", Format::Zip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| lower.ends_with(extension))
+        .map(|(extension, format)| (format, &name[..name.len() - extension.len()]))
+    }
+}
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().map_or(false, |path| {
+        path.starts_with("https://") || path.starts_with("http://")
+    })
+}
+
+/// Get the engine executable, given as a path or URL to the executable
+/// itself or to an archive that contains it. Downloads and archives are
+/// kept in the cache directory, so that they are fetched and unpacked only
+/// once. Downloads over plain HTTP could be tampered with, so they require
+/// a checksum.
+pub async fn prepare(engine: &Path, sha256: Option<&str>, cache_dir: &Path) -> io::Result<PathBuf> {
+    let file = if is_url(engine) {
+        let url = engine.to_str().expect("url");
+        if !url.starts_with("https://") && sha256.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "refusing to download {url} without https, unless --engine-sha256 is given"
+                ),
+            ));
+        }
+        download(url, cache_dir).await?
+    } else {
+        engine.to_owned()
+    };
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if Format::from_name(&name).is_none() && sha256.is_none() {
+        return Ok(file);
+    }
+    let sha256 = sha256.map(|sha256| sha256.trim().to_ascii_lowercase());
+    let cache_dir = cache_dir.to_owned();
+    task::spawn_blocking(move || {
+        let digest = hash_file(&file)?;
+        if let Some(expected) = sha256 {
+            if digest != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("checksum mismatch for {file:?}: expected {expected}, got {digest}"),
+                ));
+            }
+        }
+        match Format::from_name(&name) {
+            Some((format, stem)) => {
+                let dir = cache_dir.join("engines").join(&digest[..16]);
+                if !dir.exists() {
+                    log::info!("Unpacking {file:?} to {dir:?}");
+                    unpack_atomic(&file, format, &dir)?;
+                }
+                locate(&dir, stem)
+            }
+            None => Ok(file),
+        }
+    })
+    .await
+    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+/// The cache directory of the user, unless another is given with
+/// --cache-dir.
+pub fn cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| home::home_dir().map(|home| home.join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("remote-uci")
+}
+
+async fn download(url: &str, cache_dir: &Path) -> io::Result<PathBuf> {
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("engine");
+    let dir = cache_dir
+        .join("downloads")
+        .join(&sha256_hex(url.as_bytes())[..16]);
+    let path = dir.join(name);
+    if path.exists() {
+        log::info!("Using {path:?}, downloaded from {url}");
+        return Ok(path);
+    }
+
+    log::info!("Downloading engine from {url} ...");
+    let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+    let bytes = reqwest::get(url)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(to_io)?
+        .bytes()
+        .await
+        .map_err(to_io)?;
+    fs::create_dir_all(&dir)?;
+    let partial = dir.join(format!("{name}.part"));
+    fs::write(&partial, &bytes)?;
+    if Format::from_name(name).is_none() {
+        make_executable(&partial)?;
+    }
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Unpack into a temporary directory first, so that an interrupted attempt
+/// is not mistaken for a complete one.
+fn unpack_atomic(file: &Path, format: Format, dir: &Path) -> io::Result<()> {
+    let tmp = dir.with_extension(format!("tmp-{}", process::id()));
+    let _ = fs::remove_dir_all(&tmp);
+    fs::create_dir_all(&tmp)?;
+    let result = unpack(file, format, &tmp).and_then(|()| fs::rename(&tmp, dir));
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&tmp);
+    }
+    result
+}
+
+fn unpack(file: &Path, format: Format, dir: &Path) -> io::Result<()> {
+    let reader = BufReader::new(File::open(file)?);
+    match format {
+        Format::Tar => tar::Archive::new(reader).unpack(dir),
+        Format::TarGz => tar::Archive::new(GzDecoder::new(reader)).unpack(dir),
+        Format::Zip => {
+            let mut archive = zip::ZipArchive::new(reader)?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                // Skip entries that would end up outside of the directory.
+                let path = match entry.enclosed_name() {
+                    Some(path) => dir.join(path),
+                    None => continue,
+                };
+                if entry.is_dir() {
+                    fs::create_dir_all(&path)?;
+                    continue;
+                }
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(&mut entry, &mut File::create(&path)?)?;
+                #[cfg(unix)]
+                if entry.unix_mode().map_or(false, |mode| mode & 0o111 != 0) {
+                    make_executable(&path)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Find the engine executable among the unpacked files. Prefer the one
+/// named like the archive, as in official Stockfish releases, or else the
+/// only one.
+fn locate(dir: &Path, stem: &str) -> io::Result<PathBuf> {
+    let mut executables = Vec::new();
+    find_executables(dir, &mut executables)?;
+    executables.sort();
+    if let Some(named) = executables
+        .iter()
+        .find(|path| path.file_stem().map_or(false, |s| s == stem))
+    {
+        return Ok(named.clone());
+    }
+    match executables.len() {
+        1 => Ok(executables.swap_remove(0)),
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no executable in {dir:?}"),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "several executables in {dir:?}, pass one of them with --engine: {executables:?}"
+            ),
+        )),
+    }
+}
+
+fn find_executables(dir: &Path, executables: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_executables(&path, executables)?;
+        } else if file_type.is_file() && is_executable(&path, &entry.metadata()?) {
+            executables.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+        && !path.extension().map_or(false, |extension| {
+            SCRIPT_EXTENSIONS
+                .iter()
+                .any(|script| extension.eq_ignore_ascii_case(script))
+        })
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path, _metadata: &fs::Metadata) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("exe"))
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() -> io::Result<()> {
+        assert_eq!(
+            Format::from_name("stockfish-ubuntu-x86-64-avx2.TAR"),
+            Some((Format::Tar, "stockfish-ubuntu-x86-64-avx2"))
+        );
+        assert_eq!(Format::from_name("stockfish"), None);
+
+        let dir = env::temp_dir().join(format!("remote-uci-archive-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let archive = dir.join("stockfish-ubuntu-x86-64-avx2.tar");
+        fs::create_dir_all(&dir)?;
+        let mut builder = tar::Builder::new(File::create(&archive)?);
+        for (path, mode) in [
+            ("stockfish/stockfish-ubuntu-x86-64-avx2", 0o755),
+            ("stockfish/scripts/net.sh", 0o755),
+            ("stockfish/README.md", 0o644),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(mode);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        builder.finish()?;
+        drop(builder);
+
+        let unpacked = dir.join("unpacked");
+        unpack_atomic(&archive, Format::Tar, &unpacked)?;
+        if cfg!(unix) {
+            assert_eq!(
+                locate(&unpacked, "stockfish-ubuntu-x86-64-avx2")?,
+                unpacked.join("stockfish/stockfish-ubuntu-x86-64-avx2")
+            );
+        }
+        fs::remove_dir_all(dir)
+    }
+}
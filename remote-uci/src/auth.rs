@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    http::{HeaderMap, StatusCode},
+};
+
+use crate::{
+    admin::Guests,
+    engine::SessionLimits,
+    invite::Invite,
+    ws::{Frontend, Secret},
+};
+
+/// What a client presented when asking for a session.
+pub struct AuthRequest {
+    /// Headers of the WebSocket upgrade. Empty for QUIC.
+    pub headers: HeaderMap,
+    /// Query parameters, including the secret, if any.
+    pub query: Vec<(String, String)>,
+}
+
+impl AuthRequest {
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Who is allowed a session, and with which limits.
+pub struct Identity {
+    /// Name of the client in logs, metrics and CPU budgets.
+    pub client: String,
+    /// Guest whose secret or invite was presented, if any, so that removing
+    /// the guest closes its sessions.
+    pub guest: Option<String>,
+    pub limits: SessionLimits,
+}
+
+/// Decides who may open sessions. Embedders can implement this, e.g. to
+/// check tokens with an identity provider, and pass it to
+/// `make_server_with()`. Use `#[axum::async_trait]` for implementations.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<Identity, StatusCode>;
+}
+
+/// The built-in authenticator, accepting the secrets of frontends, guests
+/// and invites.
+pub struct Secrets {
+    pub frontends: Arc<Vec<Frontend>>,
+    pub guests: Arc<Guests>,
+}
+
+#[async_trait]
+impl Authenticator for Secrets {
+    async fn authenticate(&self, request: &AuthRequest) -> Result<Identity, StatusCode> {
+        let secret = Secret(
+            request
+                .param("secret")
+                .ok_or(StatusCode::FORBIDDEN)?
+                .to_owned(),
+        );
+        let (client, guest, limits) = if let Some(frontend) = self
+            .frontends
+            .iter()
+            .find(|frontend| frontend.secret == secret)
+        {
+            (frontend.url.clone(), None, SessionLimits::default())
+        } else if let Some((name, guest)) = self.guests.find(&secret) {
+            (format!("guest {name}"), Some(name), guest.limits())
+        } else if let Some((frontend, invite)) = self.frontends.iter().find_map(|frontend| {
+            Invite::verify(&secret, &frontend.secret).map(|invite| (frontend, invite))
+        }) {
+            match invite.guest {
+                Some(name) => {
+                    let guest = self.guests.get(&name).ok_or(StatusCode::FORBIDDEN)?;
+                    (
+                        format!("invite for guest {name}"),
+                        Some(name),
+                        guest.limits(),
+                    )
+                }
+                None => (
+                    format!("invite for {}", frontend.url),
+                    None,
+                    SessionLimits::default(),
+                ),
+            }
+        } else {
+            return Err(StatusCode::FORBIDDEN);
+        };
+        Ok(Identity {
+            client,
+            guest,
+            limits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let secrets = Secrets {
+            frontends: Arc::new(vec![Frontend {
+                url: "https://lichess.org".to_owned(),
+                secret: Secret("0123456789abcdef".to_owned()),
+            }]),
+            guests: Arc::new(Guests::load(HashMap::new(), None).unwrap()),
+        };
+        let request = |query: &[(&str, &str)]| AuthRequest {
+            headers: HeaderMap::new(),
+            query: query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+
+        let identity = secrets
+            .authenticate(&request(&[("secret", "0123456789abcdef")]))
+            .await
+            .unwrap();
+        assert_eq!(identity.client, "https://lichess.org");
+        assert_eq!(identity.guest, None);
+        assert!(identity.limits.is_unrestricted());
+
+        for query in [&[("secret", "wrong")][..], &[]] {
+            assert!(matches!(
+                secrets.authenticate(&request(query)).await,
+                Err(StatusCode::FORBIDDEN)
+            ));
+        }
+    }
+}
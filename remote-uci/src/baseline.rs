@@ -0,0 +1,161 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs, io,
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bench::BenchResult;
+
+/// Shorter searches are not compared, because engines take a moment to
+/// reach full speed.
+const MIN_SEARCH_TIME: Duration = Duration::from_secs(2);
+
+/// Number of recent searches that are compared.
+const WINDOW: usize = 10;
+
+/// Searches are considered degraded below this fraction of the baseline.
+const DEGRADED_FRACTION: f64 = 0.5;
+
+/// Nodes per second measured by the bench subcommand, by number of threads.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Baseline {
+    nps: BTreeMap<u32, u64>,
+}
+
+impl Baseline {
+    pub fn from_results(results: &[BenchResult]) -> Baseline {
+        Baseline {
+            nps: results.iter().map(|r| (r.threads, r.nps)).collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Baseline> {
+        serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Speed measured with the given number of threads, or the closest
+    /// fewer threads that were measured.
+    fn expected(&self, threads: u32) -> Option<u64> {
+        self.nps
+            .range(..=threads)
+            .next_back()
+            .map(|(_, nps)| *nps)
+            .filter(|nps| *nps > 0)
+    }
+}
+
+/// Compares the speed of searches to the baseline, to notice thermal
+/// throttling, a wrong engine binary or background load.
+pub struct NpsMonitor {
+    baseline: Baseline,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Fractions of the baseline reached by recent searches.
+    recent: VecDeque<f64>,
+    degraded: bool,
+}
+
+#[derive(Serialize)]
+pub struct NpsMonitorSnapshot {
+    /// Median fraction of the baseline reached by recent searches.
+    fraction: Option<f64>,
+    degraded: bool,
+}
+
+impl NpsMonitor {
+    pub fn new(baseline: Baseline) -> NpsMonitor {
+        NpsMonitor {
+            baseline,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Record the final speed of a search.
+    pub fn observe(&self, threads: u32, nps: u64, elapsed: Duration) {
+        if elapsed < MIN_SEARCH_TIME || nps == 0 {
+            return;
+        }
+        let expected = match self.baseline.expected(threads) {
+            Some(expected) => expected,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.recent.len() >= WINDOW {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(nps as f64 / expected as f64);
+        let fraction = match median(&state.recent) {
+            Some(fraction) if state.recent.len() >= WINDOW => fraction,
+            _ => return,
+        };
+        let degraded = fraction < DEGRADED_FRACTION;
+        if degraded && !state.degraded {
+            log::warn!(
+                "Engine reaches only {:.0}% of the benchmarked speed with {} threads over the last {} searches. Check for thermal throttling, the wrong engine binary, or background load",
+                fraction * 100.0,
+                threads,
+                WINDOW
+            );
+        } else if !degraded && state.degraded {
+            log::warn!(
+                "Engine is back at {:.0}% of the benchmarked speed",
+                fraction * 100.0
+            );
+        }
+        state.degraded = degraded;
+    }
+
+    pub fn snapshot(&self) -> NpsMonitorSnapshot {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        NpsMonitorSnapshot {
+            fraction: median(&state.recent),
+            degraded: state.degraded,
+        }
+    }
+}
+
+fn median(values: &VecDeque<f64>) -> Option<f64> {
+    let mut sorted: Vec<f64> = values.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    sorted.get(sorted.len() / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degraded() {
+        let monitor = NpsMonitor::new(Baseline {
+            nps: [(1, 1_000_000), (4, 3_500_000)].into_iter().collect(),
+        });
+        assert_eq!(monitor.baseline.expected(2), Some(1_000_000));
+        assert_eq!(monitor.baseline.expected(8), Some(3_500_000));
+
+        let search = Duration::from_secs(10);
+        monitor.observe(4, 1_000_000, Duration::from_millis(500));
+        assert!(monitor.snapshot().fraction.is_none());
+        for _ in 0..WINDOW - 1 {
+            monitor.observe(4, 1_400_000, search);
+        }
+        assert!(!monitor.snapshot().degraded);
+        monitor.observe(4, 3_500_000, search);
+        assert!(monitor.snapshot().degraded);
+        for _ in 0..WINDOW / 2 + 1 {
+            monitor.observe(4, 3_000_000, search);
+        }
+        assert!(!monitor.snapshot().degraded);
+    }
+}
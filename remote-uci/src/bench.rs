@@ -0,0 +1,181 @@
+use std::{io, time::Duration};
+
+use crate::{
+    engine::{Engine, InfoFilter, Session},
+    uci::{UciIn, UciOptionName, UciOut},
+};
+
+/// Positions searched for each thread count: opening, middlegames and an
+/// endgame.
+const POSITIONS: [&str; 4] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 0 8",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+];
+
+/// Bytes per transposition table entry, as in Stockfish.
+const HASH_ENTRY_SIZE: u64 = 10;
+
+/// Hash is suggested to hold the nodes of a search of this length.
+const HASH_SEARCH_TIME: u64 = 60;
+
+pub struct BenchResult {
+    pub threads: u32,
+    /// Average over all positions.
+    pub nps: u64,
+    pub depth: u32,
+}
+
+/// Search the positions of the suite with 1, 2, 4, ... threads, up to the
+/// given maximum.
+pub async fn run(
+    engine: &mut Engine,
+    max_threads: u32,
+    movetime: Duration,
+) -> io::Result<Vec<BenchResult>> {
+    let session = Session(0);
+    let mut results = Vec::new();
+    for threads in thread_counts(max_threads) {
+        let (mut nps, mut depth) = (0, 0);
+        for fen in POSITIONS {
+            engine.ensure_newgame(session).await?;
+            // Progress reports carry nps, but are usually filtered as noise.
+            engine.set_info_filter(InfoFilter::Off);
+            engine
+                .send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name: UciOptionName("Threads".to_owned()),
+                        value: Some(threads.to_string()),
+                    },
+                )
+                .await?;
+            engine
+                .send(
+                    session,
+                    UciIn::Position {
+                        fen: Some(fen.parse().expect("valid fen")),
+                        moves: Vec::new(),
+                    },
+                )
+                .await?;
+            engine
+                .send(
+                    session,
+                    UciIn::Go {
+                        searchmoves: None,
+                        ponder: false,
+                        wtime: None,
+                        btime: None,
+                        winc: None,
+                        binc: None,
+                        movestogo: None,
+                        depth: None,
+                        nodes: None,
+                        mate: None,
+                        movetime: Some(movetime),
+                        infinite: false,
+                    },
+                )
+                .await?;
+            let (last_nps, last_depth) = search(engine, session).await?;
+            nps += last_nps;
+            depth += last_depth;
+        }
+        let result = BenchResult {
+            threads,
+            nps: nps / POSITIONS.len() as u64,
+            depth: depth / POSITIONS.len() as u32,
+        };
+        log::info!(
+            "Threads {}: {} nps, depth {}",
+            result.threads,
+            result.nps,
+            result.depth
+        );
+        results.push(result);
+    }
+    Ok(results)
+}
+
+async fn search(engine: &mut Engine, session: Session) -> io::Result<(u64, u32)> {
+    let (mut nps, mut depth) = (0, 0);
+    loop {
+        match engine.recv(session).await? {
+            UciOut::Info {
+                nps: info_nps,
+                depth: info_depth,
+                ..
+            } => {
+                nps = info_nps.unwrap_or(nps);
+                depth = info_depth.unwrap_or(depth);
+            }
+            UciOut::Bestmove { .. } => return Ok((nps, depth)),
+            _ => (),
+        }
+    }
+}
+
+fn thread_counts(max_threads: u32) -> Vec<u32> {
+    let mut counts: Vec<u32> = (0..)
+        .map(|exp| 1 << exp)
+        .take_while(|threads| *threads < max_threads)
+        .collect();
+    counts.push(max_threads.max(1));
+    counts
+}
+
+/// The fewest threads that reach 90% of the best measured speed. More
+/// threads are unlikely to be worth keeping the host busy.
+pub fn suggest_threads(results: &[BenchResult]) -> Option<u32> {
+    let best = results.iter().map(|r| r.nps).max()?;
+    results
+        .iter()
+        .find(|r| r.nps >= best / 10 * 9)
+        .map(|r| r.threads)
+}
+
+/// Hash size (MiB) that fits about a minute of search at the best measured
+/// speed, limited to the memory that is available.
+pub fn suggest_hash(results: &[BenchResult], available: u64) -> Option<u64> {
+    let best = results.iter().map(|r| r.nps).max()?;
+    let needed = best * HASH_SEARCH_TIME * HASH_ENTRY_SIZE / (1024 * 1024);
+    Some(needed.max(16).next_power_of_two().min(available))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_counts() {
+        assert_eq!(thread_counts(1), [1]);
+        assert_eq!(thread_counts(4), [1, 2, 4]);
+        assert_eq!(thread_counts(6), [1, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let results = [
+            BenchResult {
+                threads: 1,
+                nps: 1_000_000,
+                depth: 20,
+            },
+            BenchResult {
+                threads: 2,
+                nps: 1_950_000,
+                depth: 21,
+            },
+            BenchResult {
+                threads: 4,
+                nps: 2_000_000,
+                depth: 21,
+            },
+        ];
+        assert_eq!(suggest_threads(&results), Some(2));
+        assert_eq!(suggest_hash(&results, 65536), Some(2048));
+        assert_eq!(suggest_hash(&results, 1024), Some(1024));
+    }
+}
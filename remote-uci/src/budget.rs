@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::notifications::{Notification, Notifications};
+
+/// The budget of each client is refilled completely over this period.
+const REFILL_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Engine time that each client may use, counted as search time multiplied
+/// by threads, and refilled continuously. Clients that used up their
+/// budget can still search, but with fewer threads.
+pub struct CpuBudget {
+    capacity: Duration,
+    degraded_threads: u32,
+    buckets: Mutex<HashMap<String, Arc<Mutex<Bucket>>>>,
+    notifications: Notifications,
+}
+
+struct Bucket {
+    /// Seconds of engine time. Negative after searches that overran the
+    /// budget.
+    available: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, capacity: Duration, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let refill = capacity.as_secs_f64() * elapsed.as_secs_f64() / REFILL_PERIOD.as_secs_f64();
+        self.available = (self.available + refill).min(capacity.as_secs_f64());
+        self.refilled = now;
+    }
+}
+
+impl CpuBudget {
+    pub fn new(
+        capacity: Duration,
+        degraded_threads: u32,
+        notifications: Notifications,
+    ) -> CpuBudget {
+        CpuBudget {
+            capacity,
+            degraded_threads,
+            buckets: Mutex::new(HashMap::new()),
+            notifications,
+        }
+    }
+
+    /// The account of the client, e.g. a frontend or guest.
+    pub fn account(self: &Arc<Self>, client: &str) -> Account {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(client.to_owned()).or_insert_with(|| {
+            Arc::new(Mutex::new(Bucket {
+                available: self.capacity.as_secs_f64(),
+                refilled: Instant::now(),
+            }))
+        });
+        Account {
+            client: client.to_owned(),
+            budget: Arc::clone(self),
+            bucket: Arc::clone(bucket),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Account {
+    client: String,
+    budget: Arc<CpuBudget>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl Account {
+    /// Limit for Threads of the next search, if the budget is used up.
+    pub fn max_threads(&self) -> Option<u32> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        bucket.refill(self.budget.capacity, Instant::now());
+        (bucket.available <= 0.0).then(|| self.budget.degraded_threads)
+    }
+
+    /// Deduct a finished search.
+    pub fn charge(&self, elapsed: Duration, threads: u32) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        bucket.refill(self.budget.capacity, Instant::now());
+        let exhausted = bucket.available <= 0.0;
+        bucket.available -= elapsed.as_secs_f64() * f64::from(threads);
+        if !exhausted && bucket.available <= 0.0 {
+            log::warn!(
+                "{} used up the CPU budget, limiting to {} threads",
+                self.client,
+                self.budget.degraded_threads
+            );
+            self.budget
+                .notifications
+                .send(Notification::QuotaExhausted {
+                    client: self.client.clone(),
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refill() {
+        let capacity = Duration::from_secs(2 * 60 * 60);
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            available: -capacity.as_secs_f64() / 2.0,
+            refilled: start,
+        };
+        bucket.refill(capacity, start + REFILL_PERIOD / 2);
+        assert!(bucket.available.abs() < 1.0);
+        bucket.refill(capacity, start + REFILL_PERIOD * 2);
+        assert_eq!(bucket.available, capacity.as_secs_f64());
+    }
+}
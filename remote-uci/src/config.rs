@@ -0,0 +1,457 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path, slice, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use toml::{value::Table, Value};
+
+use crate::{
+    engine::SessionLimits,
+    middleware::Builtin,
+    notifications,
+    uci::{UciIn, UciOptionName},
+    ws::Secret,
+};
+
+/// Configuration file, for settings that are too structured for command
+/// line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Options to set at the start of every session, before any preset.
+    #[serde(default)]
+    pub options: HashMap<UciOptionName, OptionValue>,
+    /// Named bundles of engine options, that clients can select when
+    /// connecting.
+    #[serde(default)]
+    pub presets: HashMap<String, Preset>,
+    /// Option names used by clients, mapped to the names used by the engine,
+    /// e.g. `Threads = "CPUCores"`.
+    #[serde(default)]
+    pub aliases: HashMap<UciOptionName, UciOptionName>,
+    /// Additional secrets with restricted permissions, by name of the guest.
+    #[serde(default)]
+    pub guests: HashMap<String, Guest>,
+    /// Transforms of commands between clients and the engine, applied in
+    /// order.
+    #[serde(default)]
+    pub middleware: Vec<Builtin>,
+    /// Restrictions on go commands of clients.
+    #[serde(default)]
+    pub go: GoPolicy,
+    /// Where to send notifications about sessions, crashes and used up
+    /// CPU budgets.
+    #[serde(default)]
+    pub notify: Vec<notifications::Subscription>,
+    /// Named sets of settings, selected with --profile, e.g. for running
+    /// the same provider at home and on hotel Wi-Fi.
+    #[serde(default)]
+    pub profile: HashMap<String, Profile>,
+}
+
+/// Settings of a profile, e.g. `[profile.travel]` with
+/// `relay = "wss://relay.example.org"`, `max-threads = 2` and
+/// `options = { Hash = 256 }`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    /// Options to set at the start of every session, overriding the
+    /// top-level options.
+    #[serde(default)]
+    pub options: HashMap<UciOptionName, OptionValue>,
+    /// Command line flags by their long name. Flags that take several
+    /// values are given as arrays, and switches as true.
+    #[serde(flatten)]
+    pub flags: Table,
+}
+
+impl Profile {
+    /// The flags as command line arguments, except for those that
+    /// `is_given` already.
+    pub fn args(&self, is_given: impl Fn(&str) -> bool) -> Result<Vec<String>, ConfigError> {
+        let mut args = Vec::new();
+        for (name, value) in &self.flags {
+            let flag = format!("--{name}");
+            if is_given(&flag) {
+                continue;
+            }
+            let values = match value {
+                Value::Array(values) => values.as_slice(),
+                value => slice::from_ref(value),
+            };
+            for value in values {
+                let value = match value {
+                    Value::Boolean(true) => None,
+                    Value::Boolean(false) => continue,
+                    Value::String(s) => Some(s.clone()),
+                    Value::Integer(i) => Some(i.to_string()),
+                    Value::Float(f) => Some(f.to_string()),
+                    _ => return Err(ConfigError::ProfileValue(name.clone())),
+                };
+                args.push(flag.clone());
+                args.extend(value);
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Guest {
+    pub secret: Secret,
+    /// Clamp Threads for sessions of this guest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_threads: Option<u32>,
+    /// Clamp Hash (MiB) for sessions of this guest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_hash: Option<u32>,
+    /// Clamp MultiPV for sessions of this guest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_multipv: Option<u32>,
+    /// Force UCI_LimitStrength, with at most this UCI_Elo, for sessions of
+    /// this guest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_elo: Option<u32>,
+    /// Whether the guest may select variants other than standard chess,
+    /// including Chess960.
+    #[serde(default = "default_variants")]
+    pub variants: bool,
+}
+
+fn default_variants() -> bool {
+    true
+}
+
+impl Guest {
+    pub fn limits(&self) -> SessionLimits {
+        SessionLimits {
+            max_threads: self.max_threads,
+            max_hash: self.max_hash,
+            max_multipv: self.max_multipv,
+            max_elo: self.max_elo,
+            chess_only: !self.variants,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Preset {
+    /// Also register the engine under this name, with the preset applied to
+    /// all sessions, e.g. `name = "Stockfish 16 - MultiPV 5"`.
+    pub name: Option<String>,
+    /// Options to set at the start of each session.
+    #[serde(default)]
+    pub options: HashMap<UciOptionName, OptionValue>,
+    /// Clamp Threads for sessions using this preset.
+    pub max_threads: Option<u32>,
+    /// Clamp Hash (MiB) for sessions using this preset.
+    pub max_hash: Option<u32>,
+}
+
+/// Search time of a go command that had nothing but clock fields, which
+/// were stripped.
+const STRIPPED_CLOCK_MOVETIME: Duration = Duration::from_secs(10);
+
+/// What to do with a field of go commands.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldPolicy {
+    #[default]
+    Allow,
+    /// Remove the field, and search anyway.
+    Strip,
+    /// Refuse the search with an error, and reply `bestmove (none)` right
+    /// away. The session stays open.
+    Reject,
+}
+
+/// Restrictions on go commands of clients, e.g. `[go]` with
+/// `ponder = "reject"` and `max-searchmoves = 5`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GoPolicy {
+    #[serde(default)]
+    pub ponder: FieldPolicy,
+    /// Clock based time controls: wtime, btime, winc, binc and movestogo.
+    /// A search without other limits searches for 10 seconds when they are
+    /// stripped, rather than until stopped.
+    #[serde(default)]
+    pub clock: FieldPolicy,
+    /// Reject searches restricted to more than this many moves.
+    pub max_searchmoves: Option<usize>,
+}
+
+impl GoPolicy {
+    /// Strip fields from a go command, or return the first field that is
+    /// not permitted.
+    pub fn apply(&self, command: &mut UciIn) -> Result<(), &'static str> {
+        if let UciIn::Go {
+            ponder,
+            searchmoves,
+            wtime,
+            btime,
+            winc,
+            binc,
+            movestogo,
+            depth,
+            nodes,
+            mate,
+            movetime,
+            infinite,
+        } = command
+        {
+            if *ponder {
+                match self.ponder {
+                    FieldPolicy::Allow => (),
+                    FieldPolicy::Strip => *ponder = false,
+                    FieldPolicy::Reject => return Err("ponder"),
+                }
+            }
+            if wtime.is_some()
+                || btime.is_some()
+                || winc.is_some()
+                || binc.is_some()
+                || movestogo.is_some()
+            {
+                match self.clock {
+                    FieldPolicy::Allow => (),
+                    FieldPolicy::Strip => {
+                        *wtime = None;
+                        *btime = None;
+                        *winc = None;
+                        *binc = None;
+                        *movestogo = None;
+                        if depth.is_none()
+                            && nodes.is_none()
+                            && mate.is_none()
+                            && movetime.is_none()
+                            && !*infinite
+                        {
+                            *movetime = Some(STRIPPED_CLOCK_MOVETIME);
+                        }
+                    }
+                    FieldPolicy::Reject => return Err("clock"),
+                }
+            }
+            if let (Some(max), Some(moves)) = (self.max_searchmoves, searchmoves) {
+                if moves.len() > max {
+                    return Err("searchmoves");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Bool(b) => b.fmt(f),
+            OptionValue::Int(i) => i.fmt(f),
+            OptionValue::String(s) => s.fmt(f),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read config: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid config: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("no profile {0:?} in config")]
+    MissingProfile(String),
+    #[error("unsupported value for {0} in profile")]
+    ProfileValue(String),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&Profile, ConfigError> {
+        self.profile
+            .get(name)
+            .ok_or_else(|| ConfigError::MissingProfile(name.to_owned()))
+    }
+
+    /// Use the options of the profile. Its flags are applied to the command
+    /// line instead.
+    pub fn select_profile(&mut self, name: &str) -> Result<(), ConfigError> {
+        let options = self.profile(name)?.options.clone();
+        self.options.extend(options);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            [presets.fast]
+            max-threads = 2
+            options = { MultiPV = 1, UCI_AnalyseMode = true }
+
+            [presets.lc0-gpu]
+            name = "Lc0 GPU"
+            options = { Backend = "cuda-fp16" }
+            "#,
+        )?;
+        let fast = &config.presets["fast"];
+        assert_eq!(fast.max_threads, Some(2));
+        assert_eq!(fast.max_hash, None);
+        assert_eq!(fast.name, None);
+        assert_eq!(
+            fast.options[&UciOptionName("multipv".to_owned())].to_string(),
+            "1"
+        );
+        assert_eq!(
+            config.presets["lc0-gpu"].options[&UciOptionName("Backend".to_owned())].to_string(),
+            "cuda-fp16"
+        );
+        assert_eq!(config.presets["lc0-gpu"].name.as_deref(), Some("Lc0 GPU"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_options() -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            [options]
+            Contempt = 0
+            "#,
+        )?;
+        assert_eq!(
+            config.options[&UciOptionName("contempt".to_owned())].to_string(),
+            "0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_guests() -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            [guests.alice]
+            secret = "not-so-secret"
+            max-threads = 2
+            variants = false
+
+            [guests.training]
+            secret = "also-not-secret"
+            max-elo = 1800
+            "#,
+        )?;
+        let limits = config.guests["alice"].limits();
+        assert_eq!(limits.max_threads, Some(2));
+        assert_eq!(limits.max_multipv, None);
+        assert_eq!(limits.max_elo, None);
+        assert_eq!(config.guests["training"].limits().max_elo, Some(1800));
+        assert!(limits.chess_only);
+        Ok(())
+    }
+
+    #[test]
+    fn test_go_policy() -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            [go]
+            ponder = "reject"
+            clock = "strip"
+            max-searchmoves = 2
+            "#,
+        )?;
+        let apply = |line: &str| {
+            let mut command = UciIn::from_line(line).unwrap().unwrap();
+            config.go.apply(&mut command).map(|()| command.to_string())
+        };
+        assert_eq!(apply("go depth 20"), Ok("go depth 20".to_owned()));
+        assert_eq!(
+            apply("go wtime 1000 btime 1000 movetime 500"),
+            Ok("go movetime 500".to_owned())
+        );
+        assert_eq!(
+            apply("go wtime 1000 btime 1000"),
+            Ok("go movetime 10000".to_owned())
+        );
+        assert_eq!(apply("go ponder infinite"), Err("ponder"));
+        assert_eq!(apply("go searchmoves e2e4 d2d4 g1f3"), Err("searchmoves"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_profiles() -> Result<(), ConfigError> {
+        let mut config: Config = toml::from_str(
+            r#"
+            [options]
+            Hash = 1024
+            Contempt = 0
+
+            [profile.travel]
+            relay = "wss://relay.example.org"
+            relay-id = "laptop"
+            max-threads = 2
+            bind = ["127.0.0.1:9670", "[::1]:9670"]
+            upnp = false
+            allow-sleep = true
+            options = { Hash = 256 }
+            "#,
+        )?;
+        let args = config
+            .profile("travel")?
+            .args(|flag| flag == "--relay-id")?;
+        assert_eq!(
+            args,
+            [
+                "--allow-sleep",
+                "--bind",
+                "127.0.0.1:9670",
+                "--bind",
+                "[::1]:9670",
+                "--max-threads",
+                "2",
+                "--relay",
+                "wss://relay.example.org",
+            ]
+        );
+        assert!(matches!(
+            config.profile("home"),
+            Err(ConfigError::MissingProfile(_))
+        ));
+        config.select_profile("travel")?;
+        assert_eq!(
+            config.options[&UciOptionName("hash".to_owned())].to_string(),
+            "256"
+        );
+        assert_eq!(config.options.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> Result<(), toml::de::Error> {
+        let config: Config = toml::from_str(
+            r#"
+            [aliases]
+            Threads = "Max CPUs"
+            "#,
+        )?;
+        assert_eq!(
+            config.aliases[&UciOptionName("threads".to_owned())],
+            UciOptionName("max cpus".to_owned())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    backtrace::Backtrace,
+    env,
+    fmt::{self, Write as _},
+    fs, io, panic,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Client;
+use sysinfo::{System, SystemExt};
+
+use crate::logs;
+
+const REPORT_PREFIX: &str = "remote-uci-crash-";
+
+/// Write a report to a file in `dir` whenever a thread panics, in addition
+/// to the usual message on stderr. The hook is shared by the whole process.
+pub fn install_panic_hook(dir: PathBuf) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        match write_report(&dir, &report(info)) {
+            Ok(path) => eprintln!("Crash report written to {path:?}"),
+            Err(err) => eprintln!("Could not write crash report: {err}"),
+        }
+    }));
+}
+
+fn report(info: &dyn fmt::Display) -> String {
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "remote-uci {} crash report",
+        env!("CARGO_PKG_VERSION")
+    );
+    let _ = writeln!(
+        report,
+        "Time: {}",
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    let _ = writeln!(
+        report,
+        "OS: {} ({})",
+        System::new()
+            .long_os_version()
+            .unwrap_or_else(|| env::consts::OS.to_owned()),
+        env::consts::ARCH
+    );
+    let _ = writeln!(
+        report,
+        "Thread: {}",
+        std::thread::current().name().unwrap_or("unnamed")
+    );
+    let _ = writeln!(report, "Panic: {info}");
+    let _ = writeln!(report, "\nBacktrace:\n{}", Backtrace::force_capture());
+    let _ = writeln!(report, "\nRecent log:");
+    for line in logs::recent() {
+        let _ = writeln!(report, "{line}");
+    }
+    report
+}
+
+fn write_report(dir: &Path, report: &str) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = dir.join(format!("{REPORT_PREFIX}{timestamp}-{}.txt", process::id()));
+    fs::write(&path, report)?;
+    Ok(path)
+}
+
+/// Upload crash reports of previous runs, and mark them as sent.
+pub async fn upload_reports(url: String, dir: PathBuf) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("Could not look for crash reports: {err}");
+            return;
+        }
+    };
+    let client = Client::new();
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        let pending = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| {
+                name.starts_with(REPORT_PREFIX) && name.ends_with(".txt")
+            });
+        if !pending {
+            continue;
+        }
+        let report = match fs::read_to_string(&path) {
+            Ok(report) => report,
+            Err(err) => {
+                log::warn!("Could not read crash report {path:?}: {err}");
+                continue;
+            }
+        };
+        match client
+            .post(&url)
+            .body(report)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+        {
+            Ok(_) => {
+                log::info!("Uploaded crash report {path:?}");
+                if let Err(err) = fs::rename(&path, path.with_extension("txt.sent")) {
+                    log::warn!("Could not mark crash report {path:?} as sent: {err}");
+                }
+            }
+            Err(err) => {
+                log::warn!("Could not upload crash report {path:?}: {err}");
+                break;
+            }
+        }
+    }
+}
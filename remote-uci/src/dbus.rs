@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, watch};
+use zbus::{dbus_interface, ConnectionBuilder};
+
+use crate::{server::Stop, ws::SharedEngine};
+
+const NAME: &str = "org.lichess.RemoteUci";
+const PATH: &str = "/org/lichess/RemoteUci";
+
+struct RemoteUci {
+    engine: Arc<SharedEngine>,
+    searching: watch::Receiver<bool>,
+    url: String,
+    stop: mpsc::Sender<Stop>,
+}
+
+#[dbus_interface(name = "org.lichess.RemoteUci")]
+impl RemoteUci {
+    /// One of idle, searching, paused or restarting.
+    #[dbus_interface(property)]
+    fn status(&self) -> String {
+        if self.engine.is_paused() {
+            "paused"
+        } else if !self.engine.health().is_running() {
+            "restarting"
+        } else if *self.searching.borrow() {
+            "searching"
+        } else {
+            "idle"
+        }
+        .to_owned()
+    }
+
+    /// Link that adds the engine to a Lichess account.
+    #[dbus_interface(property)]
+    fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Close all sessions and refuse new ones until resumed.
+    fn pause(&self) {
+        self.engine.set_paused(true);
+    }
+
+    fn resume(&self) {
+        self.engine.set_paused(false);
+    }
+
+    /// Stop the server gracefully.
+    fn shutdown(&self) {
+        log::warn!("Shutting down as requested over D-Bus");
+        let _ = self.stop.try_send(Stop::Requested);
+    }
+}
+
+/// Offer status and controls on the session bus, so that desktop widgets
+/// and scripts do not need to poll the HTTP API.
+pub async fn serve(
+    engine: Arc<SharedEngine>,
+    searching: watch::Receiver<bool>,
+    url: String,
+    stop: mpsc::Sender<Stop>,
+) -> zbus::Result<()> {
+    let connection = ConnectionBuilder::session()?
+        .name(NAME)?
+        .serve_at(
+            PATH,
+            RemoteUci {
+                engine: Arc::clone(&engine),
+                searching: searching.clone(),
+                url,
+                stop,
+            },
+        )?
+        .build()
+        .await?;
+    let iface = connection
+        .object_server()
+        .interface::<_, RemoteUci>(PATH)
+        .await?;
+    log::info!("Serving {NAME} on the D-Bus session bus");
+
+    tokio::spawn(async move {
+        let _connection = connection;
+        let mut health = engine.watch_health();
+        let mut paused = engine.watch_paused();
+        let mut searching = searching;
+        loop {
+            let changed = tokio::select! {
+                changed = health.changed() => changed,
+                changed = paused.changed() => changed,
+                changed = searching.changed() => changed,
+            };
+            if changed.is_err() {
+                break;
+            }
+            let iface_ref = iface.get().await;
+            if let Err(err) = iface_ref.status_changed(iface.signal_context()).await {
+                log::warn!("Could not signal status change on D-Bus: {err}");
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{ready, Stream, StreamExt};
+use rand::{thread_rng, Rng};
+use thiserror::Error;
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Artificial delay of messages to and from clients, to try clients against
+/// a slow connection without having one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SimulatedLatency {
+    delay: Duration,
+    jitter: Duration,
+}
+
+#[derive(Error, Debug)]
+#[error("expected ms or ms:jitter, e.g. 150:50")]
+pub struct InvalidSimulatedLatency;
+
+impl FromStr for SimulatedLatency {
+    type Err = InvalidSimulatedLatency;
+
+    fn from_str(s: &str) -> Result<SimulatedLatency, InvalidSimulatedLatency> {
+        let (delay, jitter) = s.split_once(':').unwrap_or((s, "0"));
+        let parse = |ms: &str| {
+            ms.parse()
+                .map(Duration::from_millis)
+                .map_err(|_| InvalidSimulatedLatency)
+        };
+        Ok(SimulatedLatency {
+            delay: parse(delay)?,
+            jitter: parse(jitter)?,
+        })
+    }
+}
+
+impl fmt::Display for SimulatedLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ms", self.delay.as_millis())?;
+        if !self.jitter.is_zero() {
+            write!(f, " ± {} ms", self.jitter.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+impl SimulatedLatency {
+    /// Delay for the next message, within jitter of the configured delay.
+    fn sample(&self) -> Duration {
+        let jitter = thread_rng().gen_range(Duration::ZERO..=self.jitter * 2);
+        (self.delay + jitter).saturating_sub(self.jitter)
+    }
+}
+
+/// Stream that yields each item of the inner stream only after a simulated
+/// delay. Items stay in order, like on a real connection, so that an item
+/// is never delivered before an earlier one, even with jitter.
+///
+/// The next item is taken from the inner stream only once the previous one
+/// is delivered, so that items wait in the inner stream, e.g. an outbox
+/// that drops superseded items when it is full.
+pub struct Delayed<S: Stream> {
+    inner: S,
+    latency: Option<SimulatedLatency>,
+    in_flight: Option<S::Item>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S: Stream> Delayed<S> {
+    /// Delay the items of the stream, or pass them through unchanged
+    /// without latency.
+    pub fn new(inner: S, latency: Option<SimulatedLatency>) -> Delayed<S> {
+        Delayed {
+            inner,
+            latency,
+            in_flight: None,
+            sleep: Box::pin(sleep(Duration::ZERO)),
+        }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for Delayed<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let latency = match this.latency {
+            Some(latency) => latency,
+            None => return this.inner.poll_next_unpin(cx),
+        };
+
+        if this.in_flight.is_none() {
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(item) => {
+                    this.sleep.as_mut().reset(Instant::now() + latency.sample());
+                    this.in_flight = Some(item);
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+        ready!(this.sleep.as_mut().poll(cx));
+        Poll::Ready(this.in_flight.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulated_latency() {
+        let latency: SimulatedLatency = "150:50".parse().unwrap();
+        for _ in 0..100 {
+            let delay = latency.sample();
+            assert!(Duration::from_millis(100) <= delay && delay <= Duration::from_millis(200));
+        }
+        assert_eq!(
+            "20".parse::<SimulatedLatency>().unwrap().sample(),
+            Duration::from_millis(20)
+        );
+        assert!("20:".parse::<SimulatedLatency>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delayed() {
+        let latency = "30".parse().ok();
+        let started = Instant::now();
+        let mut delayed = Delayed::new(futures_util::stream::iter([1, 2, 3]), latency);
+        for (i, expected) in [1, 2, 3].into_iter().enumerate() {
+            assert_eq!(delayed.next().await, Some(expected));
+            assert!(started.elapsed() >= Duration::from_millis(30) * (i as u32 + 1));
+        }
+        assert_eq!(delayed.next().await, None);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    net::TcpListener,
+    path::Path,
+    process::Stdio,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::future;
+use reqwest::{header::DATE, Client};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+    time::timeout,
+};
+use tokio_tungstenite::{connect_async, tungstenite};
+
+const ENGINE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clock skew that is tolerated before warning.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Result of a single diagnostic check. Problems include a suggested fix.
+pub enum Outcome {
+    Ok(String),
+    Warn(String),
+    Fail(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Ok(msg) => write!(f, "ok    {msg}"),
+            Outcome::Warn(msg) => write!(f, "warn  {msg}"),
+            Outcome::Fail(msg) => write!(f, "FAIL  {msg}"),
+        }
+    }
+}
+
+/// Print the outcome of all checks, and fail if any of them failed.
+pub fn report(checks: Vec<(&str, Outcome)>) -> Result<(), Box<dyn Error>> {
+    let mut failed = 0;
+    for (name, outcome) in &checks {
+        println!("{name:<16} {outcome}");
+        if matches!(outcome, Outcome::Fail(_)) {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} checks failed").into());
+    }
+    Ok(())
+}
+
+/// Launch the engine and wait for it to answer `uci`.
+pub async fn check_engine(path: &Path) -> Outcome {
+    let mut process = match Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(process) => process,
+        Err(err) => {
+            return Outcome::Fail(format!(
+                "could not launch {path:?}: {err}. Check that --engine points to an executable file"
+            ))
+        }
+    };
+    let started = Instant::now();
+    let mut stdin = process.stdin.take().expect("piped stdin");
+    let mut stdout = BufReader::new(process.stdout.take().expect("piped stdout")).lines();
+    let handshake = timeout(ENGINE_TIMEOUT, async {
+        stdin.write_all(b"uci\n").await?;
+        stdin.flush().await?;
+        let mut name = None;
+        while let Some(line) = stdout.next_line().await? {
+            let line = line.trim();
+            if let Some(id) = line.strip_prefix("id name ") {
+                name = Some(id.to_owned());
+            } else if line == "uciok" {
+                return Ok(name);
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+    })
+    .await;
+    let outcome = match handshake {
+        Ok(Ok(name)) => Outcome::Ok(format!(
+            "{} answered uci in {} ms",
+            name.as_deref().unwrap_or("engine"),
+            started.elapsed().as_millis()
+        )),
+        Ok(Err(_)) => Outcome::Fail(match process.wait().await {
+            Ok(status) if crashed_on_illegal_instruction(status) => {
+                "engine crashed with an illegal instruction. It was built for newer CPUs, select a build for this CPU, or give one per variant with --engine-x86-64-*".to_owned()
+            }
+            Ok(status) => format!(
+                "engine exited before uciok ({status}). Check that it is a UCI engine, and run it manually to see errors"
+            ),
+            Err(err) => format!("engine exited before uciok: {err}"),
+        }),
+        Err(_) => Outcome::Fail(format!(
+            "engine did not answer uci within {} seconds. Check that it is a UCI engine, not a GUI or a wrapper script waiting for input",
+            ENGINE_TIMEOUT.as_secs()
+        )),
+    };
+    let _ = stdin.write_all(b"quit\n").await;
+    outcome
+}
+
+#[cfg(unix)]
+fn crashed_on_illegal_instruction(status: std::process::ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    const SIGILL: i32 = 4;
+    status.signal() == Some(SIGILL)
+}
+
+#[cfg(windows)]
+fn crashed_on_illegal_instruction(status: std::process::ExitStatus) -> bool {
+    const STATUS_ILLEGAL_INSTRUCTION: u32 = 0xC000_001D;
+    status.code().map(|code| code as u32) == Some(STATUS_ILLEGAL_INSTRUCTION)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn crashed_on_illegal_instruction(_status: std::process::ExitStatus) -> bool {
+    false
+}
+
+/// Check that the secret file exists, is long enough, and is not readable
+/// by other users.
+pub fn check_secret_file(path: Option<&Path>) -> Outcome {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            return Outcome::Warn(
+                "no --secret-file, registrations stop working after each restart".to_owned(),
+            )
+        }
+    };
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Outcome::Warn(format!("{path:?} does not exist yet, and will be created"))
+        }
+        Err(err) => return Outcome::Fail(format!("could not read {path:?}: {err}")),
+    };
+    if metadata.len() < 8 {
+        return Outcome::Fail(format!(
+            "{path:?} is too short and will be ignored. Delete it to create a new secret"
+        ));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            return Outcome::Fail(format!(
+                "{path:?} is accessible by other users. Run chmod 600 {path:?}"
+            ));
+        }
+    }
+    Outcome::Ok(format!("{path:?} is private"))
+}
+
+/// Compare the local clock with the date reported by the frontend.
+pub async fn check_clock(frontend: &str) -> Outcome {
+    let res = match Client::new()
+        .head(frontend)
+        .timeout(HTTP_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => {
+            return Outcome::Warn(format!("could not reach {frontend}: {}", root_cause(&err)))
+        }
+    };
+    let remote = match res
+        .headers()
+        .get(DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(parse_http_date)
+    {
+        Some(remote) => remote,
+        None => return Outcome::Warn(format!("{frontend} did not report its time")),
+    };
+    let local = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let skew = Duration::from_secs(local.abs_diff(remote));
+    if skew > MAX_CLOCK_SKEW {
+        Outcome::Warn(format!(
+            "clock differs from {frontend} by {} seconds. Enable time synchronization (NTP), so that invite links expire on time",
+            skew.as_secs()
+        ))
+    } else {
+        Outcome::Ok(format!("clock matches {frontend}"))
+    }
+}
+
+/// Check the certificate presented at the published address. Any HTTP
+/// response means that the TLS handshake succeeded.
+pub async fn check_tls(url: &str) -> Outcome {
+    match Client::new().get(url).timeout(HTTP_TIMEOUT).send().await {
+        Ok(_) => Outcome::Ok(format!("certificate of {url} is valid")),
+        Err(err) if err.is_timeout() => {
+            Outcome::Warn(format!("{url} did not respond in time"))
+        }
+        Err(err) => Outcome::Fail(format!(
+            "TLS connection to {url} failed: {}. Renew the certificate of the proxy in front of remote-uci, or drop --publish-addr-tls",
+            root_cause(&err)
+        )),
+    }
+}
+
+/// Check that a request to the published address arrives at one of the
+/// sockets that the server would listen on.
+pub async fn check_reachable(listeners: io::Result<Vec<TcpListener>>, url: &str) -> Outcome {
+    let listeners = match listeners.and_then(|listeners| {
+        listeners
+            .into_iter()
+            .map(|listener| {
+                listener.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(listener)
+            })
+            .collect::<io::Result<Vec<_>>>()
+    }) {
+        Ok(listeners) if !listeners.is_empty() => listeners,
+        Ok(_) => return Outcome::Fail("no address to bind".to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+            return Outcome::Warn(
+                "port is in use, probably by a running provider. Stop it to check reachability"
+                    .to_owned(),
+            )
+        }
+        Err(err) => {
+            return Outcome::Fail(format!(
+                "could not bind: {err}. Choose another address with --bind"
+            ))
+        }
+    };
+    let bind = listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let accept = future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept())));
+    let request = Client::new().get(url).timeout(HTTP_TIMEOUT).send();
+    tokio::select! {
+        (accepted, _, _) = accept => match accepted {
+            Ok(_) => Outcome::Ok(format!("{url} reaches {bind}")),
+            Err(err) => Outcome::Fail(format!("could not accept on {bind}: {err}")),
+        },
+        res = request => Outcome::Fail(format!(
+            "{url} does not reach {bind}{}. Forward the port on the router, use --upnp, or serve through a --relay. Some routers can not be reached by their public address from inside the network",
+            match res {
+                Ok(res) => format!(" (answered by something else: {})", res.status()),
+                Err(err) => format!(" ({})", root_cause(&err)),
+            }
+        )),
+    }
+}
+
+/// Connect to the published address like lichess would, and complete the
+/// WebSocket handshake. Unlike the other checks, this runs against the
+/// provider that is already serving.
+pub async fn check_handshake(url: &str, secret: &str) -> Outcome {
+    let query = serde_urlencoded::to_string([("secret", secret), ("session", "self-check")])
+        .expect("encode query");
+    let err = match timeout(HTTP_TIMEOUT, connect_async(format!("{url}?{query}"))).await {
+        Ok(Ok((mut socket, _))) => {
+            let _ = socket.close(None).await;
+            return Outcome::Ok(format!("{url} reaches this provider"));
+        }
+        Ok(Err(err)) => err,
+        Err(_) => {
+            return Outcome::Fail(format!(
+                "{url} did not complete the handshake in time. Check that the port is forwarded and not blocked by a firewall"
+            ))
+        }
+    };
+    Outcome::Fail(match err {
+        tungstenite::Error::Http(res) => match res.status().as_u16() {
+            400 | 426 => format!(
+                "{url} answered {} instead of upgrading to a WebSocket. Let the reverse proxy forward the Upgrade and Connection headers (see the proxy-config subcommand)",
+                res.status()
+            ),
+            401 | 403 => format!(
+                "{url} rejected the secret ({}). It reaches another provider, or a proxy that drops the query string",
+                res.status()
+            ),
+            503 => format!(
+                "{url} answered {}. The engine is not running, or the reverse proxy can not reach this provider",
+                res.status()
+            ),
+            _ => format!(
+                "{url} answered {}, so it does not reach this provider. Point --publish-addr or the reverse proxy here (see the proxy-config subcommand)",
+                res.status()
+            ),
+        },
+        tungstenite::Error::Tls(err) => format!(
+            "TLS handshake with {url} failed: {err}. Renew the certificate, or drop --publish-addr-tls if the address does not use TLS"
+        ),
+        tungstenite::Error::Io(err) if err.kind() == io::ErrorKind::ConnectionRefused => format!(
+            "{url} refused the connection. Forward the port on the router, use --upnp, or serve through a --relay. Some routers can not be reached by their public address from inside the network"
+        ),
+        // Rustls reports plain HTTP answers as corrupt messages.
+        tungstenite::Error::Io(err) if err.kind() == io::ErrorKind::InvalidData => format!(
+            "TLS handshake with {url} failed: {}. Drop --publish-addr-tls if the address does not use TLS",
+            root_cause(&err)
+        ),
+        tungstenite::Error::Io(err) => format!(
+            "could not connect to {url}: {}. Check that the host name in --publish-addr resolves to this machine",
+            root_cause(&err)
+        ),
+        tungstenite::Error::Url(err) => {
+            format!("{url} is not a valid address: {err}. Fix --publish-addr")
+        }
+        err => format!(
+            "handshake with {url} failed: {}. It probably reaches another service instead of this provider",
+            root_cause(&err)
+        ),
+    })
+}
+
+/// The innermost error, since HTTP errors repeat their sources in their
+/// own messages.
+fn root_cause(mut err: &dyn Error) -> String {
+    while let Some(source) = err.source() {
+        err = source;
+    }
+    err.to_string()
+}
+
+/// Parse an IMF-fixdate, like `Sun, 06 Nov 1994 08:49:37 GMT`, into seconds
+/// since the Unix epoch.
+fn parse_http_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace().skip(1);
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (hour, min, sec) = (time.next()??, time.next()??, time.next()??);
+    if year < 1970 || parts.next()? != "GMT" {
+        return None;
+    }
+
+    // Days since the epoch, counting years from March to put leap days last.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let days = 365 * y + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719_468;
+    Some(days * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"),
+            Some(1_709_208_000)
+        );
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+}
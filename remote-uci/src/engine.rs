@@ -1,212 +1,1127 @@
-use std::{collections::HashMap, io, path::PathBuf, process::Stdio};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io, mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use shakmaty::{uci::Uci, CastlingMode, Chess, Color, Position};
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    process::{ChildStdin, ChildStdout, Command},
+    sync::{mpsc, watch},
+    time::timeout_at,
 };
 
-use crate::uci::{UciIn, UciOption, UciOptionName, UciOut};
+use crate::{
+    baseline::NpsMonitor,
+    budget::{Account, CpuBudget},
+    config::{GoPolicy, OptionValue, Preset},
+    history::History,
+    latency::{Latency, Reply},
+    ledger::ThreadLedger,
+    memory::{max_hash_without_swap, MemorySampler},
+    network,
+    pending::{PendingReplies, Request},
+    privacy::Privacy,
+    standby::Standby,
+    transport::{EngineReader, EngineTransport, EngineWriter},
+    uci::{Eval, UciIn, UciOption, UciOptionName, UciOut},
+    verify::Verifier,
+};
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub struct Session(pub u64);
 
 pub struct Engine {
-    pending_uciok: u64,
-    pending_readyok: u64,
+    /// Pending uci and isready commands, to match them with uciok and
+    /// readyok.
+    pending: PendingReplies,
     searching: bool,
     options: HashMap<UciOptionName, UciOption>,
     name: Option<String>,
+    author: Option<String>,
+    transport: Arc<dyn EngineTransport>,
     params: EngineParameters,
-    stdin: BufWriter<ChildStdin>,
-    stdout: BufReader<ChildStdout>,
+    session_limits: SessionLimits,
+    info_filter: InfoFilter,
+    /// Point of view of scores for the client, and whether Black is to move
+    /// in the current position.
+    perspective: Perspective,
+    black_to_move: bool,
+    debug: bool,
+    /// Threads requested by the client, and currently set in the engine.
+    threads: Option<u32>,
+    applied_threads: Option<u32>,
+    /// Hash currently set in the engine, if known.
+    hash: Option<u32>,
+    /// Messages for the client, delivered before further output of the
+    /// engine.
+    notices: VecDeque<UciOut>,
+    /// CPU budget of the client of the current session, and the start of
+    /// the search that is charged to it.
+    account: Option<Account>,
+    search_started: Option<Instant>,
+    /// Speed last reported during the current search.
+    search_nps: Option<u64>,
+    /// Last position and principal evaluation, for verification and
+    /// history.
+    position: Option<UciIn>,
+    eval: Option<Eval>,
+    depth: Option<u32>,
+    pv: Vec<Uci>,
+    /// Options changed by clients or presets, that are reset to their
+    /// defaults before the next session.
+    changed_options: Vec<UciOptionName>,
+    /// Memory available on the host, to keep Hash from making it swap.
+    memory: MemorySampler,
+    /// Options and pending search of the current session, to restore them
+    /// if the engine process has to be replaced during the session.
+    session_options: Vec<(UciOptionName, Option<String>)>,
+    search: Option<UciIn>,
+    /// Whether the current search is run by the server rather than the
+    /// client, e.g. to ponder, so that it is neither charged to the client
+    /// nor recorded.
+    server_search: bool,
+    stop_sent: Option<Instant>,
+    /// Whether the engine has not yet sent info for the current search, to
+    /// measure how long it takes to reply.
+    awaiting_info: bool,
+    stdin: mpsc::UnboundedSender<String>,
+    stdout: mpsc::Receiver<io::Result<String>>,
+}
+
+/// Number of lines from the engine that are buffered before the engine
+/// is blocked.
+const STDOUT_BUFFER: usize = 1024;
+
+/// Snapshot of what is known about the engine after the handshake.
+pub struct EngineInfo {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub evaluation: Option<Evaluation>,
+    pub options: HashMap<UciOptionName, UciOption>,
+    pub aliases: HashMap<UciOptionName, UciOptionName>,
+    pub client_options: bool,
+    /// Neural network that the engine is configured to load.
+    pub network: Option<String>,
 }
 
+impl EngineInfo {
+    /// Look up an option by the name that clients would use.
+    pub fn option(&self, name: &UciOptionName) -> Option<&UciOption> {
+        self.options.get(self.aliases.get(name).unwrap_or(name))
+    }
+
+    pub fn max_threads(&self) -> i64 {
+        self.named_option("Threads")
+            .and_then(UciOption::max)
+            .unwrap_or(1)
+    }
+
+    pub fn max_hash(&self) -> i64 {
+        self.named_option("Hash")
+            .and_then(UciOption::max)
+            .unwrap_or(16)
+    }
+
+    pub fn max_multipv(&self) -> i64 {
+        self.named_option("MultiPV")
+            .and_then(UciOption::max)
+            .unwrap_or(1)
+    }
+
+    pub fn variants(&self) -> &[String] {
+        self.named_option("UCI_Variant")
+            .and_then(UciOption::var)
+            .unwrap_or_default()
+    }
+
+    fn named_option(&self, name: &str) -> Option<&UciOption> {
+        self.option(&UciOptionName(name.to_owned()))
+    }
+
+    /// Whether clients can set the option with the given engine-side name.
+    pub fn is_settable(&self, name: &UciOptionName) -> bool {
+        self.client_options
+            && (name.is_safe()
+                || self
+                    .aliases
+                    .iter()
+                    .any(|(alias, target)| target == name && alias.is_safe()))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Evaluation {
+    Nnue,
+    Classical,
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Evaluation::Nnue => "nnue",
+            Evaluation::Classical => "classical",
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct EngineParameters {
     pub max_threads: u32,
     pub max_hash: u32,
+    pub max_multipv: u32,
+    pub info_filter: InfoFilter,
+    /// Point of view of the scores reported by the engine.
+    pub score_perspective: Perspective,
+    /// Option names used by clients, mapped to the names used by the engine.
+    pub aliases: HashMap<UciOptionName, UciOptionName>,
+    /// Number of threads that can currently be used without competing with
+    /// other programs on the host.
+    pub thread_budget: Option<watch::Receiver<u32>>,
+    /// Threads used by other instances on the host, that count towards
+    /// max_threads.
+    pub thread_ledger: Option<Arc<ThreadLedger>>,
+    /// Publishes whether the engine is searching, across restarts.
+    pub searching: Arc<watch::Sender<bool>>,
+    /// Nodes per second last reported by the engine, or 0 if not yet known.
+    pub nps: Arc<AtomicU64>,
+    /// How long the engine takes to reply, across restarts.
+    pub latency: Arc<Latency>,
+    /// Comparison of the speed with the benchmark.
+    pub nps_monitor: Option<Arc<NpsMonitor>>,
+    /// Secondary engine that double checks results.
+    pub verifier: Option<Verifier>,
+    /// Record of analysed positions.
+    pub history: Option<History>,
+    /// Engine time that each client may use.
+    pub cpu_budget: Option<Arc<CpuBudget>>,
+    /// Options set by the operator at the start of every session.
+    pub options: HashMap<UciOptionName, OptionValue>,
+    /// Whether clients may set any options at all.
+    pub client_options: bool,
+    /// Restrictions on go commands of clients.
+    pub go_policy: GoPolicy,
+    /// Whether malformed output of the engine is an error, rather than
+    /// skipped.
+    pub strict_uci: bool,
+    /// Warmed up engine process that takes over when this one fails.
+    pub standby: Option<Arc<Standby>>,
+    /// What is left out of logs and the history.
+    pub privacy: Privacy,
+}
+
+/// Point of view of scores.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ArgEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Perspective {
+    /// The side to move, as specified by UCI.
+    #[default]
+    SideToMove,
+    /// White, regardless of the side to move.
+    White,
+}
+
+/// Which info lines from the engine are considered noise, and not forwarded
+/// to clients.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, ArgEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InfoFilter {
+    /// Forward all info lines.
+    Off,
+    /// Skip only progress reports, like depth, nodes and time.
+    Conservative,
+    /// Skip all info lines without pv, score or string.
+    #[default]
+    Aggressive,
+}
+
+impl InfoFilter {
+    fn is_noise(self, command: &UciOut) -> bool {
+        match (self, command) {
+            (InfoFilter::Off, _) => false,
+            (
+                InfoFilter::Conservative,
+                UciOut::Info {
+                    pv: None,
+                    string: None,
+                    score: None,
+                    currmove: None,
+                    currmovenumber: None,
+                    hashfull: None,
+                    tbhits: None,
+                    sbhits: None,
+                    cpuload: None,
+                    refutation,
+                    currline,
+                    ..
+                },
+            ) => refutation.is_empty() && currline.is_empty(),
+            (
+                InfoFilter::Aggressive,
+                UciOut::Info {
+                    pv: None,
+                    string: None,
+                    score: None,
+                    ..
+                },
+            ) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Additional limits that apply only to the current session.
+#[derive(Clone, Default)]
+pub struct SessionLimits {
+    pub max_threads: Option<u32>,
+    pub max_hash: Option<u32>,
+    pub max_multipv: Option<u32>,
+    /// Force UCI_LimitStrength, with UCI_Elo at most this.
+    pub max_elo: Option<u32>,
+    /// Reject selecting variants other than standard chess.
+    pub chess_only: bool,
+}
+
+/// Options and position set by the client during a session, to set them
+/// again when the client continues on a different engine process or in a
+/// new session.
+#[derive(Clone, Default)]
+pub struct SessionState {
+    options: Vec<(UciOptionName, Option<String>)>,
+    position: Option<UciIn>,
 }
 
 impl Engine {
-    pub async fn new(path: PathBuf, params: EngineParameters) -> io::Result<Engine> {
-        log::info!("Starting engine {path:?} ...");
-
-        let mut process = Command::new(path)
-            .stdout(Stdio::piped())
-            .stdin(Stdio::piped())
-            .spawn()?;
-
-        let mut engine =
-            Engine {
-                pending_uciok: 0,
-                pending_readyok: 0,
-                searching: false,
-                options: HashMap::new(),
-                name: None,
-                params,
-                stdin: BufWriter::new(process.stdin.take().ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::BrokenPipe, "engine stdin closed")
-                })?),
-                stdout: BufReader::new(process.stdout.take().ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::BrokenPipe, "engine stdout closed")
-                })?),
-            };
+    pub async fn new(
+        transport: Arc<dyn EngineTransport>,
+        params: EngineParameters,
+    ) -> io::Result<Engine> {
+        let mut engine = Engine::start(transport, params).await?;
+        engine.set_searching(false).await;
+        Ok(engine)
+    }
+
+    /// Start the engine process, without publishing its state yet, so that
+    /// it can be started in the background while another one is in use.
+    pub async fn start(
+        transport: Arc<dyn EngineTransport>,
+        params: EngineParameters,
+    ) -> io::Result<Engine> {
+        log::info!("Starting engine {transport} ...");
+
+        // Pipes are served by dedicated tasks, so that writing never waits
+        // for reading, and a pending recv() can be cancelled without losing
+        // partial lines.
+        let (stdout, stdin) = transport.connect().await?;
+        let (stdin_tx, stdin_rx) = mpsc::unbounded_channel();
+        let (stdout_tx, stdout_rx) = mpsc::channel(STDOUT_BUFFER);
+        tokio::spawn(write_lines(BufWriter::new(stdin), stdin_rx));
+        tokio::spawn(read_lines(BufReader::new(stdout), stdout_tx));
+
+        let mut engine = Engine {
+            pending: PendingReplies::default(),
+            searching: false,
+            options: HashMap::new(),
+            name: None,
+            author: None,
+            transport,
+            info_filter: params.info_filter,
+            perspective: Perspective::default(),
+            black_to_move: false,
+            debug: false,
+            threads: None,
+            applied_threads: None,
+            hash: None,
+            notices: VecDeque::new(),
+            account: None,
+            search_started: None,
+            search_nps: None,
+            position: None,
+            session_options: Vec::new(),
+            search: None,
+            server_search: false,
+            stop_sent: None,
+            awaiting_info: false,
+            eval: None,
+            depth: None,
+            pv: Vec::new(),
+            params,
+            session_limits: SessionLimits::default(),
+            changed_options: Vec::new(),
+            memory: MemorySampler::default(),
+            stdin: stdin_tx,
+            stdout: stdout_rx,
+        };
 
         let session = Session(0);
         engine.send(session, UciIn::Uci).await?;
+        if engine.default_debug() {
+            engine.send(session, UciIn::Debug(true)).await?;
+        }
         engine.ensure_idle(session).await?;
         Ok(engine)
     }
 
-    pub async fn send(&mut self, session: Session, command: UciIn) -> io::Result<()> {
+    pub async fn respawn(&mut self) -> io::Result<()> {
+        *self = Engine::new(Arc::clone(&self.transport), self.params.clone()).await?;
+        self.refill_standby();
+        Ok(())
+    }
+
+    /// Replace the engine process after it failed, with the standby engine
+    /// if there is one.
+    pub async fn fail_over(&mut self) -> io::Result<()> {
+        let standby = match self.params.standby {
+            Some(ref standby) => standby.take().await,
+            None => None,
+        };
+        match standby {
+            Some(mut standby) => {
+                log::warn!("Standby engine took over");
+                standby.set_searching(false).await;
+                *self = standby;
+                self.refill_standby();
+                Ok(())
+            }
+            None => self.respawn().await,
+        }
+    }
+
+    /// Start a new standby engine in the background, if enabled.
+    pub fn refill_standby(&self) {
+        if let Some(ref standby) = self.params.standby {
+            standby.refill(Arc::clone(&self.transport), self.params.clone());
+        }
+    }
+
+    /// Set the options of the operator and wait until the engine is ready,
+    /// so that it has loaded its network and allocated the hash table
+    /// before taking over.
+    pub async fn warm_up(&mut self) -> io::Result<()> {
+        let session = Session(0);
+        for (name, value) in self.params.options.clone() {
+            self.send_dangerous(
+                session,
+                UciIn::Setoption {
+                    name,
+                    value: Some(value.to_string()),
+                },
+            )
+            .await?;
+        }
+        self.probe(session).await
+    }
+
+    /// Replace the engine process in the middle of a session, e.g. after it
+    /// crashed, and restore the options, position and pending search of the
+    /// session, so that the client does not notice.
+    pub async fn restore(&mut self, session: Session) -> io::Result<()> {
+        let session_limits = self.session_limits.clone();
+        let account = self.account.take();
+        let info_filter = self.info_filter;
+        let perspective = self.perspective;
+        let state = self.session_state();
+        let search = self.search.take();
+        let stop_sent = self.stop_sent.is_some();
+        let pending_readyok = self.pending.count(Request::Isready, session);
+
+        self.fail_over().await?;
+        self.ensure_newgame(session).await?;
+        self.session_limits = session_limits;
+        self.account = account;
+        self.info_filter = info_filter;
+        self.perspective = perspective;
+        self.resume(session, state).await?;
+        if let Some(search) = search {
+            log::warn!(
+                "{}: resuming {}",
+                session.0,
+                self.params.privacy.redact(&search)
+            );
+            self.send_dangerous(session, search).await?;
+            if stop_sent {
+                self.send_dangerous(session, UciIn::Stop).await?;
+            }
+        }
+        for _ in 0..pending_readyok {
+            self.send_dangerous(session, UciIn::Isready).await?;
+        }
+        Ok(())
+    }
+
+    /// Search on behalf of the server, e.g. to ponder, without charging
+    /// the client or recording the result.
+    pub async fn search_for_server(
+        &mut self,
+        session: Session,
+        position: UciIn,
+        go: UciIn,
+    ) -> io::Result<()> {
+        self.send(session, position).await?;
+        self.send(session, go).await?;
+        self.server_search = self.searching;
+        Ok(())
+    }
+
+    /// Options and position of the current session.
+    pub fn session_state(&self) -> SessionState {
+        SessionState {
+            options: self.session_options.clone(),
+            position: self.position.clone(),
+        }
+    }
+
+    /// Set the options and position of a previous session again, e.g. after
+    /// the engine process was replaced while the client was away.
+    pub async fn resume(&mut self, session: Session, state: SessionState) -> io::Result<()> {
+        for (name, value) in state.options {
+            self.send_dangerous(session, UciIn::Setoption { name, value })
+                .await?;
+        }
+        if let Some(position) = state.position {
+            self.send_dangerous(session, position).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send(&mut self, session: Session, mut command: UciIn) -> io::Result<()> {
         match command {
+            UciIn::Setoption { .. } if !self.params.client_options => {
+                self.reject(session, &command, "clients may not set options".to_owned());
+                Ok(())
+            }
             UciIn::Setoption { ref name, .. } if !name.is_safe() => {
-                log::error!(
-                    "{}: rejected potentially unsafe option: {}",
-                    session.0,
-                    command
-                );
+                let reason = format!("potentially unsafe option {name}");
+                self.reject(session, &command, reason);
                 Ok(())
             }
+            UciIn::Setoption {
+                ref name,
+                ref value,
+            } if !self.session_limits.permits(
+                self.params.aliases.get(name).unwrap_or(name),
+                value.as_deref(),
+            ) =>
+            {
+                let reason = format!("option {name} not permitted for this session");
+                self.reject(session, &command, reason);
+                Ok(())
+            }
+            UciIn::Go { .. } => match self.params.go_policy.apply(&mut command) {
+                Ok(()) => self.send_dangerous(session, command).await,
+                Err(field) => {
+                    self.reject(session, &command, format!("go with {field} not permitted"));
+                    // The client would otherwise wait for the search.
+                    self.notices.push_back(UciOut::Bestmove {
+                        m: None,
+                        ponder: None,
+                    });
+                    Ok(())
+                }
+            },
             _ => self.send_dangerous(session, command).await,
         }
     }
 
-    pub async fn send_dangerous(&mut self, session: Session, command: UciIn) -> io::Result<()> {
+    pub async fn send_dangerous(&mut self, session: Session, mut command: UciIn) -> io::Result<()> {
         match command {
-            UciIn::Isready => self.pending_readyok += 1,
-            UciIn::Stop | UciIn::Ponderhit => (),
+            UciIn::Isready => self.pending.push(Request::Isready, session),
+            UciIn::Stop => self.stop_sent = self.searching.then(Instant::now),
+            UciIn::Ponderhit => (),
+            UciIn::Debug(on) => self.debug = on,
             _ if self.searching => {
-                log::error!("{}: engine is busy: {}", session.0, command);
-                return Err(io::Error::new(io::ErrorKind::Other, "engine is busy"));
+                self.reject(session, &command, "engine is busy".to_owned());
+                return Ok(());
             }
             UciIn::Uci => {
-                self.pending_uciok += 1;
+                self.pending.push(Request::Uci, session);
                 self.options.clear();
                 self.name.take();
+                self.author.take();
+            }
+            UciIn::Position { .. } => {
+                self.black_to_move = side_to_move(&command) == Some(Color::Black);
+                self.position = Some(command.clone());
             }
             UciIn::Go { .. } => {
-                self.searching = true;
+                if !self.scale_threads(session).await? {
+                    let reason = "all threads are used by other instances".to_owned();
+                    self.reject(session, &command, reason);
+                    // The client would otherwise wait for the search.
+                    self.notices.push_back(UciOut::Bestmove {
+                        m: None,
+                        ponder: None,
+                    });
+                    return Ok(());
+                }
+                self.eval = None;
+                self.depth = None;
+                self.pv.clear();
+                self.set_searching(true).await;
+                self.search = Some(command.clone());
+                self.server_search = false;
+                self.stop_sent = None;
+                self.awaiting_info = true;
+                self.search_started = Some(Instant::now());
+                self.search_nps = None;
+                self.fit_hash(session)?;
             }
             UciIn::Setoption {
-                ref name,
-                ref value,
-            } => match self.options.get(name) {
-                Some(option) => {
-                    option
-                        .validate(value.clone())
-                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
-                }
-                None => {
-                    log::warn!("{}: ignoring unknown option: {}", session.0, command);
-                    return Ok(());
+                ref mut name,
+                ref mut value,
+            } => {
+                self.session_limits.clamp(name, value);
+                let clamp = *name == "MultiPV";
+                let threads = *name == "Threads";
+                let hash = *name == "Hash";
+                if hash {
+                    self.clamp_hash(session, value);
                 }
-            },
+                let requested_name = name.clone();
+                if let Some(target) = self.params.aliases.get(name) {
+                    *name = target.clone();
+                }
+                match self.options.get(name) {
+                    Some(option) => {
+                        if clamp {
+                            option.clamp(value);
+                        }
+                        if let Err(err) = option.validate(value.clone()) {
+                            let reason =
+                                format!("invalid value for option {requested_name}: {err}");
+                            self.reject(session, &command, reason);
+                            return Ok(());
+                        }
+                        if threads {
+                            self.threads = value.as_deref().and_then(|v| v.parse().ok());
+                            self.applied_threads = self.threads;
+                        }
+                        if hash {
+                            self.hash = value.as_deref().and_then(|v| v.parse().ok());
+                        }
+                        // Options set back to their default, e.g. when
+                        // resetting them, need no further reset.
+                        if *value != option.default_value() && !self.changed_options.contains(name)
+                        {
+                            self.changed_options.push(name.clone());
+                        }
+                        self.session_options.retain(|(n, _)| *n != requested_name);
+                        self.session_options.push((requested_name, value.clone()));
+                    }
+                    // Standard options are optional for engines, so clients
+                    // may well try them.
+                    None if name
+                        .0
+                        .get(..4)
+                        .map_or(false, |p| p.eq_ignore_ascii_case("UCI_")) =>
+                    {
+                        log::info!("{}: engine does not support {}", session.0, name);
+                        return Ok(());
+                    }
+                    None => {
+                        let reason = format!("unknown option {requested_name}");
+                        self.reject(session, &command, reason);
+                        return Ok(());
+                    }
+                }
+            }
             _ => (),
         }
 
+        self.write(session, &command)
+    }
+
+    fn write(&self, session: Session, command: &UciIn) -> io::Result<()> {
         let mut buf = command.to_string();
-        log::info!("{} << {}", session.0, buf);
+        log::info!("{} << {}", session.0, self.params.privacy.redact(command));
         buf.push_str("\r\n");
-        self.stdin.write_all(buf.as_bytes()).await?;
-        self.stdin.flush().await
+        self.stdin
+            .send(buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "engine stdin closed"))
+    }
+
+    /// Reduce Threads for the next search while the host is busy with other
+    /// work, other instances are searching, or the client used up its CPU
+    /// budget, and restore what the client requested once that is no longer
+    /// the case. Returns `false` if other instances use all threads.
+    async fn scale_threads(&mut self, session: Session) -> io::Result<bool> {
+        if self.params.thread_budget.is_none()
+            && self.params.thread_ledger.is_none()
+            && self.account.is_none()
+        {
+            return Ok(true);
+        }
+        let requested = match self.requested_threads() {
+            Some(requested) => requested,
+            None => return Ok(true),
+        };
+        let threads = [
+            self.params
+                .thread_budget
+                .as_ref()
+                .map(|budget| *budget.borrow()),
+            self.account.as_ref().and_then(Account::max_threads),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(requested, u32::min);
+        let threads = match self.params.thread_ledger {
+            Some(ref ledger) => match ledger.claim(threads, self.params.max_threads).await {
+                Some(threads) => threads,
+                None => return Ok(false),
+            },
+            None => threads,
+        };
+        if self.applied_threads.unwrap_or(requested) == threads {
+            return Ok(true);
+        }
+        self.applied_threads = Some(threads);
+        let name = UciOptionName("Threads".to_owned());
+        self.write(
+            session,
+            &UciIn::Setoption {
+                name: self.params.aliases.get(&name).cloned().unwrap_or(name),
+                value: Some(threads.to_string()),
+            },
+        )?;
+        Ok(true)
+    }
+
+    fn current_hash(&self) -> Option<u32> {
+        self.hash.or_else(|| {
+            self.option("Hash")
+                .and_then(UciOption::default_value)
+                .and_then(|v| v.parse().ok())
+        })
+    }
+
+    /// Reduce a requested Hash that would make the host swap, since a
+    /// swapping engine is worse than one with a smaller Hash.
+    fn clamp_hash(&mut self, session: Session, value: &mut Option<String>) {
+        let requested = match value.as_deref().and_then(|v| v.parse::<u32>().ok()) {
+            Some(requested) => requested,
+            None => return,
+        };
+        let current = self.current_hash().unwrap_or(0);
+        if requested <= current {
+            return;
+        }
+        let max_hash = max_hash_without_swap(current.into(), self.memory.available());
+        if u64::from(requested) > max_hash {
+            let hash = u32::try_from(max_hash).unwrap_or(u32::MAX).max(1);
+            *value = Some(hash.to_string());
+            self.notice(
+                session,
+                format!("Hash reduced from {requested} to {hash} MiB, to avoid swapping"),
+            );
+        }
+    }
+
+    /// Shrink Hash before the next search if the host is about to swap,
+    /// e.g. because other programs need more memory than when Hash was set.
+    fn fit_hash(&mut self, session: Session) -> io::Result<()> {
+        let current = match self.current_hash() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let max_hash = max_hash_without_swap(current.into(), self.memory.available());
+        if u64::from(current) <= max_hash || current <= 1 {
+            return Ok(());
+        }
+        let hash = u32::try_from(max_hash).unwrap_or(u32::MAX).max(1);
+        self.hash = Some(hash);
+        let name = UciOptionName("Hash".to_owned());
+        if !self.changed_options.contains(&name) {
+            self.changed_options.push(name.clone());
+        }
+        self.notice(
+            session,
+            format!("Hash reduced from {current} to {hash} MiB, since the host is about to swap"),
+        );
+        self.write(
+            session,
+            &UciIn::Setoption {
+                name: self.params.aliases.get(&name).cloned().unwrap_or(name),
+                value: Some(hash.to_string()),
+            },
+        )
+    }
+
+    fn notice(&mut self, session: Session, message: String) {
+        log::warn!("{}: {}", session.0, message);
+        self.notices.push_back(UciOut::info_string(message));
+    }
+
+    /// Tell the client why its command was rejected, rather than dropping
+    /// it silently or closing the connection.
+    fn reject(&mut self, session: Session, command: &UciIn, reason: String) {
+        log::error!(
+            "{}: rejected {}: {}",
+            session.0,
+            self.params.privacy.redact(command),
+            reason
+        );
+        self.notices
+            .push_back(UciOut::info_string(format!("error: {reason}")));
+    }
+
+    fn requested_threads(&self) -> Option<u32> {
+        self.threads.or_else(|| {
+            self.option("Threads")
+                .and_then(UciOption::default_value)
+                .and_then(|v| v.parse().ok())
+        })
     }
 
     pub async fn recv(&mut self, session: Session) -> io::Result<UciOut> {
+        if let Some(notice) = self.notices.pop_front() {
+            return Ok(notice);
+        }
         loop {
-            let mut line = String::new();
-            if self.stdout.read_line(&mut line).await? == 0 {
-                return Err(io::ErrorKind::UnexpectedEof.into());
-            }
+            let deadline = self
+                .pending
+                .next_deadline()
+                .map(|pending| (pending.request, pending.deadline()));
+            let line = match deadline {
+                Some((request, deadline)) => {
+                    match timeout_at(deadline.into(), self.stdout.recv()).await {
+                        Ok(line) => line,
+                        Err(_) => {
+                            log::error!("{}: engine did not answer {}", session.0, request);
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!("engine did not answer {request}"),
+                            ));
+                        }
+                    }
+                }
+                None => self.stdout.recv().await,
+            };
+            let line = match line {
+                Some(line) => line?,
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
             let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
 
             let mut command = match UciOut::from_line(line) {
+                Err(err) if self.pending.contains(Request::Uci) || !self.params.strict_uci => {
+                    // Some engines print banners or diagnostics before
+                    // completing the handshake, or slightly deviate from
+                    // the protocol.
+                    log::warn!(
+                        "{}: skipping malformed line ({}): {}",
+                        session.0,
+                        err,
+                        self.params.privacy.redact(&line)
+                    );
+                    continue;
+                }
                 Err(err) => {
-                    log::error!("{} >> {}", session.0, line);
+                    log::error!("{} >> {}", session.0, self.params.privacy.redact(&line));
                     return Err(io::Error::new(io::ErrorKind::InvalidData, err));
                 }
                 Ok(None) => {
-                    log::warn!("{} >> {}", session.0, line);
+                    log::warn!("{} >> {}", session.0, self.params.privacy.redact(&line));
                     continue;
                 }
                 Ok(Some(command)) => command,
             };
 
+            if let UciOut::Info {
+                score: Some(ref mut score),
+                ..
+            } = command
+            {
+                // Evaluations are kept from the point of view of the side
+                // to move.
+                if self.black_to_move && self.params.score_perspective == Perspective::White {
+                    score.flip();
+                }
+            }
+
+            if let UciOut::Info { nps, .. } = command {
+                if let Some(nps) = nps {
+                    self.params.nps.store(nps, Ordering::Relaxed);
+                    self.search_nps = Some(nps);
+                }
+                if mem::take(&mut self.awaiting_info) {
+                    if let Some(started) = self.search_started {
+                        self.params
+                            .latency
+                            .record(session, Reply::FirstInfo, started.elapsed());
+                    }
+                }
+            }
+
             match command {
-                UciOut::Info {
-                    pv: None,
-                    string: None,
-                    score: None,
-                    ..
-                } => {
-                    // Skip noise.
-                    log::trace!("{} >> {}", session.0, command);
+                UciOut::Info { .. } if self.info_filter.is_noise(&command) => {
+                    log::trace!("{} >> {}", session.0, self.params.privacy.redact(&command));
                     continue;
                 }
-                UciOut::Info { .. } => log::debug!("{} >> {}", session.0, command),
-                _ => log::info!("{} >> {}", session.0, command),
+                UciOut::Info { .. } => {
+                    log::debug!("{} >> {}", session.0, self.params.privacy.redact(&command))
+                }
+                _ => log::info!("{} >> {}", session.0, self.params.privacy.redact(&command)),
             }
 
             match command {
+                UciOut::IdName(_) | UciOut::IdAuthor(_) | UciOut::Option { .. }
+                    if !self.pending.contains(Request::Uci) =>
+                {
+                    log::warn!("{}: ignoring {} outside of handshake", session.0, command);
+                    continue;
+                }
                 UciOut::IdName(ref name) => self.name = Some(name.clone()),
-                UciOut::Uciok => self.pending_uciok = self.pending_uciok.saturating_sub(1),
-                UciOut::Readyok => self.pending_readyok = self.pending_readyok.saturating_sub(1),
-                UciOut::Bestmove { .. } => self.searching = false,
+                UciOut::IdAuthor(ref author) => self.author = Some(author.clone()),
+                UciOut::Uciok => match self.pending.resolve(Request::Uci) {
+                    Some(_) => (),
+                    None => {
+                        log::warn!("{}: ignoring unexpected uciok", session.0);
+                        continue;
+                    }
+                },
+                UciOut::Readyok => match self.pending.resolve(Request::Isready) {
+                    Some(pending) => {
+                        self.params
+                            .latency
+                            .record(session, Reply::Readyok, pending.sent.elapsed());
+                        if pending.session != session {
+                            // Requested by the provider or an earlier
+                            // session, not the current client.
+                            log::debug!("{}: readyok for session {}", session.0, pending.session.0);
+                            continue;
+                        }
+                    }
+                    None => {
+                        log::warn!("{}: ignoring unexpected readyok", session.0);
+                        continue;
+                    }
+                },
+                UciOut::Info {
+                    multipv,
+                    depth,
+                    score: Some(ref score),
+                    ref pv,
+                    ..
+                } if multipv.map_or(true, |n| n.get() == 1)
+                    && !score.lowerbound
+                    && !score.upperbound =>
+                {
+                    self.eval = Some(score.eval.clone());
+                    self.depth = depth;
+                    self.pv = pv.clone().unwrap_or_default();
+                }
+                UciOut::Bestmove { .. } => {
+                    self.set_searching(false).await;
+                    self.search = None;
+                    self.awaiting_info = false;
+                    let server_search = mem::take(&mut self.server_search);
+                    if let Some(sent) = self.stop_sent.take() {
+                        self.params
+                            .latency
+                            .record(session, Reply::Bestmove, sent.elapsed());
+                    }
+                    if let Some(elapsed) = self.search_started.take().map(|s| s.elapsed()) {
+                        let threads = self
+                            .applied_threads
+                            .or_else(|| self.requested_threads())
+                            .unwrap_or(1);
+                        if let (Some(account), false) = (&self.account, server_search) {
+                            account.charge(elapsed, threads);
+                        }
+                        if let (Some(monitor), Some(nps)) =
+                            (&self.params.nps_monitor, self.search_nps)
+                        {
+                            monitor.observe(threads, nps, elapsed);
+                        }
+                    }
+                    if server_search {
+                        self.eval = None;
+                    } else if let (Some(history), Some(position), Some(eval)) =
+                        (&self.params.history, &self.position, &self.eval)
+                    {
+                        history.record(position, self.depth, eval, &self.pv, self.params.privacy);
+                    }
+                    if let (Some(verifier), Some(position), Some(eval)) =
+                        (&self.params.verifier, &self.position, self.eval.take())
+                    {
+                        verifier.submit(position.clone(), eval);
+                    }
+                }
                 UciOut::Option {
-                    ref name,
+                    ref mut name,
                     ref mut option,
                 } => {
+                    // Clients know the option by its alias, if any.
+                    let alias = self
+                        .params
+                        .aliases
+                        .iter()
+                        .find(|(_, target)| *target == name)
+                        .map(|(alias, _)| alias.clone());
+                    let client_name = alias.as_ref().unwrap_or(name);
+
                     // Apply limits set in engine parameters.
-                    if *name == "Threads" {
+                    if *client_name == "Threads" {
                         option.limit_max(self.params.max_threads.into());
-                    } else if *name == "Hash" {
+                    } else if *client_name == "Hash" {
                         option.limit_max(self.params.max_hash.into());
+                    } else if *client_name == "MultiPV" {
+                        option.limit_max(self.params.max_multipv.into());
                     }
 
                     self.options.insert(name.clone(), option.clone());
+                    if let Some(alias) = alias {
+                        *name = alias;
+                    }
                 }
                 _ => (),
             }
 
+            if let UciOut::Info {
+                score: Some(ref mut score),
+                ..
+            } = command
+            {
+                if self.black_to_move && self.perspective == Perspective::White {
+                    score.flip();
+                }
+            }
+
             return Ok(command);
         }
     }
 
+    pub fn info(&self) -> EngineInfo {
+        EngineInfo {
+            name: self.name.clone(),
+            author: self.author.clone(),
+            evaluation: self.evaluation(),
+            options: self.options.clone(),
+            aliases: self.params.aliases.clone(),
+            client_options: self.params.client_options,
+            network: self.network(),
+        }
+    }
+
+    fn network(&self) -> Option<String> {
+        let (name, option) = network::network_option(&self.options)?;
+        match self.params.options.get(name) {
+            Some(value) => Some(value.to_string()),
+            None => option.default_value(),
+        }
+    }
+
+    /// Set an option at the start of every session, like options from the
+    /// config file.
+    pub fn set_operator_option(&mut self, name: UciOptionName, value: OptionValue) {
+        self.params.options.insert(name, value);
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
 
-    pub fn max_threads(&self) -> i64 {
-        self.options
-            .get(&UciOptionName("Threads".to_owned()))
-            .and_then(UciOption::max)
-            .unwrap_or(1)
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
     }
 
-    pub fn max_hash(&self) -> i64 {
-        self.options
-            .get(&UciOptionName("Hash".to_owned()))
-            .and_then(UciOption::max)
-            .unwrap_or(16)
+    pub fn evaluation(&self) -> Option<Evaluation> {
+        match self.option("Use NNUE") {
+            Some(UciOption::Check { default }) => Some(if *default {
+                Evaluation::Nnue
+            } else {
+                Evaluation::Classical
+            }),
+            _ => self.option("EvalFile").map(|_| Evaluation::Nnue),
+        }
     }
 
-    pub fn variants(&self) -> &[String] {
+    fn option(&self, name: &str) -> Option<&UciOption> {
+        let name = UciOptionName(name.to_owned());
         self.options
-            .get(&UciOptionName("UCI_Variant".to_owned()))
-            .and_then(UciOption::var)
-            .unwrap_or_default()
+            .get(self.params.aliases.get(&name).unwrap_or(&name))
     }
 
     pub fn is_searching(&self) -> bool {
         self.searching
     }
 
+    /// Position of the current or last search.
+    pub fn position(&self) -> Option<&UciIn> {
+        self.position.as_ref()
+    }
+
+    /// Whether the engine searches until stopped, like when a client
+    /// analyses a position.
+    pub fn is_searching_infinite(&self) -> bool {
+        self.searching && matches!(self.search, Some(UciIn::Go { infinite: true, .. }))
+    }
+
+    async fn set_searching(&mut self, searching: bool) {
+        self.searching = searching;
+        self.params.searching.send_replace(searching);
+        if let (false, Some(ledger)) = (searching, &self.params.thread_ledger) {
+            ledger.release().await;
+        }
+    }
+
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.params.verifier = Some(verifier);
+    }
+
+    /// Charge searches until the end of the session to the CPU budget of
+    /// the client.
+    pub fn set_client(&mut self, client: &str) {
+        self.account = self
+            .params
+            .cpu_budget
+            .as_ref()
+            .map(|budget| budget.account(client));
+    }
+
+    pub fn set_history(&mut self, history: History) {
+        self.params.history = Some(history);
+    }
+
+    pub fn subscribe_searching(&self) -> watch::Receiver<bool> {
+        self.params.searching.subscribe()
+    }
+
+    pub fn nps_monitor(&self) -> Option<Arc<NpsMonitor>> {
+        self.params.nps_monitor.clone()
+    }
+
+    pub fn measured_nps(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.params.nps)
+    }
+
+    pub fn latency(&self) -> Arc<Latency> {
+        Arc::clone(&self.params.latency)
+    }
+
+    pub fn privacy(&self) -> Privacy {
+        self.params.privacy
+    }
+
+    pub fn transport(&self) -> Arc<dyn EngineTransport> {
+        Arc::clone(&self.transport)
+    }
+
     pub fn is_idle(&self) -> bool {
-        self.pending_uciok == 0 && self.pending_readyok == 0 && !self.searching
+        self.pending.is_empty() && !self.searching
     }
 
     pub async fn ensure_idle(&mut self, session: Session) -> io::Result<()> {
         while !self.is_idle() {
-            if self.searching && self.pending_readyok < 1 {
+            if self.searching && !self.pending.contains(Request::Isready) {
                 self.send(session, UciIn::Stop).await?;
                 self.send(session, UciIn::Isready).await?;
             }
@@ -215,11 +1130,708 @@ impl Engine {
         Ok(())
     }
 
+    /// Check that the engine still responds.
+    pub async fn probe(&mut self, session: Session) -> io::Result<()> {
+        self.ensure_idle(session).await?;
+        self.send(session, UciIn::Isready).await?;
+        self.ensure_idle(session).await
+    }
+
     pub async fn ensure_newgame(&mut self, session: Session) -> io::Result<()> {
         self.ensure_idle(session).await?;
+        self.reset_options(session).await?;
+        for (name, value) in self.params.options.clone() {
+            self.send_dangerous(
+                session,
+                UciIn::Setoption {
+                    name,
+                    value: Some(value.to_string()),
+                },
+            )
+            .await?;
+        }
+        // Clients of the provider analyse, rather than play games, unless the
+        // operator says otherwise.
+        let analyse_mode = UciOptionName("UCI_AnalyseMode".to_owned());
+        if self.option(&analyse_mode.0).is_some()
+            && !self.params.options.contains_key(&analyse_mode)
+        {
+            self.send_dangerous(
+                session,
+                UciIn::Setoption {
+                    name: analyse_mode,
+                    value: Some("true".to_owned()),
+                },
+            )
+            .await?;
+        }
         self.send(session, UciIn::Ucinewgame).await?;
         self.send(session, UciIn::Isready).await?;
         self.ensure_idle(session).await?;
         Ok(())
     }
+
+    /// Apply additional limits until the end of the session. Fails if the
+    /// engine cannot limit its strength as required.
+    pub async fn restrict(&mut self, session: Session, limits: &SessionLimits) -> io::Result<()> {
+        self.session_limits.restrict(limits);
+        if let Some(max_elo) = self.session_limits.max_elo {
+            for (name, value) in [
+                ("UCI_LimitStrength", "true".to_owned()),
+                ("UCI_Elo", max_elo.to_string()),
+            ] {
+                if self.option(name).is_none() {
+                    log::error!(
+                        "{}: session requires {}, but engine does not support it",
+                        session.0,
+                        name
+                    );
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("engine does not support {name}"),
+                    ));
+                }
+                self.send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name: UciOptionName(name.to_owned()),
+                        value: Some(value),
+                    },
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn apply_preset(&mut self, session: Session, preset: &Preset) -> io::Result<()> {
+        self.session_limits.restrict(&SessionLimits {
+            max_threads: preset.max_threads,
+            max_hash: preset.max_hash,
+            ..SessionLimits::default()
+        });
+        for (name, value) in &preset.options {
+            self.send_dangerous(
+                session,
+                UciIn::Setoption {
+                    name: name.clone(),
+                    value: Some(value.to_string()),
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub fn set_info_filter(&mut self, info_filter: InfoFilter) {
+        self.info_filter = info_filter;
+    }
+
+    pub fn set_perspective(&mut self, perspective: Perspective) {
+        self.perspective = perspective;
+    }
+
+    /// Engines often gate useful diagnostics behind debug mode, so turn it
+    /// on whenever the provider itself logs at trace level.
+    fn default_debug(&self) -> bool {
+        log::log_enabled!(log::Level::Trace)
+    }
+
+    async fn reset_options(&mut self, session: Session) -> io::Result<()> {
+        self.session_limits = SessionLimits::default();
+        self.account = None;
+        self.notices.clear();
+        self.session_options.clear();
+        self.position = None;
+        self.search = None;
+        self.info_filter = self.params.info_filter;
+        self.perspective = Perspective::default();
+        self.black_to_move = false;
+        if self.debug != self.default_debug() {
+            self.send(session, UciIn::Debug(self.default_debug()))
+                .await?;
+        }
+        for name in mem::take(&mut self.changed_options) {
+            if let Some(value) = self.option(&name.0).and_then(UciOption::default_value) {
+                self.send_dangerous(
+                    session,
+                    UciIn::Setoption {
+                        name,
+                        value: Some(value),
+                    },
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn write_lines(
+    mut stdin: BufWriter<EngineWriter>,
+    mut lines: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(line) = lines.recv().await {
+        let res = match stdin.write_all(line.as_bytes()).await {
+            Ok(()) => stdin.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            log::error!("Could not write to engine: {err}");
+            break;
+        }
+    }
+}
+
+async fn read_lines(mut stdout: BufReader<EngineReader>, lines: mpsc::Sender<io::Result<String>>) {
+    loop {
+        // Some engines emit non-UTF-8 text, for example in id author.
+        let mut line = Vec::new();
+        let res = tokio::select! {
+            res = stdout.read_until(b'\n', &mut line) => match res {
+                Ok(0) => break,
+                Ok(_) => Ok(String::from_utf8_lossy(&line).into_owned()),
+                Err(err) => Err(err),
+            },
+            // Dropping the output also kills engine processes that are
+            // stuck, once the engine is no longer used.
+            _ = lines.closed() => break,
+        };
+        let failed = res.is_err();
+        if lines.send(res).await.is_err() || failed {
+            break;
+        }
+    }
+}
+
+impl SessionLimits {
+    pub fn is_unrestricted(&self) -> bool {
+        self.max_threads.is_none()
+            && self.max_hash.is_none()
+            && self.max_multipv.is_none()
+            && self.max_elo.is_none()
+            && !self.chess_only
+    }
+
+    fn restrict(&mut self, other: &SessionLimits) {
+        fn min(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        self.max_threads = min(self.max_threads, other.max_threads);
+        self.max_hash = min(self.max_hash, other.max_hash);
+        self.max_multipv = min(self.max_multipv, other.max_multipv);
+        self.max_elo = min(self.max_elo, other.max_elo);
+        self.chess_only |= other.chess_only;
+    }
+
+    fn permits(&self, name: &UciOptionName, value: Option<&str>) -> bool {
+        let is = |expected: &str| value.map_or(false, |v| v.eq_ignore_ascii_case(expected));
+        !self.chess_only
+            || (*name != "UCI_Variant" || is("chess")) && (*name != "UCI_Chess960" || is("false"))
+    }
+
+    fn clamp(&self, name: &UciOptionName, value: &mut Option<String>) {
+        if self.max_elo.is_some() && *name == "UCI_LimitStrength" {
+            *value = Some("true".to_owned());
+            return;
+        }
+        let limit = if *name == "Threads" {
+            self.max_threads
+        } else if *name == "Hash" {
+            self.max_hash
+        } else if *name == "MultiPV" {
+            self.max_multipv
+        } else if *name == "UCI_Elo" {
+            self.max_elo
+        } else {
+            None
+        };
+        if let (Some(limit), Some(v)) = (limit, value.as_mut()) {
+            if let Ok(requested) = v.parse::<u32>() {
+                if requested > limit {
+                    *v = limit.to_string();
+                }
+            }
+        }
+    }
+}
+
+fn side_to_move(position: &UciIn) -> Option<Color> {
+    let (fen, moves) = match position {
+        UciIn::Position { fen, moves } => (fen, moves),
+        _ => return None,
+    };
+    let mut pos: Chess = match fen {
+        Some(fen) => fen.position(CastlingMode::Chess960).ok()?,
+        None => Chess::default(),
+    };
+    for m in moves {
+        let m = m.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(pos.turn())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::async_trait;
+    use tokio::io::{duplex, split, AsyncBufReadExt};
+
+    use super::*;
+    use crate::config::FieldPolicy;
+
+    /// Replies to commands with fixed output. Output for the empty command
+    /// is printed at startup.
+    struct Scripted(Vec<(&'static str, &'static str)>);
+
+    impl fmt::Display for Scripted {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("scripted engine")
+        }
+    }
+
+    #[async_trait]
+    impl EngineTransport for Scripted {
+        async fn connect(&self) -> io::Result<(EngineReader, EngineWriter)> {
+            let (client, server) = duplex(4096);
+            let script = self.0.clone();
+            tokio::spawn(async move {
+                let (server_read, mut server_write) = split(server);
+                for (expected, output) in &script {
+                    if expected.is_empty() {
+                        let _ = server_write.write_all(output.as_bytes()).await;
+                    }
+                }
+                let mut lines = BufReader::new(server_read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let command = line.split_whitespace().next();
+                    for (expected, output) in &script {
+                        if command == Some(expected) {
+                            let _ = server_write.write_all(output.as_bytes()).await;
+                        }
+                    }
+                }
+            });
+            let (client_read, client_write) = split(client);
+            Ok((Box::new(client_read), Box::new(client_write)))
+        }
+    }
+
+    fn params() -> EngineParameters {
+        EngineParameters {
+            max_threads: 2,
+            max_hash: 64,
+            max_multipv: 1,
+            info_filter: InfoFilter::Aggressive,
+            score_perspective: Perspective::SideToMove,
+            aliases: HashMap::new(),
+            thread_budget: None,
+            thread_ledger: None,
+            searching: Arc::new(watch::channel(false).0),
+            nps: Arc::new(AtomicU64::new(0)),
+            latency: Arc::new(Latency::default()),
+            nps_monitor: None,
+            verifier: None,
+            history: None,
+            cpu_budget: None,
+            options: HashMap::new(),
+            client_options: true,
+            go_policy: GoPolicy::default(),
+            strict_uci: false,
+            standby: None,
+            privacy: Privacy::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scripted_search() -> io::Result<()> {
+        let transport = Scripted(vec![
+            (
+                "uci",
+                "id name Scripted\noption name Threads type spin default 1 min 1 max 8\nuciok\n",
+            ),
+            ("isready", "readyok\n"),
+            (
+                "go",
+                "info depth 1 nodes 20\ninfo depth 5 score cp 31 pv e2e4 e7e5\nbestmove e2e4\n",
+            ),
+        ]);
+        let mut engine = Engine::new(Arc::new(transport), params()).await?;
+        assert_eq!(engine.name(), Some("Scripted"));
+        assert_eq!(engine.info().max_threads(), 2);
+
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        for line in ["position startpos", "go depth 5"] {
+            engine
+                .send(session, UciIn::from_line(line).unwrap().unwrap())
+                .await?;
+        }
+        // Progress reports are filtered as noise.
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 5 score cp 31 pv e2e4 e7e5"
+        );
+        assert_eq!(engine.recv(session).await?.to_string(), "bestmove e2e4");
+        assert!(engine.is_idle());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_perspective() -> io::Result<()> {
+        let transport = Scripted(vec![
+            ("uci", "id name Scripted\nuciok\n"),
+            ("isready", "readyok\n"),
+            (
+                "go",
+                "info depth 5 score cp 31 lowerbound pv e7e5\ninfo depth 5 score mate 2 pv e7e5\nbestmove e7e5\n",
+            ),
+        ]);
+        let mut engine = Engine::new(Arc::new(transport), params()).await?;
+        let session = Session(1);
+        engine.set_perspective(Perspective::White);
+        for line in ["position startpos moves e2e4", "go depth 5"] {
+            engine
+                .send(session, UciIn::from_line(line).unwrap().unwrap())
+                .await?;
+        }
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 5 score cp -31 upperbound pv e7e5"
+        );
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 5 score mate -2 pv e7e5"
+        );
+        // The principal evaluation stays from the point of view of the side
+        // to move.
+        assert_eq!(engine.eval, Some(Eval::Mate(2)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_commands() -> io::Result<()> {
+        let transport = Scripted(vec![
+            (
+                "uci",
+                "id name Scripted\noption name Threads type spin default 1 min 1 max 8\nuciok\n",
+            ),
+            ("isready", "readyok\n"),
+            ("go", "info depth 5 score cp 31 pv e2e4\n"),
+        ]);
+        let mut engine = Engine::new(Arc::new(transport), params()).await?;
+        let session = Session(1);
+        for line in [
+            "setoption name Threads value many",
+            "setoption name SyzygyPath value /",
+            "setoption name MultiPV value 2",
+            "go infinite",
+            "position startpos",
+        ] {
+            engine
+                .send(session, UciIn::from_line(line).unwrap().unwrap())
+                .await?;
+        }
+        for expected in [
+            "info string error: invalid value for option Threads: invalid integer: invalid digit found in string",
+            "info string error: potentially unsafe option SyzygyPath",
+            "info string error: unknown option MultiPV",
+            "info string error: engine is busy",
+            "info depth 5 score cp 31 pv e2e4",
+        ] {
+            assert_eq!(engine.recv(session).await?.to_string(), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_go() -> io::Result<()> {
+        let transport = Scripted(vec![
+            ("uci", "id name Scripted\nuciok\n"),
+            ("go", "info depth 5 score cp 31 pv e2e4\nbestmove e2e4\n"),
+        ]);
+        let mut engine = Engine::new(
+            Arc::new(transport),
+            EngineParameters {
+                go_policy: GoPolicy {
+                    ponder: FieldPolicy::Reject,
+                    ..GoPolicy::default()
+                },
+                ..params()
+            },
+        )
+        .await?;
+        let session = Session(1);
+        for line in ["position startpos", "go ponder movetime 1000", "go depth 5"] {
+            engine
+                .send(session, UciIn::from_line(line).unwrap().unwrap())
+                .await?;
+        }
+        // The session continues after the rejected search.
+        for expected in [
+            "info string error: go with ponder not permitted",
+            "bestmove (none)",
+            "info depth 5 score cp 31 pv e2e4",
+            "bestmove e2e4",
+        ] {
+            assert_eq!(engine.recv(session).await?.to_string(), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aliased_option() -> io::Result<()> {
+        let transport = Scripted(vec![
+            (
+                "uci",
+                "id name Scripted\noption name Max CPUs type spin default 1 min 1 max 64\nuciok\n",
+            ),
+            ("isready", "readyok\n"),
+            ("setoption", "info string set\n"),
+        ]);
+        let threads = UciOptionName("Threads".to_owned());
+        let max_cpus = UciOptionName("Max CPUs".to_owned());
+        let mut engine = Engine::new(
+            Arc::new(transport),
+            EngineParameters {
+                aliases: HashMap::from([(threads.clone(), max_cpus.clone())]),
+                ..params()
+            },
+        )
+        .await?;
+        let info = engine.info();
+        assert!(info.options.contains_key(&max_cpus));
+        assert_eq!(info.max_threads(), 2);
+        assert!(info.is_settable(&max_cpus));
+
+        let session = Session(1);
+        engine
+            .send(
+                session,
+                UciIn::from_line("setoption name Threads value 2")
+                    .unwrap()
+                    .unwrap(),
+            )
+            .await?;
+        // Passed on to the engine, rather than rejected as unknown.
+        assert_eq!(engine.recv(session).await?.to_string(), "info string set");
+        assert_eq!(engine.changed_options, [max_cpus]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_malformed_output() -> io::Result<()> {
+        let script = vec![
+            ("uci", "id name Sloppy\nuciok\n"),
+            ("isready", "readyok\n"),
+            (
+                "go",
+                "info depth one\ninfo depth 2 score cp 5 pv e2e4\nbestmove e2e4\n",
+            ),
+        ];
+        let go = || UciIn::from_line("go depth 2").unwrap().unwrap();
+        let session = Session(1);
+
+        let mut engine = Engine::new(Arc::new(Scripted(script.clone())), params()).await?;
+        engine.send(session, go()).await?;
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 2 score cp 5 pv e2e4"
+        );
+
+        let mut engine = Engine::new(
+            Arc::new(Scripted(script)),
+            EngineParameters {
+                strict_uci: true,
+                ..params()
+            },
+        )
+        .await?;
+        engine.send(session, go()).await?;
+        assert_eq!(
+            engine.recv(session).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leela_preamble() -> io::Result<()> {
+        let transport = Scripted(vec![
+            (
+                "",
+                "       _\n|   _ | |\n|_ |_ |_| v0.30.0 built Jul 21 2023\n",
+            ),
+            (
+                "uci",
+                "info string Found pb network file: ./791556.pb.gz\n\
+                 option --backend=cuda-auto ignored\n\
+                 id name Lc0 v0.30.0\n\
+                 id author The LCZero Authors.\n\
+                 option name Threads type spin default 2 min 1 max 128\n\
+                 option name WeightsFile type string default <autodiscover>\n\
+                 uciok\n",
+            ),
+            ("isready", "readyok\n"),
+        ]);
+        let engine = Engine::new(Arc::new(transport), params()).await?;
+        let info = engine.info();
+        assert_eq!(info.name.as_deref(), Some("Lc0 v0.30.0"));
+        assert_eq!(info.author.as_deref(), Some("The LCZero Authors."));
+        let mut options: Vec<_> = info.options.keys().map(|name| name.0.as_str()).collect();
+        options.sort_unstable();
+        assert_eq!(options, ["Threads", "WeightsFile"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_komodo_preamble() -> io::Result<()> {
+        let transport = Scripted(vec![
+            (
+                "",
+                "Dragon by Komodo Chess 3.2 64-bit avx2 (08-Jun-2022)\n\
+                 info string NNUE evaluation using dragon-4d3b2c1a.nnue\n",
+            ),
+            (
+                "uci",
+                "id name Dragon by Komodo Chess 3.2 64-bit avx2\n\
+                 id author Don Dailey, Larry Kaufman, Mark Lefler & Komodo Chess Team\n\
+                 option name Hash type spin default 16 min 1 max 33554432\n\
+                 uciok\n",
+            ),
+            ("isready", "readyok\n"),
+            ("go", "info depth 1 score cp 20 pv d2d4\nbestmove d2d4\n"),
+        ]);
+        let mut engine = Engine::new(Arc::new(transport), params()).await?;
+        assert_eq!(
+            engine.name(),
+            Some("Dragon by Komodo Chess 3.2 64-bit avx2")
+        );
+        assert_eq!(
+            engine
+                .info()
+                .options
+                .keys()
+                .map(|name| name.0.as_str())
+                .collect::<Vec<_>>(),
+            ["Hash"]
+        );
+        assert_eq!(engine.info().max_hash(), 64);
+
+        // The engine still works after the preamble.
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        engine
+            .send(session, UciIn::from_line("go depth 1").unwrap().unwrap())
+            .await?;
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 1 score cp 20 pv d2d4"
+        );
+        assert_eq!(engine.recv(session).await?.to_string(), "bestmove d2d4");
+        Ok(())
+    }
+
+    /// Counts the engine processes that are started.
+    struct Counting(Scripted, Arc<AtomicU64>);
+
+    impl fmt::Display for Counting {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    #[async_trait]
+    impl EngineTransport for Counting {
+        async fn connect(&self) -> io::Result<(EngineReader, EngineWriter)> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.connect().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fail_over() -> io::Result<()> {
+        let started = Arc::new(AtomicU64::new(0));
+        let transport = Counting(
+            Scripted(vec![
+                ("uci", "id name Scripted\nuciok\n"),
+                ("isready", "readyok\n"),
+            ]),
+            Arc::clone(&started),
+        );
+        let mut engine = Engine::new(
+            Arc::new(transport),
+            EngineParameters {
+                standby: Some(Arc::default()),
+                ..params()
+            },
+        )
+        .await?;
+        engine.refill_standby();
+        engine.fail_over().await?;
+        assert_eq!(engine.name(), Some("Scripted"));
+        tokio::task::yield_now().await;
+        // The standby took over, and another one is starting, without
+        // waiting for a new process in between.
+        assert_eq!(started.load(Ordering::Relaxed), 3);
+        engine.probe(Session(1)).await
+    }
+
+    #[tokio::test]
+    async fn test_restore() -> io::Result<()> {
+        let started = Arc::new(AtomicU64::new(0));
+        let transport = Counting(
+            Scripted(vec![
+                (
+                    "uci",
+                    "id name Scripted\noption name Threads type spin default 1 min 1 max 8\nuciok\n",
+                ),
+                ("isready", "readyok\n"),
+                ("go", "info depth 5 score cp 31 pv e2e4\n"),
+            ]),
+            Arc::clone(&started),
+        );
+        let mut engine = Engine::new(Arc::new(transport), params()).await?;
+        let session = Session(1);
+        engine.ensure_newgame(session).await?;
+        for line in [
+            "setoption name Threads value 2",
+            "position startpos",
+            "go infinite",
+        ] {
+            engine
+                .send(session, UciIn::from_line(line).unwrap().unwrap())
+                .await?;
+        }
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 5 score cp 31 pv e2e4"
+        );
+
+        engine.restore(session).await?;
+        assert_eq!(started.load(Ordering::Relaxed), 2);
+        // The new process continues the search, with the options and
+        // position of the session.
+        assert!(engine.is_searching_infinite());
+        assert_eq!(
+            engine.recv(session).await?.to_string(),
+            "info depth 5 score cp 31 pv e2e4"
+        );
+        let state = engine.session_state();
+        assert_eq!(
+            state.options,
+            [(UciOptionName("Threads".to_owned()), Some("2".to_owned()))]
+        );
+        assert_eq!(
+            state
+                .position
+                .map(|position| position.to_string())
+                .as_deref(),
+            Some("position startpos")
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,141 @@
+use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{routing::get, Json, Router};
+use futures_util::future::join_all;
+use reqwest::Client;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use tokio::{
+    sync::watch,
+    time::{interval, MissedTickBehavior},
+};
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fields of /api/status that are relevant for an overview.
+#[derive(Deserialize)]
+struct RemoteStatus {
+    name: Option<String>,
+    version: Option<String>,
+    nps: Option<u64>,
+    sessions: Vec<IgnoredAny>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ProviderState {
+    url: String,
+    up: bool,
+    error: Option<String>,
+    version: Option<String>,
+    engine: Option<String>,
+    sessions: usize,
+    nps: Option<u64>,
+}
+
+async fn poll(client: &Client, url: &str) -> ProviderState {
+    let res = client
+        .get(format!("{}/api/status", url.trim_end_matches('/')))
+        .timeout(POLL_TIMEOUT)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    let status = match res {
+        Ok(res) => res.json::<RemoteStatus>().await,
+        Err(err) => Err(err),
+    };
+    match status {
+        Ok(status) => ProviderState {
+            url: url.to_owned(),
+            up: true,
+            error: None,
+            version: status.version,
+            engine: status.name,
+            sessions: status.sessions.len(),
+            nps: status.nps,
+        },
+        Err(err) => ProviderState {
+            url: url.to_owned(),
+            up: false,
+            error: Some(err.to_string()),
+            version: None,
+            engine: None,
+            sessions: 0,
+            nps: None,
+        },
+    }
+}
+
+async fn poll_all(client: &Client, urls: &[String]) -> Vec<ProviderState> {
+    join_all(urls.iter().map(|url| poll(client, url))).await
+}
+
+fn print(states: &[ProviderState]) {
+    println!(
+        "{:<32} {:<5} {:<8} {:<24} {:>8} {:>10}",
+        "provider", "state", "version", "engine", "sessions", "nps"
+    );
+    for state in states {
+        println!(
+            "{:<32} {:<5} {:<8} {:<24} {:>8} {:>10}",
+            state.url,
+            if state.up { "up" } else { "down" },
+            state.version.as_deref().unwrap_or("-"),
+            state.engine.as_deref().unwrap_or("-"),
+            state.sessions,
+            state
+                .nps
+                .map_or_else(|| "-".to_owned(), |nps| nps.to_string())
+        );
+    }
+}
+
+/// Print the status of all providers once, or keep polling them and serve
+/// the consolidated status on the given address.
+pub async fn run(
+    urls: Vec<String>,
+    bind: Option<SocketAddr>,
+    every: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let client = Client::new();
+    let bind = match bind {
+        Some(bind) => bind,
+        None => {
+            print(&poll_all(&client, &urls).await);
+            return Ok(());
+        }
+    };
+
+    let (tx, rx) = watch::channel(Arc::new(Vec::<ProviderState>::new()));
+    tokio::spawn(async move {
+        let mut timer = interval(every);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            let states = poll_all(&client, &urls).await;
+            for (state, previous) in states.iter().zip(tx.borrow().iter()) {
+                match (previous.up, state.up) {
+                    (true, false) => log::warn!(
+                        "Provider {} is down: {}",
+                        state.url,
+                        state.error.as_deref().unwrap_or_default()
+                    ),
+                    (false, true) => log::warn!("Provider {} is up again", state.url),
+                    _ => (),
+                }
+            }
+            let _ = tx.send(Arc::new(states));
+        }
+    });
+
+    let app = Router::new().route(
+        "/",
+        get(move || {
+            let states = Arc::clone(&rx.borrow());
+            async move { Json(states.as_ref().clone()) }
+        }),
+    );
+    log::info!("Serving fleet status on {bind}");
+    axum::Server::try_bind(&bind)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Serialize;
+
+use crate::{
+    config::OptionValue,
+    transport::EngineTransport,
+    uci::{UciOption, UciOptionName},
+};
+
+/// Lc0 selects the GPU with a backend option, e.g. gpu=1. Other backend
+/// options from the config file are kept.
+pub fn gpu_option(
+    options: &HashMap<UciOptionName, UciOption>,
+    configured: &HashMap<UciOptionName, OptionValue>,
+    gpu: u32,
+) -> Option<(UciOptionName, OptionValue)> {
+    let name = UciOptionName("BackendOptions".to_owned());
+    if !matches!(options.get(&name), Some(UciOption::String { .. })) {
+        return None;
+    }
+    let value = match configured.get(&name) {
+        Some(backend) if !backend.to_string().is_empty() => format!("{backend},gpu={gpu}"),
+        _ => format!("gpu={gpu}"),
+    };
+    Some((name, OptionValue::String(value)))
+}
+
+/// Samples the GPU that the engine runs on, so that operators can confirm
+/// that it is actually used.
+pub struct GpuMonitor {
+    index: u32,
+    #[cfg(feature = "gpu")]
+    nvml: nvml_wrapper::Nvml,
+    #[cfg(feature = "gpu")]
+    transport: Arc<dyn EngineTransport>,
+}
+
+#[derive(Serialize)]
+pub struct GpuSnapshot {
+    index: u32,
+    name: String,
+    /// Percent of time that the GPU was busy during the last sample period.
+    utilization: u32,
+    memory_used: u64,
+    memory_total: u64,
+    /// Whether the engine process is among the compute processes of the
+    /// GPU, if its id is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    engine_running: Option<bool>,
+}
+
+impl GpuMonitor {
+    /// Monitor the GPU with the given index, or `None` if NVML is not
+    /// available, e.g. without an NVIDIA driver.
+    #[cfg(feature = "gpu")]
+    pub fn open(index: u32, transport: Arc<dyn EngineTransport>) -> Option<GpuMonitor> {
+        match nvml_wrapper::Nvml::init() {
+            Ok(nvml) => Some(GpuMonitor {
+                index,
+                nvml,
+                transport,
+            }),
+            Err(err) => {
+                log::warn!("Cannot monitor GPU {index}: {err}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    pub fn open(index: u32, _transport: Arc<dyn EngineTransport>) -> Option<GpuMonitor> {
+        log::warn!("Cannot monitor GPU {index}: built without the gpu feature");
+        None
+    }
+
+    #[cfg(feature = "gpu")]
+    pub fn snapshot(&self) -> Option<GpuSnapshot> {
+        let sample = || {
+            let device = self.nvml.device_by_index(self.index)?;
+            let utilization = device.utilization_rates()?;
+            let memory = device.memory_info()?;
+            let engine_running = match self.transport.pid() {
+                Some(pid) => Some(
+                    device
+                        .running_compute_processes()?
+                        .iter()
+                        .any(|process| process.pid == pid),
+                ),
+                None => None,
+            };
+            Ok::<_, nvml_wrapper::error::NvmlError>(GpuSnapshot {
+                index: self.index,
+                name: device.name()?,
+                utilization: utilization.gpu,
+                memory_used: memory.used,
+                memory_total: memory.total,
+                engine_running,
+            })
+        };
+        sample()
+            .map_err(|err| log::debug!("Could not sample GPU {}: {err}", self.index))
+            .ok()
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    pub fn snapshot(&self) -> Option<GpuSnapshot> {
+        let _ = self.index;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_option() {
+        let options = [(
+            UciOptionName("BackendOptions".to_owned()),
+            UciOption::String {
+                default: String::new(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            gpu_option(&options, &HashMap::new(), 1).map(|(_, value)| value.to_string()),
+            Some("gpu=1".to_owned())
+        );
+        let configured = [(
+            UciOptionName("BackendOptions".to_owned()),
+            OptionValue::String("policy-head=vanilla".to_owned()),
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            gpu_option(&options, &configured, 0).map(|(_, value)| value.to_string()),
+            Some("policy-head=vanilla,gpu=0".to_owned())
+        );
+        assert!(gpu_option(&HashMap::new(), &HashMap::new(), 0).is_none());
+    }
+}
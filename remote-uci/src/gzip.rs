@@ -0,0 +1,69 @@
+use std::io::{self, Read};
+
+use axum::extract::ws::Message;
+use flate2::read::GzDecoder;
+
+/// Subprotocol for clients that send gzip compressed commands in binary
+/// messages, to save bandwidth on very constrained links. Otherwise like
+/// remote-uci.v1.
+pub const PROTOCOL: &str = "remote-uci.v1.gzip";
+
+/// Limit for a decompressed message, to guard against decompression bombs.
+const MAX_DECOMPRESSED: u64 = 64 * 1024;
+
+/// Turn a compressed binary message into one text message per line.
+pub fn decode(message: Result<Message, axum::Error>) -> Vec<Result<Message, axum::Error>> {
+    match message {
+        Ok(Message::Binary(data)) => match decompress(&data) {
+            Ok(text) => text
+                .lines()
+                .map(|line| Ok(Message::Text(line.to_owned())))
+                .collect(),
+            Err(err) => vec![Err(axum::Error::new(err))],
+        },
+        other => vec![other],
+    }
+}
+
+fn decompress(data: &[u8]) -> io::Result<String> {
+    let mut text = String::new();
+    GzDecoder::new(data)
+        .take(MAX_DECOMPRESSED + 1)
+        .read_to_string(&mut text)?;
+    if text.len() as u64 > MAX_DECOMPRESSED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed message too large",
+        ));
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(b"position startpos moves e2e4\ngo movetime 1000\n")
+            .unwrap();
+        let data = encoder.finish().unwrap();
+
+        let decoded: Vec<String> = decode(Ok(Message::Binary(data)))
+            .into_iter()
+            .map(|message| message.unwrap().into_text().unwrap())
+            .collect();
+        assert_eq!(
+            decoded,
+            ["position startpos moves e2e4", "go movetime 1000"]
+        );
+
+        assert!(decode(Ok(Message::Binary(b"not gzip".to_vec())))[0].is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Query,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+use shakmaty::{
+    fen::Fen, san::SanPlus, uci::Uci, CastlingMode, Chess, Color, EnPassantMode, Position,
+};
+
+use crate::{
+    admin,
+    privacy::Privacy,
+    proxy::ClientIp,
+    uci::{Eval, UciIn},
+    ws::Secret,
+};
+
+/// Number of analysed positions that are kept.
+const MAX_ENTRIES: usize = 1000;
+
+/// Final result of a search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    /// The position command, for positions that are not standard chess.
+    /// Empty if positions are redacted, like the FEN and best line.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    position: String,
+    fen: Option<String>,
+    depth: Option<u32>,
+    eval: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pv: String,
+}
+
+/// Analysed positions, kept in a file with one JSON object per line.
+#[derive(Clone)]
+pub struct History {
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+    path: PathBuf,
+}
+
+impl History {
+    pub fn load(path: PathBuf) -> io::Result<History> {
+        let mut entries = VecDeque::new();
+        match fs::read_to_string(&path) {
+            Ok(file) => {
+                for line in file.lines() {
+                    match serde_json::from_str(line) {
+                        Ok(entry) => entries.push_back(entry),
+                        Err(err) => log::warn!("Skipping invalid history entry: {err}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+            // Compact the file, so that it does not grow forever.
+            let mut file = String::new();
+            for entry in &entries {
+                file.push_str(&serde_json::to_string(entry).expect("serialize entry"));
+                file.push('\n');
+            }
+            fs::write(&path, file)?;
+        }
+        Ok(History {
+            entries: Arc::new(Mutex::new(entries)),
+            path,
+        })
+    }
+
+    pub fn record(
+        &self,
+        position: &UciIn,
+        depth: Option<u32>,
+        eval: &Eval,
+        pv: &[Uci],
+        privacy: Privacy,
+    ) {
+        let mut entry = Entry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            position: position.to_string(),
+            fen: fen(position),
+            depth,
+            eval: eval.to_string(),
+            pv: pv
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        if privacy.redact_positions {
+            // Keep only the depth and evaluation, for statistics.
+            entry.position.clear();
+            entry.fen = None;
+            entry.pv.clear();
+        }
+        if let Err(err) = self.append(&entry) {
+            log::error!("Could not write history {:?}: {err}", self.path);
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    fn append(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry).expect("serialize entry");
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// Most recent entries first.
+    fn recent(&self) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.iter().rev().cloned().collect()
+    }
+
+    /// All entries in the given format, oldest first. Positions of variants
+    /// other than standard chess are skipped.
+    pub fn export(&self, format: ExportFormat) -> String {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = String::new();
+        for entry in entries.iter() {
+            match format {
+                ExportFormat::Epd => write_epd(&mut out, entry),
+                ExportFormat::Pgn => write_pgn(&mut out, entry),
+            }
+        }
+        out
+    }
+}
+
+#[derive(Copy, Clone, Debug, ArgEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One line per position, with acd (depth), ce (centipawns for the side
+    /// to move), dm (moves to mate) and pv opcodes.
+    Epd,
+    /// One game per position, with the best line and its evaluation, for
+    /// import as chapters of a lichess study.
+    Pgn,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Epd => "text/plain; charset=utf-8",
+            ExportFormat::Pgn => "application/x-chess-pgn",
+        }
+    }
+}
+
+/// Position of the entry and its best line in SAN.
+fn line(entry: &Entry) -> Option<(Chess, Vec<SanPlus>)> {
+    let fen: Fen = entry.fen.as_deref()?.parse().ok()?;
+    let start: Chess = fen.position(CastlingMode::Chess960).ok()?;
+    let mut pos = start.clone();
+    let mut sans = Vec::new();
+    for uci in entry.pv.split_whitespace() {
+        let uci: Uci = match uci.parse() {
+            Ok(uci) => uci,
+            Err(_) => break,
+        };
+        let m = match uci.to_move(&pos) {
+            Ok(m) => m,
+            Err(_) => break,
+        };
+        sans.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m));
+    }
+    Some((start, sans))
+}
+
+fn parse_eval(eval: &str) -> Option<Eval> {
+    let (kind, value) = eval.split_once(' ')?;
+    match kind {
+        "cp" => value.parse().ok().map(Eval::Cp),
+        "mate" => value.parse().ok().map(Eval::Mate),
+        _ => None,
+    }
+}
+
+fn write_epd(out: &mut String, entry: &Entry) {
+    let (_, sans) = match line(entry) {
+        Some(line) => line,
+        None => return,
+    };
+    // EPD has the first four fields of FEN, followed by opcodes.
+    let fen = entry.fen.as_deref().unwrap_or_default();
+    out.push_str(&fen.split(' ').take(4).collect::<Vec<_>>().join(" "));
+    if let Some(depth) = entry.depth {
+        let _ = write!(out, " acd {depth};");
+    }
+    match parse_eval(&entry.eval) {
+        Some(Eval::Cp(cp)) => {
+            let _ = write!(out, " ce {cp};");
+        }
+        // By convention, 32767 minus the number of plies to mate.
+        Some(Eval::Mate(mate)) if mate > 0 => {
+            let _ = write!(out, " ce {}; dm {mate};", 32767 - (2 * mate - 1));
+        }
+        Some(Eval::Mate(mate)) => {
+            let _ = write!(out, " ce {};", -32767 - 2 * mate);
+        }
+        None => (),
+    }
+    if !sans.is_empty() {
+        let sans: Vec<_> = sans.iter().map(ToString::to_string).collect();
+        let _ = write!(out, " pv {};", sans.join(" "));
+    }
+    out.push('\n');
+}
+
+fn write_pgn(out: &mut String, entry: &Entry) {
+    let (pos, sans) = match line(entry) {
+        Some(line) => line,
+        None => return,
+    };
+    let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.timestamp))
+        .to_string();
+    let fen = entry.fen.as_deref().unwrap_or_default();
+    let _ = writeln!(out, "[Event \"{} {}\"]", &time[..10], &time[11..19]);
+    let _ = writeln!(out, "[Site \"remote-uci\"]");
+    let _ = writeln!(out, "[Date \"{}\"]", time[..10].replace('-', "."));
+    let _ = writeln!(out, "[Result \"*\"]");
+    let _ = writeln!(out, "[SetUp \"1\"]");
+    let _ = writeln!(out, "[FEN \"{fen}\"]\n");
+    // Evaluations in PGN are from the point of view of white.
+    let white = |n: i64| if pos.turn() == Color::White { n } else { -n };
+    let eval = match parse_eval(&entry.eval) {
+        Some(Eval::Cp(cp)) => Some(format!("{:.2}", white(cp) as f64 / 100.0)),
+        Some(Eval::Mate(mate)) => Some(format!("#{}", white(i64::from(mate)))),
+        None => None,
+    };
+    if let Some(eval) = eval {
+        match entry.depth {
+            Some(depth) => {
+                let _ = write!(out, "{{ [%eval {eval},{depth}] }} ");
+            }
+            None => {
+                let _ = write!(out, "{{ [%eval {eval}] }} ");
+            }
+        }
+    }
+    let mut fullmoves = pos.fullmoves().get();
+    let mut turn = pos.turn();
+    for (i, san) in sans.iter().enumerate() {
+        if turn == Color::White {
+            let _ = write!(out, "{fullmoves}. ");
+        } else if i == 0 {
+            let _ = write!(out, "{fullmoves}... ");
+        }
+        let _ = write!(out, "{san} ");
+        if turn == Color::Black {
+            fullmoves += 1;
+        }
+        turn = !turn;
+    }
+    out.push_str("*\n\n");
+}
+
+fn fen(position: &UciIn) -> Option<String> {
+    let (fen, moves) = match position {
+        UciIn::Position { fen, moves } => (fen, moves),
+        _ => return None,
+    };
+    let mut pos: Chess = match fen {
+        Some(fen) => fen.position(CastlingMode::Chess960).ok()?,
+        None => Chess::default(),
+    };
+    for m in moves {
+        let m = m.to_move(&pos).ok()?;
+        pos.play_unchecked(&m);
+    }
+    Some(Fen::from_position(pos, EnPassantMode::Legal).to_string())
+}
+
+/// The history reveals which positions were analysed, so like the logs it is
+/// only available on the local machine, or with the admin secret.
+pub async fn restrict<B>(
+    admin_secret: Option<Secret>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let local = req
+        .extensions()
+        .get::<ClientIp>()
+        .map_or(false, |ClientIp(ip)| ip.is_loopback());
+    if local
+        || admin_secret
+            .as_ref()
+            .map_or(false, |secret| admin::is_authorized(&req, secret))
+    {
+        Ok(next.run(req).await)
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+pub async fn api(history: History) -> Json<Vec<Entry>> {
+    Json(history.recent())
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    format: ExportFormat,
+}
+
+pub async fn export(history: History, Query(params): Query<ExportParams>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, params.format.content_type())],
+        history.export(params.format),
+    )
+}
+
+/// Table of analysed positions, with buttons to copy the FEN.
+pub async fn page(history: History) -> Html<String> {
+    let mut html = String::from(concat!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">",
+        "<title>remote-uci history</title>",
+        "<style>body{font-family:sans-serif}td{padding:2px 8px}",
+        "td.fen{font-family:monospace}</style></head><body>\n",
+        "<table><tr><th>Time</th><th>Position</th><th></th>",
+        "<th>Depth</th><th>Eval</th><th>Best line</th></tr>\n",
+    ));
+    for entry in history.recent() {
+        let time =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.timestamp));
+        let fen = entry.fen.as_deref().unwrap_or(&entry.position);
+        let _ = writeln!(
+            html,
+            "<tr><td>{time}</td><td class=\"fen\">{fen}</td>\
+             <td><button data-fen=\"{fen}\">Copy</button></td>\
+             <td>{depth}</td><td>{eval}</td><td>{pv}</td></tr>",
+            time = time,
+            fen = escape(fen),
+            depth = entry
+                .depth
+                .map_or_else(String::new, |depth| depth.to_string()),
+            eval = escape(&entry.eval),
+            pv = escape(&entry.pv),
+        );
+    }
+    html.push_str(concat!(
+        "</table>\n<script>\n",
+        "function copy(text) {\n",
+        "  if (navigator.clipboard) return navigator.clipboard.writeText(text);\n",
+        "  // Clipboard API is only available on secure origins.\n",
+        "  const area = document.createElement('textarea');\n",
+        "  area.value = text;\n",
+        "  document.body.appendChild(area);\n",
+        "  area.select();\n",
+        "  document.execCommand('copy');\n",
+        "  area.remove();\n",
+        "}\n",
+        "for (const button of document.querySelectorAll('button[data-fen]')) {\n",
+        "  button.addEventListener('click', () => copy(button.dataset.fen));\n",
+        "}\n</script></body></html>\n",
+    ));
+    Html(html)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fen() {
+        let position = UciIn::from_line("position startpos moves e2e4 c7c5")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fen(&position).as_deref(),
+            Some("rnbqkbnr/pp1ppppp/8/2p5/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2")
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let entry = Entry {
+            timestamp: 1_650_000_000,
+            position: "position fen rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1"
+                .to_owned(),
+            fen: Some("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1".to_owned()),
+            depth: Some(20),
+            eval: "cp -35".to_owned(),
+            pv: "c7c5 g1f3 d7d6".to_owned(),
+        };
+        let history = History {
+            entries: Arc::new(Mutex::new(VecDeque::from([entry]))),
+            path: PathBuf::new(),
+        };
+        assert_eq!(
+            history.export(ExportFormat::Epd),
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - acd 20; ce -35; pv c5 Nf3 d6;\n"
+        );
+        assert!(history
+            .export(ExportFormat::Pgn)
+            .ends_with("\n{ [%eval 0.35,20] } 1... c5 2. Nf3 d6 *\n\n"));
+    }
+
+    #[test]
+    fn test_record_redacted() {
+        let path = std::env::temp_dir().join(format!(
+            "remote-uci-history-test-{}.jsonl",
+            std::process::id()
+        ));
+        let history = History::load(path.clone()).unwrap();
+        let position = UciIn::from_line("position startpos moves e2e4")
+            .unwrap()
+            .unwrap();
+        let pv = ["c7c5".parse().unwrap()];
+        history.record(
+            &position,
+            Some(20),
+            &Eval::Cp(-35),
+            &pv,
+            Privacy {
+                redact_positions: true,
+            },
+        );
+        let file = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!file.contains("e2e4") && !file.contains("c7c5"));
+        assert!(file.contains("\"depth\":20") && file.contains("cp -35"));
+        assert_eq!(history.export(ExportFormat::Epd), "");
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{http::StatusCode, Json};
+use serde::Serialize;
+use shakmaty::fen::Fen;
+use tokio::time::sleep;
+
+use crate::{
+    privacy::Privacy,
+    supervisor,
+    uci::{UciIn, UciOptionName},
+    ws::SharedEngine,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time without connections before idle analysis starts, so that clients
+/// that briefly reconnect do not have to wait for the engine.
+const GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+pub struct IdleAnalysis {
+    pub queue: Arc<Queue>,
+    pub movetime: Duration,
+    pub multipv: u32,
+}
+
+/// File with one position per line, that is consumed as positions are
+/// analysed.
+pub struct Queue {
+    path: PathBuf,
+    /// Positions are appended through the API while the analysis removes
+    /// them.
+    lock: Mutex<()>,
+    analysed: AtomicU64,
+    current: Mutex<Option<String>>,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    /// Positions analysed since startup.
+    analysed: u64,
+    /// Position that is being analysed, if any.
+    current: Option<String>,
+    pending: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Appended {
+    appended: usize,
+    pending: usize,
+}
+
+impl Queue {
+    pub fn new(path: PathBuf) -> Queue {
+        Queue {
+            path,
+            lock: Mutex::new(()),
+            analysed: AtomicU64::new(0),
+            current: Mutex::new(None),
+        }
+    }
+
+    fn pending(&self) -> io::Result<Vec<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(file) => Ok(file
+                .lines()
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .map(ToOwned::to_owned)
+                .collect()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// First position in the queue, with the line it was parsed from.
+    /// Invalid lines are dropped from the queue.
+    fn next(&self, privacy: Privacy) -> io::Result<Option<(String, UciIn)>> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        for line in self.pending()? {
+            match parse_position(&line) {
+                Some(position) => return Ok(Some((line, position))),
+                None => {
+                    log::warn!(
+                        "Dropping invalid position from idle queue: {}",
+                        privacy.redact(&line.as_str())
+                    );
+                    self.remove_line(&line)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, line: &str) -> io::Result<()> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.remove_line(line)
+    }
+
+    fn remove_line(&self, line: &str) -> io::Result<()> {
+        let file = fs::read_to_string(&self.path)?;
+        let mut removed = false;
+        let mut remaining = String::new();
+        for other in file.lines() {
+            if !removed && other == line {
+                removed = true;
+            } else {
+                remaining.push_str(other);
+                remaining.push('\n');
+            }
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, remaining)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn append(&self, lines: &[&str]) -> io::Result<usize> {
+        let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut file = match fs::read_to_string(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        if !file.is_empty() && !file.ends_with('\n') {
+            file.push('\n');
+        }
+        for line in lines {
+            file.push_str(line);
+            file.push('\n');
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, file)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(self.pending()?.len())
+    }
+
+    fn set_current(&self, current: Option<String>) {
+        *self.current.lock().unwrap_or_else(PoisonError::into_inner) = current;
+    }
+}
+
+/// Analyse queued positions while no client is connected. Results end up
+/// wherever results of client searches go, e.g. the history.
+pub async fn run(shared_engine: Arc<SharedEngine>, idle: IdleAnalysis) {
+    let mut last_active = Instant::now();
+    loop {
+        sleep(CHECK_INTERVAL).await;
+        if !shared_engine.connections().is_empty() || shared_engine.is_paused() {
+            last_active = Instant::now();
+            continue;
+        }
+        if last_active.elapsed() < GRACE_PERIOD {
+            continue;
+        }
+
+        let (line, position) = match idle.queue.next(shared_engine.privacy()) {
+            Ok(Some(next)) => next,
+            Ok(None) => continue,
+            Err(err) => {
+                log::error!("Could not read idle queue {:?}: {err}", idle.queue.path);
+                continue;
+            }
+        };
+        log::info!(
+            "Idle analysis of {}",
+            shared_engine.privacy().redact(&position)
+        );
+        let commands = vec![
+            UciIn::Setoption {
+                name: UciOptionName("MultiPV".to_owned()),
+                value: Some(idle.multipv.to_string()),
+            },
+            position,
+            UciIn::Go {
+                searchmoves: None,
+                ponder: false,
+                wtime: None,
+                btime: None,
+                winc: None,
+                binc: None,
+                movestogo: None,
+                depth: None,
+                nodes: None,
+                mate: None,
+                movetime: Some(idle.movetime),
+                infinite: false,
+            },
+        ];
+        idle.queue.set_current(Some(line.clone()));
+        let searched = shared_engine.search_idle(commands).await;
+        idle.queue.set_current(None);
+        match searched {
+            Ok(true) => {
+                idle.queue.analysed.fetch_add(1, Ordering::Relaxed);
+                if let Err(err) = idle.queue.remove(&line) {
+                    log::error!("Could not update idle queue {:?}: {err}", idle.queue.path);
+                }
+            }
+            // Try again when the engine is free for long enough.
+            Ok(false) => last_active = Instant::now(),
+            Err(err) => {
+                log::error!("Idle analysis failed: {err}");
+                if supervisor::is_engine_gone(&err) {
+                    shared_engine.report_failure(&err);
+                }
+                last_active = Instant::now();
+            }
+        }
+    }
+}
+
+/// Progress of the queue, for `GET /api/queue`.
+pub async fn status(queue: Arc<Queue>) -> Result<Json<QueueStatus>, StatusCode> {
+    let pending = queue.pending().map_err(|err| {
+        log::error!("Could not read idle queue {:?}: {err}", queue.path);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(QueueStatus {
+        analysed: queue.analysed.load(Ordering::Relaxed),
+        current: queue
+            .current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone(),
+        pending,
+    }))
+}
+
+/// Append positions from the body of `POST /api/queue`, in the same format
+/// as the queue file. Nothing is appended if any line is invalid.
+pub async fn append(
+    queue: Arc<Queue>,
+    body: String,
+) -> Result<Json<Appended>, (StatusCode, String)> {
+    let lines: Vec<_> = body
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    if let Some((i, line)) = lines
+        .iter()
+        .enumerate()
+        .find(|(_, line)| parse_position(line).is_none())
+    {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("invalid position on line {}: {line}", i + 1),
+        ));
+    }
+    let pending = queue.append(&lines).map_err(|err| {
+        log::error!("Could not update idle queue {:?}: {err}", queue.path);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not update queue".to_owned(),
+        )
+    })?;
+    log::info!("Queued {} positions for idle analysis", lines.len());
+    Ok(Json(Appended {
+        appended: lines.len(),
+        pending,
+    }))
+}
+
+/// Accepts a position command, a FEN, or an EPD line (ignoring its
+/// opcodes), e.g. from the history export.
+fn parse_position(line: &str) -> Option<UciIn> {
+    let line = line.trim();
+    if line.starts_with("position ") {
+        return match UciIn::from_line(line) {
+            Ok(Some(position @ UciIn::Position { .. })) => Some(position),
+            _ => None,
+        };
+    }
+    let fen: Fen = match line.parse() {
+        Ok(fen) => fen,
+        Err(_) => line
+            .split_whitespace()
+            .take(4)
+            .collect::<Vec<_>>()
+            .join(" ")
+            .parse()
+            .ok()?,
+    };
+    Some(UciIn::Position {
+        fen: Some(fen),
+        moves: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_position() {
+        let startpos = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let epd = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - acd 20; ce 15;";
+        assert_eq!(
+            parse_position(startpos).unwrap().to_string(),
+            format!("position fen {startpos}")
+        );
+        assert_eq!(
+            parse_position(epd).unwrap().to_string(),
+            format!("position fen {startpos}")
+        );
+        assert_eq!(
+            parse_position("position startpos moves e2e4")
+                .unwrap()
+                .to_string(),
+            "position startpos moves e2e4"
+        );
+        assert!(parse_position("go infinite").is_none());
+    }
+
+    #[test]
+    fn test_queue() -> io::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("remote-uci-queue-test-{}.txt", std::process::id()));
+        let queue = Queue::new(path.clone());
+        assert_eq!(queue.append(&["invalid", "position startpos"])?, 2);
+        let (line, position) = queue.next(Privacy::default())?.unwrap();
+        assert_eq!(line, "position startpos");
+        assert_eq!(position.to_string(), "position startpos");
+        assert_eq!(queue.pending()?, ["position startpos"]);
+        queue.remove(&line)?;
+        assert!(queue.next(Privacy::default())?.is_none());
+        fs::remove_file(path)
+    }
+}
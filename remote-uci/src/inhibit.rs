@@ -0,0 +1,114 @@
+use std::{io, time::Duration};
+
+use tokio::{sync::watch, time::timeout};
+
+/// Time after a search, before the system may sleep again. Covers the short
+/// gaps between consecutive searches during analysis.
+const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Prevent the system from sleeping while the engine is searching.
+pub async fn inhibit_sleep(mut searching: watch::Receiver<bool>) {
+    let mut inhibitor = None;
+    loop {
+        let changed = if inhibitor.is_some() && !*searching.borrow() {
+            match timeout(GRACE_PERIOD, searching.changed()).await {
+                Ok(changed) => changed,
+                Err(_) => {
+                    log::debug!("Allowing system sleep");
+                    inhibitor = None;
+                    continue;
+                }
+            }
+        } else {
+            searching.changed().await
+        };
+        if changed.is_err() {
+            break;
+        }
+        if inhibitor.is_none() && *searching.borrow() {
+            match Inhibitor::acquire() {
+                Ok(acquired) => {
+                    log::debug!("Preventing system sleep while searching");
+                    inhibitor = Some(acquired);
+                }
+                Err(err) => log::warn!("Could not prevent system sleep: {err}"),
+            }
+        }
+    }
+}
+
+/// Holds off system sleep until dropped.
+struct Inhibitor {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    _child: tokio::process::Child,
+}
+
+impl Inhibitor {
+    #[cfg(target_os = "linux")]
+    fn acquire() -> io::Result<Inhibitor> {
+        // Hold a systemd-logind inhibitor lock for the lifetime of the child.
+        Ok(Inhibitor {
+            _child: tokio::process::Command::new("systemd-inhibit")
+                .args([
+                    "--what=sleep:idle",
+                    "--who=remote-uci",
+                    "--why=Engine is searching",
+                    "--mode=block",
+                    "sleep",
+                    "infinity",
+                ])
+                .kill_on_drop(true)
+                .spawn()?,
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn acquire() -> io::Result<Inhibitor> {
+        // caffeinate holds an IOKit power assertion until it is killed.
+        Ok(Inhibitor {
+            _child: tokio::process::Command::new("caffeinate")
+                .arg("-i")
+                .kill_on_drop(true)
+                .spawn()?,
+        })
+    }
+
+    #[cfg(windows)]
+    fn acquire() -> io::Result<Inhibitor> {
+        // The execution state belongs to the calling thread, which is fine
+        // on the single-threaded runtime.
+        let flags = windows::ES_CONTINUOUS | windows::ES_SYSTEM_REQUIRED;
+        if unsafe { windows::SetThreadExecutionState(flags) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inhibitor {})
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    fn acquire() -> io::Result<Inhibitor> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        unsafe {
+            windows::SetThreadExecutionState(windows::ES_CONTINUOUS);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    pub const ES_CONTINUOUS: u32 = 0x8000_0000;
+    pub const ES_SYSTEM_REQUIRED: u32 = 0x0000_0001;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn SetThreadExecutionState(flags: u32) -> u32;
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    fmt::Write as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::ws::Secret;
+
+/// A temporary secret, derived from the secret of a frontend, so that it can
+/// be verified without storing it anywhere. The secret has the form
+/// `{expires}.{guest}.{mac}`.
+pub struct Invite {
+    /// Expiry as seconds since the Unix epoch.
+    pub expires: u64,
+    /// Guest whose limits apply to sessions using the invite.
+    pub guest: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Hex encoded HMAC of the payload, keyed with the secret.
+pub fn sign(key: &Secret, payload: &str) -> Secret {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.0.as_bytes()).expect("hmac key");
+    mac.update(payload.as_bytes());
+    let mut hex = String::new();
+    for byte in mac.finalize().into_bytes() {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    Secret(hex)
+}
+
+impl Invite {
+    pub fn new(valid_for: Duration, guest: Option<String>) -> Invite {
+        Invite {
+            expires: now().saturating_add(valid_for.as_secs()),
+            guest,
+        }
+    }
+
+    pub fn to_secret(&self, key: &Secret) -> Secret {
+        let payload = format!("{}.{}", self.expires, self.guest.as_deref().unwrap_or(""));
+        let mac = sign(key, &payload);
+        Secret(format!("{payload}.{}", mac.0))
+    }
+
+    /// Check that the secret is an invite signed with the given key, and
+    /// that it has not yet expired.
+    pub fn verify(secret: &Secret, key: &Secret) -> Option<Invite> {
+        let (payload, mac) = secret.0.rsplit_once('.')?;
+        if sign(key, payload) != Secret(mac.to_owned()) {
+            return None;
+        }
+        let (expires, guest) = payload.split_once('.')?;
+        let invite = Invite {
+            expires: expires.parse().ok()?,
+            guest: Some(guest.to_owned()).filter(|g| !g.is_empty()),
+        };
+        if invite.expires <= now() {
+            log::warn!("Rejecting expired invite");
+            return None;
+        }
+        Some(invite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invite() {
+        let key = Secret("owner-secret".to_owned());
+        let secret = Invite::new(Duration::from_secs(60), Some("alice".to_owned())).to_secret(&key);
+
+        let invite = Invite::verify(&secret, &key).expect("valid invite");
+        assert_eq!(invite.guest.as_deref(), Some("alice"));
+
+        assert!(Invite::verify(&secret, &Secret("other-secret".to_owned())).is_none());
+        let tampered = Secret(secret.0.replacen("alice", "bob", 1));
+        assert!(Invite::verify(&tampered, &key).is_none());
+        let expired = Invite::new(Duration::ZERO, None).to_secret(&key);
+        assert!(Invite::verify(&expired, &key).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::engine::Session;
+
+/// Upper bounds (ms) of the histogram buckets, followed by a bucket for
+/// everything slower.
+const BUCKETS_MS: [u64; 7] = [10, 50, 100, 250, 1000, 5000, 30000];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [AtomicU64; BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct HistogramSnapshot {
+    /// Upper bounds of the buckets, with `null` for the last one.
+    le_ms: Vec<Option<u64>>,
+    counts: Vec<u64>,
+    sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|le| ms <= *le)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            le_ms: BUCKETS_MS.iter().copied().map(Some).chain([None]).collect(),
+            counts: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Replies of the engine that clients wait for.
+#[derive(Debug, Copy, Clone)]
+pub enum Reply {
+    /// readyok after isready.
+    Readyok,
+    /// bestmove after stop.
+    Bestmove,
+    /// First info after go.
+    FirstInfo,
+}
+
+impl Reply {
+    /// Replies slower than this are logged, because clients will notice.
+    fn threshold(self) -> Duration {
+        match self {
+            Reply::Readyok => Duration::from_millis(500),
+            Reply::Bestmove => Duration::from_secs(1),
+            Reply::FirstInfo => Duration::from_secs(2),
+        }
+    }
+}
+
+/// How long the engine takes to reply, across restarts.
+#[derive(Default)]
+pub struct Latency {
+    readyok: Histogram,
+    bestmove: Histogram,
+    first_info: Histogram,
+}
+
+#[derive(Serialize)]
+pub struct LatencySnapshot {
+    readyok: HistogramSnapshot,
+    bestmove_after_stop: HistogramSnapshot,
+    first_info: HistogramSnapshot,
+}
+
+impl Latency {
+    pub fn record(&self, session: Session, reply: Reply, elapsed: Duration) {
+        let histogram = match reply {
+            Reply::Readyok => &self.readyok,
+            Reply::Bestmove => &self.bestmove,
+            Reply::FirstInfo => &self.first_info,
+        };
+        histogram.observe(elapsed);
+        if elapsed > reply.threshold() {
+            log::warn!(
+                "{}: engine took {} ms for {:?}",
+                session.0,
+                elapsed.as_millis(),
+                reply
+            );
+        }
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            readyok: self.readyok.snapshot(),
+            bestmove_after_stop: self.bestmove.snapshot(),
+            first_info: self.first_info.snapshot(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(10));
+        histogram.observe(Duration::from_millis(11));
+        histogram.observe(Duration::from_secs(60));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.counts, [1, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(snapshot.sum_ms, 60_021);
+        assert_eq!(snapshot.le_ms.last(), Some(&None));
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::PathBuf,
+    process,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use sysinfo::{Pid, System, SystemExt};
+use tokio::fs as async_fs;
+
+use crate::archive;
+
+/// How long another instance is assumed to still run after it was seen.
+const LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Threads used by the searches of all remote-uci instances on the host,
+/// so that together they stay within --max-threads instead of each
+/// assuming it owns the machine.
+///
+/// Each instance keeps the Threads of its current search in a file named
+/// after its process id, in a runtime directory of the user, shared by all
+/// of their instances. Files of instances that no longer run are removed.
+pub struct ThreadLedger {
+    dir: PathBuf,
+    own: PathBuf,
+    sys: Mutex<System>,
+    /// When other instances were last seen running.
+    seen: Mutex<HashMap<Pid, Instant>>,
+}
+
+impl ThreadLedger {
+    pub fn open() -> io::Result<ThreadLedger> {
+        let dir = match env::var_os("XDG_RUNTIME_DIR") {
+            Some(runtime_dir) => PathBuf::from(runtime_dir).join("remote-uci"),
+            None => archive::cache_dir(),
+        };
+        ThreadLedger::in_dir(dir.join("threads"), &process::id().to_string())
+    }
+
+    fn in_dir(dir: PathBuf, name: &str) -> io::Result<ThreadLedger> {
+        fs::create_dir_all(&dir)?;
+        Ok(ThreadLedger {
+            own: dir.join(name),
+            dir,
+            sys: Mutex::new(System::new()),
+            seen: Mutex::default(),
+        })
+    }
+
+    /// Record a search with the given Threads, and return how many it may
+    /// actually use, given the searches of other instances, or `None` if
+    /// they use all of `max_threads`. The claim is recorded before looking
+    /// at the others, so that instances starting at the same time both back
+    /// off, rather than both taking everything.
+    pub async fn claim(&self, threads: u32, max_threads: u32) -> Option<u32> {
+        self.record(threads).await;
+        let others = self.others().await;
+        let allowed = threads.min(max_threads.saturating_sub(others));
+        if allowed == 0 {
+            self.release().await;
+            return None;
+        }
+        if allowed != threads {
+            self.record(allowed).await;
+        }
+        Some(allowed)
+    }
+
+    /// Record that the search is over.
+    pub async fn release(&self) {
+        if let Err(err) = async_fs::remove_file(&self.own).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("Could not release threads in {:?}: {err}", self.own);
+            }
+        }
+    }
+
+    async fn record(&self, threads: u32) {
+        if let Err(err) = async_fs::write(&self.own, threads.to_string()).await {
+            log::warn!("Could not record threads in {:?}: {err}", self.own);
+        }
+    }
+
+    /// Threads currently used by other instances.
+    async fn others(&self) -> u32 {
+        let mut entries = match async_fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("Could not read threads of other instances: {err}");
+                return 0;
+            }
+        };
+        let mut total = 0u32;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path == self.own {
+                continue;
+            }
+            let pid = match entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<Pid>().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if !self.is_running(pid) {
+                let _ = async_fs::remove_file(&path).await;
+                continue;
+            }
+            if let Some(threads) = async_fs::read_to_string(&path)
+                .await
+                .ok()
+                .and_then(|s| s.trim().parse::<u32>().ok())
+            {
+                total = total.saturating_add(threads);
+            }
+        }
+        total
+    }
+
+    /// Whether the process is running, looking it up only if it was not
+    /// seen recently.
+    fn is_running(&self, pid: Pid) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen
+            .get(&pid)
+            .map_or(false, |at| at.elapsed() < LIVENESS_INTERVAL)
+        {
+            return true;
+        }
+        let running = self
+            .sys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .refresh_process(pid);
+        if running {
+            seen.insert(pid, Instant::now());
+        } else {
+            seen.remove(&pid);
+        }
+        running
+    }
+}
+
+impl Drop for ThreadLedger {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.own);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_claim() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("remote-uci-threads-test-{}", process::id()));
+        // Only entries named after running processes count, so the second
+        // ledger sees the first, but not the other way around.
+        let first = ThreadLedger::in_dir(dir.clone(), &process::id().to_string())?;
+        let second = ThreadLedger::in_dir(dir.clone(), "second")?;
+        assert_eq!(first.claim(4, 6).await, Some(4));
+        assert_eq!(second.claim(4, 6).await, Some(2));
+        assert_eq!(second.claim(8, 4).await, None);
+        first.release().await;
+        assert_eq!(second.claim(4, 6).await, Some(4));
+        fs::remove_dir_all(dir)
+    }
+}
@@ -0,0 +1,180 @@
+use std::{sync::Arc, time::Duration};
+
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{ws::SharedEngine, ExternalWorkerOpts};
+
+/// Registrations are refreshed at this interval, even if nothing changed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EngineRegistration<'a> {
+    name: &'a str,
+    max_threads: i64,
+    max_hash: i64,
+    variants: &'a [String],
+    provider_secret: &'a str,
+    provider_data: &'a str,
+}
+
+impl<'a> From<&'a ExternalWorkerOpts> for EngineRegistration<'a> {
+    fn from(spec: &'a ExternalWorkerOpts) -> EngineRegistration<'a> {
+        EngineRegistration {
+            name: &spec.name,
+            max_threads: spec.max_threads,
+            max_hash: spec.max_hash,
+            variants: &spec.variants,
+            provider_secret: &spec.secret.0,
+            provider_data: &spec.url,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisteredEngine {
+    id: String,
+    provider_data: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum LichessError {
+    #[error("lichess request failed: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+/// Client for the external engine registrations of a lichess account.
+pub struct Lichess {
+    client: Client,
+    endpoint: String,
+    token: String,
+}
+
+impl Lichess {
+    pub fn new(frontend: &str, token: String) -> Lichess {
+        Lichess {
+            client: Client::new(),
+            endpoint: format!("{frontend}/api/external-engine"),
+            token,
+        }
+    }
+
+    /// Find an existing registration for the given provider URL.
+    pub async fn find(&self, url: &str) -> Result<Option<String>, LichessError> {
+        let engines: Vec<RegisteredEngine> = self
+            .client
+            .get(&self.endpoint)
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(engines
+            .into_iter()
+            .find(|engine| engine.provider_data.as_deref() == Some(url))
+            .map(|engine| engine.id))
+    }
+
+    async fn create(&self, spec: &ExternalWorkerOpts) -> Result<String, LichessError> {
+        let engine: RegisteredEngine = self
+            .client
+            .post(&self.endpoint)
+            .bearer_auth(&self.token)
+            .json(&EngineRegistration::from(spec))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(engine.id)
+    }
+
+    /// Update an existing registration. Returns `false` if the registration
+    /// no longer exists.
+    async fn update(&self, id: &str, spec: &ExternalWorkerOpts) -> Result<bool, LichessError> {
+        let res = self
+            .client
+            .put(format!("{}/{id}", self.endpoint))
+            .bearer_auth(&self.token)
+            .json(&EngineRegistration::from(spec))
+            .send()
+            .await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Create or update the registration, returning its id.
+    pub async fn register(
+        &self,
+        id: Option<String>,
+        spec: &ExternalWorkerOpts,
+    ) -> Result<String, LichessError> {
+        let id = match id {
+            Some(id) => Some(id),
+            None => self.find(&spec.url).await?,
+        };
+        if let Some(id) = id {
+            if self.update(&id, spec).await? {
+                return Ok(id);
+            }
+            log::warn!("Lichess registration {id} was deleted, registering again ...");
+        }
+        self.create(spec).await
+    }
+
+    pub async fn unregister(&self, id: &str) -> Result<(), LichessError> {
+        self.client
+            .delete(format!("{}/{id}", self.endpoint))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Keep the registration up to date, refreshing it periodically and whenever
+/// the engine changes.
+pub async fn keep_registered(
+    lichess: Lichess,
+    engine: Arc<SharedEngine>,
+    mut spec: ExternalWorkerOpts,
+    name: Option<String>,
+) {
+    let mut info = engine.watch_info();
+    let mut keepalive = interval(KEEPALIVE_INTERVAL);
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut id: Option<String> = None;
+
+    loop {
+        tokio::select! {
+            _ = keepalive.tick() => (),
+            res = info.changed() => {
+                if res.is_err() {
+                    break;
+                }
+                spec.update(&info.borrow(), name.as_deref());
+            }
+        }
+
+        match lichess.register(id.clone(), &spec).await {
+            Ok(new_id) => {
+                if id.as_ref() != Some(&new_id) {
+                    log::info!("Registered with lichess as engine {new_id}");
+                } else {
+                    log::debug!("Refreshed lichess registration {new_id}");
+                }
+                id = Some(new_id);
+            }
+            Err(err) => log::error!("Could not register with lichess: {err}"),
+        }
+    }
+}
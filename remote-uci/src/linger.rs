@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use axum::extract::ws::Message;
+use tokio::sync::oneshot;
+
+use crate::engine::Session;
+
+/// Sessions are picked up by the same client with the same session token,
+/// i.e. the same browser tab.
+type Key = (String, String);
+
+/// Searches that keep running after their client disconnected, e.g. on a
+/// flaky mobile connection, until the client reconnects or time runs out.
+pub struct Lingering {
+    duration: Duration,
+    parked: Mutex<HashMap<Key, oneshot::Sender<oneshot::Sender<Handoff>>>>,
+}
+
+/// What a reconnecting client takes over from the lingering session.
+pub struct Handoff {
+    pub session: Session,
+    /// Engine output while the client was away.
+    pub replay: Vec<Message>,
+}
+
+impl Lingering {
+    pub fn new(duration: Duration) -> Lingering {
+        Lingering {
+            duration,
+            parked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Wait for the client to reconnect. The receiver completes with the
+    /// channel for handing over the session.
+    pub fn park(&self, client: &str, token: &str) -> oneshot::Receiver<oneshot::Sender<Handoff>> {
+        let (tx, rx) = oneshot::channel();
+        self.parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((client.to_owned(), token.to_owned()), tx);
+        rx
+    }
+
+    pub fn unpark(&self, client: &str, token: &str) {
+        self.parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(client.to_owned(), token.to_owned()));
+    }
+
+    /// Take over the lingering session of the client, if any.
+    pub async fn reattach(&self, client: &str, token: &str) -> Option<Handoff> {
+        let parked = self
+            .parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(client.to_owned(), token.to_owned()))?;
+        let (tx, rx) = oneshot::channel();
+        parked.send(tx).ok()?;
+        rx.await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reattach() {
+        let lingering = Lingering::new(Duration::from_secs(60));
+        let parked = lingering.park("lichess.org", "abc");
+        assert!(lingering.reattach("other", "abc").await.is_none());
+
+        let lingering_session = async {
+            let handoff = parked.await.unwrap();
+            let _ = handoff.send(Handoff {
+                session: Session(7),
+                replay: vec![Message::Text("bestmove e2e4".to_owned())],
+            });
+        };
+        let (_, handoff) =
+            tokio::join!(lingering_session, lingering.reattach("lichess.org", "abc"));
+        let handoff = handoff.unwrap();
+        assert_eq!(handoff.session, Session(7));
+        assert_eq!(handoff.replay.len(), 1);
+        assert!(lingering.reattach("lichess.org", "abc").await.is_none());
+    }
+}
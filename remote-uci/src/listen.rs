@@ -0,0 +1,107 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub const DEFAULT_PORT: u16 = 9670;
+
+/// Bind the default addresses: loopback of both families, or all interfaces
+/// of both families if the server should be reachable from other hosts.
+/// IPv6 is skipped on hosts without it.
+pub fn bind_default(public: bool) -> io::Result<Vec<TcpListener>> {
+    if public {
+        let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_PORT));
+        match bind_tcp(v6, true) {
+            Ok(listener) => Ok(vec![listener]),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => Err(err),
+            Err(err) => {
+                log::debug!("Could not bind {v6}: {err}");
+                Ok(vec![bind_tcp(
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, DEFAULT_PORT)),
+                    false,
+                )?])
+            }
+        }
+    } else {
+        let mut listeners = vec![bind_tcp(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT)),
+            false,
+        )?];
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, DEFAULT_PORT));
+        match bind_tcp(v6, false) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => log::debug!("Could not bind {v6}: {err}"),
+        }
+        Ok(listeners)
+    }
+}
+
+/// Bind the given addresses. The unspecified IPv6 address (`[::]`) also
+/// accepts IPv4 connections, unless IPv4 is bound separately on the same
+/// port.
+pub fn bind(addrs: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let dual_stack = addr.ip() == Ipv6Addr::UNSPECIFIED
+                && !addrs
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == addr.port());
+            bind_tcp(*addr, dual_stack)
+        })
+        .collect()
+}
+
+fn bind_tcp(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Host and port of a bound address, for use in URLs. Addresses that only
+/// make sense for binding are replaced by localhost.
+pub fn url_authority(addr: SocketAddr) -> String {
+    if addr.ip().is_unspecified() {
+        format!("localhost:{}", addr.port())
+    } else {
+        addr.to_string()
+    }
+}
+
+/// Host and optional port given by the operator, for use in URLs. IPv6
+/// addresses need brackets.
+pub fn publish_authority(publish_addr: &str) -> String {
+    match publish_addr.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]"),
+        _ => publish_addr.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authority() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(url_authority(addr("[::1]:9670")), "[::1]:9670");
+        assert_eq!(url_authority(addr("[::]:9670")), "localhost:9670");
+        assert_eq!(url_authority(addr("0.0.0.0:80")), "localhost:80");
+        assert_eq!(url_authority(addr("10.0.0.2:9670")), "10.0.0.2:9670");
+
+        assert_eq!(publish_authority("2001:db8::1"), "[2001:db8::1]");
+        assert_eq!(publish_authority("[2001:db8::1]:443"), "[2001:db8::1]:443");
+        assert_eq!(
+            publish_authority("engine.example.com"),
+            "engine.example.com"
+        );
+    }
+}
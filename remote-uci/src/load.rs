@@ -0,0 +1,42 @@
+use std::{thread, time::Duration};
+
+use sysinfo::{get_current_pid, ProcessExt, ProcessRefreshKind, System, SystemExt};
+use tokio::sync::watch;
+
+/// Interval between measurements of the host load.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically measure how many cores are kept busy by other programs, and
+/// publish how many threads the engine may use without competing with them.
+///
+/// The provider and its child processes (the engine) are not counted, so
+/// that searches do not throttle themselves.
+pub fn monitor(max_threads: u32) -> watch::Receiver<u32> {
+    let (tx, rx) = watch::channel(max_threads);
+    thread::spawn(move || {
+        let own_pid = get_current_pid().ok();
+        let mut sys = System::new();
+        sys.refresh_cpu();
+        let cores = sys.cpus().len().max(1) as f32;
+        loop {
+            sys.refresh_processes_specifics(ProcessRefreshKind::new().with_cpu());
+            let busy: f32 = sys
+                .processes()
+                .iter()
+                .filter(|(pid, process)| {
+                    own_pid.map_or(true, |own| **pid != own && process.parent() != Some(own))
+                })
+                .map(|(_, process)| process.cpu_usage() / 100.0)
+                .sum();
+            let budget = ((cores - busy).round() as u32).clamp(1, max_threads.max(1));
+            if *tx.borrow() != budget {
+                log::info!("Host load {busy:.1}/{cores} cores, allowing {budget} threads");
+            }
+            if tx.send(budget).is_err() {
+                break;
+            }
+            thread::sleep(SAMPLE_INTERVAL);
+        }
+    });
+    rx
+}
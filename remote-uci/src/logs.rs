@@ -0,0 +1,91 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+/// Number of recent log lines kept in memory.
+const CAPACITY: usize = 1000;
+
+/// Lines at this level are kept in memory, even if the configured filter
+/// does not log them.
+const RECENT_LEVEL: LevelFilter = LevelFilter::Info;
+
+static RECENT: Lazy<Mutex<VecDeque<String>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(CAPACITY)));
+
+struct Logger {
+    inner: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= RECENT_LEVEL || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if record.level() <= RECENT_LEVEL {
+            let line = format!(
+                "[{} {:<5}] {}",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                record.args()
+            );
+            let mut recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+            if recent.len() >= CAPACITY {
+                recent.pop_front();
+            }
+            recent.push_back(line);
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Writes log output to standard error and a file.
+struct Tee(File);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = io::stderr().write_all(buf);
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Also append log output to the file, e.g. in portable mode.
+pub fn log_to_file(builder: &mut env_logger::Builder, path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    builder.target(env_logger::Target::Pipe(Box::new(Tee(file))));
+    Ok(())
+}
+
+/// Install the logger configured by `builder`, that additionally keeps
+/// recent lines in memory, for crash reports.
+pub fn init_logging(mut builder: env_logger::Builder) {
+    let inner = builder.build();
+    log::set_max_level(inner.filter().max(RECENT_LEVEL));
+    log::set_boxed_logger(Box::new(Logger { inner })).expect("set logger");
+}
+
+/// Recent log lines, oldest first. Returns nothing if called while logging
+/// on the same thread, e.g. when a panic interrupted a log call.
+pub fn recent() -> Vec<String> {
+    match RECENT.try_lock() {
+        Ok(recent) => recent.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
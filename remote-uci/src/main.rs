@@ -1,23 +1,83 @@
-use std::error::Error;
+use std::{env, error::Error, ffi::OsString, process::ExitCode};
 
 use clap::Parser;
 use listenfd::ListenFd;
-use remote_uci::{make_server, Opts};
+use remote_uci::{
+    check_server, init_logging, install_panic_hook, log_to_file, make_server, run_command,
+    startup_json, Opts, OutputFormat, StartupError,
+};
+use tokio::sync::oneshot;
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(
+async fn main() -> ExitCode {
+    let mut logger = env_logger::Builder::from_env(
         env_logger::Env::new()
             .filter("REMOTE_UCI_LOG")
             .default_filter_or("info")
             .write_style("REMOTE_UCI_LOG_STYLE"),
-    )
-    .format_target(false)
-    .format_module_path(false)
-    .init();
+    );
+    logger.format_target(false).format_module_path(false);
+    // Every D-Bus method call would be logged otherwise.
+    logger.filter_module("zbus", log::LevelFilter::Warn);
 
-    let (spec, server) = make_server(Opts::parse(), ListenFd::from_env()).await?;
-    println!("{}", spec.registration_url());
-    server.await?;
-    Ok(())
+    let mut opts = Opts::parse();
+    let args: Vec<OsString> = env::args_os().collect();
+    let completed = opts.complete(&args);
+    // The data directory is not created with --check.
+    if let (Some(dir), false) = (opts.data_dir(), opts.check()) {
+        let path = dir.join("remote-uci.log");
+        if let Err(err) = log_to_file(&mut logger, &path) {
+            eprintln!("Could not open log file {path:?}: {err}");
+        }
+    }
+    init_logging(logger);
+    install_panic_hook(opts.report_dir());
+
+    let result = match completed {
+        Ok(()) => run(opts).await,
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(
+                err.downcast_ref::<StartupError>()
+                    .map_or(1, StartupError::exit_code),
+            )
+        }
+    }
+}
+
+async fn run(mut opts: Opts) -> Result<(), Box<dyn Error>> {
+    if let Some(command) = opts.take_command() {
+        return run_command(command, opts).await;
+    }
+
+    if opts.check() {
+        check_server(opts, ListenFd::from_env()).await?;
+        log::info!("Configuration and engine are fine");
+        return Ok(());
+    }
+
+    let output = opts.output();
+    let (specs, server, mut shutdown, sockets) = make_server(opts, ListenFd::from_env()).await?;
+    match output {
+        OutputFormat::Text => {
+            for spec in specs {
+                println!("{}", spec.registration_url());
+            }
+        }
+        OutputFormat::Json => println!("{}", startup_json(&specs, server.local_addr(), &sockets)),
+    }
+    let (stopped_tx, stopped_rx) = oneshot::channel();
+    server
+        .with_graceful_shutdown(async move {
+            let _ = stopped_tx.send(shutdown.wait().await);
+        })
+        .await?;
+    match stopped_rx.await {
+        Ok(Err(err)) => Err(err.into()),
+        _ => Ok(()),
+    }
 }
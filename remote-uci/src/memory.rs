@@ -0,0 +1,120 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use sysinfo::{RefreshKind, System, SystemExt};
+use thiserror::Error;
+
+/// How the maximum Hash is derived from the memory available on the host.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HashStrategy {
+    /// About half of the available memory, rounded to a power of two.
+    Half,
+    /// Percentage of the available memory.
+    Percent(u32),
+    /// Fixed size (MiB), regardless of the available memory.
+    Fixed(u32),
+}
+
+#[derive(Error, Debug)]
+#[error("expected half, percent:N (1 to 100) or fixed:N (MiB)")]
+pub struct InvalidHashStrategy;
+
+impl FromStr for HashStrategy {
+    type Err = InvalidHashStrategy;
+
+    fn from_str(s: &str) -> Result<HashStrategy, InvalidHashStrategy> {
+        Ok(match s.split_once(':') {
+            None if s == "half" => HashStrategy::Half,
+            Some(("percent", n)) => match n.parse() {
+                Ok(n @ 1..=100) => HashStrategy::Percent(n),
+                _ => return Err(InvalidHashStrategy),
+            },
+            Some(("fixed", n)) => HashStrategy::Fixed(n.parse().map_err(|_| InvalidHashStrategy)?),
+            _ => return Err(InvalidHashStrategy),
+        })
+    }
+}
+
+impl HashStrategy {
+    /// Maximum Hash (MiB) given the available memory (MiB).
+    pub fn max_hash(self, available: u64) -> u64 {
+        match self {
+            HashStrategy::Half => available.next_power_of_two() / 2,
+            HashStrategy::Percent(percent) => available * u64::from(percent) / 100,
+            HashStrategy::Fixed(size) => u64::from(size),
+        }
+    }
+}
+
+/// Memory (MiB) available on the host.
+pub fn available_memory() -> u64 {
+    let sys = System::new_with_specifics(RefreshKind::new().with_memory());
+    sys.available_memory() / 1024
+}
+
+/// How long a sample of the available memory is reused.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Memory available on the host, sampled at most once per interval, since
+/// querying the system is too slow to do for every search.
+#[derive(Default)]
+pub struct MemorySampler {
+    last: Option<(Instant, u64)>,
+}
+
+impl MemorySampler {
+    /// Memory (MiB) available on the host, as of the last sample.
+    pub fn available(&mut self) -> u64 {
+        match self.last {
+            Some((sampled, available)) if sampled.elapsed() < SAMPLE_INTERVAL => available,
+            _ => {
+                let available = available_memory();
+                self.last = Some((Instant::now(), available));
+                available
+            }
+        }
+    }
+}
+
+/// Memory (MiB) to leave available when growing Hash, so that the host
+/// does not start swapping.
+const SWAP_MARGIN: u64 = 256;
+
+/// Largest Hash (MiB) that fits into the available memory (MiB), given
+/// that the current Hash (MiB) is already allocated.
+pub fn max_hash_without_swap(current: u64, available: u64) -> u64 {
+    (current + available).saturating_sub(SWAP_MARGIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_strategy() {
+        assert_eq!(
+            "half".parse::<HashStrategy>().ok(),
+            Some(HashStrategy::Half)
+        );
+        assert_eq!(
+            "percent:75".parse::<HashStrategy>().ok(),
+            Some(HashStrategy::Percent(75))
+        );
+        assert_eq!(
+            "fixed:4096".parse::<HashStrategy>().ok(),
+            Some(HashStrategy::Fixed(4096))
+        );
+        assert!("percent:0".parse::<HashStrategy>().is_err());
+        assert!("percent:101".parse::<HashStrategy>().is_err());
+        assert!("quarter".parse::<HashStrategy>().is_err());
+
+        assert_eq!(HashStrategy::Half.max_hash(30_000), 16384);
+        assert_eq!(HashStrategy::Percent(75).max_hash(48_000), 36_000);
+        assert_eq!(HashStrategy::Fixed(4096).max_hash(1024), 4096);
+
+        assert_eq!(max_hash_without_swap(1024, 2048), 2816);
+        assert_eq!(max_hash_without_swap(16, 100), 0);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// Traffic and latency of a connection, to tell whether lag is caused by
+/// the network or by the engine.
+pub struct Metrics {
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    info_lines: AtomicU64,
+    /// Round trip time of the last ping, or 0 if not yet measured.
+    rtt_micros: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    duration_secs: u64,
+    rtt_ms: Option<f64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    info_lines_per_sec: f64,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            info_lines: AtomicU64::new(0),
+            rtt_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn info_line(&self) {
+        self.info_lines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        let micros = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX);
+        self.rtt_micros.store(micros.max(1), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let elapsed = self.started.elapsed();
+        Snapshot {
+            duration_secs: elapsed.as_secs(),
+            rtt_ms: Some(self.rtt_micros.load(Ordering::Relaxed))
+                .filter(|micros| *micros > 0)
+                .map(|micros| micros as f64 / 1000.0),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            info_lines_per_sec: self.info_lines.load(Ordering::Relaxed) as f64
+                / elapsed.as_secs_f64().max(1.0),
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} s, rtt ", self.duration_secs)?;
+        match self.rtt_ms {
+            Some(rtt_ms) => write!(f, "{rtt_ms:.1} ms")?,
+            None => f.write_str("unknown")?,
+        }
+        write!(
+            f,
+            ", {} bytes in, {} bytes out, {:.1} info lines/s",
+            self.bytes_in, self.bytes_out, self.info_lines_per_sec
+        )
+    }
+}
+
+/// Open connection, with the client that authenticated it.
+struct Entry {
+    client: String,
+    guest: Option<String>,
+    ip: Option<IpAddr>,
+    metrics: Arc<Metrics>,
+    kick: Arc<Notify>,
+}
+
+#[derive(Serialize)]
+pub struct Connection {
+    id: u64,
+    client: String,
+    ip: Option<IpAddr>,
+    #[serde(flatten)]
+    metrics: Snapshot,
+}
+
+/// Metrics of all open connections.
+#[derive(Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    active: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+/// Keeps metrics in the registry until dropped.
+pub struct Registration {
+    id: u64,
+    metrics: Arc<Metrics>,
+    kick: Arc<Notify>,
+    active: Arc<Mutex<HashMap<u64, Entry>>>,
+}
+
+impl Registry {
+    pub fn register(
+        &self,
+        client: String,
+        guest: Option<String>,
+        ip: Option<IpAddr>,
+    ) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let metrics = Arc::new(Metrics::new());
+        let kick = Arc::new(Notify::new());
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                id,
+                Entry {
+                    client,
+                    guest,
+                    ip,
+                    metrics: Arc::clone(&metrics),
+                    kick: Arc::clone(&kick),
+                },
+            );
+        Registration {
+            id,
+            metrics,
+            kick,
+            active: Arc::clone(&self.active),
+        }
+    }
+
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        self.connections()
+            .into_iter()
+            .map(|connection| connection.metrics)
+            .collect()
+    }
+
+    pub fn connections(&self) -> Vec<Connection> {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut ids: Vec<&u64> = active.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .map(|id| Connection {
+                id: *id,
+                client: active[id].client.clone(),
+                ip: active[id].ip,
+                metrics: active[id].metrics.snapshot(),
+            })
+            .collect()
+    }
+
+    /// Ask the connection to close. Returns false if there is no such
+    /// connection.
+    pub fn kick(&self, id: u64) -> bool {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        match active.get(&id) {
+            Some(entry) => {
+                entry.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ask all connections to close.
+    pub fn kick_all(&self) {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        for entry in active.values() {
+            entry.kick.notify_one();
+        }
+    }
+
+    /// Ask all connections of the guest, including those with invites for
+    /// the guest, to close.
+    pub fn kick_guest(&self, guest: &str) {
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        for entry in active
+            .values()
+            .filter(|entry| entry.guest.as_deref() == Some(guest))
+        {
+            entry.kick.notify_one();
+        }
+    }
+}
+
+impl Registration {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Notified when the connection should be closed.
+    pub fn kicked(&self) -> &Notify {
+        &self.kick
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
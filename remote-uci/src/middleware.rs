@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::uci::{Eval, UciIn, UciOut};
+
+/// Transforms commands between clients and the engine. Embedders can
+/// implement this and pass it to `make_server_with()`, in addition to the
+/// built-ins from the config file.
+pub trait Middleware: Send + Sync {
+    /// Transform a command from a client before it reaches the engine, or
+    /// drop it by returning `None`.
+    fn client_command(&self, command: UciIn) -> Option<UciIn> {
+        Some(command)
+    }
+
+    /// Transform a command from the engine before it reaches the client, or
+    /// drop it by returning `None`. Dropping bestmove or readyok leaves the
+    /// client waiting.
+    fn engine_command(&self, command: UciOut) -> Option<UciOut> {
+        Some(command)
+    }
+}
+
+/// Middleware that operators can enable in the config file, e.g.
+/// `[[middleware]]` with `type = "truncate-pv"` and `length = 8`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Builtin {
+    /// Shorten principal variations to at most this many moves.
+    TruncatePv { length: usize },
+    /// Remove info strings, which some engines fill with debug output.
+    StripInfoStrings,
+    /// Mask the given words in info strings, ignoring case.
+    CensorInfoStrings { words: Vec<String> },
+    /// Convert evaluations of engines with other scales to the centipawns
+    /// that lichess expects, and report mates that the engine encodes as
+    /// centipawns as mates.
+    NormalizeEval {
+        #[serde(default)]
+        from: EvalScale,
+        /// Multiplier for centipawns after conversion.
+        #[serde(default = "default_factor")]
+        factor: f64,
+    },
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvalScale {
+    /// Centipawns on the expected scale, up to the factor.
+    #[default]
+    Linear,
+    /// Lc0, which derives centipawns from the expected outcome Q as
+    /// 90 tan(1.5637541897 Q).
+    Lc0,
+}
+
+/// Centipawns beyond this are mates, encoded like Stockfish as 32000 minus
+/// the number of plies to mate.
+const MATE_CP: i64 = 32000;
+const MATE_CP_THRESHOLD: i64 = 31000;
+
+/// Slope of the logistic model that lichess uses to turn centipawns into
+/// winning chances.
+const LICHESS_WIN_SLOPE: f64 = 0.003_682_08;
+
+fn normalize(eval: &Eval, from: EvalScale, factor: f64) -> Eval {
+    let cp = match *eval {
+        Eval::Mate(_) => return eval.clone(),
+        Eval::Cp(cp) if cp.abs() > MATE_CP_THRESHOLD && cp.abs() <= MATE_CP => {
+            let moves = (MATE_CP - cp.abs() + 1) / 2;
+            return Eval::Mate(i32::try_from(moves * cp.signum()).unwrap_or_default());
+        }
+        Eval::Cp(cp) => cp,
+    };
+    let cp = match from {
+        EvalScale::Linear => cp as f64,
+        EvalScale::Lc0 => {
+            let q = ((cp as f64 / 90.0).atan() / 1.563_754_189_7).clamp(-0.999, 0.999);
+            ((1.0 + q) / (1.0 - q)).ln() / LICHESS_WIN_SLOPE
+        }
+    };
+    Eval::Cp((cp * factor).round() as i64)
+}
+
+impl Middleware for Builtin {
+    fn engine_command(&self, mut command: UciOut) -> Option<UciOut> {
+        match (self, &mut command) {
+            (Builtin::TruncatePv { length }, UciOut::Info { ref mut pv, .. }) => {
+                if let Some(moves) = pv {
+                    moves.truncate(*length);
+                    if moves.is_empty() {
+                        *pv = None;
+                    }
+                }
+            }
+            (
+                Builtin::StripInfoStrings,
+                UciOut::Info {
+                    string: Some(_),
+                    depth: None,
+                    score: None,
+                    pv: None,
+                    ..
+                },
+            ) => return None,
+            (Builtin::StripInfoStrings, UciOut::Info { ref mut string, .. }) => *string = None,
+            (
+                Builtin::CensorInfoStrings { words },
+                UciOut::Info {
+                    string: Some(ref mut string),
+                    ..
+                },
+            ) => *string = censor(string, words),
+            (
+                Builtin::NormalizeEval { from, factor },
+                UciOut::Info {
+                    score: Some(ref mut score),
+                    ..
+                },
+            ) => score.eval = normalize(&score.eval, *from, *factor),
+            _ => (),
+        }
+        Some(command)
+    }
+}
+
+fn censor(string: &str, words: &[String]) -> String {
+    string
+        .split(' ')
+        .map(|token| {
+            let word = token.trim_matches(|c: char| !c.is_alphanumeric());
+            if !word.is_empty() && words.iter().any(|w| w.eq_ignore_ascii_case(word)) {
+                token.replace(word, &"*".repeat(word.chars().count()))
+            } else {
+                token.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Middleware applied in order, to commands from clients, and to commands
+/// from the engine.
+#[derive(Clone, Default)]
+pub struct Chain(Vec<Arc<dyn Middleware>>);
+
+impl Chain {
+    pub fn new(middleware: Vec<Arc<dyn Middleware>>) -> Chain {
+        Chain(middleware)
+    }
+
+    pub fn client_command(&self, command: UciIn) -> Option<UciIn> {
+        self.0.iter().try_fold(command, |command, middleware| {
+            middleware.client_command(command)
+        })
+    }
+
+    pub fn engine_command(&self, command: UciOut) -> Option<UciOut> {
+        self.0.iter().try_fold(command, |command, middleware| {
+            middleware.engine_command(command)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn info(line: &str) -> UciOut {
+        UciOut::from_line(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_builtins() {
+        let config: Config = toml::from_str(
+            r#"
+            [[middleware]]
+            type = "truncate-pv"
+            length = 2
+
+            [[middleware]]
+            type = "censor-info-strings"
+            words = ["darn"]
+            "#,
+        )
+        .unwrap();
+        let chain = Chain::new(
+            config
+                .middleware
+                .into_iter()
+                .map(|builtin| Arc::new(builtin) as Arc<dyn Middleware>)
+                .collect(),
+        );
+        assert_eq!(
+            chain
+                .engine_command(info("info depth 3 score cp 12 pv e2e4 e7e5 g1f3"))
+                .unwrap()
+                .to_string(),
+            "info depth 3 score cp 12 pv e2e4 e7e5"
+        );
+        assert_eq!(
+            chain
+                .engine_command(info("info string Darn, lost on time"))
+                .unwrap()
+                .to_string(),
+            "info string ****, lost on time"
+        );
+
+        assert!(matches!(
+            normalize(&Eval::Cp(31995), EvalScale::Linear, 1.0),
+            Eval::Mate(3)
+        ));
+        assert!(matches!(
+            normalize(&Eval::Cp(-31998), EvalScale::Lc0, 1.0),
+            Eval::Mate(-1)
+        ));
+        assert!(matches!(
+            normalize(&Eval::Cp(150), EvalScale::Linear, 0.5),
+            Eval::Cp(75)
+        ));
+        // Q = 0.5, i.e. 75% winning chances.
+        assert!(matches!(
+            normalize(&Eval::Cp(89), EvalScale::Lc0, 1.0),
+            Eval::Cp(296..=300)
+        ));
+
+        assert!(Builtin::StripInfoStrings
+            .engine_command(info("info string NNUE evaluation enabled"))
+            .is_none());
+        assert_eq!(
+            Builtin::StripInfoStrings
+                .engine_command(info("info depth 1 pv e2e4 string hello"))
+                .unwrap()
+                .to_string(),
+            "info depth 1 pv e2e4"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::uci::{UciOption, UciOptionName};
+
+/// Options that engines load their neural network from: EvalFile for
+/// Stockfish and WeightsFile for Lc0.
+const NETWORK_OPTIONS: [&str; 2] = ["EvalFile", "WeightsFile"];
+
+/// Stockfish networks are published here, by file name.
+const NETWORK_URL: &str = "https://tests.stockfishchess.org/api/nn/";
+
+/// The option that the engine loads its network from, if any.
+pub fn network_option(
+    options: &HashMap<UciOptionName, UciOption>,
+) -> Option<(&UciOptionName, &UciOption)> {
+    NETWORK_OPTIONS
+        .iter()
+        .find_map(|name| options.get_key_value(&UciOptionName((*name).to_owned())))
+}
+
+/// Hash prefix of a Stockfish network name like nn-ad9b42354671.nnue.
+/// Official networks are named after the SHA-256 of their contents.
+fn hash_prefix(name: &str) -> Option<&str> {
+    name.strip_prefix("nn-")
+        .and_then(|name| name.strip_suffix(".nnue"))
+        .filter(|hash| hash.len() == 12 && hash.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Get the default network of the engine, given as the default value of
+/// its EvalFile option, and return the path to it. Engine builds that do
+/// not embed their network look for it in the working directory, so a
+/// network found in `dir` is used as is. Otherwise it is downloaded into
+/// the cache directory once.
+pub async fn default_network(name: &str, dir: &Path, cache_dir: &Path) -> io::Result<PathBuf> {
+    let hash = hash_prefix(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name:?} is not the name of an official network"),
+        )
+    })?;
+    let local = dir.join(name);
+    if local.is_file() {
+        return Ok(local);
+    }
+    let dir = cache_dir.join("networks");
+    let path = dir.join(name);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let url = format!("{NETWORK_URL}{name}");
+    log::info!("Downloading network from {url} ...");
+    let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+    let bytes = reqwest::get(&url)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(to_io)?
+        .bytes()
+        .await
+        .map_err(to_io)?;
+    let digest = format!("{:x}", Sha256::digest(&bytes));
+    if !digest.starts_with(hash) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checksum mismatch for {url}: got {digest}"),
+        ));
+    }
+    fs::create_dir_all(&dir)?;
+    let partial = dir.join(format!("{name}.part"));
+    fs::write(&partial, &bytes)?;
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_prefix() {
+        assert_eq!(hash_prefix("nn-ad9b42354671.nnue"), Some("ad9b42354671"));
+        assert_eq!(hash_prefix("nn-../../etc.nnue"), None);
+        assert_eq!(hash_prefix("<autodiscover>"), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_network() -> io::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("remote-uci-network-test-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let name = "nn-ad9b42354671.nnue";
+        fs::write(dir.join(name), b"")?;
+        let path = default_network(name, &dir, &dir.join("cache")).await;
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(path?, dir.join(name));
+        Ok(())
+    }
+}
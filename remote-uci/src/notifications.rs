@@ -0,0 +1,247 @@
+use std::{error::Error, fmt, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use rand::random;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that operators may want to hear about while they are away.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Notification {
+    SessionStarted {
+        client: String,
+    },
+    SessionEnded {
+        client: String,
+        seconds: u64,
+    },
+    EngineCrashed {
+        error: String,
+    },
+    /// The client used up its CPU budget, and searches with fewer threads.
+    QuotaExhausted {
+        client: String,
+    },
+}
+
+impl Notification {
+    pub fn event(&self) -> Event {
+        match self {
+            Notification::SessionStarted { .. } => Event::SessionStarted,
+            Notification::SessionEnded { .. } => Event::SessionEnded,
+            Notification::EngineCrashed { .. } => Event::EngineCrashed,
+            Notification::QuotaExhausted { .. } => Event::QuotaExhausted,
+        }
+    }
+}
+
+/// Kinds of notifications, to select those that a notifier delivers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Event {
+    SessionStarted,
+    SessionEnded,
+    EngineCrashed,
+    QuotaExhausted,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Notification::SessionStarted { client } => write!(f, "Session started by {client}"),
+            Notification::SessionEnded { client, seconds } => {
+                write!(f, "Session of {client} ended after {seconds} s")
+            }
+            Notification::EngineCrashed { error } => write!(f, "Engine crashed: {error}"),
+            Notification::QuotaExhausted { client } => write!(f, "{client} used up the CPU budget"),
+        }
+    }
+}
+
+/// Delivers notifications to the operator. Embedders can implement this and pass
+/// it to `make_server_with()`, in addition to the built-ins from the config
+/// file. Use `#[axum::async_trait]` for implementations.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn notify(&self, notification: &Notification)
+        -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Whether the notification should be delivered to this notifier at all.
+    fn wants(&self, _notification: &Notification) -> bool {
+        true
+    }
+}
+
+/// A notifier from the config file, with the events it delivers, e.g.
+/// `events = ["engine-crashed", "quota-exhausted"]` to leave out sessions.
+/// All events by default.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Subscription {
+    #[serde(flatten)]
+    pub notifier: Builtin,
+    pub events: Option<Vec<Event>>,
+}
+
+#[async_trait]
+impl Notifier for Subscription {
+    async fn notify(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.notifier.notify(notification).await
+    }
+
+    fn wants(&self, notification: &Notification) -> bool {
+        self.events
+            .as_ref()
+            .map_or(true, |events| events.contains(&notification.event()))
+    }
+}
+
+/// Notifiers that operators can configure in the config file, e.g.
+/// `[[notify]]` with `type = "telegram"`, `token` and `chat-id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Builtin {
+    /// POST each notification as JSON, with a `text` for humans.
+    Webhook { url: String },
+    /// Post to a Matrix room, e.g. `homeserver = "https://matrix.org"` and
+    /// `room = "!abc:matrix.org"`, with the access token of a bot account
+    /// that joined the room.
+    #[serde(rename_all = "kebab-case")]
+    Matrix {
+        homeserver: String,
+        room: String,
+        access_token: String,
+    },
+    /// Message a chat through a Telegram bot.
+    #[serde(rename_all = "kebab-case")]
+    Telegram { token: String, chat_id: String },
+}
+
+#[async_trait]
+impl Notifier for Builtin {
+    async fn notify(
+        &self,
+        notification: &Notification,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let client = Client::builder().timeout(HTTP_TIMEOUT).build()?;
+        let text = notification.to_string();
+        let req = match self {
+            Builtin::Webhook { url } => {
+                let mut payload = serde_json::to_value(notification)?;
+                payload["text"] = text.into();
+                client.post(url).json(&payload)
+            }
+            Builtin::Matrix {
+                homeserver,
+                room,
+                access_token,
+            } => {
+                let mut url = Url::parse(homeserver)?;
+                url.path_segments_mut()
+                    .map_err(|_| "homeserver is not a base URL")?
+                    .pop_if_empty()
+                    .extend([
+                        "_matrix",
+                        "client",
+                        "v3",
+                        "rooms",
+                        room,
+                        "send",
+                        "m.room.message",
+                        &format!("{:016x}", random::<u64>()),
+                    ]);
+                client
+                    .put(url)
+                    .bearer_auth(access_token)
+                    .json(&json!({ "msgtype": "m.text", "body": text }))
+            }
+            Builtin::Telegram { token, chat_id } => client
+                .post(format!("https://api.telegram.org/bot{token}/sendMessage"))
+                .json(&json!({ "chat_id": chat_id, "text": text })),
+        };
+        let res = req.send().await.and_then(|res| res.error_for_status());
+        // Webhook URLs and the Telegram URL contain credentials.
+        res.map_err(|err| err.without_url())?;
+        Ok(())
+    }
+}
+
+/// The notifiers of a server.
+#[derive(Clone, Default)]
+pub struct Notifications {
+    notifiers: Arc<Vec<Arc<dyn Notifier>>>,
+}
+
+impl Notifications {
+    pub fn new(notifiers: Vec<Arc<dyn Notifier>>) -> Notifications {
+        Notifications {
+            notifiers: Arc::new(notifiers),
+        }
+    }
+
+    /// Deliver the notification to all notifiers that want it, in the
+    /// background.
+    pub fn send(&self, notification: Notification) {
+        for notifier in self.notifiers.iter() {
+            if !notifier.wants(&notification) {
+                continue;
+            }
+            let notifier = Arc::clone(notifier);
+            let notification = notification.clone();
+            tokio::spawn(async move {
+                if let Err(err) = notifier.notify(&notification).await {
+                    log::warn!("Could not send notification: {err}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() -> Result<(), toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Config {
+            notify: Vec<Subscription>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[notify]]
+            type = "telegram"
+            token = "123:abc"
+            chat-id = "42"
+
+            [[notify]]
+            type = "webhook"
+            url = "https://example.com/hook"
+            events = ["engine-crashed"]
+            "#,
+        )?;
+        assert!(matches!(
+            config.notify[0].notifier,
+            Builtin::Telegram { ref chat_id, .. } if chat_id == "42"
+        ));
+        let started = Notification::SessionStarted {
+            client: "lichess".to_owned(),
+        };
+        assert!(config.notify[0].wants(&started));
+        assert!(!config.notify[1].wants(&started));
+        assert_eq!(
+            serde_json::to_string(&Notification::QuotaExhausted {
+                client: "guest club".to_owned()
+            })
+            .unwrap(),
+            r#"{"event":"quota-exhausted","client":"guest club"}"#
+        );
+        Ok(())
+    }
+}
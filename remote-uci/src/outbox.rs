@@ -0,0 +1,156 @@
+use std::{collections::VecDeque, io, sync::Mutex};
+
+use axum::extract::ws::Message;
+use futures_util::{stream, Stream};
+use tokio::sync::Notify;
+
+/// Number of queued messages, beyond which droppable messages are discarded.
+const CAPACITY: usize = 64;
+
+/// Queue of messages to a client that is slower than the engine.
+///
+/// Once the queue is full, a droppable message (an `info` that will be
+/// superseded anyway) is discarded for each new message: preferably the
+/// oldest one that a later `info` of the same MultiPV line already
+/// supersedes, or else the oldest one. Other messages, like `bestmove` and
+/// `readyok`, are always delivered.
+pub struct Outbox {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+struct State {
+    /// Messages with the MultiPV line of droppable infos.
+    queue: VecDeque<(Message, Option<u32>)>,
+    dropped: u64,
+    closed: bool,
+}
+
+impl Outbox {
+    pub fn new() -> Outbox {
+        Outbox {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Queue a message. `line` is the MultiPV line of an `info` that may be
+    /// dropped, or `None` for messages that must be delivered.
+    pub fn push(&self, message: Message, line: Option<u32>) -> io::Result<()> {
+        let mut state = self.state.lock().expect("outbox");
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if state.queue.len() >= CAPACITY {
+            if let Some(pos) = victim(&state.queue, line) {
+                state.queue.remove(pos);
+                state.dropped += 1;
+            }
+        }
+        state.queue.push_back((message, line));
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Take the next message, or `None` once the outbox is closed and empty.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().expect("outbox");
+                if let Some((message, _)) = state.queue.pop_front() {
+                    return Some(message);
+                }
+                if state.closed {
+                    if state.dropped > 0 {
+                        log::debug!("dropped {} info messages for slow client", state.dropped);
+                    }
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Take messages as they come, until the outbox is closed and empty.
+    pub fn stream(&self) -> impl Stream<Item = Message> + Send + '_ {
+        stream::unfold(self, |outbox| async move {
+            outbox.pop().await.map(|message| (message, outbox))
+        })
+    }
+
+    /// Reject further messages, but still deliver those already queued.
+    pub fn close(&self) {
+        self.state.lock().expect("outbox").closed = true;
+        self.notify.notify_one();
+    }
+}
+
+/// The message to drop to make room for a new one of the given `line`.
+fn victim(queue: &VecDeque<(Message, Option<u32>)>, line: Option<u32>) -> Option<usize> {
+    let superseded = |pos: usize, candidate: u32| {
+        line == Some(candidate)
+            || queue
+                .iter()
+                .skip(pos + 1)
+                .any(|(_, later)| *later == Some(candidate))
+    };
+    queue
+        .iter()
+        .enumerate()
+        .position(|(pos, (_, candidate))| candidate.map_or(false, |c| superseded(pos, c)))
+        .or_else(|| queue.iter().position(|(_, candidate)| candidate.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drop_superseded_info() -> io::Result<()> {
+        let outbox = Outbox::new();
+        for i in 0..CAPACITY {
+            outbox.push(Message::Text(format!("info depth {i}")), Some(1))?;
+        }
+        outbox.push(Message::Text("readyok".to_owned()), None)?;
+        outbox.push(Message::Text("bestmove e2e4".to_owned()), None)?;
+        outbox.close();
+        assert!(outbox
+            .push(Message::Text("readyok".to_owned()), None)
+            .is_err());
+
+        let mut messages = Vec::new();
+        while let Some(Message::Text(text)) = outbox.pop().await {
+            messages.push(text);
+        }
+        assert_eq!(messages.len(), CAPACITY);
+        assert_eq!(messages[0], "info depth 2");
+        assert_eq!(messages[CAPACITY - 2..], ["readyok", "bestmove e2e4"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_latest_of_each_line() -> io::Result<()> {
+        let outbox = Outbox::new();
+        outbox.push(Message::Text("info depth 1 multipv 2".to_owned()), Some(2))?;
+        for i in 1..CAPACITY {
+            outbox.push(Message::Text(format!("info depth {i} multipv 1")), Some(1))?;
+        }
+        outbox.push(Message::Text("info depth 64 multipv 1".to_owned()), Some(1))?;
+        outbox.close();
+
+        let mut messages = Vec::new();
+        while let Some(Message::Text(text)) = outbox.pop().await {
+            messages.push(text);
+        }
+        // The only info of the second line is kept, rather than the oldest.
+        assert_eq!(messages.len(), CAPACITY);
+        assert_eq!(messages[0], "info depth 1 multipv 2");
+        assert_eq!(messages[1], "info depth 2 multipv 1");
+        Ok(())
+    }
+}
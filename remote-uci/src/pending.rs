@@ -0,0 +1,115 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::engine::Session;
+
+/// Engines may take a while to load networks or allocate the hash table
+/// before they reply, but an engine that does not reply at all is stuck.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands that the engine must acknowledge.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Request {
+    /// uci, acknowledged with uciok.
+    Uci,
+    /// isready, acknowledged with readyok.
+    Isready,
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Request::Uci => "uci",
+            Request::Isready => "isready",
+        })
+    }
+}
+
+/// A request that the engine has yet to acknowledge, with the session that
+/// sent it.
+#[derive(Debug)]
+pub struct Pending {
+    pub request: Request,
+    pub session: Session,
+    pub sent: Instant,
+}
+
+impl Pending {
+    pub fn deadline(&self) -> Instant {
+        self.sent + REPLY_TIMEOUT
+    }
+}
+
+/// Requests that the engine has yet to acknowledge, in the order they were
+/// sent. The engine acknowledges requests of each kind in order, so that
+/// each reply belongs to the oldest pending request of its kind.
+#[derive(Debug, Default)]
+pub struct PendingReplies {
+    queue: VecDeque<Pending>,
+}
+
+impl PendingReplies {
+    pub fn push(&mut self, request: Request, session: Session) {
+        self.queue.push_back(Pending {
+            request,
+            session,
+            sent: Instant::now(),
+        });
+    }
+
+    /// Take the request answered by a reply.
+    pub fn resolve(&mut self, request: Request) -> Option<Pending> {
+        let index = self.queue.iter().position(|p| p.request == request)?;
+        self.queue.remove(index)
+    }
+
+    pub fn contains(&self, request: Request) -> bool {
+        self.queue.iter().any(|p| p.request == request)
+    }
+
+    /// Number of pending requests of the kind sent by the session.
+    pub fn count(&self, request: Request, session: Session) -> usize {
+        self.queue
+            .iter()
+            .filter(|p| p.request == request && p.session == session)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// The request that times out first.
+    pub fn next_deadline(&self) -> Option<&Pending> {
+        self.queue.front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut pending = PendingReplies::default();
+        pending.push(Request::Isready, Session(1));
+        pending.push(Request::Uci, Session(2));
+        pending.push(Request::Isready, Session(2));
+        assert_eq!(pending.count(Request::Isready, Session(2)), 1);
+        assert_eq!(pending.resolve(Request::Uci).unwrap().session, Session(2));
+        assert!(!pending.contains(Request::Uci));
+        assert_eq!(
+            pending.resolve(Request::Isready).unwrap().session,
+            Session(1)
+        );
+        assert_eq!(
+            pending.resolve(Request::Isready).unwrap().session,
+            Session(2)
+        );
+        assert!(pending.resolve(Request::Isready).is_none());
+        assert!(pending.is_empty());
+    }
+}
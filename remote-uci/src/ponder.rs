@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use shakmaty::uci::Uci;
+
+use crate::uci::{UciIn, UciOut};
+
+/// Pondering ends by itself after this time, in case the client does not
+/// continue at all.
+const MAX_PONDER_TIME: Duration = Duration::from_secs(10);
+
+/// Search of the position after the best move, while the client looks at
+/// the result of its previous search. Clients stepping forward through a
+/// line usually continue with that move.
+pub struct Ponder {
+    /// Position of the search of the client.
+    previous: UciIn,
+    position: UciIn,
+    /// Latest info with a score, by multipv.
+    infos: BTreeMap<u32, UciOut>,
+}
+
+impl Ponder {
+    /// Ponder the position after the best move of the search of `position`.
+    pub fn after(position: &UciIn, bestmove: &Uci) -> Option<Ponder> {
+        match position {
+            UciIn::Position { fen, moves } => Some(Ponder {
+                previous: position.clone(),
+                position: UciIn::Position {
+                    fen: fen.clone(),
+                    moves: moves.iter().chain([bestmove]).cloned().collect(),
+                },
+                infos: BTreeMap::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn position(&self) -> &UciIn {
+        &self.position
+    }
+
+    pub fn go() -> UciIn {
+        UciIn::Go {
+            searchmoves: None,
+            ponder: false,
+            wtime: None,
+            btime: None,
+            winc: None,
+            binc: None,
+            movestogo: None,
+            depth: None,
+            nodes: None,
+            mate: None,
+            movetime: Some(MAX_PONDER_TIME),
+            infinite: false,
+        }
+    }
+
+    pub fn record(&mut self, command: UciOut) {
+        if let UciOut::Info {
+            multipv,
+            score: Some(_),
+            ..
+        } = command
+        {
+            self.infos
+                .insert(multipv.map_or(1, |multipv| multipv.get()), command);
+        }
+    }
+
+    /// Stop pondering for the next command of the client.
+    pub fn end(self, command: &UciIn) -> Ended {
+        if self.position != *command || self.infos.is_empty() {
+            return Ended::Miss(match command {
+                // The client sets the position itself.
+                UciIn::Position { .. } => None,
+                _ => Some(self.previous),
+            });
+        }
+        Ended::Hit(Cached {
+            depth: self
+                .infos
+                .values()
+                .filter_map(|info| match info {
+                    UciOut::Info { depth, .. } => *depth,
+                    _ => None,
+                })
+                .min()
+                .unwrap_or(0),
+            infos: self.infos.into_values().collect(),
+        })
+    }
+}
+
+/// How pondering ended for the next command of the client.
+pub enum Ended {
+    /// The client continued with the expected position.
+    Hit(Cached),
+    /// The client did something else. The engine is still on the pondered
+    /// position, so this position of the client is to be sent again before
+    /// its command, if any.
+    Miss(Option<UciIn>),
+}
+
+/// Results of pondering, sent as soon as the client starts its search.
+pub struct Cached {
+    /// Infos of the new search up to this depth are held back, since the
+    /// client already has better ones.
+    pub depth: u32,
+    pub infos: Vec<UciOut>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ponder_hit() {
+        let position = UciIn::from_line("position startpos moves e2e4")
+            .unwrap()
+            .unwrap();
+        let mut ponder = Ponder::after(&position, &"e7e5".parse().unwrap()).unwrap();
+        assert_eq!(
+            ponder.position().to_string(),
+            "position startpos moves e2e4 e7e5"
+        );
+        for line in [
+            "info depth 10 score cp 20 pv g1f3",
+            "info depth 11 score cp 25 pv g1f3 b8c6",
+            "bestmove g1f3",
+        ] {
+            ponder.record(UciOut::from_line(line).unwrap().unwrap());
+        }
+        let expected = ponder.position().clone();
+        let cached = match ponder.end(&expected) {
+            Ended::Hit(cached) => cached,
+            Ended::Miss(_) => panic!("expected ponder hit"),
+        };
+        assert_eq!(cached.depth, 11);
+        assert_eq!(cached.infos.len(), 1);
+    }
+
+    #[test]
+    fn test_ponder_miss() {
+        let uci_in = |line: &str| UciIn::from_line(line).unwrap().unwrap();
+        let position = uci_in("position startpos moves e2e4");
+        let pondering = || {
+            let mut ponder = Ponder::after(&position, &"e7e5".parse().unwrap()).unwrap();
+            ponder.record(
+                UciOut::from_line("info depth 10 score cp 20 pv g1f3")
+                    .unwrap()
+                    .unwrap(),
+            );
+            ponder
+        };
+        // Searching again goes back to the position of the client.
+        assert!(matches!(
+            pondering().end(&uci_in("go depth 20")),
+            Ended::Miss(Some(ref previous)) if *previous == position
+        ));
+        assert!(matches!(
+            pondering().end(&uci_in("setoption name MultiPV value 2")),
+            Ended::Miss(Some(ref previous)) if *previous == position
+        ));
+        // Another position replaces the pondered one anyway.
+        assert!(matches!(
+            pondering().end(&uci_in("position startpos moves d2d4")),
+            Ended::Miss(None)
+        ));
+    }
+}
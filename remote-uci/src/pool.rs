@@ -0,0 +1,333 @@
+use std::{
+    cmp::Reverse,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use tokio::{
+    net::TcpStream,
+    time::{interval, timeout},
+};
+use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+
+use crate::uci::{UciIn, UciOptionName, UciOut};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time for a worker to answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Other remote-uci instances, that take over sessions while the local
+/// engine is busy.
+pub struct Pool {
+    workers: Vec<Arc<Worker>>,
+    split: bool,
+}
+
+struct Worker {
+    /// Socket URL of the worker, including its secret.
+    url: String,
+    sessions: AtomicUsize,
+    healthy: AtomicBool,
+    /// Last observed nodes per second, or 0 if not yet measured.
+    nps: AtomicU64,
+}
+
+/// Fields of /api/status of a worker.
+#[derive(Deserialize)]
+struct WorkerStatus {
+    nps: Option<u64>,
+}
+
+/// A session forwarded to a worker.
+pub struct Forwarded {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    worker: Arc<Worker>,
+}
+
+impl Forwarded {
+    pub fn socket_mut(&mut self) -> &mut WebSocketStream<MaybeTlsStream<TcpStream>> {
+        &mut self.socket
+    }
+}
+
+impl Drop for Forwarded {
+    fn drop(&mut self) {
+        self.worker.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Pool {
+    pub fn new(urls: Vec<String>, split: bool) -> Pool {
+        Pool {
+            split,
+            workers: urls
+                .into_iter()
+                .map(|url| {
+                    Arc::new(Worker {
+                        url,
+                        sessions: AtomicUsize::new(0),
+                        healthy: AtomicBool::new(true),
+                        nps: AtomicU64::new(0),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Whether searches should be split across all workers.
+    pub fn splits(&self) -> bool {
+        self.split && !self.is_empty()
+    }
+
+    /// Open a session on the least busy worker that is reachable, passing
+    /// on the query parameters of the client except for its secret. Among
+    /// idle workers, the fastest healthy one is preferred.
+    pub async fn connect(&self, query: &str) -> Option<Forwarded> {
+        let mut workers = self.workers.clone();
+        workers.sort_by_key(|worker| {
+            (
+                !worker.healthy.load(Ordering::SeqCst),
+                worker.sessions.load(Ordering::SeqCst),
+                Reverse(worker.nps.load(Ordering::SeqCst)),
+            )
+        });
+        for worker in workers {
+            if let Some(forwarded) = worker.connect(query).await {
+                return Some(forwarded);
+            }
+        }
+        None
+    }
+
+    /// Open a session on each reachable worker.
+    pub async fn connect_all(&self, query: &str) -> Vec<Forwarded> {
+        let mut sessions = Vec::new();
+        for worker in &self.workers {
+            sessions.extend(Arc::clone(worker).connect(query).await);
+        }
+        sessions
+    }
+}
+
+impl Worker {
+    async fn connect(self: Arc<Self>, query: &str) -> Option<Forwarded> {
+        let url = format!("{}&{}", self.url, query);
+        match timeout(CONNECT_TIMEOUT, connect_async(&url)).await {
+            Ok(Ok((socket, _))) => {
+                self.sessions.fetch_add(1, Ordering::SeqCst);
+                self.healthy.store(true, Ordering::SeqCst);
+                log::info!("Forwarding session to worker {}", self.name());
+                Some(Forwarded {
+                    socket,
+                    worker: self,
+                })
+            }
+            Ok(Err(err)) => {
+                log::warn!("Worker {} failed: {err}", self.name());
+                self.healthy.store(false, Ordering::SeqCst);
+                None
+            }
+            Err(_) => {
+                log::warn!("Worker {} timed out", self.name());
+                self.healthy.store(false, Ordering::SeqCst);
+                None
+            }
+        }
+    }
+
+    /// Check that the engine of the worker is running, and take over the
+    /// speed it measured in its own searches. Only the HTTP API of the worker
+    /// is asked, so that searches in progress on the worker are not
+    /// interrupted by a new session.
+    async fn probe(&self, client: &Client) -> Result<(), Box<dyn Error + Send + Sync>> {
+        client
+            .get(self.api_url("/health")?)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        let status: WorkerStatus = client
+            .get(self.api_url("/api/status")?)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if let Some(nps) = status.nps {
+            self.nps.store(nps, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// HTTP URL of the given path on the worker, without the secret.
+    fn api_url(&self, path: &str) -> Result<Url, Box<dyn Error + Send + Sync>> {
+        let mut url = Url::parse(&self.url)?;
+        let scheme = match url.scheme() {
+            "wss" => "https",
+            _ => "http",
+        };
+        url.set_scheme(scheme)
+            .map_err(|()| format!("unsupported worker url: {}", self.name()))?;
+        url.set_path(path);
+        url.set_query(None);
+        Ok(url)
+    }
+
+    /// URL without the secret, for logging.
+    fn name(&self) -> &str {
+        self.url.split('?').next().unwrap_or_default()
+    }
+}
+
+/// Periodically probe workers, so that failed workers are avoided and
+/// recovered workers are used again.
+pub async fn check_health(pool: Arc<Pool>) {
+    let client = Client::new();
+    let mut interval = interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        for worker in &pool.workers {
+            let healthy = match worker.probe(&client).await {
+                Ok(()) => true,
+                Err(err) => {
+                    log::debug!("Worker {} probe failed: {err}", worker.name());
+                    false
+                }
+            };
+            if worker.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+                log::warn!(
+                    "Worker {} is {}",
+                    worker.name(),
+                    if healthy { "healthy" } else { "unhealthy" }
+                );
+            }
+        }
+    }
+}
+
+/// Relay messages between the client and a worker, until either side
+/// closes the connection. If the worker fails, the session continues on
+/// another worker, by replaying options, position and a pending search.
+pub async fn forward(client: WebSocket, pool: Arc<Pool>, query: String, mut forwarded: Forwarded) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let mut replay = Replay::default();
+    loop {
+        let worker = Arc::clone(&forwarded.worker);
+        let (mut worker_tx, mut worker_rx) = forwarded.socket_mut().split();
+        let mut worker_failed = false;
+        for line in replay.lines() {
+            if worker_tx
+                .send(tungstenite::Message::Text(line))
+                .await
+                .is_err()
+            {
+                worker_failed = true;
+                break;
+            }
+        }
+        while !worker_failed {
+            tokio::select! {
+                message = client_rx.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        replay.client(&text);
+                        worker_failed = worker_tx.send(tungstenite::Message::Text(text)).await.is_err();
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => (),
+                },
+                message = worker_rx.next() => match message {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        replay.worker(&text, &worker);
+                        if client_tx.send(Message::Text(text)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(Ok(tungstenite::Message::Close(_)) | Err(_)) | None => worker_failed = true,
+                    Some(Ok(_)) => (),
+                },
+            }
+        }
+        if !worker_failed {
+            let _ = worker_tx.close().await;
+            break;
+        }
+
+        worker.healthy.store(false, Ordering::SeqCst);
+        log::warn!("Worker {} failed, migrating session ...", worker.name());
+        drop((worker_tx, worker_rx));
+        match pool.connect(&query).await {
+            Some(next) => forwarded = next,
+            None => {
+                log::error!("No worker available to continue session");
+                break;
+            }
+        }
+    }
+    let _ = client_tx.send(Message::Close(None)).await;
+}
+
+/// Commands needed to continue a session on another worker.
+#[derive(Default)]
+struct Replay {
+    options: Vec<(UciOptionName, String)>,
+    position: Option<String>,
+    go: Option<String>,
+    stop: bool,
+}
+
+impl Replay {
+    fn client(&mut self, line: &str) {
+        match UciIn::from_line(line) {
+            Ok(Some(UciIn::Setoption { name, .. })) => {
+                self.options.retain(|(n, _)| *n != name);
+                self.options.push((name, line.to_owned()));
+            }
+            Ok(Some(UciIn::Position { .. })) => self.position = Some(line.to_owned()),
+            Ok(Some(UciIn::Go { .. })) => {
+                self.go = Some(line.to_owned());
+                self.stop = false;
+            }
+            Ok(Some(UciIn::Stop)) => self.stop = true,
+            _ => (),
+        }
+    }
+
+    fn worker(&mut self, line: &str, worker: &Worker) {
+        match UciOut::from_line(line) {
+            Ok(Some(UciOut::Info { nps: Some(nps), .. })) => {
+                worker.nps.store(nps, Ordering::SeqCst);
+            }
+            Ok(Some(UciOut::Bestmove { .. })) => {
+                self.go = None;
+                self.stop = false;
+            }
+            _ => (),
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self.options.iter().map(|(_, line)| line.clone()).collect();
+        lines.extend(self.position.clone());
+        lines.extend(self.go.clone());
+        if self.go.is_some() && self.stop {
+            lines.push("stop".to_owned());
+        }
+        lines
+    }
+}
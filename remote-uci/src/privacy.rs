@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::uci::{UciIn, UciOut};
+
+const REDACTED: &str = "<redacted>";
+
+/// What a server leaves out of logs and other records.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Privacy {
+    /// Leave out positions and moves, for operators who analyse private
+    /// preparation.
+    pub redact_positions: bool,
+}
+
+impl Privacy {
+    /// Displays the command for logs.
+    pub fn redact<T>(self, command: &T) -> Redacted<'_, T> {
+        Redacted {
+            command,
+            positions: self.redact_positions,
+        }
+    }
+}
+
+/// Displays a command for logs, without positions and moves if they are
+/// redacted.
+pub struct Redacted<'a, T> {
+    command: &'a T,
+    positions: bool,
+}
+
+impl fmt::Display for Redacted<'_, UciIn> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.positions {
+            return self.command.fmt(f);
+        }
+        match self.command {
+            UciIn::Position { .. } => write!(f, "position {REDACTED}"),
+            UciIn::Go {
+                searchmoves: Some(_),
+                ..
+            } => {
+                let mut go = self.command.clone();
+                if let UciIn::Go {
+                    ref mut searchmoves,
+                    ..
+                } = go
+                {
+                    *searchmoves = None;
+                }
+                write!(f, "{go} searchmoves {REDACTED}")
+            }
+            command => command.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Redacted<'_, UciOut> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.positions {
+            return self.command.fmt(f);
+        }
+        match self.command {
+            UciOut::Bestmove { .. } => write!(f, "bestmove {REDACTED}"),
+            UciOut::Info { .. } => {
+                let mut info = self.command.clone();
+                let mut redacted = false;
+                if let UciOut::Info {
+                    ref mut currmove,
+                    ref mut refutation,
+                    ref mut currline,
+                    ref mut pv,
+                    ..
+                } = info
+                {
+                    redacted = currmove.take().is_some()
+                        | !refutation.is_empty()
+                        | !currline.is_empty()
+                        | pv.take().is_some();
+                    refutation.clear();
+                    currline.clear();
+                }
+                info.fmt(f)?;
+                if redacted {
+                    write!(f, " pv {REDACTED}")?;
+                }
+                Ok(())
+            }
+            command => command.fmt(f),
+        }
+    }
+}
+
+/// Raw lines are redacted after the first token, which is usually the
+/// command.
+impl fmt::Display for Redacted<'_, &str> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.positions {
+            return f.write_str(self.command);
+        }
+        match self.command.split_once(' ') {
+            Some((command, _)) => write!(f, "{command} {REDACTED}"),
+            None => f.write_str(self.command),
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_, &str> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_string().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        let privacy = Privacy {
+            redact_positions: true,
+        };
+        let position = UciIn::from_line("position startpos moves e2e4 e7e5")
+            .unwrap()
+            .unwrap();
+        assert_eq!(privacy.redact(&position).to_string(), "position <redacted>");
+        assert_eq!(
+            Privacy::default().redact(&position).to_string(),
+            "position startpos moves e2e4 e7e5"
+        );
+        let info = UciOut::from_line("info depth 20 score cp 31 nodes 1000 pv e2e4 e7e5")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            privacy.redact(&info).to_string(),
+            "info depth 20 nodes 1000 score cp 31 pv <redacted>"
+        );
+        assert_eq!(
+            privacy.redact(&"bestmove e2e4 ponder e7e5").to_string(),
+            "bestmove <redacted>"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// Write a file that only the current user can read, like a secret file.
+/// The contents are written to a temporary file first, and then replace the
+/// file, so that a crash does not leave it truncated. Permissions of
+/// existing files are kept, so that operators can share them on purpose.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    // A leftover from a crash may have other permissions.
+    match fs::remove_file(&tmp) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+
+    #[test]
+    fn test_write() -> io::Result<()> {
+        let path = env::temp_dir().join(format!("remote-uci-private-{}", process::id()));
+        write(&path, "secret")?;
+        assert_eq!(fs::read_to_string(&path)?, "secret");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        write(&path, "shared")?;
+        assert_eq!(fs::read_to_string(&path)?, "shared");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o640);
+        assert!(!tmp_path(&path).exists());
+        fs::remove_file(path)
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use thiserror::Error;
+
+/// Network of reverse proxies that are trusted to report the address of
+/// their clients in X-Forwarded-For, like 10.0.0.0/8 or ::1.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid network, expected address or address/prefix")]
+pub struct InvalidTrustedProxy;
+
+impl FromStr for TrustedProxy {
+    type Err = InvalidTrustedProxy;
+
+    fn from_str(s: &str) -> Result<TrustedProxy, InvalidTrustedProxy> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| InvalidTrustedProxy)?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| InvalidTrustedProxy)?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(InvalidTrustedProxy);
+        }
+        Ok(TrustedProxy { network, prefix })
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, to_canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treat IPv4 clients of dual stack sockets like IPv4 clients.
+fn to_canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Address of the client that made a request, as determined by
+/// `client_ip()`.
+#[derive(Debug, Copy, Clone)]
+pub struct ClientIp(pub IpAddr);
+
+/// Determine the address of the client. X-Forwarded-For is only considered
+/// if the direct peer is a trusted proxy, and then the rightmost address
+/// that is not also a trusted proxy is used. IPv4 addresses are never
+/// reported as IPv4-mapped IPv6 addresses.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[TrustedProxy]) -> IpAddr {
+    let peer = to_canonical(peer);
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|proxy| proxy.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for value in headers.get_all("x-forwarded-for").iter().rev() {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return client,
+        };
+        for hop in value.rsplit(',') {
+            match hop.trim().parse().map(to_canonical) {
+                Ok(ip) if is_trusted(ip) => client = ip,
+                Ok(ip) => return ip,
+                Err(_) => return client,
+            }
+        }
+    }
+    client
+}
+
+/// Attach the `ClientIp` to requests, for use in logs and access checks.
+pub async fn resolve<B>(
+    trusted: Arc<[TrustedProxy]>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() {
+        let ip = client_ip(peer.ip(), req.headers(), &trusted);
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_client_ip() {
+        let trusted: Vec<TrustedProxy> = ["127.0.0.1", "10.0.0.0/8", "fd00::/8"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("203.0.113.7, 198.51.100.1, 10.1.2.3"),
+        );
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            client_ip(ip("127.0.0.1"), &headers, &trusted),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip(ip("::ffff:10.0.0.1"), &headers, &trusted),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip(ip("192.0.2.1"), &headers, &trusted),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            client_ip(ip("fd12::1"), &HeaderMap::new(), &trusted),
+            ip("fd12::1")
+        );
+
+        // Local IPv4 clients of dual stack sockets.
+        let local = client_ip(ip("::ffff:127.0.0.1"), &HeaderMap::new(), &[]);
+        assert_eq!(local, ip("127.0.0.1"));
+        assert!(local.is_loopback());
+        assert_eq!(
+            client_ip(ip("::ffff:192.0.2.1"), &headers, &trusted),
+            ip("192.0.2.1")
+        );
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+    }
+}
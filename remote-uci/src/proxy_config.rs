@@ -0,0 +1,229 @@
+use std::{
+    fmt::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use clap::ArgEnum;
+
+use crate::listen::DEFAULT_PORT;
+
+/// Public host name in generated configurations, if --publish-addr is not
+/// given.
+const PLACEHOLDER_HOST: &str = "engine.example.com";
+
+/// Clients are pinged every 10 seconds, so that the connection is never
+/// idle for long. Proxies may still close connections that are idle for
+/// longer than this.
+const READ_TIMEOUT_SECS: u32 = 300;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ArgEnum)]
+pub enum ProxyServer {
+    Nginx,
+    Caddy,
+    Traefik,
+}
+
+/// What the generated configuration is based on.
+pub struct ProxyConfig {
+    /// Address of the provider, as seen by the proxy.
+    upstream: SocketAddr,
+    /// Public host name of the proxy, without port.
+    host: Option<String>,
+}
+
+impl ProxyConfig {
+    pub fn new(bind: Option<SocketAddr>, publish_addr: Option<&str>) -> ProxyConfig {
+        let mut upstream =
+            bind.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT));
+        // The proxy connects to a concrete address.
+        match upstream.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => upstream.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => upstream.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => (),
+        }
+        ProxyConfig {
+            upstream,
+            host: publish_addr.map(strip_port),
+        }
+    }
+
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or(PLACEHOLDER_HOST)
+    }
+
+    /// Configuration snippet for the proxy server, with the matching
+    /// options of the provider in comments.
+    pub fn render(&self, server: ProxyServer) -> String {
+        let host = self.host();
+        let upstream = self.upstream;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# Reverse proxy for remote-uci, terminating TLS for {host}."
+        );
+        if self.host.is_none() {
+            let _ = writeln!(
+                out,
+                "# Replace {PLACEHOLDER_HOST} with your domain, or pass --publish-addr."
+            );
+        }
+        let _ = writeln!(out, "# Run the provider behind it with:");
+        let _ = writeln!(
+            out,
+            "#   remote-uci --bind {upstream} --publish-addr {host} --publish-addr-tls --trusted-proxy {}",
+            self.proxy_network()
+        );
+        let _ = writeln!(
+            out,
+            "# so that lichess is given wss://{host}/socket, and client addresses are taken"
+        );
+        let _ = writeln!(out, "# from X-Forwarded-For.");
+        out.push('\n');
+        out.push_str(&match server {
+            ProxyServer::Nginx => self.nginx(),
+            ProxyServer::Caddy => self.caddy(),
+            ProxyServer::Traefik => self.traefik(),
+        });
+        out
+    }
+
+    /// The proxy connects from the loopback interface if the provider is
+    /// bound to it, otherwise from anywhere on the local network.
+    fn proxy_network(&self) -> String {
+        match self.upstream.ip() {
+            ip if ip.is_loopback() => ip.to_string(),
+            IpAddr::V4(_) => "10.0.0.0/8".to_owned(),
+            IpAddr::V6(_) => "fc00::/7".to_owned(),
+        }
+    }
+
+    fn nginx(&self) -> String {
+        format!(
+            r#"map $http_upgrade $connection_upgrade {{
+    default upgrade;
+    '' close;
+}}
+
+server {{
+    listen 443 ssl http2;
+    server_name {host};
+
+    ssl_certificate /etc/letsencrypt/live/{host}/fullchain.pem;
+    ssl_certificate_key /etc/letsencrypt/live/{host}/privkey.pem;
+
+    location / {{
+        proxy_pass http://{upstream};
+        proxy_http_version 1.1;
+        proxy_set_header Upgrade $http_upgrade;
+        proxy_set_header Connection $connection_upgrade;
+        proxy_set_header Host $host;
+        proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;
+        proxy_set_header X-Forwarded-Proto $scheme;
+
+        # Forward engine output as soon as it is available.
+        proxy_buffering off;
+        proxy_request_buffering off;
+
+        # Analysis sessions are long-lived.
+        proxy_read_timeout {READ_TIMEOUT_SECS}s;
+        proxy_send_timeout {READ_TIMEOUT_SECS}s;
+    }}
+}}
+"#,
+            host = self.host(),
+            upstream = self.upstream,
+        )
+    }
+
+    fn caddy(&self) -> String {
+        format!(
+            r#"{host} {{
+    # WebSocket upgrades are proxied automatically.
+    reverse_proxy {upstream} {{
+        # Forward engine output as soon as it is available.
+        flush_interval -1
+        transport http {{
+            read_timeout {READ_TIMEOUT_SECS}s
+            write_timeout {READ_TIMEOUT_SECS}s
+        }}
+    }}
+}}
+"#,
+            host = self.host(),
+            upstream = self.upstream,
+        )
+    }
+
+    fn traefik(&self) -> String {
+        format!(
+            r#"# Dynamic configuration (file provider). WebSocket upgrades are proxied
+# automatically. In the static configuration, allow long-lived sessions on
+# the entry point:
+#
+#   entryPoints:
+#     websecure:
+#       address: ":443"
+#       transport:
+#         respondingTimeouts:
+#           readTimeout: {READ_TIMEOUT_SECS}s
+#           writeTimeout: {READ_TIMEOUT_SECS}s
+
+http:
+  routers:
+    remote-uci:
+      rule: "Host(`{host}`)"
+      entryPoints:
+        - websecure
+      service: remote-uci
+      tls:
+        certResolver: letsencrypt
+  services:
+    remote-uci:
+      loadBalancer:
+        # Forward engine output as soon as it is available.
+        responseForwarding:
+          flushInterval: "-1"
+        servers:
+          - url: "http://{upstream}"
+"#,
+            host = self.host(),
+            upstream = self.upstream,
+        )
+    }
+}
+
+/// Host name of the publish address, without the port that the proxy
+/// listens on.
+fn strip_port(publish_addr: &str) -> String {
+    if publish_addr.parse::<IpAddr>().is_ok() {
+        return publish_addr.to_owned();
+    }
+    match publish_addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host.to_owned(),
+        _ => publish_addr.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_config() {
+        let config = ProxyConfig::new(
+            Some("0.0.0.0:9670".parse().unwrap()),
+            Some("engine.example.org:443"),
+        );
+        assert_eq!(config.upstream, "127.0.0.1:9670".parse().unwrap());
+        assert_eq!(config.host.as_deref(), Some("engine.example.org"));
+        let nginx = config.render(ProxyServer::Nginx);
+        assert!(nginx.contains("proxy_pass http://127.0.0.1:9670;"));
+        assert!(nginx.contains("--publish-addr engine.example.org --publish-addr-tls"));
+        assert!(config
+            .render(ProxyServer::Caddy)
+            .starts_with("# Reverse proxy"));
+
+        assert_eq!(strip_port("::1"), "::1");
+        assert_eq!(strip_port("[::1]:443"), "[::1]");
+    }
+}
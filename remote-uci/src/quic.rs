@@ -0,0 +1,245 @@
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::Bytes,
+    extract::ws::Message,
+    http::{HeaderMap, StatusCode},
+};
+use futures_util::{Sink, Stream, StreamExt};
+use quinn::{Connecting, Connection, Endpoint, NewConnection, RecvStream, SendStream, VarInt};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+};
+
+use crate::{
+    auth::{AuthRequest, Authenticator},
+    config::Config,
+    ws::{self, Params, SharedEngine},
+};
+
+/// ALPN protocol identifier for sessions over QUIC.
+const ALPN: &[u8] = b"remote-uci";
+
+/// Start an experimental QUIC listener for the engine channel, and return
+/// the address it is bound to.
+///
+/// Each session is a bidirectional stream. The client first sends the query
+/// string that it would otherwise use for `/socket`, followed by UCI commands,
+/// one per line. The provider responds with one command per line, except that
+/// `info` lines are sent as unreliable datagrams whenever they fit, so that a
+/// lost packet in a burst of `info` does not hold back `bestmove`.
+pub fn listen(
+    bind: SocketAddr,
+    engine: Arc<SharedEngine>,
+    authenticator: Arc<dyn Authenticator>,
+    config: Arc<Config>,
+) -> Result<SocketAddr, Box<dyn Error>> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der()?)],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let (endpoint, mut incoming) =
+        Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), bind)?;
+    let local_addr = endpoint.local_addr()?;
+    log::warn!("Experimental QUIC listener on {local_addr} (self-signed certificate)");
+
+    tokio::spawn(async move {
+        let _endpoint = endpoint;
+        while let Some(connecting) = incoming.next().await {
+            tokio::spawn(handle_connection(
+                connecting,
+                Arc::clone(&engine),
+                Arc::clone(&authenticator),
+                Arc::clone(&config),
+            ));
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn handle_connection(
+    connecting: Connecting,
+    engine: Arc<SharedEngine>,
+    authenticator: Arc<dyn Authenticator>,
+    config: Arc<Config>,
+) {
+    let NewConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = match connecting.await {
+        Ok(new_connection) => new_connection,
+        Err(err) => {
+            log::error!("QUIC handshake failed: {err}");
+            return;
+        }
+    };
+    while let Some(Ok((send, recv))) = bi_streams.next().await {
+        tokio::spawn(handle_stream(
+            connection.clone(),
+            send,
+            recv,
+            Arc::clone(&engine),
+            Arc::clone(&authenticator),
+            Arc::clone(&config),
+        ));
+    }
+}
+
+/// Authenticate the first line of a stream, like the query string of an
+/// upgrade to a WebSocket.
+async fn authorize(
+    authenticator: &dyn Authenticator,
+    config: &Config,
+    line: &str,
+    peer: SocketAddr,
+) -> Result<ws::SessionParams, StatusCode> {
+    let params: Params = serde_urlencoded::from_str(line).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let identity = authenticator
+        .authenticate(&AuthRequest {
+            headers: HeaderMap::new(),
+            query: serde_urlencoded::from_str(line).map_err(|_| StatusCode::BAD_REQUEST)?,
+        })
+        .await?;
+    ws::session_params(config, identity, params, Some(peer.ip()))
+}
+
+async fn handle_stream(
+    connection: Connection,
+    mut send: SendStream,
+    recv: RecvStream,
+    engine: Arc<SharedEngine>,
+    authenticator: Arc<dyn Authenticator>,
+    config: Arc<Config>,
+) {
+    let mut lines = BufReader::new(recv).lines();
+    let params = match lines.next_line().await {
+        Ok(Some(line)) => {
+            authorize(&*authenticator, &config, &line, connection.remote_address()).await
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    };
+    let params = match params {
+        Ok(_) if engine.is_paused() || !engine.health().is_running() => {
+            let _ = send.reset(VarInt::from_u32(
+                StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+            ));
+            return;
+        }
+        Ok(params) => params,
+        Err(status) => {
+            let _ = send.reset(VarInt::from_u32(status.as_u16().into()));
+            return;
+        }
+    };
+
+    let (inbound_tx, inbound) = mpsc::unbounded_channel();
+    let (outbound, mut outbound_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn({
+        let inbound_tx = inbound_tx.clone();
+        async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                if inbound_tx.send(Ok(Message::Text(line))).is_err() {
+                    return;
+                }
+            }
+            let _ = inbound_tx.send(Ok(Message::Close(None)));
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(message) = outbound_rx.recv().await {
+            let mut line = match message {
+                Message::Text(line) => line,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if line.starts_with("info ")
+                && connection
+                    .max_datagram_size()
+                    .map_or(false, |max| line.len() <= max)
+                && connection.send_datagram(Bytes::from(line.clone())).is_ok()
+            {
+                continue;
+            }
+            line.push('\n');
+            if send.write_all(line.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+        let _ = send.finish().await;
+    });
+
+    ws::handle_socket(
+        engine,
+        QuicSocket {
+            inbound,
+            inbound_tx,
+            outbound,
+        },
+        params,
+    )
+    .await;
+}
+
+/// Adapts the line-based stream to the message interface used by the
+/// session handler.
+struct QuicSocket {
+    inbound: mpsc::UnboundedReceiver<Result<Message, axum::Error>>,
+    inbound_tx: mpsc::UnboundedSender<Result<Message, axum::Error>>,
+    outbound: mpsc::UnboundedSender<Message>,
+}
+
+impl Stream for QuicSocket {
+    type Item = Result<Message, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inbound.poll_recv(cx)
+    }
+}
+
+impl Sink<Message> for QuicSocket {
+    type Error = axum::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        let sent = match message {
+            // QUIC has its own keep-alive, so answer pings locally.
+            Message::Ping(data) => self.inbound_tx.send(Ok(Message::Pong(data))).is_ok(),
+            message => self.outbound.send(message).is_ok(),
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(axum::Error::new(io::Error::from(io::ErrorKind::BrokenPipe)))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, Request, Response, StatusCode, Uri},
+    response::IntoResponse,
+    routing::{any, get},
+    Router,
+};
+use futures_util::{future, SinkExt, TryStreamExt};
+use rand::random;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{copy_bidirectional, duplex},
+    sync::{mpsc, oneshot},
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use crate::tunnel::{bridge, Frame};
+
+/// How long to wait for a provider to pick up a relayed connection.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of registered provider ids, so that anonymous providers
+/// can not grow the registry without bound.
+const MAX_REGISTRATIONS: usize = 10_000;
+
+/// Relay for providers that can not accept incoming connections. Providers
+/// keep a control connection open, and open a new outbound connection for
+/// each client, which the relay then forwards.
+#[derive(Default)]
+struct Relay {
+    providers: Mutex<HashMap<String, mpsc::UnboundedSender<String>>>,
+    pending: Mutex<HashMap<String, oneshot::Sender<WebSocket>>>,
+    /// SHA-256 of the token of each provider id. The first provider to
+    /// connect with an id registers its token, and only it can provide
+    /// that id from then on.
+    tokens: Mutex<HashMap<String, String>>,
+    /// File with one id and token hash per line, to keep registrations
+    /// across restarts.
+    registry: Option<PathBuf>,
+}
+
+impl Relay {
+    fn load(registry: PathBuf) -> io::Result<Relay> {
+        let mut tokens = HashMap::new();
+        match fs::read_to_string(&registry) {
+            Ok(file) => {
+                for line in file.lines() {
+                    // The first registration of an id stands.
+                    if let Some((id, hash)) = line.split_once(' ') {
+                        tokens
+                            .entry(id.to_owned())
+                            .or_insert_with(|| hash.to_owned());
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        Ok(Relay {
+            tokens: Mutex::new(tokens),
+            registry: Some(registry),
+            ..Relay::default()
+        })
+    }
+
+    /// Check the token of a provider, registering it for ids that are not
+    /// yet taken.
+    fn authorize(&self, id: &str, token: &str) -> Result<(), StatusCode> {
+        let hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+        let mut tokens = self.tokens.lock().expect("tokens");
+        match tokens.get(id) {
+            Some(registered) if *registered == hash => Ok(()),
+            Some(_) => {
+                log::warn!("Rejected provider {id} with wrong token");
+                Err(StatusCode::FORBIDDEN)
+            }
+            None if tokens.len() >= MAX_REGISTRATIONS => {
+                log::warn!("Rejected provider {id}, registry is full");
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            }
+            None => {
+                if let Some(ref path) = self.registry {
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut file| writeln!(file, "{id} {hash}"))
+                        .map_err(|err| {
+                            log::error!("Could not write registry {path:?}: {err}");
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        })?;
+                }
+                log::info!("Registered provider {id}");
+                tokens.insert(id.to_owned(), hash);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub async fn run(bind: SocketAddr, registry: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let relay = Arc::new(match registry {
+        Some(registry) => Relay::load(registry)?,
+        None => Relay::default(),
+    });
+    log::info!("Relay listening on {bind}");
+    axum::Server::try_bind(&bind)?
+        .serve(router(relay).into_make_service())
+        .await?;
+    Ok(())
+}
+
+fn router(relay: Arc<Relay>) -> Router {
+    Router::new()
+        .route(
+            "/provide/:id",
+            get({
+                let relay = Arc::clone(&relay);
+                move |id, headers, ws| provide(relay, id, headers, ws)
+            }),
+        )
+        .route(
+            "/accept/:token",
+            get({
+                let relay = Arc::clone(&relay);
+                move |token, ws| accept(relay, token, ws)
+            }),
+        )
+        .route("/p/*path", any(move |req| proxy(relay, req)))
+}
+
+async fn provide(
+    relay: Arc<Relay>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, StatusCode> {
+    if id.contains(char::is_whitespace) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    relay.authorize(&id, token)?;
+    // The provider proved its identity, so it replaces a previous control
+    // connection that may not have noticed yet that it is gone.
+    let (tx, rx) = mpsc::unbounded_channel();
+    relay
+        .providers
+        .lock()
+        .expect("providers")
+        .insert(id.clone(), tx);
+    Ok(ws.on_upgrade(move |socket| control(relay, id, socket, rx)))
+}
+
+async fn control(
+    relay: Arc<Relay>,
+    id: String,
+    mut socket: WebSocket,
+    mut tokens: mpsc::UnboundedReceiver<String>,
+) {
+    log::info!("Provider {id} connected");
+    let mut keepalive = interval(Duration::from_secs(30));
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let res = tokio::select! {
+            token = tokens.recv() => match token {
+                Some(token) => socket.send(Message::Text(token)).await,
+                None => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => Ok(()),
+            },
+            _ = keepalive.tick() => socket.send(Message::Ping(Vec::new())).await,
+        };
+        if res.is_err() {
+            break;
+        }
+    }
+    drop(tokens);
+    relay
+        .providers
+        .lock()
+        .expect("providers")
+        .retain(|_, tx| !tx.is_closed());
+    log::info!("Provider {id} disconnected");
+}
+
+async fn accept(
+    relay: Arc<Relay>,
+    Path(token): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, StatusCode> {
+    let tx = relay
+        .pending
+        .lock()
+        .expect("pending")
+        .remove(&token)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(ws.on_upgrade(move |socket| async move {
+        let _ = tx.send(socket);
+    }))
+}
+
+async fn proxy(relay: Arc<Relay>, mut req: Request<Body>) -> Result<Response<Body>, StatusCode> {
+    // Split /p/{id}/{rest} into the provider id and the path to forward.
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .to_owned();
+    let (id, rest) = path_and_query["/p/".len()..]
+        .split_once('/')
+        .ok_or(StatusCode::NOT_FOUND)?;
+    *req.uri_mut() = format!("/{rest}")
+        .parse::<Uri>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let token = format!("{:032x}", random::<u128>());
+    let (tx, rx) = oneshot::channel();
+    relay
+        .pending
+        .lock()
+        .expect("pending")
+        .insert(token.clone(), tx);
+    let announced = relay
+        .providers
+        .lock()
+        .expect("providers")
+        .get(id)
+        .map_or(false, |provider| provider.send(token.clone()).is_ok());
+    let socket = if announced {
+        timeout(ACCEPT_TIMEOUT, rx).await.ok().and_then(Result::ok)
+    } else {
+        None
+    };
+    relay.pending.lock().expect("pending").remove(&token);
+    let socket = socket.ok_or(StatusCode::BAD_GATEWAY)?;
+
+    let socket = socket
+        .map_ok(|message| match message {
+            Message::Binary(data) => Frame::Data(data),
+            Message::Close(_) => Frame::Close,
+            _ => Frame::Other,
+        })
+        .with(|data| future::ok::<_, axum::Error>(Message::Binary(data)));
+    let (local, remote) = duplex(64 * 1024);
+    tokio::spawn(bridge(socket, remote));
+    let (mut sender, conn) = hyper::client::conn::Builder::new()
+        .handshake(local)
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    tokio::spawn(conn);
+
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let mut res = sender
+        .send_request(req)
+        .await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?;
+    if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let provider_upgrade = hyper::upgrade::on(&mut res);
+        tokio::spawn(async move {
+            if let (Ok(mut client), Ok(mut provider)) =
+                tokio::join!(client_upgrade, provider_upgrade)
+            {
+                let _ = copy_bidirectional(&mut client, &mut provider).await;
+            }
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use tokio::time::sleep;
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{self, client::IntoClientRequest},
+    };
+
+    use super::*;
+    use crate::{tunnel, ws::Secret};
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let relay = Arc::new(Relay::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router(Arc::clone(&relay)).into_make_service()),
+        );
+
+        let app = Router::new().route("/hello", get(|| async { "hello" }));
+        let token = tunnel::token(&Secret("topsecret".to_owned()), "laptop");
+        tokio::spawn(tunnel::serve(
+            format!("ws://{addr}"),
+            "laptop".to_owned(),
+            token,
+            app,
+        ));
+        for _ in 0..100 {
+            if relay.providers.lock().unwrap().contains_key("laptop") {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        // Another provider can not take over the id.
+        let mut req = format!("ws://{addr}/provide/laptop")
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert(AUTHORIZATION, "Bearer guessed".parse().unwrap());
+        assert!(matches!(
+            connect_async(req).await,
+            Err(tungstenite::Error::Http(res)) if res.status() == StatusCode::FORBIDDEN
+        ));
+        assert!(matches!(
+            connect_async(format!("ws://{addr}/provide/laptop")).await,
+            Err(tungstenite::Error::Http(res)) if res.status() == StatusCode::UNAUTHORIZED
+        ));
+
+        // Requests are forwarded to the registered provider.
+        let res = reqwest::get(format!("http://{addr}/p/laptop/hello"))
+            .await
+            .unwrap();
+        assert_eq!(res.text().await.unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_registry() {
+        let path =
+            std::env::temp_dir().join(format!("remote-uci-relay-test-{}.txt", std::process::id()));
+        fs::write(&path, "laptop first\nlaptop second\n").unwrap();
+        let relay = Relay::load(path.clone()).unwrap();
+        assert_eq!(relay.tokens.lock().unwrap()["laptop"], "first");
+
+        relay
+            .tokens
+            .lock()
+            .unwrap()
+            .extend((1..MAX_REGISTRATIONS).map(|i| (format!("provider{i}"), "hash".to_owned())));
+        assert_eq!(
+            relay.authorize("desktop", "token"),
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        let registry = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(registry, "laptop first\nlaptop second\n");
+    }
+}
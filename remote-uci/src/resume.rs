@@ -0,0 +1,36 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::time::sleep;
+
+use crate::ws::SharedEngine;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delay beyond the check interval, that indicates that the host was
+/// suspended.
+const MIN_GAP: Duration = Duration::from_secs(30);
+
+/// Detect when the host resumes from sleep, and resynchronize the engine.
+///
+/// Depending on the platform, the monotonic clock may or may not advance
+/// while suspended, so compare it with the wall clock as well.
+pub async fn watch_resume(shared_engine: Arc<SharedEngine>) {
+    loop {
+        let (monotonic, wall) = (Instant::now(), SystemTime::now());
+        sleep(CHECK_INTERVAL).await;
+        let elapsed = monotonic.elapsed().max(wall.elapsed().unwrap_or_default());
+        if elapsed > CHECK_INTERVAL + MIN_GAP {
+            log::warn!(
+                "Host resumed after about {}s, resynchronizing ...",
+                elapsed.as_secs()
+            );
+            if let Err(err) = shared_engine.resync().await {
+                log::error!("Could not restart engine after resume: {err}");
+                shared_engine.report_failure(&err);
+            }
+        }
+    }
+}
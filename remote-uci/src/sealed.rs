@@ -0,0 +1,203 @@
+use std::io;
+
+use clap::ArgEnum;
+use thiserror::Error;
+
+pub use self::imp::{seal, unseal};
+
+/// Marks secret files that are encrypted, followed by the hex encoded salt,
+/// nonce and ciphertext.
+const PREFIX: &str = "remote-uci-sealed-v1:";
+
+/// Read the passphrase from this environment variable instead of
+/// prompting, e.g. when running as a service.
+const PASSPHRASE_VAR: &str = "REMOTE_UCI_PASSPHRASE";
+
+/// Where the key of encrypted secret files comes from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ArgEnum)]
+pub enum KeySource {
+    /// Keyring of the operating system, like the Windows Credential
+    /// Manager, macOS Keychain, or Secret Service on Linux.
+    Keyring,
+    /// Passphrase prompted at startup.
+    Passphrase,
+}
+
+#[derive(Error, Debug)]
+#[cfg_attr(not(feature = "sealed"), allow(dead_code))]
+pub enum SealError {
+    #[error("malformed encrypted secret")]
+    Malformed,
+    #[error("could not decrypt, wrong key or passphrase")]
+    Decrypt,
+    #[error("no key in the keyring")]
+    MissingKey,
+    #[cfg(feature = "sealed")]
+    #[error("keyring: {0}")]
+    Keyring(#[from] keyring::Error),
+    #[error(
+        "could not read passphrase (set {PASSPHRASE_VAR} when running without a terminal): {0}"
+    )]
+    Passphrase(io::Error),
+    #[cfg(feature = "sealed")]
+    #[error("could not derive key: {0}")]
+    Kdf(argon2::Error),
+    #[cfg(not(feature = "sealed"))]
+    #[error("built without the sealed feature")]
+    Unsupported,
+}
+
+pub fn is_sealed(contents: &str) -> bool {
+    contents.starts_with(PREFIX)
+}
+
+#[cfg(feature = "sealed")]
+mod imp {
+    use std::{env, fmt::Write as _};
+
+    use argon2::Argon2;
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit},
+        ChaCha20Poly1305, Key, Nonce,
+    };
+    use once_cell::sync::OnceCell;
+    use rand::{random, thread_rng, Rng};
+
+    use super::{KeySource, SealError, PASSPHRASE_VAR, PREFIX};
+
+    const SALT_LEN: usize = 16;
+    const NONCE_LEN: usize = 12;
+
+    /// Entry in the keyring of the operating system, that holds the key of
+    /// encrypted secret files.
+    const KEYRING_SERVICE: &str = "remote-uci";
+    const KEYRING_USER: &str = "secret-file-key";
+
+    /// The passphrase is asked for once, even for several secret files.
+    static PASSPHRASE: OnceCell<String> = OnceCell::new();
+
+    /// Encrypt the secret for storing it in a file.
+    pub fn seal(secret: &str, source: KeySource) -> Result<String, SealError> {
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        thread_rng().fill(&mut salt);
+        thread_rng().fill(&mut nonce);
+        let cipher = cipher(&key_material(source, true)?, &salt)?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), secret.as_bytes())
+            .expect("encrypt secret");
+        let mut sealed = PREFIX.to_owned();
+        for byte in salt.iter().chain(&nonce).chain(&ciphertext) {
+            let _ = write!(sealed, "{byte:02x}");
+        }
+        Ok(sealed)
+    }
+
+    /// Decrypt the contents of an encrypted secret file.
+    pub fn unseal(contents: &str, source: KeySource) -> Result<String, SealError> {
+        let hex = contents
+            .trim_end()
+            .strip_prefix(PREFIX)
+            .ok_or(SealError::Malformed)?;
+        let bytes = decode_hex(hex).ok_or(SealError::Malformed)?;
+        if bytes.len() < SALT_LEN + NONCE_LEN {
+            return Err(SealError::Malformed);
+        }
+        let (salt, rest) = bytes.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plaintext = cipher(&key_material(source, false)?, salt)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| SealError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| SealError::Malformed)
+    }
+
+    /// Get the passphrase, or the key from the keyring, creating a new key if
+    /// requested.
+    fn key_material(source: KeySource, create: bool) -> Result<String, SealError> {
+        match source {
+            KeySource::Passphrase => PASSPHRASE
+                .get_or_try_init(|| match env::var(PASSPHRASE_VAR) {
+                    Ok(passphrase) => Ok(passphrase),
+                    Err(_) => rpassword::prompt_password("Passphrase for the secret file: "),
+                })
+                .cloned()
+                .map_err(SealError::Passphrase),
+            KeySource::Keyring => {
+                let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)?;
+                match entry.get_password() {
+                    Ok(key) => Ok(key),
+                    Err(keyring::Error::NoEntry) if create => {
+                        let key = format!("{:032x}", random::<u128>());
+                        entry.set_password(&key)?;
+                        log::warn!("Stored new key for the secret file in the keyring");
+                        Ok(key)
+                    }
+                    Err(keyring::Error::NoEntry) => Err(SealError::MissingKey),
+                    Err(err) => Err(err.into()),
+                }
+            }
+        }
+    }
+
+    fn cipher(material: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, SealError> {
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(material.as_bytes(), salt, &mut key)
+            .map_err(SealError::Kdf)?;
+        Ok(ChaCha20Poly1305::new(&key))
+    }
+
+    fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if hex.len() % 2 != 0 {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "sealed"))]
+mod imp {
+    use super::{KeySource, SealError};
+
+    pub fn seal(_secret: &str, _source: KeySource) -> Result<String, SealError> {
+        Err(SealError::Unsupported)
+    }
+
+    pub fn unseal(_contents: &str, _source: KeySource) -> Result<String, SealError> {
+        Err(SealError::Unsupported)
+    }
+}
+
+#[cfg(all(test, feature = "sealed"))]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        env::set_var(PASSPHRASE_VAR, "correct horse battery staple");
+        let sealed = seal("0123456789abcdef", KeySource::Passphrase).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("0123456789abcdef"));
+        assert_eq!(
+            unseal(&sealed, KeySource::Passphrase).unwrap(),
+            "0123456789abcdef"
+        );
+
+        let mut tampered = sealed.clone();
+        let last = if tampered.ends_with('0') { "1" } else { "0" };
+        tampered.replace_range(tampered.len() - 1.., last);
+        assert!(matches!(
+            unseal(&tampered, KeySource::Passphrase),
+            Err(SealError::Decrypt)
+        ));
+        assert!(matches!(
+            unseal(&sealed[..sealed.len() - 1], KeySource::Passphrase),
+            Err(SealError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,2129 @@
+use std::{
+    cmp::min,
+    env,
+    error::Error,
+    ffi::OsString,
+    fs, io,
+    iter::{self, zip},
+    net::{SocketAddr, TcpListener},
+    ops::Not,
+    path::{Path, PathBuf},
+    process,
+    sync::{atomic::AtomicU64, Arc},
+    thread,
+    time::Duration,
+};
+
+use axum::{
+    extract::connect_info::IntoMakeServiceWithConnectInfo, response::Redirect, routing::get, Router,
+};
+use clap::{ArgEnum, Parser, Subcommand};
+use futures_util::future;
+use hyper::server::conn::AddrIncoming;
+use listenfd::ListenFd;
+use serde::Serialize;
+use serde_with::{serde_as, CommaSeparator, DisplayFromStr, StringWithSeparator};
+use thiserror::Error;
+use tokio::{sync::mpsc, time::sleep};
+use tokio_rustls::TlsAcceptor;
+
+#[cfg(feature = "dbus")]
+use crate::dbus;
+#[cfg(feature = "quic")]
+use crate::quic;
+use crate::{
+    admin::{self, Guests},
+    api, archive,
+    auth::{Authenticator, Secrets},
+    baseline::{Baseline, NpsMonitor},
+    bench,
+    budget::CpuBudget,
+    config::{Config, OptionValue, Preset},
+    crash,
+    delay::SimulatedLatency,
+    doctor::{self, Outcome},
+    engine::{Engine, EngineInfo, EngineParameters, Evaluation, InfoFilter, Perspective},
+    fleet,
+    gpu::{self, GpuMonitor},
+    history::{self, ExportFormat, History},
+    idle::{self, IdleAnalysis, Queue},
+    inhibit,
+    invite::Invite,
+    ledger::ThreadLedger,
+    lichess::{self, Lichess},
+    listen, load,
+    memory::{available_memory, HashStrategy},
+    middleware::{Chain, Middleware},
+    network,
+    notifications::{Notifications, Notifier},
+    pool::{self, Pool},
+    privacy::Privacy,
+    private_file,
+    proxy::{self, TrustedProxy},
+    proxy_config::{ProxyConfig, ProxyServer},
+    relay, resume,
+    sealed::{self, KeySource},
+    share, supervisor, tls, top_moves,
+    transport::{EngineTransport, Process},
+    tunnel, upnp,
+    verify::Verifier,
+    watch,
+    ws::{self, Frontend, Secret, SharedEngine},
+};
+
+/// Name of the data directory for --portable.
+const PORTABLE_DIR: &str = "remote-uci-data";
+
+/// External UCI engine provider for lichess.org.
+#[derive(Debug, Parser)]
+#[clap(version, subcommand_negates_reqs = true)]
+pub struct Opts {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    engine: EngineOpts,
+    /// Bind server on this socket address, e.g. [::]:9670 for IPv6 and
+    /// IPv4. Can be given multiple times. Defaults to localhost:9670, or
+    /// [::]:9670 with --upnp.
+    #[clap(long)]
+    bind: Vec<SocketAddr>,
+    /// Serve the API on this separate socket address (e.g. 127.0.0.1:9671)
+    /// instead of the public one.
+    #[clap(long)]
+    admin_bind: Option<SocketAddr>,
+    /// Take client addresses from X-Forwarded-For if the connection comes
+    /// from this network (e.g. 127.0.0.1 or 10.0.0.0/8) of reverse proxies.
+    /// Can be given multiple times.
+    #[clap(long)]
+    trusted_proxy: Vec<TrustedProxy>,
+    /// The publically accessible address used when registering with lichess
+    #[clap(long)]
+    publish_addr: Option<String>,
+    /// Pass this flag if the public_addr endpoint uses TLS
+    #[clap(long)]
+    publish_addr_tls: bool,
+    /// After starting, connect to the published address like lichess would,
+    /// and exit with code 7 if the WebSocket handshake fails, e.g. because
+    /// the port is not forwarded or a reverse proxy is misconfigured.
+    #[clap(long)]
+    check_publish_addr: bool,
+    /// Terminate TLS on this socket address, using --tls-cert and
+    /// --tls-key. The plain HTTP server then only redirects to it.
+    #[clap(long, requires_all = &["tls-cert", "tls-key"])]
+    tls_bind: Option<SocketAddr>,
+    /// Certificate chain (PEM) for --tls-bind.
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+    /// Private key (PEM) for --tls-bind.
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
+    /// Request a port mapping from the router via UPnP, and publish the
+    /// external address unless --publish-addr is given. Binds on all
+    /// interfaces by default.
+    #[clap(long)]
+    upnp: bool,
+    /// Serve sessions through this relay (e.g. wss://relay.example.org, see
+    /// the relay subcommand), by keeping an outbound connection open. For
+    /// hosts that can not accept incoming connections at all.
+    #[clap(long, requires = "relay-id")]
+    relay: Option<String>,
+    /// Name under which the engine is reachable on the relay. The relay
+    /// reserves it for a token derived from the secret, so keep the secret
+    /// with --secret-file to reclaim it after a restart.
+    #[clap(long)]
+    relay_id: Option<String>,
+    /// Also accept sessions over QUIC on this socket address
+    /// (experimental).
+    #[cfg(feature = "quic")]
+    #[clap(long)]
+    quic_bind: Option<SocketAddr>,
+    /// Offer status and controls as org.lichess.RemoteUci on the D-Bus
+    /// session bus, for desktop widgets and scripts.
+    #[cfg(feature = "dbus")]
+    #[clap(long)]
+    dbus: bool,
+    /// Overwrite engine name.
+    #[clap(long)]
+    name: Option<String>,
+    /// Limit number of threads.
+    #[clap(long)]
+    max_threads: Option<u32>,
+    /// Limit size of hash table (MiB).
+    #[clap(long)]
+    max_hash: Option<u32>,
+    /// Derive the limit for the size of the hash table from available
+    /// memory: half (rounded to a power of two), percent:N, or fixed:N (MiB).
+    /// --max-hash applies in addition.
+    #[clap(long, default_value = "half")]
+    hash_strategy: HashStrategy,
+    /// Limit number of principal variations.
+    #[clap(long)]
+    max_multipv: Option<u32>,
+    /// Reject all setoption commands from clients. Options are then only set
+    /// by the operator, with options and presets in the --config file.
+    #[clap(long)]
+    no_client_options: bool,
+    /// Which info lines from the engine to skip as noise. Clients can
+    /// override this per session.
+    #[clap(long, arg_enum, default_value = "aggressive")]
+    info_filter: InfoFilter,
+    /// Point of view of the scores reported by the engine. UCI specifies
+    /// the side to move, but some engines report from the point of view of
+    /// White. Clients can ask for either with the perspective parameter.
+    #[clap(long, arg_enum, default_value = "side-to-move")]
+    score_perspective: Perspective,
+    /// Close sessions when the engine sends malformed output, instead of
+    /// logging and skipping the offending lines.
+    #[clap(long)]
+    strict_uci: bool,
+    /// Provide file with secret token to use instead of a random one.
+    /// Frontends other than the first use a separate secret, stored next to
+    /// it with the host name of the frontend appended.
+    #[clap(long)]
+    secret_file: Option<PathBuf>,
+    /// Encrypt secret files at rest, with a key kept in the keyring of the
+    /// operating system, or derived from a passphrase that is prompted at
+    /// startup (or read from REMOTE_UCI_PASSPHRASE). Existing secret files
+    /// are encrypted when loaded. Requires building with the sealed
+    /// feature.
+    #[clap(long, arg_enum)]
+    encrypt_secret: Option<KeySource>,
+    /// Keep the secret file, config file, guests, log, crash reports and
+    /// downloads in a directory next to the executable, e.g. to run from a
+    /// USB stick.
+    /// Relative engine and network paths are resolved against that
+    /// directory.
+    #[clap(long)]
+    portable: bool,
+    /// Keep downloaded and unpacked engines and networks in this directory,
+    /// instead of the cache directory of the user.
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// The data directory of --portable, once created.
+    #[clap(skip)]
+    data_dir: Option<PathBuf>,
+    /// Advertise the engine to this lichess-compatible frontend. Can be
+    /// given multiple times.
+    #[clap(long = "frontend", default_value = "https://lichess.org")]
+    frontends: Vec<String>,
+    /// Load additional settings, like option presets, from this TOML file.
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Use the flags and engine options of this [profile.<name>] in the
+    /// --config file. Flags on the command line take precedence.
+    #[clap(long)]
+    profile: Option<String>,
+    /// Enable the admin API (/api/admin), for requests authenticated with
+    /// the secret token in this file.
+    #[clap(long)]
+    admin_secret_file: Option<PathBuf>,
+    /// Keep guests added through the admin API in this TOML file.
+    /// Otherwise they are forgotten on restart.
+    #[clap(long)]
+    guests_file: Option<PathBuf>,
+    /// Register the engine with this lichess API token (scope
+    /// engine:write), and keep the registration up to date. Can be given
+    /// once per --frontend, in the same order.
+    #[clap(long)]
+    lichess_token: Vec<String>,
+    /// Open the registration URL in the default browser. Requires building
+    /// with the desktop feature.
+    #[clap(long)]
+    open: bool,
+    /// Copy the registration URL to the clipboard. Requires building with
+    /// the desktop feature.
+    #[clap(long)]
+    copy_url: bool,
+    /// Promise that the selected engine is a recent official Stockfish
+    /// release.
+    #[clap(long, hide = true)]
+    promise_official_stockfish: bool,
+    /// Restart the engine whenever its executable is updated on disk, as soon
+    /// as it is idle.
+    #[clap(long)]
+    watch_engine: bool,
+    /// Neural network file for the engine to load, instead of its default.
+    /// Passed as EvalFile to Stockfish, or as WeightsFile to Lc0.
+    #[clap(long)]
+    eval_file: Option<PathBuf>,
+    /// Download the default network of the engine if it is not in the
+    /// working directory (or the --portable data directory), for Stockfish
+    /// builds that do not embed it.
+    /// Downloads are kept in the cache directory.
+    #[clap(long, conflicts_with = "eval-file")]
+    download_net: bool,
+    /// Run the engine on the GPU with this index, for engines like Lc0 that
+    /// select it with BackendOptions. Utilization and memory of the GPU are
+    /// then shown in /api/status, if built with the gpu feature. GPUs are
+    /// numbered in PCI bus order, like in nvidia-smi.
+    #[clap(long)]
+    gpu: Option<u32>,
+    /// Keep a second, warmed up engine process idle, to take over right
+    /// away if the engine crashes or hangs, instead of starting a new one.
+    /// Takes twice the memory for the hash table.
+    #[clap(long)]
+    hot_standby: bool,
+    /// If a client drops during an infinite search, keep searching for up
+    /// to this many minutes, and replay the results when it reconnects
+    /// with the same session, e.g. on a flaky mobile connection.
+    #[clap(long)]
+    continue_on_disconnect: Option<u64>,
+    /// After each search, keep the engine searching the position after the
+    /// best move, and show the results right away if the client continues
+    /// with that move, e.g. when stepping forward through a line. Pondering
+    /// stops after at most 10 seconds.
+    #[clap(long)]
+    ponder_ahead: bool,
+    /// Reduce Threads for new searches while other programs keep the host
+    /// busy, and restore them when it is idle.
+    #[clap(long)]
+    autoscale_threads: bool,
+    /// Coordinate Threads with other instances on this host, so that their
+    /// searches together use at most --max-threads, rather than each
+    /// assuming it owns the machine. Searches are rejected while the others
+    /// use all of them.
+    #[clap(long)]
+    share_threads: bool,
+    /// Warn if searches are consistently much slower than the nodes per
+    /// second in this file, e.g. due to thermal throttling. The bench
+    /// subcommand measures them and saves them to this file.
+    #[clap(long)]
+    nps_baseline: Option<PathBuf>,
+    /// Engine hours per day that each frontend or guest may use, counted as
+    /// search time multiplied by threads. The budget refills continuously.
+    /// Clients that used it up can keep searching with --cpu-budget-threads.
+    #[clap(long, parse(try_from_str = parse_hours))]
+    cpu_budget: Option<f64>,
+    /// Threads for searches of clients that used up their CPU budget.
+    #[clap(long, default_value = "1")]
+    cpu_budget_threads: u32,
+    /// Do not prevent the system from sleeping while the engine is
+    /// searching.
+    #[clap(long)]
+    allow_sleep: bool,
+    /// Socket URL of another remote-uci instance, including its secret, that
+    /// takes over new sessions while the local engine is busy. Can be
+    /// repeated to pool several machines.
+    #[clap(long = "worker")]
+    workers: Vec<String>,
+    /// Divide the root moves of each search between all workers, and merge
+    /// their results, instead of forwarding whole sessions.
+    #[clap(long, requires = "workers")]
+    split_root_moves: bool,
+    /// Re-search final positions with this secondary engine, and log large
+    /// disagreements with the primary engine.
+    #[clap(long)]
+    verify_engine: Option<PathBuf>,
+    /// Search depth of the secondary engine.
+    #[clap(long, default_value = "12")]
+    verify_depth: u32,
+    /// Log disagreements of more than this many centipawns.
+    #[clap(long, default_value = "150")]
+    verify_threshold: u32,
+    /// Remember analysed positions with their final evaluation in this
+    /// file, and show them at /history and /api/history. Export them with
+    /// /api/history/export?format=epd (or pgn). Like the logs, these are
+    /// only available on the local machine, or with the admin secret.
+    #[clap(long)]
+    history_file: Option<PathBuf>,
+    /// Leave positions and moves out of logs and the history, keeping only
+    /// aggregate statistics, e.g. when analysing private preparation.
+    #[clap(long)]
+    no_log_positions: bool,
+    /// While no client is connected, analyse the positions in this file, and
+    /// remove them once done. One FEN, EPD or UCI position command per
+    /// line. Results are kept in --history-file. Scripts can add positions
+    /// with POST /api/queue, authenticated like the admin API.
+    #[clap(long, requires = "history-file")]
+    idle_queue: Option<PathBuf>,
+    /// Seconds per position of idle analysis.
+    #[clap(long, default_value = "60")]
+    idle_movetime: u64,
+    /// Number of principal variations for idle analysis.
+    #[clap(long, default_value = "1")]
+    idle_multipv: u32,
+    /// Upload crash reports of previous runs to this URL. Reports include
+    /// recent log lines, which may contain analysed positions.
+    #[clap(long)]
+    upload_crash_reports: Option<String>,
+    /// Print the registration URLs as text, or a single JSON object with
+    /// the URLs, bound sockets (public, admin, TLS and QUIC), engine
+    /// information and process id, for wrapper scripts.
+    #[clap(long, arg_enum, default_value = "text")]
+    output: OutputFormat,
+    /// Delay messages to and from clients by this many milliseconds,
+    /// optionally varying by up to the given jitter, e.g. 150:50. For
+    /// trying clients against a slow connection.
+    #[clap(long)]
+    simulate_latency: Option<SimulatedLatency>,
+    /// Load the configuration and secrets, bind sockets and start the engine,
+    /// then exit, without creating files, mapping ports or registering.
+    /// Exit codes: 0 if everything is fine, 3 for invalid configuration, 4
+    /// for secret files, 5 for binding sockets, 6 for starting the engine,
+    /// 1 for anything else.
+    #[clap(long)]
+    check: bool,
+}
+
+/// Why the provider could not start. Each has its own exit code, so that
+/// scripts and service managers can tell them apart.
+#[derive(Error, Debug)]
+pub enum StartupError {
+    #[error("invalid configuration: {0}")]
+    Config(Box<dyn Error>),
+    #[error("secret file {0:?}: {1}")]
+    Secret(PathBuf, String),
+    #[error("could not bind: {0}")]
+    Bind(io::Error),
+    #[error("could not start engine: {0}")]
+    Engine(io::Error),
+    #[error("published address is not reachable: {0}")]
+    Publish(String),
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            StartupError::Config(_) => 3,
+            StartupError::Secret(..) => 4,
+            StartupError::Bind(_) => 5,
+            StartupError::Engine(_) => 6,
+            StartupError::Publish(_) => 7,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ArgEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Register the engine with a lichess account on the first frontend,
+    /// print the id of the registration and exit.
+    Register {
+        /// Lichess API token with scope engine:write.
+        #[clap(long)]
+        token: String,
+    },
+    /// Delete the registration from a lichess account on the first frontend,
+    /// print its id and exit.
+    Unregister {
+        /// Lichess API token with scope engine:write.
+        #[clap(long)]
+        token: String,
+        /// Id of the registration. Defaults to the registration of the
+        /// published address.
+        #[clap(long)]
+        id: Option<String>,
+    },
+    /// Print a registration link for the first frontend, that stops working
+    /// after the given number of hours, and exit. Requires --secret-file.
+    Invite {
+        /// Number of hours until the link expires.
+        #[clap(long, default_value = "24")]
+        hours: u64,
+        /// Apply the limits of this guest from the config file.
+        #[clap(long)]
+        guest: Option<String>,
+    },
+    /// Check the engine, secret file, clock and network setup, print
+    /// suggested fixes for problems, and exit.
+    Doctor,
+    /// Search a fixed suite of positions with increasing numbers of threads,
+    /// print nodes per second and depth, and suggest limits.
+    Bench {
+        /// Milliseconds per position and number of threads.
+        #[clap(long, default_value = "3000")]
+        movetime: u64,
+    },
+    /// Print the status of several providers, e.g. http://engine-box:9670,
+    /// or keep polling them and serve a consolidated status with --bind.
+    /// Requires the status API of each provider to be reachable.
+    Fleet {
+        /// Base URLs of the providers.
+        #[clap(required = true)]
+        providers: Vec<String>,
+        /// Serve the consolidated status as JSON on this socket address.
+        #[clap(long)]
+        bind: Option<SocketAddr>,
+        /// Seconds between polls, with --bind.
+        #[clap(long, default_value = "30", parse(try_from_str = parse_positive))]
+        interval: u64,
+    },
+    /// Print the positions in --history-file with their evaluations, e.g.
+    /// for import into a lichess study, and exit.
+    History {
+        #[clap(long, arg_enum, default_value = "pgn")]
+        format: ExportFormat,
+    },
+    /// Run a relay server, that forwards connections to providers using
+    /// --relay.
+    Relay {
+        /// Bind relay on this socket address.
+        #[clap(long, default_value = "0.0.0.0:9671")]
+        bind: SocketAddr,
+        /// Remember which provider registered each id in this file, so
+        /// that others can not take over the id after a restart.
+        #[clap(long)]
+        registry: Option<PathBuf>,
+    },
+    /// Print a reverse proxy configuration for the provider, based on
+    /// --bind and --publish-addr, and the options to run the provider
+    /// behind it, and exit.
+    ProxyConfig {
+        #[clap(arg_enum)]
+        server: ProxyServer,
+    },
+}
+
+fn parse_hours(s: &str) -> Result<f64, String> {
+    let hours: f64 = s.parse().map_err(|err| format!("{err}"))?;
+    if hours.is_finite() && (0.0..=24.0).contains(&hours) {
+        Ok(hours)
+    } else {
+        Err("expected hours between 0 and 24".to_owned())
+    }
+}
+
+fn parse_positive(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("must be positive".to_owned()),
+        Ok(n) => Ok(n),
+        Err(err) => Err(format!("{err}")),
+    }
+}
+
+impl Opts {
+    pub fn take_command(&mut self) -> Option<Command> {
+        self.command.take()
+    }
+
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+
+    pub fn check(&self) -> bool {
+        self.check
+    }
+
+    /// Complete the options parsed from the command line `args` with the
+    /// files of --portable and the flags of the selected --profile. All
+    /// frontends need to call this, or profiles would be ignored.
+    pub fn complete(&mut self, args: &[OsString]) -> Result<(), StartupError> {
+        // The profile may come from the config file in the data directory.
+        // Parsing again with the flags of the profile starts over, including
+        // paths relative to the data directory.
+        self.make_portable()?;
+        self.apply_profile(args)?;
+        self.make_portable()
+    }
+
+    /// The data directory of --portable, with the log and crash reports.
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_deref()
+    }
+
+    /// With --portable, create the data directory next to the executable,
+    /// and use files in it unless others are given. --check only resolves
+    /// the paths, without creating anything.
+    fn make_portable(&mut self) -> Result<(), StartupError> {
+        if !self.portable {
+            return Ok(());
+        }
+        let exe = env::current_exe().map_err(|err| StartupError::Config(err.into()))?;
+        let dir = exe
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join(PORTABLE_DIR);
+        if !self.check {
+            fs::create_dir_all(&dir).map_err(|err| {
+                StartupError::Config(format!("could not create {dir:?}: {err}").into())
+            })?;
+        }
+        self.secret_file
+            .get_or_insert_with(|| dir.join("secret.txt"));
+        self.guests_file
+            .get_or_insert_with(|| dir.join("guests.toml"));
+        self.cache_dir.get_or_insert_with(|| dir.join("cache"));
+        if self.config.is_none() {
+            let config = dir.join("config.toml");
+            if config.exists() {
+                self.config = Some(config);
+            }
+        }
+        self.engine.resolve_relative(&dir);
+        if let Some(ref mut eval_file) = self.eval_file {
+            resolve_relative(eval_file, &dir);
+        }
+        self.data_dir = Some(dir);
+        Ok(())
+    }
+
+    /// Parse the command line `args` again, with the flags of the selected
+    /// --profile added, unless the command line has them already.
+    fn apply_profile(&mut self, args: &[OsString]) -> Result<(), StartupError> {
+        let (name, path) = match (&self.profile, &self.config) {
+            (Some(name), Some(path)) => (name.clone(), path.clone()),
+            (Some(_), None) => {
+                return Err(StartupError::Config("--profile requires --config".into()))
+            }
+            (None, _) => return Ok(()),
+        };
+        let is_given = |flag: &str| {
+            args.iter().any(|arg| {
+                arg.to_str().map_or(false, |arg| {
+                    arg == flag || arg.strip_prefix(flag).map_or(false, |v| v.starts_with('='))
+                })
+            })
+        };
+        let flags = load_config(Some(&path), None)?
+            .profile(&name)
+            .and_then(|profile| profile.args(is_given))
+            .map_err(|err| StartupError::Config(err.into()))?;
+        let args = args
+            .iter()
+            .take(1)
+            .cloned()
+            .chain(flags.into_iter().map(OsString::from))
+            .chain(args.iter().skip(1).cloned());
+        *self = Opts::try_parse_from(args).map_err(|err| {
+            let err = err.to_string();
+            let reason = err.lines().next().unwrap_or_default();
+            StartupError::Config(
+                format!("profile {name}: {}", reason.trim_start_matches("error: ")).into(),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Path to the executable of the selected engine, downloaded and
+    /// unpacked if necessary.
+    async fn engine_path(&self) -> Result<PathBuf, Box<dyn Error>> {
+        let path = self
+            .engine
+            .clone()
+            .best()
+            .ok_or_else(|| StartupError::Config("missing --engine".into()))?;
+        Ok(archive::prepare(
+            &path,
+            self.engine.engine_sha256.as_deref(),
+            &self.cache_dir(),
+        )
+        .await
+        .map_err(|err| {
+            log::error!("Could not prepare engine {path:?}: {err}");
+            StartupError::Engine(err)
+        })?)
+    }
+
+    fn cache_dir(&self) -> PathBuf {
+        self.cache_dir.clone().unwrap_or_else(archive::cache_dir)
+    }
+
+    /// Where relative paths are looked up: the data directory with
+    /// --portable, or else the working directory.
+    fn base_dir(&self) -> PathBuf {
+        match self.data_dir {
+            Some(ref dir) => dir.clone(),
+            None => env::current_dir().unwrap_or_default(),
+        }
+    }
+
+    /// Where crash reports are written and uploaded from.
+    pub fn report_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(env::temp_dir)
+    }
+
+    fn privacy(&self) -> Privacy {
+        Privacy {
+            redact_positions: self.no_log_positions,
+        }
+    }
+
+    async fn engine_transport(&self) -> Result<Arc<dyn EngineTransport>, Box<dyn Error>> {
+        let mut process = Process::new(self.engine_path().await?);
+        if self.gpu.is_some() {
+            // CUDA numbers devices fastest first by default, but NVML and
+            // the monitor go by PCI bus.
+            process = process.env("CUDA_DEVICE_ORDER", "PCI_BUS_ID");
+        }
+        Ok(Arc::new(process))
+    }
+
+    fn publish_url(&self, local_addr: Option<SocketAddr>) -> String {
+        if let (Some(relay), Some(id)) = (&self.relay, &self.relay_id) {
+            return tunnel::public_url(relay, id);
+        }
+        format!(
+            "{}://{}/socket",
+            get_external_protocol(self.publish_addr_tls || self.tls_bind.is_some()),
+            self.publish_addr.as_deref().map_or_else(
+                || {
+                    local_addr
+                        .or_else(|| self.bind.first().copied())
+                        .map_or_else(
+                            || format!("localhost:{}", listen::DEFAULT_PORT),
+                            listen::url_authority,
+                        )
+                },
+                listen::publish_authority,
+            )
+        )
+    }
+
+    fn frontends(&self) -> Result<Vec<Frontend>, StartupError> {
+        self.frontends
+            .iter()
+            .enumerate()
+            .map(|(i, url)| {
+                let url = url.trim_end_matches('/').to_owned();
+                let secret_file = self.secret_file.as_ref().map(|path| {
+                    if i == 0 {
+                        path.clone()
+                    } else {
+                        let host = url
+                            .split("://")
+                            .last()
+                            .unwrap_or(&url)
+                            .replace(['/', ':'], "_");
+                        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+                        file_name.push(format!(".{host}"));
+                        path.with_file_name(file_name)
+                    }
+                });
+                Ok(Frontend {
+                    secret: load_secret(secret_file.as_deref(), self.encrypt_secret, self.check)?,
+                    url,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Parser)]
+pub struct EngineOpts {
+    /// UCI engine executable to use if the CPU supports the x86-64 feature
+    /// VNNI512.
+    #[clap(long, display_order = 0)]
+    engine_x86_64_vnni512: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x64-64 feature AVX512.
+    #[clap(long, display_order = 1)]
+    engine_x86_64_avx512: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x86-64 feature BMI2 with fast PEXT/PDEP.
+    #[clap(long, display_order = 2)]
+    engine_x86_64_bmi2: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x86-64 feature AVX2.
+    #[clap(long, display_order = 3)]
+    engine_x86_64_avx2: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x86-64 features SSE41 and POPCNT.
+    #[clap(long, display_order = 4)]
+    engine_x86_64_sse41_popcnt: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x86-64 feature SSSE3.
+    #[clap(long, display_order = 5)]
+    engine_x86_64_ssse3: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use if the CPU supports the
+    /// x86-64 features SSE3 and POPCNT.
+    #[clap(long, display_order = 6)]
+    engine_x86_64_sse3_popcnt: Option<PathBuf>,
+    /// UCI engine executable to use if the CPU supports the ARMv8 feature
+    /// DotProd, e.g. on recent Snapdragon laptops.
+    #[clap(long, display_order = 7)]
+    engine_armv8_dotprod: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use on ARMv8 (with NEON).
+    #[clap(long, display_order = 8)]
+    engine_armv8: Option<PathBuf>,
+    /// Or else, the UCI engine executable to use.
+    ///
+    /// Each engine can also be a zip or tar archive that contains the
+    /// executable, like official Stockfish releases, or a URL to download
+    /// it from. Archives and downloads are kept in the cache directory.
+    #[clap(long, display_order = 9, required_unless_present = "profile")]
+    engine: Option<PathBuf>,
+    /// SHA-256 checksum of the selected engine executable or archive, to
+    /// verify before using it. Required to download it over plain http.
+    #[clap(long, display_order = 10)]
+    engine_sha256: Option<String>,
+}
+
+impl EngineOpts {
+    fn resolve_relative(&mut self, dir: &Path) {
+        for path in [
+            &mut self.engine_x86_64_vnni512,
+            &mut self.engine_x86_64_avx512,
+            &mut self.engine_x86_64_bmi2,
+            &mut self.engine_x86_64_avx2,
+            &mut self.engine_x86_64_sse41_popcnt,
+            &mut self.engine_x86_64_ssse3,
+            &mut self.engine_x86_64_sse3_popcnt,
+            &mut self.engine_armv8_dotprod,
+            &mut self.engine_armv8,
+            &mut self.engine,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !archive::is_url(path) {
+                resolve_relative(path, dir);
+            }
+        }
+    }
+
+    /// Name of the option that selected the engine executable for this CPU.
+    fn selected_flag(&self) -> Option<&'static str> {
+        let best = self.clone().best()?;
+        [
+            ("--engine-x86-64-vnni512", &self.engine_x86_64_vnni512),
+            ("--engine-x86-64-avx512", &self.engine_x86_64_avx512),
+            ("--engine-x86-64-bmi2", &self.engine_x86_64_bmi2),
+            ("--engine-x86-64-avx2", &self.engine_x86_64_avx2),
+            (
+                "--engine-x86-64-sse41-popcnt",
+                &self.engine_x86_64_sse41_popcnt,
+            ),
+            ("--engine-x86-64-ssse3", &self.engine_x86_64_ssse3),
+            (
+                "--engine-x86-64-sse3-popcnt",
+                &self.engine_x86_64_sse3_popcnt,
+            ),
+            ("--engine-armv8-dotprod", &self.engine_armv8_dotprod),
+            ("--engine-armv8", &self.engine_armv8),
+            ("--engine", &self.engine),
+        ]
+        .into_iter()
+        .find(|(_, path)| path.as_ref() == Some(&best))
+        .map(|(flag, _)| flag)
+    }
+
+    fn has_variants(&self) -> bool {
+        self.engine_x86_64_vnni512.is_some()
+            || self.engine_x86_64_avx512.is_some()
+            || self.engine_x86_64_bmi2.is_some()
+            || self.engine_x86_64_avx2.is_some()
+            || self.engine_x86_64_sse41_popcnt.is_some()
+            || self.engine_x86_64_ssse3.is_some()
+            || self.engine_x86_64_sse3_popcnt.is_some()
+            || self.engine_armv8_dotprod.is_some()
+            || self.engine_armv8.is_some()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn best(self) -> Option<PathBuf> {
+        self.engine_x86_64_vnni512
+            .filter(|_| {
+                is_x86_feature_detected!("avx512dq")
+                    && is_x86_feature_detected!("avx512vl")
+                    && is_x86_feature_detected!("avx512vnni")
+            })
+            .or(self.engine_x86_64_avx512)
+            .filter(|_| is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw"))
+            .or(self.engine_x86_64_bmi2)
+            .filter(|_| {
+                is_x86_feature_detected!("bmi2") && {
+                    // AMD was using slow software emulation for PEXT for a
+                    // long time. The Zen 3 family (0x19) is the first to
+                    // implement it in hardware.
+                    let cpuid = raw_cpuid::CpuId::new();
+                    cpuid
+                        .get_vendor_info()
+                        .map_or(true, |v| v.as_str() != "AuthenticAMD")
+                        || cpuid
+                            .get_feature_info()
+                            .map_or(false, |f| f.family_id() >= 0x19)
+                }
+            })
+            .or(self.engine_x86_64_avx2)
+            .filter(|_| is_x86_feature_detected!("avx2"))
+            .or(self.engine_x86_64_sse41_popcnt)
+            .filter(|_| is_x86_feature_detected!("sse4.1"))
+            .or(self.engine_x86_64_ssse3)
+            .filter(|_| is_x86_feature_detected!("ssse3"))
+            .or(self.engine_x86_64_sse3_popcnt)
+            .filter(|_| is_x86_feature_detected!("sse3") && is_x86_feature_detected!("popcnt"))
+            .or(self.engine)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn best(self) -> Option<PathBuf> {
+        self.engine_armv8_dotprod
+            .filter(|_| std::arch::is_aarch64_feature_detected!("dotprod"))
+            .or(self.engine_armv8)
+            .filter(|_| std::arch::is_aarch64_feature_detected!("neon"))
+            .or(self.engine)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn best(self) -> Option<PathBuf> {
+        self.engine
+    }
+}
+
+/// Make a relative `path` relative to `dir` instead.
+fn resolve_relative(path: &mut PathBuf, dir: &Path) {
+    if path.is_relative() {
+        *path = dir.join(&*path);
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalWorkerOpts {
+    #[serde(skip)]
+    frontend: String,
+    pub(crate) url: String,
+    pub(crate) secret: Secret,
+    pub(crate) name: String,
+    pub(crate) max_threads: i64,
+    pub(crate) max_hash: i64,
+    max_multi_pv: i64,
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, String>")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) variants: Vec<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(skip_serializing_if = "Not::not")]
+    official_stockfish: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    engine_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    engine_author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    evaluation: Option<Evaluation>,
+    /// Preset that all sessions of this registration use.
+    #[serde(skip)]
+    preset: Option<Preset>,
+}
+
+impl ExternalWorkerOpts {
+    /// Refresh all fields that are derived from the engine.
+    pub(crate) fn update(&mut self, info: &EngineInfo, name: Option<&str>) {
+        self.name = name
+            .or(info.name.as_deref())
+            .unwrap_or("remote-uci")
+            .to_owned();
+        self.max_threads = info.max_threads();
+        self.max_hash = info.max_hash();
+        self.max_multi_pv = info.max_multipv();
+        self.variants = info.variants().to_vec();
+        self.engine_name = info.name.clone();
+        self.engine_author = info.author.clone();
+        self.evaluation = info.evaluation;
+        if let Some(ref preset) = self.preset {
+            if let Some(ref name) = preset.name {
+                self.name = name.clone();
+            }
+            if let Some(max_threads) = preset.max_threads {
+                self.max_threads = self.max_threads.min(i64::from(max_threads));
+            }
+            if let Some(max_hash) = preset.max_hash {
+                self.max_hash = self.max_hash.min(i64::from(max_hash));
+            }
+        }
+    }
+
+    /// Registration of a virtual endpoint, that selects the preset when
+    /// connecting.
+    fn with_preset(
+        &self,
+        key: &str,
+        preset: &Preset,
+        info: &EngineInfo,
+        name: Option<&str>,
+    ) -> ExternalWorkerOpts {
+        let mut spec = self.clone();
+        spec.url = format!(
+            "{}?{}",
+            self.url,
+            serde_urlencoded::to_string([("preset", key)]).expect("encode preset")
+        );
+        spec.preset = Some(preset.clone());
+        spec.update(info, name);
+        spec
+    }
+
+    pub fn registration_url(&self) -> String {
+        format!(
+            "{}/analysis/external?{}",
+            self.frontend,
+            serde_urlencoded::to_string(&self).expect("serialize spec"),
+        )
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Startup<'a> {
+    pid: u32,
+    version: &'static str,
+    bind: SocketAddr,
+    sockets: &'a [BoundSocket],
+    engine: StartupEngine<'a>,
+    registrations: Vec<StartupRegistration<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StartupEngine<'a> {
+    name: Option<&'a str>,
+    author: Option<&'a str>,
+    max_threads: i64,
+    max_hash: i64,
+    max_multi_pv: i64,
+    variants: &'a [String],
+}
+
+#[derive(Serialize)]
+struct StartupRegistration<'a> {
+    frontend: &'a str,
+    url: String,
+}
+
+/// Startup information for --output json.
+pub fn startup_json(
+    specs: &[ExternalWorkerOpts],
+    bind: SocketAddr,
+    sockets: &[BoundSocket],
+) -> String {
+    let spec = &specs[0];
+    serde_json::to_string(&Startup {
+        pid: process::id(),
+        version: env!("CARGO_PKG_VERSION"),
+        bind,
+        sockets,
+        engine: StartupEngine {
+            name: spec.engine_name.as_deref(),
+            author: spec.engine_author.as_deref(),
+            max_threads: spec.max_threads,
+            max_hash: spec.max_hash,
+            max_multi_pv: spec.max_multi_pv,
+            variants: &spec.variants,
+        },
+        registrations: specs
+            .iter()
+            .map(|spec| StartupRegistration {
+                frontend: &spec.frontend,
+                url: spec.registration_url(),
+            })
+            .collect(),
+    })
+    .expect("serialize startup")
+}
+
+fn get_external_protocol(tls: bool) -> String {
+    match tls {
+        true => "wss".to_string(),
+        false => "ws".to_string(),
+    }
+}
+
+fn load_config(path: Option<&Path>, profile: Option<&str>) -> Result<Config, StartupError> {
+    let mut config = match path {
+        Some(path) => Config::load(path).map_err(|err| {
+            log::error!("Could not load config {path:?}: {err}");
+            StartupError::Config(err.into())
+        })?,
+        None => Config::default(),
+    };
+    if let Some(name) = profile {
+        log::info!("Using profile {name}");
+        config
+            .select_profile(name)
+            .map_err(|err| StartupError::Config(err.into()))?;
+    }
+    Ok(config)
+}
+
+/// Load the secret from the file, creating or encrypting it if necessary,
+/// unless it is a `dry_run`.
+fn load_secret(
+    path: Option<&Path>,
+    encrypt: Option<KeySource>,
+    dry_run: bool,
+) -> Result<Secret, StartupError> {
+    let fail = |reason: String| {
+        log::error!("Secret file {path:?}: {reason}");
+        StartupError::Secret(path.unwrap_or(Path::new("")).to_owned(), reason)
+    };
+    let write = |path: &Path, secret: &Secret| match encrypt {
+        _ if dry_run => Ok(()),
+        Some(source) => sealed::seal(&secret.0, source)
+            .map_err(|err| err.to_string())
+            .and_then(|sealed| private_file::write(path, sealed).map_err(|err| err.to_string())),
+        None => private_file::write(path, &secret.0).map_err(|err| err.to_string()),
+    };
+    Ok(match path {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(contents) if sealed::is_sealed(&contents) => {
+                let source =
+                    encrypt.ok_or_else(|| fail("encrypted, but no --encrypt-secret".to_owned()))?;
+                let secret =
+                    sealed::unseal(&contents, source).map_err(|err| fail(err.to_string()))?;
+                log::debug!("Loaded encrypted secret file {path:?}");
+                Secret(secret)
+            }
+            Ok(secret) if secret.len() >= 8 => {
+                log::debug!("Loaded secret file {path:?}");
+                let secret = Secret(secret);
+                if encrypt.is_some() {
+                    write(path, &secret)
+                        .map_err(|err| fail(format!("could not encrypt: {err}")))?;
+                    if !dry_run {
+                        log::warn!("Encrypted secret file {path:?}");
+                    }
+                }
+                secret
+            }
+            Ok(_) => return Err(fail("too short".to_owned())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let secret = Secret::random();
+                write(path, &secret).map_err(|err| fail(format!("could not create: {err}")))?;
+                if dry_run {
+                    log::info!("Secret file {path:?} will be created");
+                } else {
+                    log::warn!("Created new secret file {path:?}");
+                }
+                secret
+            }
+            Err(err) => return Err(fail(format!("could not load: {err}"))),
+        },
+        None => Secret::random(),
+    })
+}
+
+fn load_nps_monitor(path: Option<&Path>) -> Result<Option<Arc<NpsMonitor>>, StartupError> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    match Baseline::load(path) {
+        Ok(baseline) => Ok(Some(Arc::new(NpsMonitor::new(baseline)))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log::warn!("No nps baseline in {path:?} yet, measure it with the bench subcommand");
+            Ok(None)
+        }
+        Err(err) => Err(StartupError::Config(
+            format!("nps baseline {path:?}: {err}").into(),
+        )),
+    }
+}
+
+async fn start_engine(
+    transport: Arc<dyn EngineTransport>,
+    opts: &Opts,
+    config: &Config,
+    notifications: &Notifications,
+) -> Result<Engine, Box<dyn Error>> {
+    let max_threads = min(
+        opts.max_threads.unwrap_or(u32::MAX),
+        u32::try_from(usize::from(
+            thread::available_parallelism().expect("available threads"),
+        ))
+        .unwrap_or(u32::MAX),
+    );
+    let eval_file = match opts.eval_file {
+        Some(ref path) => {
+            let path = opts.base_dir().join(path);
+            if !path.is_file() {
+                return Err(
+                    StartupError::Config(format!("--eval-file {path:?} not found").into()).into(),
+                );
+            }
+            Some(path)
+        }
+        None => None,
+    };
+    let mut engine = Engine::new(
+        transport,
+        EngineParameters {
+            max_threads,
+            max_hash: min(
+                opts.max_hash.unwrap_or(u32::MAX),
+                u32::try_from(opts.hash_strategy.max_hash(available_memory())).unwrap_or(u32::MAX),
+            ),
+            max_multipv: opts.max_multipv.unwrap_or(u32::MAX),
+            info_filter: opts.info_filter,
+            score_perspective: opts.score_perspective,
+            aliases: config.aliases.clone(),
+            thread_budget: opts.autoscale_threads.then(|| load::monitor(max_threads)),
+            thread_ledger: if opts.share_threads {
+                Some(Arc::new(ThreadLedger::open().map_err(|err| {
+                    StartupError::Config(format!("could not share threads: {err}").into())
+                })?))
+            } else {
+                None
+            },
+            searching: Arc::new(tokio::sync::watch::channel(false).0),
+            nps: Arc::new(AtomicU64::new(0)),
+            latency: Arc::default(),
+            nps_monitor: load_nps_monitor(opts.nps_baseline.as_deref())?,
+            verifier: None,
+            history: None,
+            cpu_budget: opts.cpu_budget.map(|hours| {
+                Arc::new(CpuBudget::new(
+                    Duration::from_secs_f64(hours * 60.0 * 60.0),
+                    opts.cpu_budget_threads,
+                    notifications.clone(),
+                ))
+            }),
+            options: config.options.clone(),
+            client_options: !opts.no_client_options,
+            go_policy: config.go.clone(),
+            strict_uci: opts.strict_uci,
+            standby: opts.hot_standby.then(Arc::default),
+            privacy: opts.privacy(),
+        },
+    )
+    .await
+    .map_err(|err| {
+        log::error!("Could not start engine: {err}");
+        StartupError::Engine(err)
+    })?;
+
+    log::info!(
+        "Engine: {} by {} ({} evaluation)",
+        engine.name().unwrap_or("unknown"),
+        engine.author().unwrap_or("unknown"),
+        engine
+            .evaluation()
+            .map_or("unknown".to_owned(), |e| e.to_string()),
+    );
+
+    if eval_file.is_some() || opts.download_net {
+        let info = engine.info();
+        let (name, option) = network::network_option(&info.options)
+            .ok_or("engine has no EvalFile or WeightsFile option for the network")?;
+        let path = match eval_file {
+            Some(path) => path,
+            None => {
+                let default = option.default_value().unwrap_or_default();
+                network::default_network(&default, &opts.base_dir(), &opts.cache_dir())
+                    .await
+                    .map_err(|err| {
+                        log::error!("Could not get network {default:?}: {err}");
+                        StartupError::Engine(err)
+                    })?
+            }
+        };
+        log::info!("Network: {path:?}");
+        engine.set_operator_option(
+            name.clone(),
+            OptionValue::String(path.to_string_lossy().into_owned()),
+        );
+    }
+    if let Some(gpu) = opts.gpu {
+        let (name, value) = gpu::gpu_option(&engine.info().options, &config.options, gpu)
+            .ok_or("engine has no BackendOptions option to select the GPU")?;
+        log::info!("GPU: {name} = {value}");
+        engine.set_operator_option(name, value);
+    }
+
+    // Validate options and presets against the options of the engine, rather
+    // than failing only when a client selects them.
+    let info = engine.info();
+    for (name, value) in &config.options {
+        let valid = info.option(name).map_or(false, |option| {
+            option.validate(Some(value.to_string())).is_ok()
+        });
+        if !valid {
+            log::error!("Invalid option in config: {name} = {value}");
+            return Err("invalid option in config".into());
+        }
+    }
+    for (preset_name, preset) in &config.presets {
+        for (name, value) in &preset.options {
+            let valid = info.option(name).map_or(false, |option| {
+                option.validate(Some(value.to_string())).is_ok()
+            });
+            if !valid {
+                log::error!("Invalid option in preset {preset_name:?}: {name} = {value}");
+                return Err(format!("invalid option in preset {preset_name:?}").into());
+            }
+        }
+    }
+
+    Ok(engine)
+}
+
+fn make_spec(
+    opts: &Opts,
+    url: String,
+    frontend: &Frontend,
+    info: &EngineInfo,
+) -> ExternalWorkerOpts {
+    let mut spec = ExternalWorkerOpts {
+        frontend: frontend.url.clone(),
+        url,
+        secret: frontend.secret.clone(),
+        name: String::new(),
+        max_threads: 1,
+        max_hash: 16,
+        max_multi_pv: 1,
+        variants: Vec::new(),
+        official_stockfish: opts.promise_official_stockfish,
+        engine_name: None,
+        engine_author: None,
+        evaluation: None,
+        preset: None,
+    };
+    spec.update(info, opts.name.as_deref());
+    spec
+}
+
+/// Run a subcommand instead of the server.
+pub async fn run_command(command: Command, opts: Opts) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Register { token } => {
+            if opts.secret_file.is_none() {
+                log::warn!("Registering without --secret-file, the secret will be lost on exit");
+            }
+            let config = load_config(opts.config.as_deref(), opts.profile.as_deref())?;
+            let frontend = opts.frontends()?.swap_remove(0);
+            let engine = start_engine(
+                opts.engine_transport().await?,
+                &opts,
+                &config,
+                &Notifications::default(),
+            )
+            .await?;
+            let spec = make_spec(&opts, opts.publish_url(None), &frontend, &engine.info());
+            let id = Lichess::new(&frontend.url, token)
+                .register(None, &spec)
+                .await?;
+            println!("{id}");
+        }
+        Command::Unregister { token, id } => {
+            let lichess = Lichess::new(opts.frontends[0].trim_end_matches('/'), token);
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    let url = opts.publish_url(None);
+                    lichess
+                        .find(&url)
+                        .await?
+                        .ok_or_else(|| format!("no registration for {url}"))?
+                }
+            };
+            lichess.unregister(&id).await?;
+            println!("{id}");
+        }
+        Command::Invite { hours, guest } => {
+            if opts.secret_file.is_none() {
+                return Err("invites require --secret-file, to verify them later".into());
+            }
+            let config = load_config(opts.config.as_deref(), opts.profile.as_deref())?;
+            if let Some(ref guest) = guest {
+                if !config.guests.contains_key(guest) {
+                    return Err(format!("unknown guest {guest}").into());
+                }
+            }
+            let valid_for = hours
+                .checked_mul(60 * 60)
+                .ok_or_else(|| format!("--hours {hours} is too large"))?;
+            let mut frontend = opts.frontends()?.swap_remove(0);
+            frontend.secret =
+                Invite::new(Duration::from_secs(valid_for), guest).to_secret(&frontend.secret);
+            let engine = start_engine(
+                opts.engine_transport().await?,
+                &opts,
+                &config,
+                &Notifications::default(),
+            )
+            .await?;
+            let spec = make_spec(&opts, opts.publish_url(None), &frontend, &engine.info());
+            log::info!("Invite expires in {hours} hours");
+            println!("{}", spec.registration_url());
+        }
+        Command::Doctor => {
+            let path = opts.engine_path().await?;
+            let mut checks = vec![
+                ("engine", doctor::check_engine(&path).await),
+                (
+                    "cpu variant",
+                    match opts.engine.selected_flag() {
+                        Some("--engine") if opts.engine.has_variants() => Outcome::Warn(
+                            "CPU supports none of the --engine-* variants, using --engine"
+                                .to_owned(),
+                        ),
+                        Some(flag) => Outcome::Ok(format!("selected {flag} for this CPU")),
+                        None => Outcome::Fail("no engine selected".to_owned()),
+                    },
+                ),
+                (
+                    "secret file",
+                    doctor::check_secret_file(opts.secret_file.as_deref()),
+                ),
+                (
+                    "clock",
+                    doctor::check_clock(opts.frontends[0].trim_end_matches('/')).await,
+                ),
+            ];
+            // Probe the published address over HTTP, which reaches the bind
+            // address through proxies as well.
+            let url = opts.publish_url(None).replacen("ws", "http", 1);
+            if opts.relay.is_some() {
+                checks.push((
+                    "reachability",
+                    Outcome::Ok("served through relay, no incoming connections needed".to_owned()),
+                ));
+            } else {
+                if opts.publish_addr_tls {
+                    checks.push(("tls", doctor::check_tls(&url).await));
+                }
+                let listeners = if opts.bind.is_empty() {
+                    listen::bind_default(opts.upnp)
+                } else {
+                    listen::bind(&opts.bind)
+                };
+                checks.push((
+                    "reachability",
+                    doctor::check_reachable(listeners, &url).await,
+                ));
+            }
+            doctor::report(checks)?;
+        }
+        Command::Bench { movetime } => {
+            let config = load_config(opts.config.as_deref(), opts.profile.as_deref())?;
+            let mut engine = start_engine(
+                opts.engine_transport().await?,
+                &opts,
+                &config,
+                &Notifications::default(),
+            )
+            .await?;
+            let max_threads = u32::try_from(engine.info().max_threads()).unwrap_or(1);
+            let results =
+                bench::run(&mut engine, max_threads, Duration::from_millis(movetime)).await?;
+            println!("threads        nps  depth");
+            for result in &results {
+                println!(
+                    "{:>7} {:>10} {:>6}",
+                    result.threads, result.nps, result.depth
+                );
+            }
+            if let Some(ref path) = opts.nps_baseline {
+                Baseline::from_results(&results).save(path)?;
+                println!("Saved nps baseline to {path:?}");
+            }
+            if let (Some(threads), Some(hash)) = (
+                bench::suggest_threads(&results),
+                bench::suggest_hash(&results, opts.hash_strategy.max_hash(available_memory())),
+            ) {
+                println!("Suggested: --max-threads {threads} --max-hash {hash}");
+            }
+        }
+        Command::Fleet {
+            providers,
+            bind,
+            interval,
+        } => fleet::run(providers, bind, Duration::from_secs(interval)).await?,
+        Command::History { format } => {
+            let path = opts
+                .history_file
+                .ok_or("exporting history requires --history-file")?;
+            print!("{}", History::load(path)?.export(format));
+        }
+        Command::Relay { bind, registry } => relay::run(bind, registry).await?,
+        Command::ProxyConfig { server } => {
+            let config = ProxyConfig::new(opts.bind.first().copied(), opts.publish_addr.as_deref());
+            print!("{}", config.render(server));
+        }
+    }
+    Ok(())
+}
+
+/// Extension points for programs that embed the provider.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    /// Decides who may open sessions, instead of the secrets of frontends,
+    /// guests and invites. The secrets are still used for registration.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// How to reach the engine, instead of starting --engine as a child
+    /// process.
+    pub transport: Option<Arc<dyn EngineTransport>>,
+    /// Transforms of commands between clients and the engine, applied after
+    /// those from the config file.
+    pub middleware: Vec<Arc<dyn Middleware>>,
+    /// Receive notifications, in addition to those from the config file.
+    pub notifiers: Vec<Arc<dyn Notifier>>,
+    /// Pause the provider while true, e.g. from a button of the embedding
+    /// program. Like the Pause method on D-Bus.
+    pub pause: Option<tokio::sync::watch::Receiver<bool>>,
+}
+
+type MadeServer = (
+    Vec<ExternalWorkerOpts>,
+    hyper::Server<AddrIncoming, IntoMakeServiceWithConnectInfo<Router, SocketAddr>>,
+    Shutdown,
+    Vec<BoundSocket>,
+);
+
+/// What a socket of the server is for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketKind {
+    /// Sessions and registration.
+    Public,
+    /// The API, with --admin-bind or a socket named admin.
+    Admin,
+    Tls,
+    Quic,
+}
+
+/// A socket that the server listens on.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct BoundSocket {
+    pub kind: SocketKind,
+    pub addr: SocketAddr,
+}
+
+/// Why the server stops by itself.
+pub(crate) enum Stop {
+    /// Asked to over D-Bus.
+    #[cfg(feature = "dbus")]
+    Requested,
+    Unreachable(String),
+}
+
+/// Resolves when the server should stop by itself, e.g. when asked to over
+/// D-Bus, or when its published address turns out to be unreachable with
+/// --check-publish-addr. Await it alongside the server, e.g. as its
+/// graceful shutdown.
+pub struct Shutdown(mpsc::Receiver<Stop>);
+
+impl Shutdown {
+    pub async fn wait(&mut self) -> Result<(), StartupError> {
+        match self.0.recv().await {
+            #[cfg(feature = "dbus")]
+            Some(Stop::Requested) => Ok(()),
+            Some(Stop::Unreachable(msg)) => Err(StartupError::Publish(msg)),
+            None => future::pending().await,
+        }
+    }
+}
+
+pub async fn make_server(opts: Opts, listen_fds: ListenFd) -> Result<MadeServer, Box<dyn Error>> {
+    make_server_with(opts, listen_fds, Extensions::default()).await
+}
+
+/// What is validated by --check: configuration, secrets, sockets and the
+/// engine. Nothing outside of the process is changed yet.
+struct Prepared {
+    config: Config,
+    frontends: Vec<Frontend>,
+    admin_secret: Option<Secret>,
+    listeners: Vec<TcpListener>,
+    admin_listeners: Vec<TcpListener>,
+    tls_listener: Option<(TcpListener, TlsAcceptor)>,
+    notifications: Notifications,
+    engine: Engine,
+}
+
+async fn prepare(
+    opts: &Opts,
+    listen_fds: &mut ListenFd,
+    transport: Option<Arc<dyn EngineTransport>>,
+    notifiers: Vec<Arc<dyn Notifier>>,
+) -> Result<Prepared, Box<dyn Error>> {
+    let config = load_config(opts.config.as_deref(), opts.profile.as_deref())?;
+    let notifications = Notifications::new(
+        config
+            .notify
+            .iter()
+            .map(|subscription| Arc::new(subscription.clone()) as Arc<dyn Notifier>)
+            .chain(notifiers)
+            .collect(),
+    );
+    if opts.lichess_token.len() > opts.frontends.len() {
+        return Err(StartupError::Config("more --lichess-token than --frontend".into()).into());
+    }
+    let frontends = opts.frontends()?;
+    let admin_secret = match opts.admin_secret_file {
+        Some(ref path) => Some(load_secret(Some(path), opts.encrypt_secret, opts.check)?),
+        None => None,
+    };
+
+    let mut listeners = Vec::new();
+    let mut admin_listeners = Vec::new();
+    if opts.bind.is_empty() {
+        for (i, role) in socket_roles(listen_fds.len()).into_iter().enumerate() {
+            if let Some(listener) = listen_fds
+                .take_tcp_listener(i)
+                .map_err(StartupError::Bind)?
+            {
+                match role {
+                    SocketRole::Public => listeners.push(listener),
+                    SocketRole::Admin => admin_listeners.push(listener),
+                }
+            }
+        }
+    }
+    if listeners.is_empty() {
+        listeners = if opts.bind.is_empty() {
+            listen::bind_default(opts.upnp)
+        } else {
+            listen::bind(&opts.bind)
+        }
+        .map_err(|err| {
+            log::error!("Could not bind server: {err}");
+            StartupError::Bind(err)
+        })?;
+    }
+    if let Some(admin_bind) = opts.admin_bind {
+        admin_listeners.push(TcpListener::bind(admin_bind).map_err(|err| {
+            log::error!("Could not bind API server: {err}");
+            StartupError::Bind(err)
+        })?);
+    }
+    let tls_listener = match opts.tls_bind {
+        Some(tls_bind) => {
+            let acceptor = tls::acceptor(
+                opts.tls_cert.as_deref().expect("tls cert"),
+                opts.tls_key.as_deref().expect("tls key"),
+            )
+            .map_err(|err| {
+                log::error!("Could not load TLS certificate: {err}");
+                StartupError::Config(err.into())
+            })?;
+            let tls_listener = TcpListener::bind(tls_bind).map_err(|err| {
+                log::error!("Could not bind TLS server: {err}");
+                StartupError::Bind(err)
+            })?;
+            Some((tls_listener, acceptor))
+        }
+        None => None,
+    };
+
+    // Retry like restarts of the engine, e.g. while the network drive with
+    // the engine is not mounted yet. Only --check fails right away.
+    let mut attempts = 0;
+    let engine = loop {
+        let started = match transport {
+            Some(ref transport) => {
+                start_engine(Arc::clone(transport), opts, &config, &notifications).await
+            }
+            None => match opts.engine_transport().await {
+                Ok(transport) => start_engine(transport, opts, &config, &notifications).await,
+                Err(err) => Err(err),
+            },
+        };
+        match started {
+            Err(err)
+                if !opts.check && matches!(err.downcast_ref(), Some(StartupError::Engine(_))) =>
+            {
+                attempts += 1;
+                let retry_in = supervisor::backoff(attempts);
+                log::error!(
+                    "Could not start engine (attempt {attempts}), retrying in {}s: {err}",
+                    retry_in.as_secs()
+                );
+                sleep(retry_in).await;
+            }
+            started => break started?,
+        }
+    };
+
+    Ok(Prepared {
+        config,
+        frontends,
+        admin_secret,
+        listeners,
+        admin_listeners,
+        tls_listener,
+        notifications,
+        engine,
+    })
+}
+
+/// Validate the configuration, secrets, sockets and engine, like starting
+/// the server, but without any of its effects on the outside, like port
+/// mappings, registrations or notifications.
+pub async fn check_server(opts: Opts, mut listen_fds: ListenFd) -> Result<(), Box<dyn Error>> {
+    prepare(&opts, &mut listen_fds, None, Vec::new()).await?;
+    Ok(())
+}
+
+pub async fn make_server_with(
+    opts: Opts,
+    mut listen_fds: ListenFd,
+    extensions: Extensions,
+) -> Result<MadeServer, Box<dyn Error>> {
+    let Prepared {
+        config,
+        frontends,
+        admin_secret,
+        mut listeners,
+        admin_listeners,
+        tls_listener,
+        notifications,
+        mut engine,
+    } = prepare(
+        &opts,
+        &mut listen_fds,
+        extensions.transport,
+        extensions.notifiers,
+    )
+    .await?;
+    if let Some(ref url) = opts.upload_crash_reports {
+        tokio::spawn(crash::upload_reports(url.clone(), opts.report_dir()));
+    }
+    let frontends = Arc::new(frontends);
+    let listener = listeners.remove(0);
+    // Sessions are published on the TLS socket, if any.
+    let local_addr = match tls_listener {
+        Some((ref tls_listener, _)) => tls_listener.local_addr(),
+        None => listener.local_addr(),
+    }
+    .expect("local addr");
+
+    let public_addr = if opts.upnp {
+        if local_addr.ip().is_loopback() {
+            log::warn!("Requesting port mapping, but server is bound to {local_addr}");
+        }
+        let mapping = upnp::map_port(local_addr.port()).await.map_err(|err| {
+            log::error!("Could not map port: {err}");
+            err
+        })?;
+        log::info!("Mapped external address {}", mapping.external_addr());
+        let external_addr = SocketAddr::V4(mapping.external_addr());
+        tokio::spawn(upnp::keep_mapped(mapping));
+        external_addr
+    } else {
+        local_addr
+    };
+
+    if opts.hot_standby {
+        log::info!("Starting standby engine ...");
+        engine.refill_standby();
+    }
+    let watched_path = if opts.watch_engine {
+        Some(opts.engine_path().await?)
+    } else {
+        None
+    };
+    if let Some(ref path) = opts.verify_engine {
+        let verifier = Verifier::start(
+            path.clone(),
+            opts.verify_depth,
+            opts.verify_threshold,
+            opts.privacy(),
+        )
+        .await
+        .map_err(|err| {
+            log::error!("Could not start verification engine: {err}");
+            StartupError::Engine(err)
+        })?;
+        engine.set_verifier(verifier);
+    }
+    let history = match opts.history_file {
+        Some(ref path) => {
+            let history = History::load(path.clone()).map_err(|err| {
+                log::error!("Could not load history {path:?}: {err}");
+                err
+            })?;
+            engine.set_history(history.clone());
+            Some(history)
+        }
+        None => None,
+    };
+    let guests = Arc::new(
+        Guests::load(config.guests.clone(), opts.guests_file.clone()).map_err(|err| {
+            log::error!("Could not load guests file: {err}");
+            StartupError::Config(err.into())
+        })?,
+    );
+    let config = Arc::new(config);
+    let authenticator = extensions.authenticator.unwrap_or_else(|| {
+        Arc::new(Secrets {
+            frontends: Arc::clone(&frontends),
+            guests: Arc::clone(&guests),
+        })
+    });
+
+    let url = opts.publish_url(Some(public_addr));
+    let info = engine.info();
+    let specs: Vec<_> = frontends
+        .iter()
+        .map(|frontend| make_spec(&opts, url.clone(), frontend, &info))
+        .collect();
+
+    let mut presets: Vec<_> = config
+        .presets
+        .iter()
+        .filter(|(_, preset)| preset.name.is_some())
+        .collect();
+    presets.sort_by_key(|(key, _)| *key);
+    let preset_specs: Vec<_> = specs
+        .iter()
+        .flat_map(|spec| {
+            presets
+                .iter()
+                .map(|(key, preset)| spec.with_preset(key, preset, &info, opts.name.as_deref()))
+        })
+        .collect();
+
+    // Registrations contain the secret of the guest, so they are printed
+    // like those of invites, rather than kept in logs and crash reports.
+    for (name, guest) in &config.guests {
+        let mut spec = specs[0].clone();
+        spec.secret = guest.secret.clone();
+        if opts.output == OutputFormat::Text {
+            log::info!("Printing registration for guest {name}");
+            println!("guest {name}: {}", spec.registration_url());
+        }
+    }
+
+    if opts.open {
+        share::open_in_browser(&specs[0].registration_url());
+    }
+    if opts.copy_url {
+        share::copy_to_clipboard(&specs[0].registration_url());
+    }
+
+    if !opts.allow_sleep {
+        tokio::spawn(inhibit::inhibit_sleep(engine.subscribe_searching()));
+    }
+    #[cfg(feature = "dbus")]
+    let searching = engine.subscribe_searching();
+
+    let middleware = config
+        .middleware
+        .iter()
+        .map(|builtin| Arc::new(builtin.clone()) as Arc<dyn Middleware>)
+        .chain(extensions.middleware)
+        .collect();
+    let transport = engine.transport();
+    let mut engine = SharedEngine::new(engine, Chain::new(middleware));
+    if let Some(latency) = opts.simulate_latency {
+        log::warn!("Simulating latency of {latency} to and from clients");
+        engine.simulate_latency(latency);
+    }
+    if opts.ponder_ahead {
+        engine.ponder_ahead();
+    }
+    if let Some(minutes) = opts.continue_on_disconnect {
+        engine.continue_on_disconnect(Duration::from_secs(minutes.saturating_mul(60)));
+    }
+    if let Some(monitor) = opts
+        .gpu
+        .and_then(|index| GpuMonitor::open(index, Arc::clone(&transport)))
+    {
+        engine.monitor_gpu(monitor);
+    }
+    engine.set_notifications(notifications);
+    let engine = Arc::new(engine);
+    tokio::spawn(resume::watch_resume(Arc::clone(&engine)));
+    if let Some(mut pause) = extensions.pause {
+        let engine = Arc::clone(&engine);
+        tokio::spawn(async move {
+            loop {
+                engine.set_paused(*pause.borrow());
+                if pause.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    tokio::spawn(supervisor::supervise(Arc::clone(&engine)));
+    let (stop_tx, stop_rx) = mpsc::channel(1);
+    #[cfg(feature = "dbus")]
+    if opts.dbus {
+        dbus::serve(
+            Arc::clone(&engine),
+            searching,
+            specs[0].registration_url(),
+            stop_tx.clone(),
+        )
+        .await
+        .map_err(|err| {
+            log::error!("Could not serve on D-Bus: {err}");
+            err
+        })?;
+    }
+    let queue = opts
+        .idle_queue
+        .clone()
+        .map(|path| Arc::new(Queue::new(path)));
+    if let Some(ref queue) = queue {
+        tokio::spawn(idle::run(
+            Arc::clone(&engine),
+            IdleAnalysis {
+                queue: Arc::clone(queue),
+                movetime: Duration::from_secs(opts.idle_movetime),
+                multipv: opts.idle_multipv,
+            },
+        ));
+    }
+
+    for (token, spec) in zip(opts.lichess_token, &specs) {
+        let virtual_specs = preset_specs
+            .iter()
+            .filter(|preset_spec| preset_spec.frontend == spec.frontend);
+        for spec in iter::once(spec).chain(virtual_specs) {
+            tokio::spawn(lichess::keep_registered(
+                Lichess::new(&spec.frontend, token.clone()),
+                Arc::clone(&engine),
+                spec.clone(),
+                opts.name.clone(),
+            ));
+        }
+    }
+
+    if let Some(engine_path) = watched_path {
+        watch::watch_engine(&engine_path, Arc::clone(&engine)).map_err(|err| {
+            log::error!("Could not watch engine {engine_path:?}: {err}");
+            err
+        })?;
+    }
+
+    #[cfg(feature = "quic")]
+    let quic_addr = match opts.quic_bind {
+        Some(bind) => Some(quic::listen(
+            bind,
+            Arc::clone(&engine),
+            Arc::clone(&authenticator),
+            Arc::clone(&config),
+        )?),
+        None => None,
+    };
+    #[cfg(not(feature = "quic"))]
+    let quic_addr = None;
+
+    let sockets = iter::once(&listener)
+        .chain(&listeners)
+        .map(|listener| (SocketKind::Public, listener))
+        .chain(
+            admin_listeners
+                .iter()
+                .map(|listener| (SocketKind::Admin, listener)),
+        )
+        .chain(
+            tls_listener
+                .iter()
+                .map(|(listener, _)| (SocketKind::Tls, listener)),
+        )
+        .map(|(kind, listener)| {
+            Ok(BoundSocket {
+                kind,
+                addr: listener.local_addr()?,
+            })
+        })
+        .chain(quic_addr.map(|addr| {
+            Ok(BoundSocket {
+                kind: SocketKind::Quic,
+                addr,
+            })
+        }))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let pool = Arc::new(Pool::new(opts.workers, opts.split_root_moves));
+    if !pool.is_empty() {
+        tokio::spawn(pool::check_health(Arc::clone(&pool)));
+    }
+
+    let trusted_proxies: Arc<[TrustedProxy]> = opts.trusted_proxy.clone().into();
+    let resolve_client_ip = axum::middleware::from_fn(move |req, next| {
+        proxy::resolve(Arc::clone(&trusted_proxies), req, next)
+    });
+
+    let api = Router::new()
+        .route(
+            "/api/status",
+            get({
+                let engine = Arc::clone(&engine);
+                move || api::status(engine)
+            }),
+        )
+        .route(
+            "/api/options",
+            get({
+                let engine = Arc::clone(&engine);
+                move || api::options(engine)
+            }),
+        )
+        .route("/api/logs", get(api::logs))
+        .route(
+            "/health",
+            get({
+                let engine = Arc::clone(&engine);
+                move || api::health(engine)
+            }),
+        );
+    let api = match history {
+        Some(history) => api.merge(
+            Router::new()
+                .route(
+                    "/api/history",
+                    get({
+                        let history = history.clone();
+                        move || history::api(history)
+                    }),
+                )
+                .route(
+                    "/api/history/export",
+                    get({
+                        let history = history.clone();
+                        move |params| history::export(history, params)
+                    }),
+                )
+                .route("/history", get(move || history::page(history)))
+                .route_layer(axum::middleware::from_fn({
+                    let admin_secret = admin_secret.clone();
+                    move |req, next| history::restrict(admin_secret.clone(), req, next)
+                })),
+        ),
+        None => api,
+    };
+    let api = match admin_secret {
+        Some(admin_secret) => api.merge(admin::router(
+            admin_secret,
+            Arc::clone(&engine),
+            Arc::clone(&guests),
+            specs[0].clone(),
+            queue,
+        )),
+        None => api,
+    };
+    let api = api.layer(resolve_client_ip.clone());
+
+    let app = Router::new()
+        .route(
+            "/",
+            get({
+                let spec = specs[0].clone();
+                move || redirect(spec)
+            }),
+        )
+        .route(
+            "/top-moves",
+            get({
+                let engine = Arc::clone(&engine);
+                let authenticator = Arc::clone(&authenticator);
+                move |params, query, headers| {
+                    top_moves::handler(engine, authenticator, params, query, headers)
+                }
+            }),
+        )
+        .route(
+            "/socket",
+            get({
+                let engine = Arc::clone(&engine);
+                let config = Arc::clone(&config);
+                move |params, query, headers, client_ip, socket| {
+                    ws::handler(
+                        engine,
+                        authenticator,
+                        config,
+                        pool,
+                        params,
+                        query,
+                        headers,
+                        client_ip,
+                        socket,
+                    )
+                }
+            }),
+        )
+        .layer(resolve_client_ip);
+
+    // Keep the API off public sockets, if there is a separate socket for it.
+    let app = if admin_listeners.is_empty() {
+        app.merge(api)
+    } else {
+        for listener in admin_listeners {
+            log::info!("Serving API on {}", listener.local_addr()?);
+            tokio::spawn(
+                axum::Server::from_tcp(listener)?.serve(
+                    api.clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                ),
+            );
+        }
+        app
+    };
+
+    if let (Some(relay), Some(id)) = (opts.relay, opts.relay_id) {
+        let token = tunnel::token(&specs[0].secret, &id);
+        tokio::spawn(tunnel::serve(relay, id, token, app.clone()));
+    }
+
+    // With TLS, plain HTTP only redirects to the TLS socket.
+    let app = match tls_listener {
+        Some((tls_listener, acceptor)) => {
+            log::info!("Serving TLS on {local_addr}");
+            tls_listener.set_nonblocking(true)?;
+            tokio::spawn(tls::serve(
+                tokio::net::TcpListener::from_std(tls_listener)?,
+                acceptor,
+                app,
+            ));
+            tls::redirect_app(local_addr.port())
+        }
+        None => app,
+    };
+
+    for listener in listeners {
+        tokio::spawn(
+            axum::Server::from_tcp(listener)?.serve(
+                app.clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            ),
+        );
+    }
+
+    if opts.check_publish_addr {
+        tokio::spawn(check_publish_addr(url, specs[0].secret.0.clone(), stop_tx));
+    }
+
+    Ok((
+        specs.into_iter().chain(preset_specs).collect(),
+        axum::Server::from_tcp(listener)?
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+        Shutdown(stop_rx),
+        sockets,
+    ))
+}
+
+/// Fail fast if the published address does not reach the running server,
+/// rather than letting users discover it when lichess can not connect.
+async fn check_publish_addr(url: String, secret: String, stop: mpsc::Sender<Stop>) {
+    match doctor::check_handshake(&url, &secret).await {
+        Outcome::Fail(msg) => {
+            log::error!("Published address is not reachable: {msg}");
+            let _ = stop.send(Stop::Unreachable(msg)).await;
+        }
+        Outcome::Ok(msg) | Outcome::Warn(msg) => log::info!("Checked published address: {msg}"),
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum SocketRole {
+    /// Serves sessions and registration.
+    Public,
+    /// Serves the API, which should not be exposed publicly.
+    Admin,
+}
+
+/// Roles of the sockets passed by the service manager. Sockets named admin
+/// (FileDescriptorName=admin in the systemd socket unit) serve the API,
+/// and all others are public.
+fn socket_roles(count: usize) -> Vec<SocketRole> {
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (0..count)
+        .map(|_| match names.next() {
+            Some("admin") => SocketRole::Admin,
+            _ => SocketRole::Public,
+        })
+        .collect()
+}
+
+async fn redirect(spec: ExternalWorkerOpts) -> Redirect {
+    Redirect::to(&spec.registration_url())
+}
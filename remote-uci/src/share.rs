@@ -0,0 +1,38 @@
+#[cfg(feature = "desktop")]
+use std::mem;
+
+/// Open the URL in the default browser of the user.
+#[cfg(feature = "desktop")]
+pub fn open_in_browser(url: &str) {
+    if let Err(err) = webbrowser::open(url) {
+        log::error!("Could not open browser: {err}");
+    }
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn open_in_browser(_url: &str) {
+    log::error!("Could not open browser: built without the desktop feature");
+}
+
+#[cfg(feature = "desktop")]
+pub fn copy_to_clipboard(url: &str) {
+    let mut clipboard = match arboard::Clipboard::new() {
+        Ok(clipboard) => clipboard,
+        Err(err) => {
+            log::error!("Could not access clipboard: {err}");
+            return;
+        }
+    };
+    match clipboard.set_text(url.to_owned()) {
+        Ok(()) => log::info!("Registration URL copied to clipboard"),
+        Err(err) => log::error!("Could not copy to clipboard: {err}"),
+    }
+    // On some platforms, the contents of the clipboard are served by the
+    // program that set them, and vanish once it lets go.
+    mem::forget(clipboard);
+}
+
+#[cfg(not(feature = "desktop"))]
+pub fn copy_to_clipboard(_url: &str) {
+    log::error!("Could not copy to clipboard: built without the desktop feature");
+}
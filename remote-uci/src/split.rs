@@ -0,0 +1,391 @@
+use std::{collections::HashMap, num::NonZeroU32};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{stream::select_all, SinkExt, StreamExt};
+use shakmaty::{uci::Uci, CastlingMode, Chess, Position};
+use tokio_tungstenite::tungstenite;
+
+use crate::{
+    pool::Forwarded,
+    uci::{UciIn, UciOptionName, UciOut},
+};
+
+/// Serve a session by dividing the root moves of each search between
+/// several workers, and merging their principal variations, so that the
+/// client sees a single wider analysis.
+///
+/// Only the first worker answers handshake commands like `uci` and
+/// `isready`.
+pub async fn handle_socket(client: WebSocket, mut workers: Vec<Forwarded>) {
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut worker_txs, worker_rxs): (Vec<_>, Vec<_>) = workers
+        .iter_mut()
+        .map(|worker| worker.socket_mut().split())
+        .unzip();
+    let mut worker_rx = select_all(
+        worker_rxs
+            .into_iter()
+            .enumerate()
+            .map(|(i, rx)| rx.map(move |message| (i, message))),
+    );
+
+    let mut split = Split::new(worker_txs.len());
+    loop {
+        let (outbound, inbound) = tokio::select! {
+            message = client_rx.next() => match message {
+                Some(Ok(Message::Text(text))) => match UciIn::from_line(&text) {
+                    Ok(Some(command)) => (split.command(command), Vec::new()),
+                    Ok(None) => continue,
+                    Err(err) => {
+                        log::error!("split: {err}");
+                        break;
+                    }
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            message = worker_rx.next() => match message {
+                Some((i, Ok(tungstenite::Message::Text(text)))) => {
+                    (Vec::new(), split.output(i, &text))
+                }
+                Some((_, Ok(tungstenite::Message::Close(_)) | Err(_))) | None => {
+                    log::warn!("split: worker ended session");
+                    break;
+                }
+                Some(_) => continue,
+            },
+        };
+        for (i, command) in outbound {
+            if worker_txs[i]
+                .send(tungstenite::Message::Text(command.to_string()))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        for line in inbound {
+            if client_tx.send(Message::Text(line)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    let _ = client_tx.send(Message::Close(None)).await;
+    for mut worker_tx in worker_txs {
+        let _ = worker_tx.close().await;
+    }
+}
+
+struct Split {
+    workers: usize,
+    multipv: u32,
+    chess960: bool,
+    variant: bool,
+    position: Option<UciIn>,
+    searching: Vec<bool>,
+    /// Number of bestmoves still to come from previous searches of each
+    /// worker, e.g. when the client started a new search right after
+    /// stopping one. Their output is ignored.
+    stale: Vec<u32>,
+    /// Latest principal variations by worker and local multipv.
+    lines: HashMap<(usize, u32), UciOut>,
+}
+
+impl Split {
+    fn new(workers: usize) -> Split {
+        Split {
+            workers,
+            multipv: 1,
+            chess960: false,
+            variant: false,
+            position: None,
+            searching: vec![false; workers],
+            stale: vec![0; workers],
+            lines: HashMap::new(),
+        }
+    }
+
+    /// Commands to send to each worker for a command from the client.
+    fn command(&mut self, command: UciIn) -> Vec<(usize, UciIn)> {
+        match command {
+            UciIn::Setoption {
+                ref name,
+                ref value,
+            } if *name == "MultiPV" => {
+                if let Some(multipv) = value.as_deref().and_then(|v| v.parse().ok()) {
+                    self.multipv = multipv;
+                }
+                return Vec::new();
+            }
+            UciIn::Setoption {
+                ref name,
+                ref value,
+            } if *name == "UCI_Chess960" => {
+                self.chess960 = value.as_deref() == Some("true");
+            }
+            UciIn::Setoption {
+                ref name,
+                ref value,
+            } if *name == "UCI_Variant" => {
+                self.variant = value.as_deref().map_or(false, |v| v != "chess");
+            }
+            UciIn::Position { .. } => self.position = Some(command.clone()),
+            UciIn::Go {
+                ref searchmoves, ..
+            } => {
+                self.lines.clear();
+                let root = match searchmoves {
+                    Some(moves) => Some(moves.clone()),
+                    None if !self.variant => self.root_moves(),
+                    None => None,
+                };
+                return self.go(command.clone(), root);
+            }
+            _ => (),
+        }
+        (0..self.workers).map(|i| (i, command.clone())).collect()
+    }
+
+    fn go(&mut self, go: UciIn, root: Option<Vec<Uci>>) -> Vec<(usize, UciIn)> {
+        let parts = root
+            .as_ref()
+            .map_or(1, |root| self.workers.min(root.len()).max(1));
+        for (i, (searching, stale)) in self.searching.iter_mut().zip(&mut self.stale).enumerate() {
+            if *searching {
+                *stale += 1;
+            }
+            *searching = i < parts;
+        }
+        let mut commands = Vec::new();
+        for i in 0..parts {
+            let mut go = go.clone();
+            let mut multipv = self.multipv;
+            if let (
+                Some(root),
+                UciIn::Go {
+                    ref mut searchmoves,
+                    ..
+                },
+            ) = (&root, &mut go)
+            {
+                if parts > 1 {
+                    let subset: Vec<Uci> = root.iter().skip(i).step_by(parts).cloned().collect();
+                    multipv = multipv.min(subset.len() as u32);
+                    *searchmoves = Some(subset);
+                }
+            }
+            commands.push((
+                i,
+                UciIn::Setoption {
+                    name: UciOptionName("MultiPV".to_owned()),
+                    value: Some(multipv.to_string()),
+                },
+            ));
+            commands.push((i, go));
+        }
+        commands
+    }
+
+    fn root_moves(&self) -> Option<Vec<Uci>> {
+        let (fen, moves) = match self.position {
+            Some(UciIn::Position { ref fen, ref moves }) => (fen, moves),
+            _ => return None,
+        };
+        let mut pos: Chess = match fen {
+            Some(fen) => fen.position(CastlingMode::Chess960).ok()?,
+            None => Chess::default(),
+        };
+        for m in moves {
+            let m = m.to_move(&pos).ok()?;
+            pos.play_unchecked(&m);
+        }
+        let mode = if self.chess960 {
+            CastlingMode::Chess960
+        } else {
+            CastlingMode::Standard
+        };
+        Some(
+            pos.legal_moves()
+                .iter()
+                .map(|m| Uci::from_move(m, mode))
+                .collect(),
+        )
+    }
+
+    /// Lines to send to the client for a line of output from a worker.
+    fn output(&mut self, worker: usize, line: &str) -> Vec<String> {
+        let command = match UciOut::from_line(line) {
+            Ok(Some(command)) => command,
+            _ if worker == 0 => return vec![line.to_owned()],
+            _ => return Vec::new(),
+        };
+        if self.stale[worker] > 0 {
+            match command {
+                UciOut::Bestmove { .. } => {
+                    self.stale[worker] -= 1;
+                    return Vec::new();
+                }
+                UciOut::Info { .. } => return Vec::new(),
+                _ => (),
+            }
+        }
+        match command {
+            UciOut::Info {
+                multipv,
+                pv: Some(_),
+                ..
+            } => {
+                let multipv = multipv.map_or(1, NonZeroU32::get);
+                self.lines.insert((worker, multipv), command);
+                self.merged()
+                    .into_iter()
+                    .map(|info| info.to_string())
+                    .collect()
+            }
+            UciOut::Info {
+                string: Some(_), ..
+            } => vec![command.to_string()],
+            UciOut::Info { .. } => Vec::new(),
+            UciOut::Bestmove { .. } => {
+                self.searching[worker] = false;
+                if self.searching.iter().any(|s| *s) {
+                    return Vec::new();
+                }
+                let best = self
+                    .merged()
+                    .into_iter()
+                    .next()
+                    .and_then(|info| match info {
+                        UciOut::Info { pv: Some(pv), .. } => Some(pv),
+                        _ => None,
+                    });
+                vec![match best {
+                    Some(pv) if !pv.is_empty() => UciOut::Bestmove {
+                        m: pv.first().cloned(),
+                        ponder: pv.get(1).cloned(),
+                    }
+                    .to_string(),
+                    _ => command.to_string(),
+                }]
+            }
+            _ if worker == 0 => vec![command.to_string()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The best principal variations of all workers, renumbered.
+    fn merged(&self) -> Vec<UciOut> {
+        let mut lines: Vec<&UciOut> = self.lines.values().collect();
+        lines.sort_by_key(|info| match info {
+            UciOut::Info {
+                score: Some(score), ..
+            } => -score.eval.to_cp(),
+            _ => i64::MAX,
+        });
+        lines
+            .into_iter()
+            .take(self.multipv as usize)
+            .zip(1..)
+            .map(|(info, rank)| {
+                let mut info = info.clone();
+                if let UciOut::Info {
+                    ref mut multipv, ..
+                } = info
+                {
+                    *multipv = NonZeroU32::new(rank);
+                }
+                info
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uci_in(line: &str) -> UciIn {
+        UciIn::from_line(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_split_root_moves() {
+        let mut split = Split::new(2);
+        assert!(split
+            .command(uci_in("setoption name MultiPV value 2"))
+            .is_empty());
+        assert_eq!(split.command(uci_in("position startpos")).len(), 2);
+        let commands: Vec<_> = split
+            .command(uci_in("go depth 10 searchmoves e2e4 d2d4 c2c4"))
+            .into_iter()
+            .map(|(i, command)| (i, command.to_string()))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                (0, "setoption name MultiPV value 2".to_owned()),
+                (0, "go searchmoves e2e4 c2c4 depth 10".to_owned()),
+                (1, "setoption name MultiPV value 1".to_owned()),
+                (1, "go searchmoves d2d4 depth 10".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut split = Split::new(2);
+        split.command(uci_in("setoption name MultiPV value 2"));
+        split.command(uci_in("position startpos"));
+        assert_eq!(split.command(uci_in("go depth 10")).len(), 4);
+
+        assert_eq!(
+            split.output(0, "info multipv 1 depth 10 score cp 20 pv e2e4 e7e5"),
+            ["info multipv 1 depth 10 score cp 20 pv e2e4 e7e5"]
+        );
+        assert_eq!(
+            split.output(1, "info multipv 1 depth 10 score cp 30 pv d2d4 d7d5"),
+            [
+                "info multipv 1 depth 10 score cp 30 pv d2d4 d7d5",
+                "info multipv 2 depth 10 score cp 20 pv e2e4 e7e5",
+            ]
+        );
+        // Wait for all workers before choosing the best move.
+        assert!(split.output(0, "bestmove e2e4").is_empty());
+        assert_eq!(
+            split.output(1, "bestmove d2d4"),
+            ["bestmove d2d4 ponder d7d5"]
+        );
+    }
+
+    #[test]
+    fn test_stale_bestmove() {
+        let mut split = Split::new(2);
+        split.command(uci_in("position startpos"));
+        split.command(uci_in("go infinite"));
+        split.command(uci_in("stop"));
+        // The client searches again before the workers stopped.
+        split.command(uci_in("go depth 10"));
+        assert!(split.output(0, "bestmove e2e4").is_empty());
+        assert_eq!(split.output(1, "readyok"), Vec::<String>::new());
+        assert_eq!(split.output(0, "readyok"), ["readyok"]);
+        assert!(split
+            .output(1, "info depth 20 score cp 90 pv d2d4 d7d5")
+            .is_empty());
+        assert!(split.output(1, "bestmove d2d4").is_empty());
+
+        assert_eq!(
+            split.output(0, "info depth 10 score cp 20 pv e2e4 e7e5"),
+            ["info multipv 1 depth 10 score cp 20 pv e2e4 e7e5"]
+        );
+        assert!(split.output(0, "bestmove e2e4").is_empty());
+        assert_eq!(
+            split.output(1, "info depth 10 score cp 10 pv d2d4 d7d5"),
+            ["info multipv 1 depth 10 score cp 20 pv e2e4 e7e5"]
+        );
+        assert_eq!(
+            split.output(1, "bestmove d2d4"),
+            ["bestmove e2e4 ponder e7e5"]
+        );
+    }
+}
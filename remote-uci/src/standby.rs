@@ -0,0 +1,75 @@
+use std::{
+    io,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{task::JoinHandle, time::timeout};
+
+use crate::{
+    engine::{Engine, EngineParameters, Session},
+    transport::EngineTransport,
+};
+
+/// Time for the standby engine to answer isready before taking over. It
+/// has been idle, so that it should answer right away.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Second engine process that is started and warmed up in advance, and
+/// kept idle, so that it can take over as soon as the engine crashes or
+/// hangs, rather than after starting a new process and loading the
+/// network.
+#[derive(Default)]
+pub struct Standby {
+    engine: Mutex<Option<JoinHandle<io::Result<Engine>>>>,
+}
+
+impl Standby {
+    /// Start a new standby engine in the background, replacing the current
+    /// one, e.g. after it took over, or when the executable changed.
+    pub fn refill(&self, transport: Arc<dyn EngineTransport>, params: EngineParameters) {
+        let handle = tokio::spawn(async move {
+            let mut engine = Engine::start(transport, params).await?;
+            engine.warm_up().await?;
+            log::info!("Standby engine ready");
+            Ok(engine)
+        });
+        if let Some(previous) = self
+            .engine
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(handle)
+        {
+            previous.abort();
+        }
+    }
+
+    /// Take the standby engine, waiting until it is started if necessary.
+    /// Returns `None` if it failed in the meantime.
+    pub async fn take(&self) -> Option<Engine> {
+        let handle = self
+            .engine
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()?;
+        let mut engine = match handle.await {
+            Ok(Ok(engine)) => engine,
+            Ok(Err(err)) => {
+                log::error!("Standby engine failed to start: {err}");
+                return None;
+            }
+            Err(_) => return None,
+        };
+        match timeout(PROBE_TIMEOUT, engine.probe(Session(0))).await {
+            Ok(Ok(())) => Some(engine),
+            Ok(Err(err)) => {
+                log::error!("Standby engine failed: {err}");
+                None
+            }
+            Err(_) => {
+                log::error!("Standby engine not responding");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{io, sync::Arc, time::Duration};
+
+use serde::Serialize;
+use tokio::time::sleep;
+
+use crate::ws::SharedEngine;
+
+/// Delay after the first failed restart, doubled with every further
+/// failure, so that a missing executable or network file does not keep
+/// the host busy.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// State of the engine process, as reported by `/health`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum Health {
+    Running,
+    /// The engine failed, and has not been restarted yet.
+    Restarting {
+        attempts: u32,
+        error: String,
+        retry_in_secs: u64,
+    },
+}
+
+impl Health {
+    pub fn is_running(&self) -> bool {
+        matches!(self, Health::Running)
+    }
+}
+
+/// Whether the error means that the engine process is gone or stuck, as
+/// opposed to a rejected command.
+pub fn is_engine_gone(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe | io::ErrorKind::TimedOut
+    )
+}
+
+pub fn backoff(attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Restart the engine whenever it fails, retrying with exponential backoff
+/// until it starts again.
+pub async fn supervise(shared_engine: Arc<SharedEngine>) {
+    loop {
+        shared_engine.failed().await;
+        let mut attempts = 0;
+        loop {
+            match shared_engine.fail_over().await {
+                Ok(()) => {
+                    log::warn!("Engine restarted");
+                    shared_engine.set_health(Health::Running);
+                    break;
+                }
+                Err(err) => {
+                    attempts += 1;
+                    let retry_in = backoff(attempts);
+                    log::error!(
+                        "Could not restart engine (attempt {attempts}), retrying in {}s: {err}",
+                        retry_in.as_secs()
+                    );
+                    shared_engine.set_health(Health::Restarting {
+                        attempts,
+                        error: err.to_string(),
+                        retry_in_secs: retry_in.as_secs(),
+                    });
+                    sleep(retry_in).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(20), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+}
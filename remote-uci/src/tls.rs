@@ -0,0 +1,169 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::any,
+    Extension, Router,
+};
+use hyper::server::conn::Http;
+use thiserror::Error;
+use tokio::{net::TcpListener, time::timeout};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Browsers remember to use TLS for this long (one year).
+const HSTS: &str = "max-age=31536000";
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("could not read {0:?}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("no certificate in {0:?}")]
+    NoCertificate(PathBuf),
+    #[error("no private key in {0:?}")]
+    NoPrivateKey(PathBuf),
+    #[error("invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Load a certificate chain and private key from PEM files.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .map_err(|err| TlsError::Io(cert.to_owned(), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(cert.to_owned()));
+    }
+    let private_key = rustls_pemfile::read_all(&mut open(key)?)
+        .map_err(|err| TlsError::Io(key.to_owned(), err))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey(key.to_owned()))?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            certs.into_iter().map(Certificate).collect(),
+            PrivateKey(private_key),
+        )?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| TlsError::Io(path.to_owned(), err))
+}
+
+/// Serve the app over TLS, telling browsers to always use TLS from now on.
+pub async fn serve(listener: TcpListener, acceptor: TlsAcceptor, app: Router) {
+    let app = app.layer(middleware::from_fn(hsts));
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::error!("Could not accept TLS connection: {err}");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone().layer(Extension(ConnectInfo(peer)));
+        tokio::spawn(async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    log::debug!("TLS handshake with {peer} failed: {err}");
+                    return;
+                }
+                Err(_) => {
+                    log::debug!("TLS handshake with {peer} timed out");
+                    return;
+                }
+            };
+            if let Err(err) = Http::new()
+                .serve_connection(stream, app)
+                .with_upgrades()
+                .await
+            {
+                log::debug!("TLS connection with {peer} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn hsts<B>(req: Request<B>, next: Next<B>) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut().insert(
+        header::STRICT_TRANSPORT_SECURITY,
+        HeaderValue::from_static(HSTS),
+    );
+    res
+}
+
+/// App that permanently redirects all plain HTTP requests to the TLS port.
+pub fn redirect_app(tls_port: u16) -> Router {
+    Router::new().fallback(any(move |headers: HeaderMap, uri: Uri| async move {
+        let host = match headers.get(header::HOST).and_then(|h| h.to_str().ok()) {
+            Some(host) => host,
+            None => return StatusCode::BAD_REQUEST.into_response(),
+        };
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        (
+            StatusCode::MOVED_PERMANENTLY,
+            [(header::LOCATION, https_location(host, tls_port, path))],
+        )
+            .into_response()
+    }))
+}
+
+fn https_location(host: &str, port: u16, path: &str) -> String {
+    // Strip the port, but not parts of a bracketed IPv6 address.
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.contains(']') => hostname,
+        _ => host,
+    };
+    if port == 443 {
+        format!("https://{hostname}{path}")
+    } else {
+        format!("https://{hostname}:{port}{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("example.org", 443, "/socket?secret=x"),
+            "https://example.org/socket?secret=x"
+        );
+        assert_eq!(
+            https_location("example.org:80", 9443, "/"),
+            "https://example.org:9443/"
+        );
+        assert_eq!(
+            https_location("[::1]:9670", 9443, "/"),
+            "https://[::1]:9443/"
+        );
+        assert_eq!(https_location("[::1]", 443, "/"), "https://[::1]/");
+    }
+}
@@ -0,0 +1,219 @@
+use std::{cmp::min, collections::BTreeMap, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, RawQuery},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use shakmaty::{fen::Fen, san::SanPlus, CastlingMode, Chess, EnPassantMode};
+
+use crate::{
+    auth::{AuthRequest, Authenticator},
+    uci::{Eval, UciIn, UciOptionName, UciOut},
+    ws::SharedEngine,
+};
+
+const DEFAULT_MOVES: u32 = 3;
+const DEFAULT_MOVETIME: Duration = Duration::from_secs(1);
+
+/// Requests can not hold the engine for longer than this.
+const MAX_MOVETIME: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+pub struct Params {
+    fen: String,
+    /// Number of moves, limited by the MultiPV of the engine.
+    moves: Option<u32>,
+    /// Search time in milliseconds.
+    movetime: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct TopMoves {
+    fen: String,
+    moves: Vec<TopMove>,
+}
+
+/// First move of a line, with the evaluation of the line from the point of
+/// view of the side to move.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TopMove {
+    uci: String,
+    san: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mate: Option<i32>,
+    depth: u32,
+}
+
+/// Best moves in the position of `GET /top-moves?fen=...`, for tools that
+/// want evaluations without speaking UCI. Takes over the engine like a new
+/// session, authenticated like one.
+pub async fn handler(
+    engine: Arc<SharedEngine>,
+    authenticator: Arc<dyn Authenticator>,
+    Query(params): Query<Params>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Result<Json<TopMoves>, (StatusCode, String)> {
+    let identity = authenticator
+        .authenticate(&AuthRequest {
+            headers,
+            query: serde_urlencoded::from_str(query.as_deref().unwrap_or_default())
+                .map_err(|_| (StatusCode::BAD_REQUEST, "invalid query".to_owned()))?,
+        })
+        .await
+        .map_err(|status| (status, "not authorized".to_owned()))?;
+    if engine.is_paused() || !engine.health().is_running() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "engine not available".to_owned(),
+        ));
+    }
+
+    let fen: Fen = params.fen.parse().map_err(|err| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("invalid fen: {err}"),
+        )
+    })?;
+    let pos: Chess = fen
+        .position(CastlingMode::Chess960)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    let fen = Fen::from_position(pos.clone(), EnPassantMode::Legal);
+
+    let max_moves = identity
+        .limits
+        .max_multipv
+        .map_or(u32::MAX, |max| max.max(1));
+    let max_moves = min(
+        max_moves,
+        u32::try_from(engine.info().max_multipv()).unwrap_or(1),
+    );
+    let moves = params.moves.unwrap_or(DEFAULT_MOVES).clamp(1, max_moves);
+    let movetime = params
+        .movetime
+        .map_or(DEFAULT_MOVETIME, Duration::from_millis)
+        .min(MAX_MOVETIME);
+
+    let output = engine
+        .search_once(
+            &identity.client,
+            &identity.limits,
+            vec![
+                UciIn::Setoption {
+                    name: UciOptionName("MultiPV".to_owned()),
+                    value: Some(moves.to_string()),
+                },
+                UciIn::Position {
+                    fen: Some(fen.clone()),
+                    moves: Vec::new(),
+                },
+                UciIn::Go {
+                    searchmoves: None,
+                    ponder: false,
+                    wtime: None,
+                    btime: None,
+                    winc: None,
+                    binc: None,
+                    movestogo: None,
+                    depth: None,
+                    nodes: None,
+                    mate: None,
+                    movetime: Some(movetime),
+                    infinite: false,
+                },
+            ],
+        )
+        .await
+        .map_err(|err| {
+            log::error!("Search for top moves failed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+
+    Ok(Json(TopMoves {
+        fen: fen.to_string(),
+        moves: top_moves(&pos, output),
+    }))
+}
+
+/// The last scored line of each MultiPV index, best first.
+fn top_moves(pos: &Chess, output: Vec<UciOut>) -> Vec<TopMove> {
+    let mut lines = BTreeMap::new();
+    for command in output {
+        if let UciOut::Info {
+            multipv,
+            depth,
+            score: Some(score),
+            pv: Some(pv),
+            ..
+        } = command
+        {
+            if let Some(first) = pv.into_iter().next() {
+                lines.insert(
+                    multipv.map_or(1, |multipv| multipv.get()),
+                    (first, depth, score.eval),
+                );
+            }
+        }
+    }
+    lines
+        .into_values()
+        .filter_map(|(uci, depth, eval)| {
+            let m = uci.to_move(pos).ok()?;
+            Some(TopMove {
+                uci: uci.to_string(),
+                san: SanPlus::from_move(pos.clone(), &m).to_string(),
+                cp: match eval {
+                    Eval::Cp(cp) => Some(cp),
+                    Eval::Mate(_) => None,
+                },
+                mate: match eval {
+                    Eval::Mate(mate) => Some(mate),
+                    Eval::Cp(_) => None,
+                },
+                depth: depth.unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_moves() {
+        let output = [
+            "info depth 10 multipv 1 score cp 30 pv e2e4 e7e5",
+            "info depth 10 multipv 2 score cp 25 pv d2d4",
+            "info depth 11 multipv 1 score cp 28 pv g1f3",
+            "info depth 11 currmove e2e4 currmovenumber 2",
+            "info depth 11 multipv 2 score mate -9 pv f2f3",
+        ]
+        .into_iter()
+        .map(|line| UciOut::from_line(line).unwrap().unwrap())
+        .collect();
+        assert_eq!(
+            top_moves(&Chess::default(), output),
+            [
+                TopMove {
+                    uci: "g1f3".to_owned(),
+                    san: "Nf3".to_owned(),
+                    cp: Some(28),
+                    mate: None,
+                    depth: 11,
+                },
+                TopMove {
+                    uci: "f2f3".to_owned(),
+                    san: "f3".to_owned(),
+                    cp: None,
+                    mate: Some(-9),
+                    depth: 11,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll},
+};
+
+use axum::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdout, Command},
+};
+
+pub type EngineReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type EngineWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How to reach the engine. Embedders can implement this for engines that
+/// are not local processes, e.g. behind a TCP socket or in a container.
+#[async_trait]
+pub trait EngineTransport: fmt::Display + Send + Sync {
+    /// Start a new instance of the engine, and return its output and input.
+    /// The engine should exit when the input is closed.
+    async fn connect(&self) -> io::Result<(EngineReader, EngineWriter)>;
+
+    /// Id of the most recently started instance, if it is a local process.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// Engine running as a child process, talking UCI on stdin and stdout.
+pub struct Process {
+    path: PathBuf,
+    envs: Vec<(String, String)>,
+    pid: AtomicU32,
+}
+
+impl Process {
+    pub fn new(path: PathBuf) -> Process {
+        Process {
+            path,
+            envs: Vec::new(),
+            pid: AtomicU32::new(0),
+        }
+    }
+
+    /// Set an environment variable for the engine.
+    pub fn env(mut self, key: &str, value: &str) -> Process {
+        self.envs.push((key.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.path)
+    }
+}
+
+#[async_trait]
+impl EngineTransport for Process {
+    async fn connect(&self) -> io::Result<(EngineReader, EngineWriter)> {
+        let mut process = Command::new(&self.path)
+            .envs(self.envs.iter().map(|(key, value)| (key, value)))
+            .stdout(Stdio::piped())
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        self.pid
+            .store(process.id().unwrap_or_default(), Ordering::Relaxed);
+        let stdin = process
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "engine stdin closed"))?;
+        let stdout = process
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "engine stdout closed"))?;
+        Ok((
+            Box::new(ProcessOutput {
+                stdout,
+                _process: process,
+            }),
+            Box::new(stdin),
+        ))
+    }
+
+    fn pid(&self) -> Option<u32> {
+        Some(self.pid.load(Ordering::Relaxed)).filter(|pid| *pid != 0)
+    }
+}
+
+/// Output of an engine process. The process is killed once its output is
+/// dropped, so that engines that do not exit when their input is closed
+/// do not linger.
+struct ProcessOutput {
+    stdout: ChildStdout,
+    _process: Child,
+}
+
+impl AsyncRead for ProcessOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
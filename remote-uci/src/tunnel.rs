@@ -0,0 +1,136 @@
+use std::{error::Error, time::Duration};
+
+use axum::Router;
+use futures_util::{future, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION},
+    server::conn::Http,
+};
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time::sleep,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, Message},
+};
+
+use crate::{invite, ws::Secret};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What a WebSocket message means for the stream tunnelled through it.
+pub enum Frame {
+    Data(Vec<u8>),
+    Close,
+    Other,
+}
+
+/// The address under which clients can reach the engine through the relay.
+pub fn public_url(relay: &str, id: &str) -> String {
+    format!("{}/p/{id}/socket", relay.trim_end_matches('/'))
+}
+
+/// Token that the provider presents to the relay. The relay remembers the
+/// first token for each id, so it is derived from the secret, and stays the
+/// same across restarts with --secret-file.
+pub fn token(secret: &Secret, id: &str) -> Secret {
+    invite::sign(secret, &format!("relay.{id}"))
+}
+
+/// Keep a control connection to the relay open, and serve each session that
+/// the relay announces over a new outbound connection.
+pub async fn serve(relay: String, id: String, token: Secret, app: Router) {
+    let relay = relay.trim_end_matches('/');
+    loop {
+        match serve_once(relay, &id, &token, app.clone()).await {
+            Ok(()) => log::warn!("Relay {relay} closed the connection"),
+            Err(tungstenite::Error::Http(res)) if res.status() == 403 => {
+                log::error!("Relay {relay} rejected the token for {id}, which is registered by another provider");
+            }
+            Err(err) => log::error!("Relay connection failed: {err}"),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn serve_once(
+    relay: &str,
+    id: &str,
+    token: &Secret,
+    app: Router,
+) -> Result<(), tungstenite::Error> {
+    let mut req = format!("{relay}/provide/{id}").into_client_request()?;
+    req.headers_mut().insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token.0)).expect("token header"),
+    );
+    let (mut control, _) = connect_async(req).await?;
+    log::info!("Connected to relay {relay} as {id}");
+    while let Some(message) = control.next().await {
+        match message? {
+            Message::Text(token) => {
+                let url = format!("{relay}/accept/{token}");
+                let app = app.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = accept(url, app).await {
+                        log::error!("Relayed connection failed: {err}");
+                    }
+                });
+            }
+            Message::Close(_) => break,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+async fn accept(url: String, app: Router) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (socket, _) = connect_async(url).await?;
+    let socket = socket
+        .map_ok(|message| match message {
+            Message::Binary(data) => Frame::Data(data),
+            Message::Close(_) => Frame::Close,
+            _ => Frame::Other,
+        })
+        .with(|data| future::ok::<_, tungstenite::Error>(Message::Binary(data)));
+    let (local, remote) = duplex(64 * 1024);
+    tokio::spawn(bridge(socket, remote));
+    Http::new()
+        .http1_only(true)
+        .serve_connection(local, app)
+        .with_upgrades()
+        .await?;
+    Ok(())
+}
+
+/// Pump bytes between binary WebSocket messages and a local stream.
+pub async fn bridge<S, E>(mut socket: S, io: DuplexStream)
+where
+    S: Stream<Item = Result<Frame, E>> + Sink<Vec<u8>> + Unpin,
+{
+    let (mut reader, mut writer) = split(io);
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        tokio::select! {
+            frame = socket.next() => match frame {
+                Some(Ok(Frame::Data(data))) => {
+                    if writer.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Frame::Close) | Err(_)) | None => break,
+                Some(Ok(Frame::Other)) => (),
+            },
+            n = reader.read(&mut buf) => match n {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if socket.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+    let _ = socket.close().await;
+}
@@ -1,19 +1,21 @@
 use std::{
-    collections::HashMap,
     fmt,
     hash::{Hash, Hasher},
+    mem,
     num::{NonZeroU32, ParseIntError},
     time::Duration,
 };
 
 use memchr::{memchr2, memchr2_iter};
+use serde::{Deserialize, Serialize};
 use shakmaty::{
     fen::{Fen, ParseFenError},
     uci::{ParseUciError, Uci},
 };
 use thiserror::Error;
 
-#[derive(Clone, Debug, Eq)]
+#[derive(Clone, Debug, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct UciOptionName(pub String);
 
 impl UciOptionName {
@@ -60,7 +62,8 @@ impl fmt::Display for UciOptionName {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum UciOption {
     Check { default: bool },
     Spin { default: i64, min: i64, max: i64 },
@@ -112,6 +115,17 @@ impl UciOption {
         }
     }
 
+    pub fn default_value(&self) -> Option<String> {
+        match self {
+            UciOption::Check { default } => Some(default.to_string()),
+            UciOption::Spin { default, .. } => Some(default.to_string()),
+            UciOption::Combo { default, .. } | UciOption::String { default } => {
+                Some(default.clone())
+            }
+            UciOption::Button => None,
+        }
+    }
+
     pub fn var(&self) -> Option<&[String]> {
         match self {
             UciOption::Combo { var, .. } => Some(var),
@@ -119,6 +133,14 @@ impl UciOption {
         }
     }
 
+    pub fn clamp(&self, value: &mut Option<String>) {
+        if let (UciOption::Spin { min, max, .. }, Some(v)) = (self, value.as_mut()) {
+            if let Ok(requested) = v.parse::<i64>() {
+                *v = requested.max(*min).min(*max).to_string();
+            }
+        }
+    }
+
     pub fn limit_max(&mut self, limit: i64) {
         if let UciOption::Spin { min, max, default } = self {
             *max = limit.clamp(*min, *max);
@@ -156,9 +178,21 @@ pub enum UciOptionValue {
     String(String),
 }
 
+impl fmt::Display for UciOptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciOptionValue::Check(value) => value.fmt(f),
+            UciOptionValue::Spin(value) => value.fmt(f),
+            UciOptionValue::Combo(value) | UciOptionValue::String(value) => f.write_str(value),
+            UciOptionValue::Button => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UciIn {
     Uci,
+    Debug(bool),
     Isready,
     Setoption {
         name: UciOptionName,
@@ -197,6 +231,7 @@ impl fmt::Display for UciIn {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             UciIn::Uci => f.write_str("uci"),
+            UciIn::Debug(on) => f.write_str(if *on { "debug on" } else { "debug off" }),
             UciIn::Isready => f.write_str("isready"),
             UciIn::Setoption { name, value } => {
                 write!(f, "setoption name {name}")?;
@@ -287,6 +322,19 @@ pub enum Eval {
     Mate(i32),
 }
 
+impl Eval {
+    /// Centipawns from the point of view of the side to move, with mates
+    /// ranked beyond any regular evaluation.
+    pub fn to_cp(&self) -> i64 {
+        const MATE_CP: i64 = 100_000;
+        match *self {
+            Eval::Cp(cp) => cp.clamp(-MATE_CP + 1000, MATE_CP - 1000),
+            Eval::Mate(mate) if mate > 0 => MATE_CP - i64::from(mate),
+            Eval::Mate(mate) => -MATE_CP - i64::from(mate),
+        }
+    }
+}
+
 impl fmt::Display for Eval {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -298,9 +346,20 @@ impl fmt::Display for Eval {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Score {
-    eval: Eval,
-    lowerbound: bool,
-    upperbound: bool,
+    pub eval: Eval,
+    pub lowerbound: bool,
+    pub upperbound: bool,
+}
+
+impl Score {
+    /// Turn the score into one from the point of view of the other side.
+    pub fn flip(&mut self) {
+        self.eval = match self.eval {
+            Eval::Cp(cp) => Eval::Cp(-cp),
+            Eval::Mate(mate) => Eval::Mate(-mate),
+        };
+        mem::swap(&mut self.lowerbound, &mut self.upperbound);
+    }
 }
 
 impl fmt::Display for Score {
@@ -341,8 +400,8 @@ pub enum UciOut {
         tbhits: Option<u64>,
         sbhits: Option<u64>,
         cpuload: Option<u32>,
-        refutation: HashMap<Uci, Vec<Uci>>,
-        currline: HashMap<u32, Vec<Uci>>,
+        refutation: Vec<(Uci, Vec<Uci>)>,
+        currline: Vec<(u32, Vec<Uci>)>,
         pv: Option<Vec<Uci>>,
         string: Option<String>,
     },
@@ -356,6 +415,28 @@ impl UciOut {
     pub fn from_line(s: &str) -> Result<Option<UciOut>, ProtocolError> {
         Parser::new(s)?.parse_out()
     }
+
+    pub fn info_string(string: String) -> UciOut {
+        UciOut::Info {
+            multipv: None,
+            depth: None,
+            seldepth: None,
+            time: None,
+            nodes: None,
+            score: None,
+            currmove: None,
+            currmovenumber: None,
+            hashfull: None,
+            nps: None,
+            tbhits: None,
+            sbhits: None,
+            cpuload: None,
+            refutation: Vec::new(),
+            currline: Vec::new(),
+            pv: None,
+            string: Some(string),
+        }
+    }
 }
 
 impl fmt::Display for UciOut {
@@ -669,6 +750,16 @@ impl<'a> Parser<'a> {
                 self.end()?;
                 UciIn::Uci
             }
+            Some("debug") => {
+                let on = match self.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    Some(_) => return Err(ProtocolError::UnexpectedToken),
+                    None => return Err(ProtocolError::UnexpectedEndOfLine),
+                };
+                self.end()?;
+                UciIn::Debug(on)
+            }
             Some("isready") => {
                 self.end()?;
                 UciIn::Isready
@@ -866,8 +957,8 @@ impl<'a> Parser<'a> {
         let mut tbhits = None;
         let mut sbhits = None;
         let mut cpuload = None;
-        let mut refutation = HashMap::new();
-        let mut currline = HashMap::new();
+        let mut refutation = Vec::new();
+        let mut currline = Vec::new();
         let mut pv = None;
         let mut string = None;
         loop {
@@ -958,20 +1049,20 @@ impl<'a> Parser<'a> {
                     )
                 }
                 Some("refutation") => {
-                    refutation.insert(
+                    refutation.push((
                         self.next()
                             .ok_or(ProtocolError::UnexpectedEndOfLine)?
                             .parse()?,
                         self.parse_moves(),
-                    );
+                    ));
                 }
                 Some("currline") => {
-                    currline.insert(
+                    currline.push((
                         self.next()
                             .ok_or(ProtocolError::UnexpectedEndOfLine)?
                             .parse()?,
                         self.parse_moves(),
-                    );
+                    ));
                 }
                 Some("pv") => pv = Some(self.parse_moves()),
                 Some("string") => {
@@ -1125,6 +1216,14 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_debug() -> Result<(), ProtocolError> {
+        assert_eq!(UciIn::from_line("debug on")?, Some(UciIn::Debug(true)));
+        assert_eq!(UciIn::from_line("debug off")?, Some(UciIn::Debug(false)));
+        assert!(UciIn::from_line("debug").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_option() -> Result<(), ProtocolError> {
         assert_eq!(
@@ -1140,4 +1239,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_currline_order() -> Result<(), ProtocolError> {
+        let line = "info refutation d1h5 g6h5 refutation f1c4 currline 3 g1f3 currline 1 e2e4 e7e5 currline 2 d2d4";
+        let info = UciOut::from_line(line)?.expect("info");
+        assert_eq!(info.to_string(), line);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamp() {
+        let option = UciOption::Spin {
+            default: 1,
+            min: 1,
+            max: 5,
+        };
+        let mut value = Some("500".to_owned());
+        option.clamp(&mut value);
+        assert_eq!(value.as_deref(), Some("5"));
+        let mut value = Some("x".to_owned());
+        option.clamp(&mut value);
+        assert_eq!(value.as_deref(), Some("x"));
+    }
 }
@@ -0,0 +1,119 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, UdpSocket},
+    time::Duration,
+};
+
+use igd::{Gateway, PortMappingProtocol, SearchOptions};
+use thiserror::Error;
+use tokio::{task, time::sleep};
+
+/// Port mappings expire after this duration, unless renewed.
+const LEASE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+const DESCRIPTION: &str = "remote-uci";
+
+#[derive(Error, Debug)]
+pub enum UpnpError {
+    #[error("upnp request failed: {0}")]
+    Igd(#[from] igd::Error),
+    #[error("could not determine local address: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A TCP port mapping on the router, pointing at this machine.
+pub struct PortMapping {
+    gateway: Gateway,
+    local_addr: SocketAddrV4,
+    external_addr: SocketAddrV4,
+}
+
+impl PortMapping {
+    fn request(port: u16) -> Result<PortMapping, UpnpError> {
+        let gateway = igd::search_gateway(SearchOptions::default()).map_err(igd::Error::from)?;
+        let local_addr = SocketAddrV4::new(local_ip_towards(gateway.addr)?, port);
+        let external_ip = gateway.get_external_ip().map_err(igd::Error::from)?;
+
+        // Prefer the same external port, so that the published address stays
+        // the same across restarts.
+        let external_port = match gateway.add_port(
+            PortMappingProtocol::TCP,
+            port,
+            local_addr,
+            LEASE_DURATION.as_secs() as u32,
+            DESCRIPTION,
+        ) {
+            Ok(()) => port,
+            Err(err) => {
+                log::debug!("Could not map external port {port}: {err}");
+                gateway
+                    .add_any_port(
+                        PortMappingProtocol::TCP,
+                        local_addr,
+                        LEASE_DURATION.as_secs() as u32,
+                        DESCRIPTION,
+                    )
+                    .map_err(igd::Error::from)?
+            }
+        };
+
+        Ok(PortMapping {
+            gateway,
+            local_addr,
+            external_addr: SocketAddrV4::new(external_ip, external_port),
+        })
+    }
+
+    fn renew(&self) -> Result<(), UpnpError> {
+        self.gateway
+            .add_port(
+                PortMappingProtocol::TCP,
+                self.external_addr.port(),
+                self.local_addr,
+                LEASE_DURATION.as_secs() as u32,
+                DESCRIPTION,
+            )
+            .map_err(igd::Error::from)?;
+        Ok(())
+    }
+
+    pub fn external_addr(&self) -> SocketAddrV4 {
+        self.external_addr
+    }
+}
+
+/// Find the address of the interface that routes to the gateway.
+fn local_ip_towards(gateway: SocketAddrV4) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(gateway)?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no ipv4 route to gateway",
+        )),
+    }
+}
+
+/// Ask the router to forward the given local port.
+pub async fn map_port(port: u16) -> Result<PortMapping, UpnpError> {
+    task::spawn_blocking(move || PortMapping::request(port))
+        .await
+        .expect("join upnp request")
+}
+
+/// Renew the port mapping well before the lease expires.
+pub async fn keep_mapped(mut mapping: PortMapping) {
+    loop {
+        sleep(LEASE_DURATION / 2).await;
+        mapping = task::spawn_blocking(move || {
+            match mapping.renew() {
+                Ok(()) => log::debug!("Renewed port mapping {}", mapping.external_addr),
+                Err(err) => log::error!("Could not renew port mapping: {err}"),
+            }
+            mapping
+        })
+        .await
+        .expect("join upnp renewal");
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    config::GoPolicy,
+    engine::{Engine, EngineParameters, InfoFilter, Perspective, Session},
+    privacy::Privacy,
+    transport::Process,
+    uci::{Eval, UciIn, UciOut},
+};
+
+/// A finished search of the primary engine.
+struct Job {
+    position: UciIn,
+    eval: Eval,
+}
+
+/// Handle to a secondary engine, that re-searches final positions of the
+/// primary engine at low depth, and logs large disagreements.
+#[derive(Clone)]
+pub struct Verifier {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Verifier {
+    pub async fn start(
+        path: PathBuf,
+        depth: u32,
+        threshold: u32,
+        privacy: Privacy,
+    ) -> io::Result<Verifier> {
+        let engine = Engine::new(
+            Arc::new(Process::new(path)),
+            EngineParameters {
+                max_threads: u32::MAX,
+                max_hash: u32::MAX,
+                max_multipv: u32::MAX,
+                info_filter: InfoFilter::Aggressive,
+                score_perspective: Perspective::SideToMove,
+                aliases: HashMap::new(),
+                thread_budget: None,
+                thread_ledger: None,
+                nps_monitor: None,
+                searching: Arc::new(watch::channel(false).0),
+                nps: Arc::new(AtomicU64::new(0)),
+                latency: Arc::default(),
+                verifier: None,
+                history: None,
+                cpu_budget: None,
+                options: HashMap::new(),
+                client_options: true,
+                go_policy: GoPolicy::default(),
+                strict_uci: false,
+                standby: None,
+                privacy,
+            },
+        )
+        .await?;
+        log::info!(
+            "Verifying with {} at depth {depth}",
+            engine.name().unwrap_or("unknown engine")
+        );
+
+        // Verification is best effort. Skip positions while the secondary
+        // engine is still busy.
+        let (jobs, mut rx) = mpsc::channel::<Job>(1);
+        tokio::spawn(async move {
+            let mut engine = engine;
+            while let Some(job) = rx.recv().await {
+                match verify(&mut engine, &job.position, depth).await {
+                    Ok(Some(eval)) if disagree(&job.eval, &eval, threshold) => log::warn!(
+                        "Verification disagrees on {}: {} vs {} at depth {depth}",
+                        privacy.redact(&job.position),
+                        job.eval,
+                        eval
+                    ),
+                    Ok(_) => (),
+                    Err(err) => {
+                        log::error!("Verification engine failed: {err}");
+                        if let Err(err) = engine.respawn().await {
+                            log::error!("Could not restart verification engine: {err}");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Verifier { jobs })
+    }
+
+    pub fn submit(&self, position: UciIn, eval: Eval) {
+        let _ = self.jobs.try_send(Job { position, eval });
+    }
+}
+
+async fn verify(engine: &mut Engine, position: &UciIn, depth: u32) -> io::Result<Option<Eval>> {
+    let session = Session(0);
+    engine.ensure_newgame(session).await?;
+    engine.send(session, position.clone()).await?;
+    engine
+        .send(
+            session,
+            UciIn::Go {
+                searchmoves: None,
+                ponder: false,
+                wtime: None,
+                btime: None,
+                winc: None,
+                binc: None,
+                movestogo: None,
+                depth: Some(depth),
+                nodes: None,
+                mate: None,
+                movetime: None,
+                infinite: false,
+            },
+        )
+        .await?;
+    let mut last = None;
+    loop {
+        match engine.recv(session).await? {
+            UciOut::Info {
+                multipv,
+                score: Some(score),
+                ..
+            } if multipv.map_or(true, |n| n.get() == 1)
+                && !score.lowerbound
+                && !score.upperbound =>
+            {
+                last = Some(score.eval)
+            }
+            UciOut::Bestmove { .. } => return Ok(last),
+            _ => (),
+        }
+    }
+}
+
+fn disagree(a: &Eval, b: &Eval, threshold: u32) -> bool {
+    (a.to_cp() - b.to_cp()).abs() > i64::from(threshold)
+}
@@ -0,0 +1,22 @@
+use wasm_bindgen::prelude::*;
+
+use crate::uci::{UciIn, UciOut};
+
+/// Parse a command sent to the engine, and format it in canonical form.
+/// Returns `undefined` for empty lines, and throws for invalid commands.
+#[wasm_bindgen(js_name = parseUciIn)]
+pub fn parse_uci_in(line: &str) -> Result<Option<String>, JsValue> {
+    UciIn::from_line(line)
+        .map(|command| command.map(|c| c.to_string()))
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// Parse a line of engine output, and format it in canonical form.
+/// Returns `undefined` for empty lines and unknown commands, and throws for
+/// invalid commands.
+#[wasm_bindgen(js_name = parseUciOut)]
+pub fn parse_uci_out(line: &str) -> Result<Option<String>, JsValue> {
+    UciOut::from_line(line)
+        .map(|command| command.map(|c| c.to_string()))
+        .map_err(|err| JsValue::from_str(&err.to_string()))
+}
@@ -0,0 +1,74 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{sync::Notify, time::timeout};
+
+use crate::ws::SharedEngine;
+
+/// Time without further changes, before the engine executable is considered
+/// completely written.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(err) if path.components().count() == 1 => {
+            // Bare executable names are looked up in PATH.
+            env::var_os("PATH")
+                .iter()
+                .flat_map(env::split_paths)
+                .map(|dir| dir.join(path))
+                .find_map(|candidate| fs::canonicalize(candidate).ok())
+                .ok_or(err)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn watch_engine(path: &Path, shared_engine: Arc<SharedEngine>) -> notify::Result<()> {
+    let path = resolve(path)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| notify::Error::path_not_found().add_path(path.clone()))?
+        .to_owned();
+
+    let changed = Arc::new(Notify::new());
+    let mut watcher = RecommendedWatcher::new(
+        {
+            let changed = Arc::clone(&changed);
+            let path = path.clone();
+            move |res: notify::Result<Event>| match res {
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => (),
+                Ok(event) if event.paths.contains(&path) => changed.notify_one(),
+                Ok(_) => (),
+                Err(err) => log::error!("Engine watcher: {err}"),
+            }
+        },
+        notify::Config::default(),
+    )?;
+
+    // Watch the directory rather than the file itself, so that the
+    // executable can be atomically replaced.
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    log::info!("Watching engine {path:?} for changes");
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        loop {
+            changed.notified().await;
+            while timeout(SETTLE_TIME, changed.notified()).await.is_ok() {}
+            log::warn!("Engine {path:?} changed, restarting when idle ...");
+            match shared_engine.respawn().await {
+                Ok(()) => log::warn!("Engine restarted"),
+                Err(err) => log::error!("Could not restart engine, keeping previous: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -1,57 +1,444 @@
 use std::{
     io,
     iter::zip,
+    net::IpAddr,
+    num::NonZeroU32,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Query,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, RawQuery,
     },
-    http::StatusCode,
-    response::IntoResponse,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
 };
+use futures_util::{stream, Sink, SinkExt, Stream, StreamExt};
 use rand::random;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::{
-    sync::{Mutex, MutexGuard, Notify},
-    time::{interval, MissedTickBehavior},
+    sync::{watch, Mutex, MutexGuard, Notify},
+    time::{interval, sleep, timeout, MissedTickBehavior},
 };
 
 use crate::{
-    engine::{Engine, Session},
+    auth::{AuthRequest, Authenticator, Identity},
+    baseline::{NpsMonitor, NpsMonitorSnapshot},
+    config::{Config, Preset},
+    delay::{Delayed, SimulatedLatency},
+    engine::{Engine, EngineInfo, InfoFilter, Perspective, Session, SessionLimits, SessionState},
+    gpu::{GpuMonitor, GpuSnapshot},
+    gzip,
+    latency::{Latency, LatencySnapshot},
+    linger::{Handoff, Lingering},
+    metrics::{Connection, Registration, Registry, Snapshot},
+    middleware::Chain,
+    notifications::{Notification, Notifications},
+    outbox::Outbox,
+    ponder::{Cached, Ended, Ponder},
+    pool::{self, Pool},
+    privacy::Privacy,
+    proxy::ClientIp,
+    split,
+    supervisor::{self, Health},
     uci::{UciIn, UciOut},
 };
 
+/// Time for the engine to answer isready after the host resumed from sleep.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Times a session transparently replaces a crashed engine, before giving
+/// up, e.g. because the engine crashes on the position of the session.
+const MAX_RESTORES: u32 = 2;
+
+/// Subprotocol for clients that know about protocol versions. Sessions
+/// start with an info string announcing the version, so that later
+/// versions can add features without breaking older clients.
+const PROTOCOL_V1: &str = "remote-uci.v1";
+
+/// Subprotocol negotiated on upgrade. Clients that do not ask for one
+/// speak plain UCI.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+enum Protocol {
+    #[default]
+    Plain,
+    V1,
+    V1Gzip,
+}
+
+impl Protocol {
+    fn from_selected(selected: Option<&HeaderValue>) -> Protocol {
+        match selected.and_then(|value| value.to_str().ok()) {
+            Some(PROTOCOL_V1) => Protocol::V1,
+            Some(gzip::PROTOCOL) => Protocol::V1Gzip,
+            _ => Protocol::Plain,
+        }
+    }
+
+    fn version(self) -> Option<u32> {
+        match self {
+            Protocol::Plain => None,
+            Protocol::V1 | Protocol::V1Gzip => Some(1),
+        }
+    }
+}
+
 pub struct SharedEngine {
     session: AtomicU64,
     notify: Notify,
+    info: watch::Sender<Arc<EngineInfo>>,
+    resumed: watch::Sender<()>,
+    health: watch::Sender<Health>,
+    failed: Notify,
+    paused: watch::Sender<bool>,
+    nps: Arc<AtomicU64>,
+    latency: Arc<Latency>,
+    nps_monitor: Option<Arc<NpsMonitor>>,
+    metrics: Registry,
+    middleware: Chain,
+    simulated_latency: Option<SimulatedLatency>,
+    gpu_monitor: Option<GpuMonitor>,
+    lingering: Option<Lingering>,
+    ponder_ahead: bool,
+    notifications: Notifications,
+    privacy: Privacy,
     engine: Mutex<Engine>,
 }
 
 impl SharedEngine {
-    pub fn new(engine: Engine) -> SharedEngine {
+    pub fn new(engine: Engine, middleware: Chain) -> SharedEngine {
         SharedEngine {
             session: AtomicU64::new(0),
             notify: Notify::new(),
+            info: watch::channel(Arc::new(engine.info())).0,
+            resumed: watch::channel(()).0,
+            health: watch::channel(Health::Running).0,
+            failed: Notify::new(),
+            paused: watch::channel(false).0,
+            nps: engine.measured_nps(),
+            latency: engine.latency(),
+            nps_monitor: engine.nps_monitor(),
+            metrics: Registry::default(),
+            middleware,
+            simulated_latency: None,
+            gpu_monitor: None,
+            lingering: None,
+            ponder_ahead: false,
+            notifications: Notifications::default(),
+            privacy: engine.privacy(),
             engine: Mutex::new(engine),
         }
     }
+
+    /// Information about the engine, available without waiting for the
+    /// current session.
+    pub fn info(&self) -> Arc<EngineInfo> {
+        Arc::clone(&self.info.borrow())
+    }
+
+    /// Delay all messages of sessions, to try clients against a slow
+    /// connection.
+    pub fn simulate_latency(&mut self, latency: SimulatedLatency) {
+        self.simulated_latency = Some(latency);
+    }
+
+    /// Keep infinite searches running for up to the given duration after
+    /// their client disconnected, and replay their output when it
+    /// reconnects.
+    pub fn continue_on_disconnect(&mut self, duration: Duration) {
+        self.lingering = Some(Lingering::new(duration));
+    }
+
+    /// After each search of a client, ponder the position after the best
+    /// move, in case the client continues with it.
+    pub fn ponder_ahead(&mut self) {
+        self.ponder_ahead = true;
+    }
+
+    pub fn monitor_gpu(&mut self, monitor: GpuMonitor) {
+        self.gpu_monitor = Some(monitor);
+    }
+
+    /// Tell the operator about sessions and crashes.
+    pub fn set_notifications(&mut self, notifications: Notifications) {
+        self.notifications = notifications;
+    }
+
+    pub fn privacy(&self) -> Privacy {
+        self.privacy
+    }
+
+    /// Subscribe to changes of the engine information, e.g. after a restart.
+    pub fn watch_info(&self) -> watch::Receiver<Arc<EngineInfo>> {
+        self.info.subscribe()
+    }
+
+    /// Speed of the most recent search, for comparing providers.
+    pub fn nps(&self) -> Option<u64> {
+        Some(self.nps.load(Ordering::Relaxed)).filter(|nps| *nps > 0)
+    }
+
+    /// How long the engine takes to reply to commands.
+    pub fn latency(&self) -> LatencySnapshot {
+        self.latency.snapshot()
+    }
+
+    /// How the speed of recent searches compares to the benchmark, if
+    /// there is one.
+    pub fn nps_baseline(&self) -> Option<NpsMonitorSnapshot> {
+        self.nps_monitor.as_ref().map(|monitor| monitor.snapshot())
+    }
+
+    pub fn gpu(&self) -> Option<GpuSnapshot> {
+        self.gpu_monitor.as_ref().and_then(GpuMonitor::snapshot)
+    }
+
+    /// Traffic and latency of all open connections.
+    pub fn sessions(&self) -> Vec<Snapshot> {
+        self.metrics.snapshots()
+    }
+
+    /// Open connections, with their ids and clients.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.metrics.connections()
+    }
+
+    /// Close the connection with the given id, if it is still open.
+    pub fn kick(&self, id: u64) -> bool {
+        self.metrics.kick(id)
+    }
+
+    /// Close all connections of the client.
+    pub fn kick_guest(&self, guest: &str) {
+        self.metrics.kick_guest(guest)
+    }
+
+    /// Whether a session is currently using the engine.
+    pub fn is_busy(&self) -> bool {
+        self.engine.try_lock().is_err()
+    }
+
+    /// Whether the engine is running, or waiting to be restarted.
+    pub fn health(&self) -> Health {
+        self.health.borrow().clone()
+    }
+
+    /// Subscribe to changes of the health, e.g. for status displays.
+    #[cfg(feature = "dbus")]
+    pub fn watch_health(&self) -> watch::Receiver<Health> {
+        self.health.subscribe()
+    }
+
+    /// Whether new sessions are refused, because the operator needs the
+    /// machine for something else.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Refuse new sessions and close open ones, or accept sessions again.
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.send_replace(paused) != paused {
+            if paused {
+                log::warn!("Paused, closing all sessions");
+                self.metrics.kick_all();
+                // Stops idle analysis.
+                self.notify.notify_waiters();
+            } else {
+                log::warn!("Accepting sessions again");
+            }
+        }
+    }
+
+    #[cfg(feature = "dbus")]
+    pub fn watch_paused(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+
+    pub fn set_health(&self, health: Health) {
+        self.health.send_replace(health);
+    }
+
+    /// Hand the engine over to the supervisor, which restarts it.
+    pub fn report_failure(&self, err: &io::Error) {
+        if self.health.borrow().is_running() {
+            log::error!("Engine failed, restarting: {err}");
+            self.notifications.send(Notification::EngineCrashed {
+                error: err.to_string(),
+            });
+            self.set_health(Health::Restarting {
+                attempts: 0,
+                error: err.to_string(),
+                retry_in_secs: 0,
+            });
+            self.failed.notify_one();
+        }
+    }
+
+    /// Wait until the engine is reported as failed.
+    pub async fn failed(&self) {
+        self.failed.notified().await;
+    }
+
+    /// Replace the engine process, waiting until no session is using it.
+    pub async fn respawn(&self) -> io::Result<()> {
+        let mut engine = self.engine.lock().await;
+        engine.respawn().await?;
+        self.info.send_replace(Arc::new(engine.info()));
+        Ok(())
+    }
+
+    /// Replace the failed engine process, with the standby engine if there
+    /// is one.
+    pub async fn fail_over(&self) -> io::Result<()> {
+        let mut engine = self.engine.lock().await;
+        engine.fail_over().await?;
+        self.info.send_replace(Arc::new(engine.info()));
+        Ok(())
+    }
+
+    /// Close sessions that are likely stale after the host resumed from
+    /// sleep, and restart the engine if it no longer responds.
+    pub async fn resync(&self) -> io::Result<()> {
+        self.resumed.send_replace(());
+        let mut engine = self.engine.lock().await;
+        match timeout(PROBE_TIMEOUT, engine.probe(Session(0))).await {
+            Ok(Ok(())) => {
+                log::info!("Engine still responding after resume");
+                return Ok(());
+            }
+            Ok(Err(err)) => log::error!("Engine failed after resume, restarting: {err}"),
+            Err(_) => log::error!("Engine not responding after resume, restarting"),
+        }
+        engine.respawn().await?;
+        self.info.send_replace(Arc::new(engine.info()));
+        Ok(())
+    }
+
+    /// Send the commands of a search on behalf of the operator, and wait
+    /// until it is done. Gives up the engine as soon as a client starts a
+    /// session, or does not start if one is in progress. Returns whether
+    /// the search completed.
+    pub async fn search_idle(&self, commands: Vec<UciIn>) -> io::Result<bool> {
+        if self.is_paused() {
+            return Ok(false);
+        }
+        let mut engine = match self.engine.try_lock() {
+            Ok(engine) => engine,
+            Err(_) => return Ok(false),
+        };
+        let session = Session(self.session.fetch_add(1, Ordering::SeqCst) + 1);
+        log::info!("{}: starting idle analysis", session.0);
+        engine.ensure_newgame(session).await?;
+        for command in commands {
+            engine.send_dangerous(session, command).await?;
+        }
+        loop {
+            tokio::select! {
+                command = engine.recv(session) => {
+                    if let UciOut::Bestmove { .. } = command? {
+                        return Ok(true);
+                    }
+                }
+                _ = self.notify.notified() => {
+                    if self.is_paused() {
+                        log::warn!("{}: paused, stopping idle analysis", session.0);
+                        engine.ensure_idle(session).await?;
+                        return Ok(false);
+                    }
+                    if session != Session(self.session.load(Ordering::SeqCst)) {
+                        log::warn!("{}: client connected, stopping idle analysis", session.0);
+                        engine.ensure_idle(session).await?;
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run a single search for a client without a connection, taking over
+    /// the engine like a new session. Returns the engine output up to the
+    /// bestmove. The search ends early if another session takes over.
+    pub async fn search_once(
+        &self,
+        client: &str,
+        limits: &SessionLimits,
+        commands: Vec<UciIn>,
+    ) -> io::Result<Vec<UciOut>> {
+        let session = Session(self.session.fetch_add(1, Ordering::SeqCst) + 1);
+        log::warn!("{}: starting single search ...", session.0);
+        self.notify.notify_one();
+        let mut engine = self.engine.lock().await;
+        engine.ensure_newgame(session).await?;
+        engine.restrict(session, limits).await?;
+        engine.set_client(client);
+        for command in commands {
+            if let Some(command) = self.middleware.client_command(command) {
+                engine.send(session, command).await?;
+            }
+        }
+        let mut output = Vec::new();
+        loop {
+            tokio::select! {
+                command = engine.recv(session) => {
+                    match self.middleware.engine_command(command?) {
+                        Some(UciOut::Bestmove { .. }) => return Ok(output),
+                        Some(command) => output.push(command),
+                        None => (),
+                    }
+                }
+                _ = self.notify.notified() => {
+                    if session != Session(self.session.load(Ordering::SeqCst)) {
+                        log::warn!("{}: another session takes over, stopping search", session.0);
+                        engine.send(session, UciIn::Stop).await?;
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[derive(Eq, Serialize, Deserialize, Clone, Debug)]
 pub struct Secret(pub String);
 
+/// A lichess-compatible site that the engine is advertised to, with its own
+/// secret.
+pub struct Frontend {
+    pub url: String,
+    pub secret: Secret,
+}
+
+/// Query parameters of the client, except for credentials, which are up
+/// to the authenticator.
 #[derive(Deserialize)]
 pub struct Params {
-    secret: Secret,
-    #[serde(rename = "session")]
-    _session: String,
+    session: String,
+    preset: Option<String>,
+    info_filter: Option<InfoFilter>,
+    perspective: Option<Perspective>,
+}
+
+/// Settings selected by the client when connecting.
+#[derive(Default)]
+pub struct SessionParams {
+    preset: Option<Preset>,
+    limits: SessionLimits,
+    info_filter: Option<InfoFilter>,
+    perspective: Option<Perspective>,
+    protocol: Protocol,
+    /// Frontend or guest that authenticated the session.
+    client: String,
+    guest: Option<String>,
+    /// Session token of the client, reused when it reconnects.
+    token: String,
+    /// Network address of the client, if known.
+    ip: Option<IpAddr>,
 }
 
 impl Secret {
@@ -68,24 +455,273 @@ impl PartialEq for Secret {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handler(
     engine: Arc<SharedEngine>,
-    secret: Secret,
+    authenticator: Arc<dyn Authenticator>,
+    config: Arc<Config>,
+    pool: Arc<Pool>,
     Query(params): Query<Params>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    client_ip: Option<Extension<ClientIp>>,
     ws: WebSocketUpgrade,
-) -> Result<impl IntoResponse, StatusCode> {
-    if secret == params.secret {
-        Ok(ws.on_upgrade(move |socket| handle_socket(engine, socket)))
-    } else {
-        Err(StatusCode::FORBIDDEN)
+) -> Result<Response, StatusCode> {
+    let identity = authenticator
+        .authenticate(&AuthRequest {
+            headers,
+            query: serde_urlencoded::from_str(query.as_deref().unwrap_or_default())
+                .map_err(|_| StatusCode::BAD_REQUEST)?,
+        })
+        .await?;
+    let ip = client_ip.map(|Extension(ClientIp(ip))| ip);
+    let params = session_params(&config, identity, params, ip)?;
+    if engine.is_paused() || !engine.health().is_running() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    if pool.splits() && params.limits.is_unrestricted() {
+        let workers = pool.connect_all(&forwarded_query(query.as_deref())).await;
+        if !workers.is_empty() {
+            return Ok(ws
+                .on_upgrade(move |socket: WebSocket| split::handle_socket(socket, workers))
+                .into_response());
+        }
+    }
+
+    // Hand over to a worker while the local engine is busy. Sessions with
+    // limits stay local, because workers would not know about them.
+    if !pool.is_empty() && engine.is_busy() && params.limits.is_unrestricted() {
+        let query = forwarded_query(query.as_deref());
+        if let Some(forwarded) = pool.connect(&query).await {
+            return Ok(ws
+                .on_upgrade(move |socket: WebSocket| pool::forward(socket, pool, query, forwarded))
+                .into_response());
+        }
+    }
+
+    Ok(ws
+        .protocols([gzip::PROTOCOL, PROTOCOL_V1])
+        .on_upgrade(move |socket: WebSocket| {
+            let params = SessionParams {
+                protocol: Protocol::from_selected(socket.protocol()),
+                ..params
+            };
+            handle_socket(engine, socket, params)
+        })
+        .into_response())
+}
+
+/// Query parameters of the client, except for the secret.
+fn forwarded_query(query: Option<&str>) -> String {
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query.unwrap_or_default()).unwrap_or_default();
+    serde_urlencoded::to_string(
+        params
+            .into_iter()
+            .filter(|(key, _)| key != "secret")
+            .collect::<Vec<_>>(),
+    )
+    .expect("serialize query")
+}
+
+/// Look up the preset selected by an authenticated client.
+pub fn session_params(
+    config: &Config,
+    identity: Identity,
+    params: Params,
+    ip: Option<IpAddr>,
+) -> Result<SessionParams, StatusCode> {
+    let Identity {
+        client,
+        guest,
+        limits,
+    } = identity;
+    let preset = match params.preset {
+        Some(name) => Some(
+            config
+                .presets
+                .get(&name)
+                .cloned()
+                .ok_or(StatusCode::BAD_REQUEST)?,
+        ),
+        None => None,
+    };
+    match ip {
+        Some(ip) => log::info!("Accepted connection from {client} at {ip}"),
+        None => log::info!("Accepted connection from {client}"),
+    }
+    Ok(SessionParams {
+        preset,
+        limits,
+        info_filter: params.info_filter,
+        perspective: params.perspective,
+        protocol: Protocol::Plain,
+        client,
+        guest,
+        token: params.session,
+        ip,
+    })
+}
+
+/// Serve a session over any transport that carries WebSocket messages.
+pub async fn handle_socket<S>(shared_engine: Arc<SharedEngine>, socket: S, params: SessionParams)
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Sink<Message, Error = axum::Error> + Unpin,
+{
+    // Write from a separate future, so that a slow client does not stall
+    // reading from the engine.
+    let (mut sink, stream) = socket.split();
+    let outbox = Outbox::new();
+    let started = Instant::now();
+    shared_engine
+        .notifications
+        .send(Notification::SessionStarted {
+            client: params.client.clone(),
+        });
+    let registration =
+        shared_engine
+            .metrics
+            .register(params.client.clone(), params.guest.clone(), params.ip);
+    let metrics = registration.metrics();
+    let stream = stream
+        .inspect(|message| {
+            if let Ok(message) = message {
+                metrics.received(message_len(message));
+            }
+        })
+        .flat_map(|message| {
+            stream::iter(if params.protocol == Protocol::V1Gzip {
+                gzip::decode(message)
+            } else {
+                vec![message]
+            })
+        });
+    let mut stream = Delayed::new(stream, shared_engine.simulated_latency);
+    if let Some(version) = params.protocol.version() {
+        let _ = outbox.push(
+            Message::Text(
+                UciOut::info_string(format!(
+                    "remote-uci protocol {version} provider {}",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .to_string(),
+            ),
+            None,
+        );
+    }
+    let session = async {
+        let frame =
+            match handle_socket_inner(&shared_engine, &mut stream, &outbox, &params, &registration)
+                .await
+            {
+                Ok(()) => None,
+                Err(reason) => {
+                    if reason.is_error() {
+                        log::error!("handler: {}", reason);
+                    }
+                    if let CloseReason::Engine(ref err) = reason {
+                        if supervisor::is_engine_gone(err) {
+                            shared_engine.report_failure(err);
+                        }
+                    }
+                    Some(reason.frame())
+                }
+            };
+        let _ = outbox.push(Message::Close(frame), None);
+        outbox.close();
+    };
+    let writer = async {
+        let mut outgoing = Delayed::new(Box::pin(outbox.stream()), shared_engine.simulated_latency);
+        while let Some(message) = outgoing.next().await {
+            let len = message_len(&message);
+            if sink.send(message).await.is_err() {
+                outbox.close();
+                break;
+            }
+            metrics.sent(len);
+        }
+    };
+    tokio::join!(session, writer);
+    log::info!("Connection closed: {}", metrics.snapshot());
+    shared_engine
+        .notifications
+        .send(Notification::SessionEnded {
+            client: params.client.clone(),
+            seconds: started.elapsed().as_secs(),
+        });
+}
+
+/// Private use close code for sessions that stopped answering pings.
+const CLOSE_PING_TIMEOUT: u16 = 4000;
+
+/// Private use close code for sessions closed through the admin API.
+const CLOSE_KICKED: u16 = 4001;
+
+/// Why the provider ended a session. Sent to the client in the close frame,
+/// so that it can tell the user.
+#[derive(Error, Debug)]
+enum CloseReason {
+    #[error("ping timeout")]
+    PingTimeout,
+    #[error("host resumed from sleep")]
+    Resumed,
+    #[error("disconnected by the operator")]
+    Kicked,
+    #[error("binary messages not supported")]
+    BinaryMessage,
+    #[error("invalid command: {0}")]
+    InvalidCommand(io::Error),
+    #[error("engine error: {0}")]
+    Engine(#[from] io::Error),
+    #[error("connection failed: {0}")]
+    Connection(io::Error),
+}
+
+impl CloseReason {
+    /// Errors of the client that sent a command, as opposed to errors of
+    /// the engine.
+    fn rejected(err: io::Error) -> CloseReason {
+        match err.kind() {
+            io::ErrorKind::BrokenPipe => CloseReason::Engine(err),
+            _ => CloseReason::InvalidCommand(err),
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            CloseReason::PingTimeout | CloseReason::Resumed | CloseReason::Kicked
+        )
+    }
+
+    fn frame(&self) -> CloseFrame<'static> {
+        let code = match self {
+            CloseReason::PingTimeout => CLOSE_PING_TIMEOUT,
+            CloseReason::Resumed => close_code::RESTART,
+            CloseReason::Kicked => CLOSE_KICKED,
+            CloseReason::BinaryMessage => close_code::UNSUPPORTED,
+            CloseReason::InvalidCommand(_) => close_code::POLICY,
+            CloseReason::Engine(_) | CloseReason::Connection(_) => close_code::ERROR,
+        };
+        // The reason must fit into a control frame.
+        let mut reason = self.to_string();
+        while reason.len() > 123 {
+            reason.pop();
+        }
+        CloseFrame {
+            code,
+            reason: reason.into(),
+        }
     }
 }
 
-async fn handle_socket(shared_engine: Arc<SharedEngine>, mut socket: WebSocket) {
-    if let Err(err) = handle_socket_inner(&shared_engine, &mut socket).await {
-        log::error!("handler: {}", err);
+fn message_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(_) => 0,
     }
-    let _ = socket.send(Message::Close(None)).await;
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -94,20 +730,58 @@ enum Event {
     Engine(io::Result<UciOut>),
     CheckSession,
     Tick,
+    Resumed,
+    Kicked,
 }
 
-async fn handle_socket_inner(
+async fn handle_socket_inner<S>(
     shared_engine: &SharedEngine,
-    socket: &mut WebSocket,
-) -> io::Result<()> {
+    socket: &mut S,
+    outbox: &Outbox,
+    params: &SessionParams,
+    registration: &Registration,
+) -> Result<(), CloseReason>
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let metrics = registration.metrics();
     let mut locked_engine: Option<MutexGuard<Engine>> = None;
     let mut session = Session(0);
+    let mut saved: Option<SessionState> = None;
+    let mut restores = 0;
+    let mut resumed = shared_engine.resumed.subscribe();
+    let mut ponder: Option<Ponder> = None;
+    let mut cached: Option<Cached> = None;
+    let mut hold_back: Option<u32> = None;
 
     let mut missed_pong = false;
+    let mut ping_sent = Instant::now();
     let mut timeout = interval(Duration::from_secs(10));
     timeout.set_missed_tick_behavior(MissedTickBehavior::Delay);
     timeout.reset();
 
+    // Pick up the search of a previous connection that dropped.
+    if let Some(ref lingering) = shared_engine.lingering {
+        if let Some(Handoff {
+            session: previous,
+            replay,
+        }) = lingering.reattach(&params.client, &params.token).await
+        {
+            log::warn!(
+                "{}: client reconnected, replaying {} messages",
+                previous.0,
+                replay.len()
+            );
+            for message in replay {
+                outbox
+                    .push(message, None)
+                    .map_err(CloseReason::Connection)?;
+            }
+            session = previous;
+            locked_engine = Some(shared_engine.engine.lock().await);
+        }
+    }
+
     loop {
         // Try to end session if another session wants to take over.
         // We send a stop command, and keep the previous session the engine
@@ -120,6 +794,7 @@ async fn handle_socket_inner(
                 }
                 if engine.is_idle() {
                     log::warn!("{}: session ended", session.0);
+                    saved = Some(engine.session_state());
                 } else {
                     locked_engine = Some(engine);
                 }
@@ -131,15 +806,19 @@ async fn handle_socket_inner(
         // Select next event to handle.
         let event = if let Some(ref mut engine) = locked_engine {
             tokio::select! {
-                engine_in = socket.recv() => Event::Socket(engine_in),
+                engine_in = socket.next() => Event::Socket(engine_in),
                 engine_out = engine.recv(session) => Event::Engine(engine_out),
                 _ = shared_engine.notify.notified() => Event::CheckSession,
                 _ = timeout.tick() => Event::Tick,
+                _ = resumed.changed() => Event::Resumed,
+                _ = registration.kicked().notified() => Event::Kicked,
             }
         } else {
             tokio::select! {
-                engine_in = socket.recv() => Event::Socket(engine_in),
+                engine_in = socket.next() => Event::Socket(engine_in),
                 _ = timeout.tick() => Event::Tick,
+                _ = resumed.changed() => Event::Resumed,
+                _ = registration.kicked().notified() => Event::Kicked,
             }
         };
 
@@ -147,25 +826,58 @@ async fn handle_socket_inner(
         match event {
             Event::CheckSession => continue,
 
+            Event::Resumed => {
+                log::warn!("{}: closing connection after system resume", session.0);
+                break Err(CloseReason::Resumed);
+            }
+
+            Event::Kicked => {
+                log::warn!("{}: closing connection as requested by operator", session.0);
+                if let Some(ref mut engine) = locked_engine {
+                    engine.ensure_idle(session).await?;
+                }
+                break Err(CloseReason::Kicked);
+            }
+
             Event::Tick => {
                 if missed_pong {
                     log::error!("{}: ping timeout", session.0);
                     if let Some(ref mut engine) = locked_engine {
-                        engine.ensure_idle(session).await?;
+                        release(shared_engine, engine, session, params, ponder.is_some()).await?;
                     }
-                    break Ok(());
+                    break Err(CloseReason::PingTimeout);
                 } else {
-                    socket
-                        .send(Message::Ping(Vec::new()))
-                        .await
-                        .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+                    outbox
+                        .push(Message::Ping(Vec::new()), None)
+                        .map_err(CloseReason::Connection)?;
+                    ping_sent = Instant::now();
                     missed_pong = true;
                 }
             }
 
             Event::Socket(Some(Ok(Message::Text(text)))) => {
-                if let Some(command) = UciIn::from_line(&text)
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+                let command = match UciIn::from_line(&text) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        log::error!(
+                            "{}: rejected {:?}: {}",
+                            session.0,
+                            shared_engine.privacy.redact(&text.as_str()),
+                            err
+                        );
+                        outbox
+                            .push(
+                                Message::Text(
+                                    UciOut::info_string(format!("error: {err}")).to_string(),
+                                ),
+                                None,
+                            )
+                            .map_err(CloseReason::Connection)?;
+                        continue;
+                    }
+                };
+                if let Some(command) =
+                    command.and_then(|command| shared_engine.middleware.client_command(command))
                 {
                     let mut engine = match locked_engine.take() {
                         Some(engine) => engine,
@@ -182,52 +894,252 @@ async fn handle_socket_inner(
                             let mut engine = shared_engine.engine.lock().await;
                             log::warn!("{}: new session started", session.0);
                             engine.ensure_newgame(session).await?;
+                            engine.restrict(session, &params.limits).await?;
+                            engine.set_client(&params.client);
+                            if let Some(ref preset) = params.preset {
+                                engine.apply_preset(session, preset).await?;
+                            }
+                            if let Some(info_filter) = params.info_filter {
+                                engine.set_info_filter(info_filter);
+                            }
+                            if let Some(perspective) = params.perspective {
+                                engine.set_perspective(perspective);
+                            }
 
-                            // TODO: Should track and restore options and
-                            // positions of the session. Not required for
-                            // lichess.org.
+                            // Continue with the options and position the
+                            // client had set, even if the engine was
+                            // restarted in the meantime.
+                            if let Some(state) = saved.take() {
+                                engine.resume(session, state).await?;
+                            }
                             engine
                         }
                     };
 
-                    engine.send(session, command).await?;
+                    // Stop pondering before anything else, but keep the
+                    // results if the client continues as expected.
+                    let hit = cached.take();
+                    if let Some(pondering) = ponder.take() {
+                        engine.ensure_idle(session).await?;
+                        match pondering.end(&command) {
+                            Ended::Hit(hit) => cached = Some(hit),
+                            Ended::Miss(Some(previous)) => engine
+                                .send(session, previous)
+                                .await
+                                .map_err(CloseReason::rejected)?,
+                            Ended::Miss(None) => (),
+                        }
+                    }
+                    let go = matches!(command, UciIn::Go { .. });
+
+                    engine
+                        .send(session, command)
+                        .await
+                        .map_err(CloseReason::rejected)?;
+                    if let (true, Some(hit)) = (go, hit) {
+                        log::info!("{}: ponder hit at depth {}", session.0, hit.depth);
+                        for info in hit.infos {
+                            outbox
+                                .push(Message::Text(info.to_string()), superseded_line(&info))
+                                .map_err(CloseReason::Connection)?;
+                        }
+                        hold_back = Some(hit.depth);
+                    }
                     locked_engine = Some(engine);
                 }
             }
-            Event::Socket(Some(Ok(Message::Pong(_)))) => missed_pong = false,
-            Event::Socket(Some(Ok(Message::Ping(data)))) => socket
-                .send(Message::Pong(data))
-                .await
-                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?,
+            Event::Socket(Some(Ok(Message::Pong(_)))) => {
+                if missed_pong {
+                    metrics.set_rtt(ping_sent.elapsed());
+                }
+                missed_pong = false;
+            }
+            Event::Socket(Some(Ok(Message::Ping(data)))) => outbox
+                .push(Message::Pong(data), None)
+                .map_err(CloseReason::Connection)?,
             Event::Socket(Some(Ok(Message::Binary(_)))) => {
                 if let Some(ref mut engine) = locked_engine {
                     engine.ensure_idle(session).await?;
                 }
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "binary messages not supported",
-                ));
+                return Err(CloseReason::BinaryMessage);
             }
-            Event::Socket(None | Some(Ok(Message::Close(_)))) => {
+            Event::Socket(Some(Ok(Message::Close(_)))) => {
                 if let Some(ref mut engine) = locked_engine {
                     engine.ensure_idle(session).await?;
                 }
                 break Ok(());
             }
+            Event::Socket(None) => {
+                if let Some(ref mut engine) = locked_engine {
+                    release(shared_engine, engine, session, params, ponder.is_some()).await?;
+                }
+                break Ok(());
+            }
             Event::Socket(Some(Err(err))) => {
                 if let Some(ref mut engine) = locked_engine {
-                    engine.ensure_idle(session).await?;
+                    release(shared_engine, engine, session, params, ponder.is_some()).await?;
                 }
-                return Err(io::Error::new(io::ErrorKind::BrokenPipe, err));
+                return Err(CloseReason::Connection(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    err,
+                )));
             }
 
             Event::Engine(Ok(command)) => {
-                socket
-                    .send(Message::Text(command.to_string()))
-                    .await
-                    .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))?;
+                let command = match shared_engine.middleware.engine_command(command) {
+                    Some(command) => command,
+                    None => continue,
+                };
+                if let Some(ref mut pondering) = ponder {
+                    pondering.record(command);
+                    continue;
+                }
+                match command {
+                    UciOut::Info {
+                        depth: Some(depth),
+                        string: None,
+                        ..
+                    } if hold_back.map_or(false, |held| depth <= held) => continue,
+                    UciOut::Bestmove { .. } => hold_back = None,
+                    _ => (),
+                }
+                if matches!(command, UciOut::Info { .. }) {
+                    metrics.info_line();
+                }
+                outbox
+                    .push(
+                        Message::Text(command.to_string()),
+                        superseded_line(&command),
+                    )
+                    .map_err(CloseReason::Connection)?;
+                if let (UciOut::Bestmove { m: Some(ref m), .. }, Some(engine)) =
+                    (command, &mut locked_engine)
+                {
+                    if shared_engine.ponder_ahead && engine.is_idle() {
+                        if let Some(pondering) = engine
+                            .position()
+                            .and_then(|position| Ponder::after(position, m))
+                        {
+                            // Pondering is optional, so failures are left
+                            // for the next command of the client to notice.
+                            let sent = engine
+                                .search_for_server(
+                                    session,
+                                    pondering.position().clone(),
+                                    Ponder::go(),
+                                )
+                                .await;
+                            match sent {
+                                Ok(()) => ponder = Some(pondering),
+                                Err(err) => {
+                                    log::warn!("{}: could not ponder: {err}", session.0)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Event::Engine(Err(err)) => match locked_engine {
+                Some(ref mut engine)
+                    if supervisor::is_engine_gone(&err) && restores < MAX_RESTORES =>
+                {
+                    restores += 1;
+                    log::error!("{}: engine failed, restoring session: {err}", session.0);
+                    shared_engine
+                        .notifications
+                        .send(Notification::EngineCrashed {
+                            error: err.to_string(),
+                        });
+                    engine.restore(session).await?;
+                    // The replacement may be the standby engine, or
+                    // otherwise differ from the failed one.
+                    shared_engine.info.send_replace(Arc::new(engine.info()));
+                }
+                _ => return Err(CloseReason::Engine(err)),
+            },
+        }
+    }
+}
+
+/// The MultiPV line of an info that later infos of the line supersede, so
+/// that it can be dropped for slow clients.
+fn superseded_line(command: &UciOut) -> Option<u32> {
+    match *command {
+        UciOut::Info {
+            string: None,
+            multipv,
+            ..
+        } => Some(multipv.map_or(1, NonZeroU32::get)),
+        _ => None,
+    }
+}
+
+/// Stop the search of a client that went away without closing the
+/// connection, unless it may come back to pick up an infinite search.
+async fn release(
+    shared_engine: &SharedEngine,
+    engine: &mut Engine,
+    session: Session,
+    params: &SessionParams,
+    pondering: bool,
+) -> io::Result<()> {
+    match shared_engine.lingering {
+        Some(ref lingering) if !pondering && engine.is_searching_infinite() => {
+            linger(shared_engine, lingering, engine, session, params).await
+        }
+        _ => engine.ensure_idle(session).await,
+    }
+}
+
+/// Keep searching and buffer the output, until the client reconnects,
+/// another session takes over, or time runs out.
+async fn linger(
+    shared_engine: &SharedEngine,
+    lingering: &Lingering,
+    engine: &mut Engine,
+    session: Session,
+    params: &SessionParams,
+) -> io::Result<()> {
+    log::warn!(
+        "{}: client dropped, searching for up to {} more seconds ...",
+        session.0,
+        lingering.duration().as_secs()
+    );
+    let mut reattach = lingering.park(&params.client, &params.token);
+    let buffer = Outbox::new();
+    let deadline = sleep(lingering.duration());
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            engine_out = engine.recv(session) => {
+                if let Some(command) = shared_engine.middleware.engine_command(engine_out?) {
+                    let line = superseded_line(&command);
+                    let _ = buffer.push(Message::Text(command.to_string()), line);
+                }
+            }
+            Ok(handoff) = &mut reattach => {
+                buffer.close();
+                let mut replay = Vec::new();
+                while let Some(message) = buffer.pop().await {
+                    replay.push(message);
+                }
+                if handoff.send(Handoff { session, replay }).is_ok() {
+                    return Ok(());
+                }
+                break;
+            }
+            _ = shared_engine.notify.notified() => {
+                if session != Session(shared_engine.session.load(Ordering::SeqCst)) {
+                    log::warn!("{}: another session takes over", session.0);
+                    break;
+                }
+            }
+            _ = &mut deadline => {
+                log::warn!("{}: client did not come back in time", session.0);
+                break;
             }
-            Event::Engine(Err(err)) => return Err(err),
         }
     }
+    lingering.unpark(&params.client, &params.token);
+    engine.ensure_idle(session).await
 }